@@ -0,0 +1,59 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use tfhe::integer::gen_keys_radix;
+use tfhe::shortint::parameters::{Parameters, PARAM_MESSAGE_1_CARRY_1, PARAM_MESSAGE_2_CARRY_2};
+
+use fhe_regex::regex::ciphertext::encrypt_str;
+use fhe_regex::regex::engine::{has_match_warm, MatchOptions};
+use fhe_regex::regex::test_keys::test_keys;
+use fhe_regex::regex::warmup::warmup;
+
+// Every layout encodes a character (8 bits) in blocks of the parameters'
+// message size. The engine itself only runs on the 2_2 layout (see
+// ciphertext::validate_layout), the primitive operations are benched on
+// both to back the choice of layout with numbers.
+const LAYOUTS: [(&str, Parameters, usize); 2] = [
+    ("1_1x8", PARAM_MESSAGE_1_CARRY_1, 8),
+    ("2_2x4", PARAM_MESSAGE_2_CARRY_2, 4),
+];
+
+fn bench_primitives(c: &mut Criterion) {
+    let mut group = c.benchmark_group("primitives");
+    group.sample_size(10);
+    for (name, params, num_blocks) in LAYOUTS {
+        let (client_key, server_key) = gen_keys_radix(&params, num_blocks);
+        let ct_a = client_key.encrypt(b'a' as u64);
+        let ct_b = client_key.encrypt(b'b' as u64);
+
+        group.bench_with_input(BenchmarkId::new("ct_eq", name), &(), |b, _| {
+            b.iter(|| server_key.smart_eq(&mut ct_a.clone(), &mut ct_b.clone()))
+        });
+        group.bench_with_input(BenchmarkId::new("ct_and", name), &(), |b, _| {
+            b.iter(|| server_key.smart_bitand(&mut ct_a.clone(), &mut ct_b.clone()))
+        });
+    }
+    group.finish();
+}
+
+fn bench_matches(c: &mut Criterion) {
+    let (client_key, server_key) = test_keys();
+    let key = warmup(&server_key);
+
+    let mut group = c.benchmark_group("matches");
+    group.sample_size(10);
+    for (content, pattern) in [
+        ("a", "/^[a-z]$/"),
+        ("a", "/^[^a-z]$/"),
+        ("abc", "/abc/"),
+        ("abc", "/a.c/"),
+        ("abcdef", "/b?c+d/"),
+    ] {
+        let ct_content = encrypt_str(&client_key, content).unwrap();
+        group.bench_with_input(BenchmarkId::new(pattern, content), &(), |b, _| {
+            b.iter(|| has_match_warm(&key, &ct_content, pattern, &MatchOptions::default()).unwrap())
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_primitives, bench_matches);
+criterion_main!(benches);
@@ -0,0 +1,15 @@
+#[macro_use]
+extern crate log;
+
+pub mod regex;
+
+// the stable api, see regex::api
+pub use regex::api::{ClientKey, ContentCiphertext, Decryptor, EncryptKey, EvalKey, MatchCiphertext};
+#[cfg(feature = "server")]
+pub use regex::engine::{Engine, MatchOptions, ShortContent};
+#[cfg(feature = "server")]
+pub use regex::observe::Observer;
+#[cfg(feature = "server")]
+pub use regex::scheduler::EvalOrder;
+pub use regex::parser::{AnchorPrecedence, ParseLimits};
+pub use regex::profile::Profile;
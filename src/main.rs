@@ -1,23 +1,656 @@
 #[macro_use]
 extern crate log;
 
-mod regex;
-
 use std::env;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 use env_logger::Env;
+use zeroize::Zeroizing;
+
+use fhe_regex::regex;
+use fhe_regex::regex::RunFiles;
+use fhe_regex::regex::ciphertext::{
+    deserialize_compressed_server_key, deserialize_server_key, gen_compressed_server_key,
+    gen_client_key, gen_server_key, serialize_compressed_server_key, serialize_server_key, with_seed,
+};
+use fhe_regex::regex::client::{
+    export_client_key, import_client_key, load_client_key, reencrypt_content_to_writer,
+    save_client_key, save_encryption_key, EncryptionKey,
+};
+use fhe_regex::regex::config::{ConfigWatcher, ServeConfig, RELOAD_INTERVAL};
+use fhe_regex::regex::engine::{check_budget, MatchOptions};
+use fhe_regex::regex::estimate::{estimate_protocol, plan_summary};
+use fhe_regex::regex::explain::TracedRun;
+use fhe_regex::regex::leakage::leakage_reports;
+use fhe_regex::regex::parser::{
+    parse_with, AnchorPrecedence, ParseLimits,
+};
+use fhe_regex::regex::preprocess::Preprocess;
+use fhe_regex::regex::profile::Profile;
+use fhe_regex::regex::provenance::SignedProvenance;
+use fhe_regex::regex::query::Query;
+use fhe_regex::regex::rules::RuleSet;
+use fhe_regex::regex::serve::{ServerLimits, UdsServer};
+use fhe_regex::regex::shard::{register_worker, Coordinator, WorkerCapabilities, HEARTBEAT_INTERVAL};
+use fhe_regex::regex::storage::MappedContent;
+use fhe_regex::regex::test_keys::{generate_test_keys, verify_test_keys};
+use fhe_regex::regex::warmup::warmup;
 
 fn main() {
     let env = Env::default().filter_or("RUST_LOG", "info");
     env_logger::init_from_env(env);
 
-    let args: Vec<String> = env::args().collect();
-    let content = &args[1];
-    let pattern = &args[2];
+    match env::args().nth(1).as_deref() {
+        Some("test-keys") => return test_keys(env::args().skip(2).collect()),
+        Some("serve") => return serve(env::args().skip(2).collect()),
+        Some("verify-plan") => return verify_plan(env::args().skip(2).collect()),
+        Some("keygen") => return keygen(env::args().skip(2).collect()),
+        Some("export-key") => return export_key(env::args().skip(2).collect()),
+        Some("import-key") => return import_key(env::args().skip(2).collect()),
+        Some("rotate-content") => return rotate_content(env::args().skip(2).collect()),
+        Some("estimate") => return estimate(env::args().skip(2).collect()),
+        Some("leakage") => return leakage(env::args().skip(2).collect()),
+        Some("scan") => return scan(env::args().skip(2).collect()),
+        Some("explain") => return explain(env::args().skip(2).collect()),
+        _ => (),
+    }
 
-    match crate::regex::parser::parse(pattern) {
+    let mut flags: Vec<String> = vec![];
+    let mut args: Vec<String> = vec![];
+    let mut profile = Profile::default();
+    let mut match_opts = MatchOptions::default();
+    let mut plan_cache: Option<PathBuf> = None;
+    let mut trace_ops: Option<PathBuf> = None;
+    let mut trace_results: Option<PathBuf> = None;
+    let mut provenance: Option<PathBuf> = None;
+    let mut provenance_key: Option<PathBuf> = None;
+    let mut query: Option<PathBuf> = None;
+    let mut argv = env::args().skip(1);
+    while let Some(arg) = argv.next() {
+        if arg == "--profile" {
+            let name = argv.next().expect("--profile requires a profile name");
+            profile = name.parse().unwrap_or_else(|e| panic!("{}", e));
+        } else if arg == "--engine" {
+            let name = argv.next().expect("--engine requires an engine name");
+            match_opts.engine = name.parse().unwrap_or_else(|e| panic!("{}", e));
+        } else if arg == "--plan-cache" {
+            let dir = argv.next().expect("--plan-cache requires a directory");
+            plan_cache = Some(PathBuf::from(dir));
+        } else if arg == "--threads" {
+            let n = argv.next().expect("--threads requires a number");
+            match_opts.threads = Some(n.parse().expect("--threads requires a number"));
+        } else if arg == "--eval-order" {
+            let name = argv.next().expect("--eval-order requires an order");
+            match_opts.eval_order = name.parse().unwrap_or_else(|e| panic!("{}", e));
+        } else if arg == "--max-bootstraps" {
+            let max = argv.next().expect("--max-bootstraps requires a number");
+            match_opts.max_bootstraps = Some(max.parse().expect("--max-bootstraps requires a number"));
+        } else if arg == "--max-memory-mib" {
+            let max = argv.next().expect("--max-memory-mib requires a number");
+            match_opts.max_memory = Some(mib_to_bytes(&max));
+        } else if arg == "--trace-ops" {
+            let path = argv.next().expect("--trace-ops requires a file");
+            trace_ops = Some(PathBuf::from(path));
+        } else if arg == "--trace-results" {
+            let path = argv.next().expect("--trace-results requires a file");
+            trace_results = Some(PathBuf::from(path));
+        } else if arg == "--provenance" {
+            let path = argv.next().expect("--provenance requires a file");
+            provenance = Some(PathBuf::from(path));
+        } else if arg == "--provenance-key" {
+            let path = argv.next().expect("--provenance-key requires a file");
+            provenance_key = Some(PathBuf::from(path));
+        } else if arg == "--max-pattern-len" {
+            let max = argv.next().expect("--max-pattern-len requires a number");
+            match_opts.parse_limits.max_len = max.parse().expect("--max-pattern-len requires a number");
+        } else if arg == "--max-nesting-depth" {
+            let max = argv.next().expect("--max-nesting-depth requires a number");
+            match_opts.parse_limits.max_depth = max.parse().expect("--max-nesting-depth requires a number");
+        } else if arg == "--max-repetitions" {
+            let max = argv.next().expect("--max-repetitions requires a number");
+            match_opts.parse_limits.max_repetitions =
+                max.parse().expect("--max-repetitions requires a number");
+        } else if arg == "--max-content-len" {
+            let max = argv.next().expect("--max-content-len requires a number");
+            match_opts.max_content_len = max.parse().expect("--max-content-len requires a number");
+        } else if arg == "--query" {
+            let path = argv.next().expect("--query requires a file");
+            query = Some(PathBuf::from(path));
+        } else if arg.starts_with("--") {
+            flags.push(arg);
+        } else {
+            args.push(arg);
+        }
+    }
+    if let Some(path) = query {
+        return run_query(&path, match_opts.parse_limits);
+    }
+    let has_flag = |flag: &str| flags.iter().any(|f| f == flag);
+    if has_flag("--standard-anchors") {
+        match_opts.precedence = AnchorPrecedence::Standard;
+    }
+    match_opts.case_insensitive = has_flag("--ignore-case");
+    match_opts.sanitize_output = has_flag("--sanitize-output");
+    // the content may be empty, but has to be given
+    if args.len() != 2 {
+        panic!("usage: fhe-regex [flags] <content> <pattern>");
+    }
+    let content = &args[0];
+    let pattern = &args[1];
+    // summarizes the plan instead of generating keys and matching
+    if has_flag("--plan-only") {
+        let summary = plan_summary(content.len(), pattern, &match_opts, profile)
+            .unwrap_or_else(|e| panic!("{}", e));
+        println!("{}", summary);
+        return;
+    }
+
+    let opts = Preprocess {
+        lowercase: has_flag("--lowercase"),
+        normalize_whitespace: has_flag("--normalize-whitespace"),
+        strip_punctuation: has_flag("--strip-punctuation"),
+    };
+
+    let provenance_key = provenance_key.map(|path| read_file(&path.to_string_lossy()));
+    let files = RunFiles {
+        plan_cache: plan_cache.as_deref(),
+        trace_ops: trace_ops.as_deref(),
+        trace_results: trace_results.as_deref(),
+        provenance: provenance.as_deref(),
+        provenance_key: provenance_key.as_deref(),
+    };
+    let res = run(content, pattern, &opts, profile, &match_opts, &files);
+    println!("res: {:?}", res);
+}
+
+// runs the job described by a query file (see regex::query), in place of the
+// positional arguments and flags, but for the parse limits
+fn run_query(path: &Path, parse_limits: ParseLimits) {
+    let query = Query::load(path).unwrap_or_else(|e| panic!("{}", e));
+    let content = query.content().unwrap_or_else(|e| panic!("{}", e));
+    let profile = query.profile().unwrap_or_else(|e| panic!("{}", e));
+    let mut match_opts = query.match_options().unwrap_or_else(|e| panic!("{}", e));
+    match_opts.parse_limits = parse_limits;
+
+    let provenance_key = query
+        .options
+        .provenance_key
+        .as_ref()
+        .map(|path| read_file(&path.to_string_lossy()));
+    let files = RunFiles {
+        plan_cache: query.options.plan_cache.as_deref(),
+        trace_ops: query.options.trace_ops.as_deref(),
+        trace_results: query.options.trace_results.as_deref(),
+        provenance: query.options.provenance.as_deref(),
+        provenance_key: provenance_key.as_deref(),
+    };
+    let res = run(
+        &content,
+        &query.pattern,
+        &query.preprocess(),
+        profile,
+        &match_opts,
+        &files,
+    );
+    match &query.output {
+        Some(output) => std::fs::write(output, format!("{}\n", res))
+            .unwrap_or_else(|e| panic!("failed to write result to {:?}: {}", output, e)),
+        None => println!("res: {:?}", res),
+    }
+}
+
+fn run(
+    content: &str,
+    pattern: &str,
+    opts: &Preprocess,
+    profile: Profile,
+    match_opts: &MatchOptions,
+    files: &RunFiles,
+) -> u64 {
+    match parse_with(pattern, match_opts.precedence, match_opts.parse_limits) {
         Ok(p) => info!("parsed: {:?}", p),
         Err(e) => panic!("failed to parse: {}", e),
     };
+    // checked here as well, to reject patterns before any keys are generated
+    if match_opts.max_bootstraps.is_some() {
+        match check_budget(pattern, content.len(), match_opts) {
+            Ok(estimate) => info!("estimated {} bootstraps", estimate),
+            Err(e) => panic!("{}", e),
+        }
+    }
+    if let Some(max_memory) = match_opts.max_memory {
+        let summary = plan_summary(content.len(), pattern, match_opts, profile)
+            .unwrap_or_else(|e| panic!("{}", e));
+        if summary.peak_memory_bytes > max_memory {
+            panic!(
+                "the match is estimated to hold {} bytes of ciphertexts at once, over the limit of {}",
+                summary.peak_memory_bytes, max_memory
+            );
+        }
+        info!("estimated peak memory of {} bytes", summary.peak_memory_bytes);
+    }
+
+    regex::main(content, pattern, opts, profile, match_opts, files)
+}
+
+// fhe-regex serve --uds <socket> (--server-key <file> | --compressed-server-key
+// <file>), serves match requests on a unix socket (see regex::serve) with the
+// given server key until killed. With --shard-worker <socket> (any number of
+// times) and --shard-len <n>, the requests are matched by the workers serving
+// on those sockets instead (see regex::shard); with --registry-uds <socket>,
+// also by the workers that register on that socket. With --register-with
+// <socket>, the server registers itself as a worker with the coordinator
+// accepting workers on that socket, and sends it heartbeats. With
+// --key-slots <n>, it keeps up to n of the server keys requests send it.
+// --max-pattern-len, --max-nesting-depth, --max-repetitions,
+// --max-content-len, --max-memory-mib, --max-bootstraps and --max-threads
+// hold every request to those limits, whatever the request asks for. With
+// --config <file>, all of these are taken from the file (see
+// regex::config), flags after it override it; while the server runs, the
+// limits are reloaded whenever the file changes, replacing those of the
+// flags.
+fn serve(args: Vec<String>) {
+    let mut socket: Option<PathBuf> = None;
+    let mut server_key = None;
+    let mut batch_window = None;
+    let mut planner_threads = None;
+    let mut shard_workers = vec![];
+    let mut shard_len = None;
+    let mut registry = None;
+    let mut register_with = None;
+    let mut key_slots = 0;
+    let mut limits = ServerLimits::default();
+    let mut config = None;
+    let mut argv = args.into_iter();
+    while let Some(arg) = argv.next() {
+        let mut value = || argv.next().unwrap_or_else(|| panic!("{} requires a value", arg));
+        match arg.as_str() {
+            "--uds" => socket = Some(PathBuf::from(value())),
+            "--config" => {
+                let path = PathBuf::from(value());
+                let loaded = ServeConfig::load(&path).unwrap_or_else(|e| panic!("{}", e));
+                socket = Some(loaded.uds.clone());
+                let key_path = |path: &Path| read_file(&path.to_string_lossy());
+                if let Some(path) = &loaded.server_key {
+                    server_key = Some(deserialize_server_key(&key_path(path)).unwrap_or_else(|e| panic!("{}", e)));
+                }
+                if let Some(path) = &loaded.compressed_server_key {
+                    server_key = Some(
+                        deserialize_compressed_server_key(&key_path(path)).unwrap_or_else(|e| panic!("{}", e)),
+                    );
+                }
+                key_slots = loaded.key_slots;
+                planner_threads = loaded.planner_threads.or(planner_threads);
+                batch_window = loaded.batch_window_ms.map(Duration::from_millis).or(batch_window);
+                shard_workers.extend(loaded.shard_workers.iter().cloned());
+                shard_len = loaded.shard_len.or(shard_len);
+                registry = loaded.registry_uds.clone().or(registry);
+                register_with = loaded.register_with.clone().or(register_with);
+                limits = loaded.limits.server_limits().unwrap_or_else(|e| panic!("{}", e));
+                config = Some((path, loaded));
+            }
+            "--shard-worker" => shard_workers.push(PathBuf::from(value())),
+            "--registry-uds" => registry = Some(PathBuf::from(value())),
+            "--register-with" => register_with = Some(PathBuf::from(value())),
+            "--key-slots" => key_slots = value().parse().expect("--key-slots requires a number"),
+            "--shard-len" => shard_len = Some(
+                value().parse::<usize>().expect("--shard-len requires a number"),
+            ),
+            "--planner-threads" => planner_threads = Some(
+                value().parse::<usize>().expect("--planner-threads requires a number"),
+            ),
+            "--batch-window-ms" => batch_window = Some(Duration::from_millis(
+                value().parse().expect("--batch-window-ms requires a number"),
+            )),
+            "--max-content-len" => limits.max_content_len =
+                value().parse().expect("--max-content-len requires a number"),
+            "--max-pattern-len" => limits.parse.max_len =
+                value().parse().expect("--max-pattern-len requires a number"),
+            "--max-nesting-depth" => limits.parse.max_depth =
+                value().parse().expect("--max-nesting-depth requires a number"),
+            "--max-repetitions" => limits.parse.max_repetitions =
+                value().parse().expect("--max-repetitions requires a number"),
+            "--max-memory-mib" => limits.max_memory = Some(mib_to_bytes(&value())),
+            "--max-bootstraps" => limits.max_bootstraps = Some(
+                value().parse().expect("--max-bootstraps requires a number"),
+            ),
+            "--max-threads" => limits.max_threads = Some(
+                value().parse().expect("--max-threads requires a number"),
+            ),
+            "--server-key" => {
+                let serialized = read_file(&value());
+                server_key = Some(deserialize_server_key(&serialized).unwrap_or_else(|e| panic!("{}", e)));
+            }
+            "--compressed-server-key" => {
+                let serialized = read_file(&value());
+                server_key = Some(
+                    deserialize_compressed_server_key(&serialized).unwrap_or_else(|e| panic!("{}", e)),
+                );
+            }
+            _ => panic!("unknown serve argument {}", arg),
+        }
+    }
+    let socket = socket.expect("serve requires --uds <socket>");
+    let server_key = server_key.expect("serve requires --server-key or --compressed-server-key");
+
+    let coordinator = match (shard_workers.is_empty() && registry.is_none(), shard_len) {
+        (true, None) => None,
+        (false, Some(shard_len)) => Some(
+            Coordinator::new(shard_workers, shard_len, server_key.clone())
+                .unwrap_or_else(|e| panic!("{}", e))
+                .with_key_slots(key_slots),
+        ),
+        _ => panic!("sharding requires --shard-len <n>, and --shard-worker <socket> or --registry-uds <socket>"),
+    };
+    if let (Some(coordinator), Some(registry)) = (&coordinator, &registry) {
+        coordinator.spawn_registry(registry).unwrap_or_else(|e| panic!("{}", e));
+    }
+
+    info!("warming up server key..");
+    let key = warmup(&server_key);
+    let mut server = UdsServer::bind(&socket, key)
+        .unwrap_or_else(|e| panic!("{}", e))
+        .with_key_slots(key_slots)
+        .with_limits(limits);
+    if let Some(coordinator) = coordinator {
+        server = server.with_sharding(coordinator);
+    }
+    if let Some(threads) = planner_threads {
+        server = server.with_planner_threads(threads).unwrap_or_else(|e| panic!("{}", e));
+    }
+    if let Some(window) = batch_window {
+        server = server.with_batching(window);
+    }
+    if let Some((path, loaded)) = config {
+        ConfigWatcher::new(path, loaded, server.limits()).spawn(RELOAD_INTERVAL);
+    }
+    if let Some(registry) = register_with {
+        let capabilities = WorkerCapabilities::of(&server_key, key_slots).unwrap_or_else(|e| panic!("{}", e));
+        let socket = socket.clone();
+        std::thread::spawn(move || {
+            if let Err(e) = register_worker(&registry, &socket, capabilities, HEARTBEAT_INTERVAL) {
+                warn!("no longer registered with {:?}: {}", registry, e);
+            }
+        });
+    }
+    server.run().unwrap_or_else(|e| panic!("{}", e));
+}
+
+// fhe-regex verify-plan [--provenance-key <file>] <record.json> <pattern>,
+// checks that the provenance record was made for the plan of pattern (and
+// its signature, given the key)
+fn verify_plan(args: Vec<String>) {
+    let mut key = None;
+    let mut positional = vec![];
+    let mut argv = args.into_iter();
+    while let Some(arg) = argv.next() {
+        if arg == "--provenance-key" {
+            let path = argv.next().expect("--provenance-key requires a file");
+            key = Some(read_file(&path));
+        } else {
+            positional.push(arg);
+        }
+    }
+    if positional.len() != 2 {
+        panic!("usage: fhe-regex verify-plan [--provenance-key <file>] <record.json> <pattern>");
+    }
+
+    let record = SignedProvenance::load(&positional[0]).unwrap_or_else(|e| panic!("{}", e));
+    if let Some(key) = key {
+        record.verify(&key).unwrap_or_else(|e| panic!("{}", e));
+        info!("the signature is valid");
+    }
+    record
+        .record
+        .verify_plan(&positional[1])
+        .unwrap_or_else(|e| panic!("{}", e));
+    info!("the record matches the plan of {}", positional[1]);
+}
+
+// fhe-regex keygen --client-key <file> [--server-key <file>]
+// [--compressed-server-key <file>] [--encryption-key <file>] [--profile <profile>]
+// [--seed-file <file>], generates a client key and the server key (and the
+// encrypt only key, see client::EncryptionKey) belonging to it. With a seed file
+// all keys are derived from its contents, the same seed file always resulting
+// in the same keys.
+fn keygen(args: Vec<String>) {
+    let mut profile = Profile::default();
+    let mut seed = None;
+    let mut client_key_path = None;
+    let mut server_key_path = None;
+    let mut compressed_server_key_path = None;
+    let mut encryption_key_path = None;
+    let mut argv = args.into_iter();
+    while let Some(arg) = argv.next() {
+        let mut value = || argv.next().unwrap_or_else(|| panic!("{} requires a value", arg));
+        match arg.as_str() {
+            "--profile" => profile = value().parse().unwrap_or_else(|e| panic!("{}", e)),
+            "--seed-file" => seed = Some(Zeroizing::new(read_file(&value()))),
+            "--client-key" => client_key_path = Some(value()),
+            "--server-key" => server_key_path = Some(value()),
+            "--compressed-server-key" => compressed_server_key_path = Some(value()),
+            "--encryption-key" => encryption_key_path = Some(value()),
+            _ => panic!("unknown keygen argument {}", arg),
+        }
+    }
+    let client_key_path = client_key_path.expect("keygen requires --client-key <file>");
+
+    let generate = || {
+        info!("generating keys ({} profile)..", profile);
+        let client_key = gen_client_key(profile);
+        save_client_key(&client_key, &client_key_path).unwrap_or_else(|e| panic!("{}", e));
+        if let Some(path) = &server_key_path {
+            let serialized = serialize_server_key(&gen_server_key(&client_key));
+            write_file(path, &serialized.unwrap_or_else(|e| panic!("{}", e)));
+        }
+        if let Some(path) = &compressed_server_key_path {
+            let serialized = serialize_compressed_server_key(&gen_compressed_server_key(&client_key));
+            write_file(path, &serialized.unwrap_or_else(|e| panic!("{}", e)));
+        }
+        if let Some(path) = &encryption_key_path {
+            let key = EncryptionKey::new(&client_key).unwrap_or_else(|e| panic!("{}", e));
+            save_encryption_key(&key, path).unwrap_or_else(|e| panic!("{}", e));
+        }
+    };
+    match seed {
+        Some(seed) => with_seed(&seed, generate),
+        None => generate(),
+    }
+}
+
+// fhe-regex export-key <client key> <backup>, encrypts the client key with a
+// password (see client::export_client_key)
+fn export_key(args: Vec<String>) {
+    if args.len() != 2 {
+        panic!("usage: fhe-regex export-key <client key> <backup>");
+    }
+    let client_key = load_client_key(&args[0]).unwrap_or_else(|e| panic!("{}", e));
+    export_client_key(&client_key, &read_password(), &args[1]).unwrap_or_else(|e| panic!("{}", e));
+    info!("client key backed up to {}", args[1]);
+}
+
+// fhe-regex import-key <backup> <client key>, restores a client key from a
+// backup made by export-key
+fn import_key(args: Vec<String>) {
+    if args.len() != 2 {
+        panic!("usage: fhe-regex import-key <backup> <client key>");
+    }
+    let client_key = import_client_key(&args[0], &read_password()).unwrap_or_else(|e| panic!("{}", e));
+    save_client_key(&client_key, &args[1]).unwrap_or_else(|e| panic!("{}", e));
+    info!("client key restored to {}", args[1]);
+}
+
+// fhe-regex scan [--profile <profile>] <rules> <content>, matches every rule
+// of the rules file (see regex::rules) on content, printing a line per rule
+fn scan(args: Vec<String>) {
+    let mut profile = Profile::default();
+    let mut positional = vec![];
+    let mut argv = args.into_iter();
+    while let Some(arg) = argv.next() {
+        if arg == "--profile" {
+            let name = argv.next().expect("--profile requires a profile name");
+            profile = name.parse().unwrap_or_else(|e| panic!("{}", e));
+        } else {
+            positional.push(arg);
+        }
+    }
+    if positional.len() != 2 {
+        panic!("usage: fhe-regex scan [--profile <profile>] <rules> <content>");
+    }
+    let rules = RuleSet::load(&positional[0]).unwrap_or_else(|e| panic!("{}", e));
+
+    let res = regex::scan(&positional[1], &rules, profile, &MatchOptions::default());
+    let (rule_res, policy_res) = res.split_at(rules.rules.len());
+    for (rule, (name, matched)) in rules.rules.iter().zip(rule_res) {
+        println!("{} ({}): {}", name, rule.severity, *matched as u8);
+    }
+    for (name, matched) in policy_res {
+        println!("{} (policy): {}", name, *matched as u8);
+    }
+}
+
+// fhe-regex estimate [--profile <profile>] <content length> <pattern>, prints
+// what matching pattern on content of that length takes (see
+// regex::estimate)
+fn estimate(args: Vec<String>) {
+    let mut profile = Profile::default();
+    let mut positional = vec![];
+    let mut argv = args.into_iter();
+    while let Some(arg) = argv.next() {
+        if arg == "--profile" {
+            let name = argv.next().expect("--profile requires a profile name");
+            profile = name.parse().unwrap_or_else(|e| panic!("{}", e));
+        } else {
+            positional.push(arg);
+        }
+    }
+    if positional.len() != 2 {
+        panic!("usage: fhe-regex estimate [--profile <profile>] <content length> <pattern>");
+    }
+    let content_len = positional[0].parse().expect("the content length must be a number");
+    let estimate = estimate_protocol(content_len, &positional[1], profile).unwrap_or_else(|e| panic!("{}", e));
+    println!("{}", estimate);
+}
+
+// fhe-regex leakage [--ignore-case] [--standard-anchors] <content length>
+// <pattern>, prints what the server learns from the query under every engine
+// that can match the pattern
+fn leakage(args: Vec<String>) {
+    let mut opts = MatchOptions::default();
+    let mut positional = vec![];
+    for arg in args {
+        match arg.as_str() {
+            "--ignore-case" => opts.case_insensitive = true,
+            "--standard-anchors" => opts.precedence = AnchorPrecedence::Standard,
+            _ => positional.push(arg),
+        }
+    }
+    if positional.len() != 2 {
+        panic!("usage: fhe-regex leakage [--ignore-case] [--standard-anchors] <content length> <pattern>");
+    }
+    let content_len = positional[0].parse().expect("the content length must be a number");
+    let reports = leakage_reports(&positional[1], content_len, &opts).unwrap_or_else(|e| panic!("{}", e));
+    let reports: Vec<String> = reports.iter().map(|report| report.to_string()).collect();
+    println!("{}", reports.join("\n\n"));
+}
+
+// fhe-regex rotate-content <old client key> <new client key> <content> <out>,
+// re-encrypts content (as written by client::encrypt_content_to_writer) from
+// the old client key to the new one
+fn rotate_content(args: Vec<String>) {
+    if args.len() != 4 {
+        panic!("usage: fhe-regex rotate-content <old client key> <new client key> <content> <out>");
+    }
+    let old_key = load_client_key(&args[0]).unwrap_or_else(|e| panic!("{}", e));
+    let new_key = load_client_key(&args[1]).unwrap_or_else(|e| panic!("{}", e));
+    let content = MappedContent::open(&args[2]).unwrap_or_else(|e| panic!("{}", e));
+    let out = std::fs::File::create(&args[3])
+        .unwrap_or_else(|e| panic!("failed to create {}: {}", args[3], e));
+    reencrypt_content_to_writer(&old_key, &new_key, &content, BufWriter::new(out), |p| {
+        info!("  {} ({}/{})", p.stage, p.done, p.total)
+    })
+    .unwrap_or_else(|e| panic!("{}", e));
+    info!("{} re-encrypted to {}", args[2], args[3]);
+}
+
+// fhe-regex explain <client key> <op trace> <op results> <content> [<id>..],
+// decrypts the results of a traced run (see --trace-results) and compares them
+// against the plaintext reference on content: the given operations, or else
+// every operation that diverged
+fn explain(args: Vec<String>) {
+    if args.len() < 4 {
+        panic!("usage: fhe-regex explain <client key> <op trace> <op results> <content> [<id>..]");
+    }
+    let client_key = load_client_key(&args[0]).unwrap_or_else(|e| panic!("{}", e));
+    let run = TracedRun::load(&args[1], &args[2]).unwrap_or_else(|e| panic!("{}", e));
+    let content = args[3].as_bytes();
+
+    if args.len() > 4 {
+        for id in &args[4..] {
+            let id: usize = id.parse().expect("operation ids are numbers");
+            let kind = run.node(id).map_or("?", |node| node.kind.as_str());
+            let decrypted = run.decrypt(&client_key, id).unwrap_or_else(|e| panic!("{}", e));
+            let expected = run.expected(id, content).unwrap_or_else(|e| panic!("{}", e));
+            println!("{} ({}): decrypted {}, expected {}", id, kind, decrypted, expected);
+        }
+        return;
+    }
+    let divergences = run.explain(&client_key, content).unwrap_or_else(|e| panic!("{}", e));
+    for d in divergences {
+        let origin = if d.inputs_agree { " <- diverged here" } else { "" };
+        println!(
+            "{} ({}): decrypted {}, expected {}{}",
+            d.id, d.kind, d.decrypted, d.expected, origin
+        );
+    }
+}
+
+// the password of key backups, from FHE_REGEX_KEY_PASSWORD or else the first
+// line of stdin, scrubbed from memory when dropped
+fn read_password() -> Zeroizing<Vec<u8>> {
+    if let Ok(password) = env::var("FHE_REGEX_KEY_PASSWORD") {
+        return Zeroizing::new(password.into_bytes());
+    }
+    let mut password = Zeroizing::new(String::new());
+    std::io::stdin()
+        .read_line(&mut password)
+        .unwrap_or_else(|e| panic!("failed to read password: {}", e));
+    let trimmed = password.trim_end_matches(['\r', '\n']);
+    if trimmed.is_empty() {
+        panic!("the password of a key backup must not be empty");
+    }
+    Zeroizing::new(trimmed.as_bytes().to_vec())
+}
+
+fn write_file(path: &str, contents: &[u8]) {
+    std::fs::write(path, contents).unwrap_or_else(|e| panic!("failed to write {}: {}", path, e))
+}
+
+// the bytes of a --max-memory-mib value
+fn mib_to_bytes(mib: &str) -> u64 {
+    let mib: u64 = mib.parse().expect("--max-memory-mib requires a number");
+    mib.checked_mul(1 << 20)
+        .unwrap_or_else(|| panic!("--max-memory-mib {} is more bytes than fit in 64 bits", mib))
+}
+
+fn read_file(path: &str) -> Vec<u8> {
+    std::fs::read(path).unwrap_or_else(|e| panic!("failed to read {}: {}", path, e))
+}
 
-    regex::main(content, pattern)
+// fhe-regex test-keys generate|verify [dir], manages the keys the tests run
+// with, dir defaults to test_data
+fn test_keys(args: Vec<String>) {
+    let dir = args.get(1).map(String::as_str).unwrap_or("test_data");
+    match args.first().map(String::as_str) {
+        Some("generate") => {
+            info!("generating test keys in {}..", dir);
+            generate_test_keys(dir).unwrap_or_else(|e| panic!("{}", e));
+        }
+        Some("verify") => {
+            verify_test_keys(dir).unwrap_or_else(|e| panic!("{}", e));
+            info!("test keys in {} are up to date", dir);
+        }
+        _ => panic!("usage: fhe-regex test-keys generate|verify [dir]"),
+    }
 }
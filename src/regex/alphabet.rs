@@ -0,0 +1,115 @@
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use tfhe::integer::RadixClientKey;
+
+use crate::regex::ciphertext::{encrypt_str, StringCiphertext};
+
+// The characters that content can consist of, declared by the client at
+// encryption time. The engine never has to distinguish between characters
+// outside of the alphabet, which makes character tests cheaper, and tests
+// for characters outside of it are known to fail without any ciphertext
+// operation.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Alphabet {
+    bitmap: u128,
+}
+
+impl Alphabet {
+    pub fn ascii() -> Self {
+        Self { bitmap: !0 }
+    }
+
+    pub fn digits() -> Self {
+        Self::from_range(b'0', b'9')
+    }
+
+    pub fn hex() -> Self {
+        Self::digits()
+            .union(&Self::from_range(b'a', b'f'))
+            .union(&Self::from_range(b'A', b'F'))
+    }
+
+    pub fn lowercase() -> Self {
+        Self::from_range(b'a', b'z')
+    }
+
+    pub fn from_chars(cs: &[u8]) -> Self {
+        let mut bitmap = 0;
+        for c in cs.iter().filter(|c| c.is_ascii()) {
+            bitmap |= 1 << c;
+        }
+        Self { bitmap }
+    }
+
+    pub fn from_range(from: u8, to: u8) -> Self {
+        Self::from_chars(&(from..=to).collect::<Vec<u8>>())
+    }
+
+    pub fn union(&self, other: &Self) -> Self {
+        Self {
+            bitmap: self.bitmap | other.bitmap,
+        }
+    }
+
+    pub fn contains(&self, c: u8) -> bool {
+        c.is_ascii() && self.bitmap & (1 << c) != 0
+    }
+
+    pub(crate) fn bitmap(&self) -> u128 {
+        self.bitmap
+    }
+}
+
+impl Default for Alphabet {
+    fn default() -> Self {
+        Self::ascii()
+    }
+}
+
+// encrypted content along with the alphabet it was declared to consist of
+#[derive(Clone, Serialize, Deserialize)]
+pub struct RestrictedContent {
+    pub alphabet: Alphabet,
+    pub content: StringCiphertext,
+}
+
+// encrypts content that consists of characters of alphabet only, content
+// with any other character is rejected
+pub fn encrypt_restricted(
+    client_key: &RadixClientKey,
+    content: &str,
+    alphabet: Alphabet,
+) -> Result<RestrictedContent> {
+    if let Some(c) = content.bytes().find(|c| !alphabet.contains(*c)) {
+        return Err(anyhow!(
+            "content contains {:?}, which is not in the declared alphabet",
+            c as char
+        ));
+    }
+    Ok(RestrictedContent {
+        alphabet,
+        content: encrypt_str(client_key, content)?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::regex::alphabet::{encrypt_restricted, Alphabet};
+    use crate::regex::test_keys::test_client_key;
+
+    #[test]
+    fn test_presets() {
+        assert!(Alphabet::hex().contains(b'F'));
+        assert!(!Alphabet::hex().contains(b'g'));
+        assert!(Alphabet::digits().contains(b'0'));
+        assert!(!Alphabet::lowercase().contains(b'A'));
+        assert!(!Alphabet::ascii().contains(200));
+    }
+
+    #[test]
+    fn test_content_outside_alphabet_rejected() {
+        let client_key = test_client_key();
+        assert!(encrypt_restricted(&client_key, "0123", Alphabet::digits()).is_ok());
+        assert!(encrypt_restricted(&client_key, "01a3", Alphabet::digits()).is_err());
+    }
+}
@@ -0,0 +1,301 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use tfhe::integer::{RadixCiphertext, RadixClientKey, ServerKey};
+
+#[cfg(feature = "server")]
+use crate::regex::ciphertext::key_params;
+use crate::regex::ciphertext::{gen_keys_with_profile, StringCiphertext};
+use crate::regex::client::{decrypt_result, encrypt_content, EncryptionKey};
+#[cfg(feature = "server")]
+use crate::regex::engine::{has_match_with, MatchOptions};
+use crate::regex::profile::Profile;
+#[cfg(feature = "server")]
+use crate::regex::warmup::WarmKey;
+
+// The stable surface of the crate, re-exported at the crate root. These wrap
+// the tfhe types the rest of the crate works with, so that tfhe upgrades and
+// changes to how content is encoded do not change the types downstream users
+// touch. Serialized, they are identical to the types they wrap. The
+// conversions from and to the tfhe types are an escape hatch into the rest of
+// the crate, and are not covered by the same guarantee.
+
+// the key content is encrypted with and results are decrypted with, to be
+// kept by the client. It is not scrubbed from memory when dropped: tfhe
+// keeps the secret key material private and offers no way to wipe it, only
+// the buffers the key is serialized to are (see client::save_client_key).
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct ClientKey(RadixClientKey);
+
+// the key that encrypts content for a client key, but decrypts nothing (see
+// client::EncryptionKey), for producers of content that must not be able to
+// read verdicts
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct EncryptKey(EncryptionKey);
+
+// the key the server evaluates patterns with, derived from the client key
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct EvalKey(ServerKey);
+
+// encrypted content
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct ContentCiphertext(StringCiphertext);
+
+// the encrypted result of a match
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct MatchCiphertext(RadixCiphertext);
+
+// Whatever decrypts match results. The client key is the reference
+// implementation. In deployments where no single party may hold the full
+// client key, an implementation hands the results (serialized, see above) to
+// a threshold decryption service instead, which decrypts them only when
+// enough of the parties holding a share of the key agree.
+pub trait Decryptor {
+    // true if the pattern matched
+    fn decrypt(&self, result: &MatchCiphertext) -> Result<bool>;
+
+    // services that decrypt in rounds can override this to decrypt all of
+    // results in a single round
+    fn decrypt_all(&self, results: &[MatchCiphertext]) -> Result<Vec<bool>> {
+        results.iter().map(|result| self.decrypt(result)).collect()
+    }
+}
+
+impl ClientKey {
+    pub fn generate(profile: Profile) -> (ClientKey, EvalKey) {
+        let (client_key, server_key) = gen_keys_with_profile(profile);
+        (ClientKey(client_key), EvalKey(server_key))
+    }
+
+    pub fn encrypt(&self, content: &str) -> Result<ContentCiphertext> {
+        Ok(ContentCiphertext(encrypt_content(&self.0, content)?))
+    }
+
+    // true if the pattern matched
+    pub fn decrypt(&self, result: &MatchCiphertext) -> bool {
+        decrypt_result(&self.0, &result.0)
+    }
+
+    pub fn encrypt_key(&self) -> Result<EncryptKey> {
+        Ok(EncryptKey(EncryptionKey::new(&self.0)?))
+    }
+}
+
+impl Decryptor for ClientKey {
+    fn decrypt(&self, result: &MatchCiphertext) -> Result<bool> {
+        Ok(ClientKey::decrypt(self, result))
+    }
+}
+
+impl Decryptor for RadixClientKey {
+    fn decrypt(&self, result: &MatchCiphertext) -> Result<bool> {
+        Ok(decrypt_result(self, &result.0))
+    }
+}
+
+impl EncryptKey {
+    pub fn encrypt(&self, content: &str) -> Result<ContentCiphertext> {
+        Ok(ContentCiphertext(self.0.encrypt(content)?))
+    }
+}
+
+#[cfg(feature = "server")]
+impl EvalKey {
+    // when matching many patterns with the same key, warm it up once instead
+    // (see warmup::warmup) and use engine::has_match_with. Padded content,
+    // and content encrypted for another profile than the key, is rejected.
+    pub fn has_match(
+        &self,
+        content: &ContentCiphertext,
+        pattern: &str,
+        opts: &MatchOptions,
+    ) -> Result<MatchCiphertext> {
+        let key = WarmKey::cold(self.0.clone());
+        content.0.check_unpadded()?;
+        content.0.check_params(key_params(key.shortkey())?)?;
+        let (ct_res, _) = has_match_with(&key, &content.0, pattern, opts)?;
+        Ok(MatchCiphertext(ct_res))
+    }
+}
+
+impl ContentCiphertext {
+    // in characters
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl From<RadixClientKey> for ClientKey {
+    fn from(client_key: RadixClientKey) -> Self {
+        Self(client_key)
+    }
+}
+
+impl From<ClientKey> for RadixClientKey {
+    fn from(client_key: ClientKey) -> Self {
+        client_key.0
+    }
+}
+
+impl MatchCiphertext {
+    // true if the pattern matched, decrypted by whoever can (see
+    // Decryptor), e.g. a threshold decryption service
+    pub fn decrypt_with<D: Decryptor + ?Sized>(&self, decryptor: &D) -> Result<bool> {
+        decryptor.decrypt(self)
+    }
+}
+
+impl From<EncryptionKey> for EncryptKey {
+    fn from(key: EncryptionKey) -> Self {
+        Self(key)
+    }
+}
+
+impl From<EncryptKey> for EncryptionKey {
+    fn from(key: EncryptKey) -> Self {
+        key.0
+    }
+}
+
+impl From<ServerKey> for EvalKey {
+    fn from(server_key: ServerKey) -> Self {
+        Self(server_key)
+    }
+}
+
+impl From<EvalKey> for ServerKey {
+    fn from(eval_key: EvalKey) -> Self {
+        eval_key.0
+    }
+}
+
+impl From<StringCiphertext> for ContentCiphertext {
+    fn from(content: StringCiphertext) -> Self {
+        Self(content)
+    }
+}
+
+impl From<ContentCiphertext> for StringCiphertext {
+    fn from(content: ContentCiphertext) -> Self {
+        content.0
+    }
+}
+
+impl From<RadixCiphertext> for MatchCiphertext {
+    fn from(result: RadixCiphertext) -> Self {
+        Self(result)
+    }
+}
+
+impl From<MatchCiphertext> for RadixCiphertext {
+    fn from(result: MatchCiphertext) -> Self {
+        result.0
+    }
+}
+
+#[cfg(all(test, feature = "server"))]
+mod tests {
+    use crate::regex::api::{ClientKey, ContentCiphertext, Decryptor, EvalKey, MatchCiphertext};
+    use crate::regex::ciphertext::StringCiphertext;
+    use crate::regex::engine::tests::KEYS;
+    use crate::regex::engine::{Engine, MatchOptions};
+
+    #[test]
+    fn test_match_through_api() {
+        let client_key = ClientKey::from(KEYS.0.clone());
+        let eval_key = EvalKey::from(KEYS.1.clone());
+
+        let content = client_key.encrypt("abc").unwrap();
+        // serialized the same as the wrapped types
+        let serialized = bincode::serialize(&content).unwrap();
+        let content: ContentCiphertext = bincode::deserialize(&serialized).unwrap();
+        assert_eq!(3, content.len());
+
+        let res = eval_key.has_match(&content, "/b/", &MatchOptions::default()).unwrap();
+        let serialized = bincode::serialize(&res).unwrap();
+        let res: MatchCiphertext = bincode::deserialize(&serialized).unwrap();
+        assert!(client_key.decrypt(&res));
+        assert!(res.decrypt_with(&KEYS.0).unwrap());
+    }
+
+    #[test]
+    fn test_match_encrypted_by_producer() {
+        let client_key = ClientKey::from(KEYS.0.clone());
+        let eval_key = EvalKey::from(KEYS.1.clone());
+        let encrypt_key = client_key.encrypt_key().unwrap();
+
+        let content = encrypt_key.encrypt("abc").unwrap();
+        let res = eval_key.has_match(&content, "/bc$/", &MatchOptions::default()).unwrap();
+        assert!(client_key.decrypt(&res));
+    }
+
+    #[test]
+    fn test_padded_content_rejected() {
+        let client_key = ClientKey::from(KEYS.0.clone());
+        let eval_key = EvalKey::from(KEYS.1.clone());
+
+        let content = StringCiphertext::from(client_key.encrypt("abc ").unwrap());
+        let content = ContentCiphertext::from(content.with_padding(1).unwrap());
+        let err = eval_key.has_match(&content, "/c$/", &MatchOptions::default()).err().unwrap();
+        assert!(err.to_string().contains("padded"), "{}", err);
+    }
+
+    #[test]
+    fn test_match_options_setters() {
+        let client_key = ClientKey::from(KEYS.0.clone());
+        let eval_key = EvalKey::from(KEYS.1.clone());
+
+        let content = client_key.encrypt("xABx").unwrap();
+        let opts = MatchOptions::default()
+            .engine(Engine::Branches)
+            .case_insensitive(true)
+            .max_content_len(4);
+        let res = eval_key.has_match(&content, "/ab/", &opts).unwrap();
+        assert!(client_key.decrypt(&res));
+
+        let opts = opts.max_content_len(3);
+        assert!(eval_key.has_match(&content, "/ab/", &opts).is_err());
+    }
+
+    // a decryptor standing in for a threshold decryption service, counting
+    // the rounds it is asked to decrypt in
+    struct Service {
+        client_key: ClientKey,
+        rounds: std::cell::Cell<usize>,
+    }
+
+    impl Decryptor for Service {
+        fn decrypt(&self, result: &MatchCiphertext) -> anyhow::Result<bool> {
+            self.decrypt_all(std::slice::from_ref(result)).map(|res| res[0])
+        }
+
+        fn decrypt_all(&self, results: &[MatchCiphertext]) -> anyhow::Result<Vec<bool>> {
+            self.rounds.set(self.rounds.get() + 1);
+            Ok(results.iter().map(|result| self.client_key.decrypt(result)).collect())
+        }
+    }
+
+    #[test]
+    fn test_decryptor() {
+        let client_key = ClientKey::from(KEYS.0.clone());
+        let results: Vec<MatchCiphertext> = (0..3).map(|n| MatchCiphertext::from(KEYS.0.encrypt(n))).collect();
+        assert_eq!(vec![false, true, true], Decryptor::decrypt_all(&client_key, &results).unwrap());
+        assert!(results[1].decrypt_with(&client_key).unwrap());
+
+        let service = Service {
+            client_key,
+            rounds: std::cell::Cell::new(0),
+        };
+        assert_eq!(vec![false, true, true], service.decrypt_all(&results).unwrap());
+        assert!(!results[0].decrypt_with(&service).unwrap());
+        assert_eq!(2, service.rounds.get());
+    }
+}
@@ -0,0 +1,234 @@
+use anyhow::{anyhow, Result};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Sender};
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+use tfhe::integer::RadixCiphertext;
+
+use crate::regex::ciphertext::{fingerprint, Fingerprint, StringCiphertext};
+use crate::regex::engine::{combined_engine, has_match_plans, plan_for, MatchOptions};
+use crate::regex::warmup::WarmKey;
+
+// the most requests a batch waits for, a full batch is evaluated without
+// waiting out the window
+pub const DEFAULT_MAX_BATCH: usize = 64;
+
+// Merges match requests that arrive close together, from any number of
+// threads, into one pass per content: the requests on the same content with
+// the same options are evaluated like has_match_patterns, so that the
+// comparisons and subexpressions their patterns have in common are evaluated
+// once, and the threads of the pass are kept busy by all of them at once. The
+// first request of a batch waits for the window to pass (or the batch to
+// fill up), then evaluates the batch on its own thread and hands every
+// request its own result. A request that can't be planned (e.g. its pattern
+// does not parse) fails on its own, without failing the rest of its batch.
+pub struct Batcher {
+    key: WarmKey,
+    window: Duration,
+    max_batch: usize,
+    pending: Mutex<Vec<Pending>>,
+    full: Condvar,
+    batches: AtomicUsize,
+}
+
+struct Pending {
+    content: StringCiphertext,
+    pattern: String,
+    opts: MatchOptions,
+    result: Sender<Result<RadixCiphertext>>,
+}
+
+impl Batcher {
+    pub fn new(key: WarmKey, window: Duration) -> Self {
+        Self {
+            key,
+            window,
+            max_batch: DEFAULT_MAX_BATCH,
+            pending: Mutex::new(vec![]),
+            full: Condvar::new(),
+            batches: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn max_batch(mut self, max_batch: usize) -> Self {
+        self.max_batch = max_batch.max(1);
+        self
+    }
+
+    // matches pattern on content as has_match_with does, along with whatever
+    // other requests arrive within the window. Blocks until the result is in.
+    pub fn has_match(
+        &self,
+        content: StringCiphertext,
+        pattern: &str,
+        opts: &MatchOptions,
+    ) -> Result<RadixCiphertext> {
+        let (result, received) = channel();
+        let first = {
+            let mut pending = self.pending.lock().unwrap();
+            pending.push(Pending {
+                content,
+                pattern: pattern.to_string(),
+                opts: opts.clone(),
+                result,
+            });
+            if pending.len() >= self.max_batch {
+                self.full.notify_one();
+            }
+            pending.len() == 1
+        };
+        if first {
+            let batch = self.wait_for_batch();
+            self.evaluate(batch);
+        }
+        received
+            .recv()
+            .map_err(|_| anyhow!("the batch of the request was dropped"))?
+    }
+
+    // the number of batches evaluated so far
+    pub fn batches(&self) -> usize {
+        self.batches.load(Ordering::Relaxed)
+    }
+
+    fn wait_for_batch(&self) -> Vec<Pending> {
+        let deadline = Instant::now() + self.window;
+        let mut pending = self.pending.lock().unwrap();
+        while pending.len() < self.max_batch {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            pending = self.full.wait_timeout(pending, deadline - now).unwrap().0;
+        }
+        std::mem::take(&mut *pending)
+    }
+
+    fn evaluate(&self, batch: Vec<Pending>) {
+        self.batches.fetch_add(1, Ordering::Relaxed);
+        let mut groups: Vec<(Fingerprint, Vec<Pending>)> = vec![];
+        for request in batch {
+            let content_fingerprint = match fingerprint(&request.content) {
+                Ok(content_fingerprint) => content_fingerprint,
+                Err(e) => {
+                    let _ = request.result.send(Err(e));
+                    continue;
+                }
+            };
+            let group = groups.iter_mut().find(|(group_fingerprint, group)| {
+                *group_fingerprint == content_fingerprint && group[0].opts == request.opts
+            });
+            match group {
+                Some((_, group)) => group.push(request),
+                None => groups.push((content_fingerprint, vec![request])),
+            }
+        }
+        for (_, group) in groups {
+            self.evaluate_group(group);
+        }
+    }
+
+    // evaluates requests on the same content with the same options in one
+    // pass
+    fn evaluate_group(&self, group: Vec<Pending>) {
+        let (content, opts) = (&group[0].content, &group[0].opts);
+        let mut planned = vec![];
+        let mut engines = vec![];
+        let mut plans = vec![];
+        for request in group.iter() {
+            match plan_for(&request.pattern, content.len(), opts) {
+                Ok((engine, plan)) => {
+                    planned.push(request);
+                    engines.push(engine);
+                    plans.push(plan);
+                }
+                Err(e) => {
+                    let _ = request.result.send(Err(e));
+                }
+            }
+        }
+        if planned.is_empty() {
+            return;
+        }
+
+        match has_match_plans(&self.key, content, combined_engine(engines), &plans, opts) {
+            Ok((results, stats)) => {
+                info!("batch of {} requests: {}", planned.len(), stats);
+                for (request, ct_res) in planned.into_iter().zip(results) {
+                    // the requester is gone when it can't be sent to, which
+                    // is its own business
+                    let _ = request.result.send(Ok(ct_res));
+                }
+            }
+            Err(e) => {
+                for request in planned {
+                    let _ = request.result.send(Err(anyhow!("{}", e)));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::regex::batch::Batcher;
+    use crate::regex::engine::tests::{encrypt_trivial, KEYS};
+    use crate::regex::engine::MatchOptions;
+    use crate::regex::warmup::warmup;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[test]
+    fn test_batched_requests() {
+        let batcher = Arc::new(Batcher::new(warmup(&KEYS.1), Duration::from_secs(1)));
+        let content = encrypt_trivial("abc");
+        let requests = [("/b/", Some(1)), ("/^b/", Some(0)), ("/(/", None), ("/bc$/", Some(1))];
+
+        let threads: Vec<_> = requests
+            .iter()
+            .map(|(pattern, _)| {
+                let (batcher, content) = (batcher.clone(), content.clone());
+                let pattern = pattern.to_string();
+                std::thread::spawn(move || {
+                    batcher
+                        .has_match(content, &pattern, &MatchOptions::default())
+                        .map(|ct_res| KEYS.0.decrypt(&ct_res))
+                        .ok()
+                })
+            })
+            .collect();
+        for (thread, (_, exp)) in threads.into_iter().zip(requests) {
+            assert_eq!(exp, thread.join().unwrap());
+        }
+        assert_eq!(1, batcher.batches());
+    }
+
+    #[test]
+    fn test_full_batch_not_delayed() {
+        let batcher = Batcher::new(warmup(&KEYS.1), Duration::from_secs(3600)).max_batch(1);
+        let ct_res = batcher
+            .has_match(encrypt_trivial("ab"), "/a/", &MatchOptions::default())
+            .unwrap();
+        assert_eq!(1, KEYS.0.decrypt(&ct_res));
+    }
+
+    #[test]
+    fn test_different_contents() {
+        let batcher = Arc::new(Batcher::new(warmup(&KEYS.1), Duration::from_secs(1)));
+        let threads: Vec<_> = [("ab", 1), ("cd", 0)]
+            .into_iter()
+            .map(|(content, exp)| {
+                let batcher = batcher.clone();
+                std::thread::spawn(move || {
+                    let ct_res = batcher
+                        .has_match(encrypt_trivial(content), "/a/", &MatchOptions::default())
+                        .unwrap();
+                    assert_eq!(exp, KEYS.0.decrypt(&ct_res));
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+    }
+}
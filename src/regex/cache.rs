@@ -0,0 +1,285 @@
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use tfhe::integer::RadixCiphertext;
+
+use crate::regex::ciphertext::Fingerprint;
+use crate::regex::execution::Executed;
+
+// Results of testing content positions against constants and character
+// classes. These only depend on the content (not on the pattern), so they can
+// be shared by all queries that are applied to the same content. The cache
+// is bound to the fingerprint of the content its results were computed on,
+// and is emptied when it is used for different content.
+#[derive(Default)]
+pub struct ContentCache {
+    fingerprint: Option<Fingerprint>,
+    comparisons: HashMap<Executed, RadixCiphertext>,
+}
+
+impl ContentCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.comparisons.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.comparisons.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.fingerprint = None;
+        self.comparisons.clear();
+    }
+
+    pub fn fingerprint(&self) -> Option<Fingerprint> {
+        self.fingerprint
+    }
+
+    // binds the cache to the content with the given fingerprint, dropping
+    // the cached results if they were computed on other content
+    pub fn bind(&mut self, fingerprint: Fingerprint) {
+        if self.fingerprint != Some(fingerprint) {
+            if self.fingerprint.is_some() {
+                debug!("content changed, invalidating {} cached comparisons", self.len());
+            }
+            self.clear();
+            self.fingerprint = Some(fingerprint);
+        }
+    }
+
+    pub(crate) fn take(&mut self) -> HashMap<Executed, RadixCiphertext> {
+        std::mem::take(&mut self.comparisons)
+    }
+
+    pub(crate) fn store(&mut self, comparisons: HashMap<Executed, RadixCiphertext>) {
+        self.comparisons.extend(comparisons);
+    }
+}
+
+// bump whenever the serialized comparisons change (changes to Executed or to
+// the ciphertexts), so that files written by older versions are not loaded
+const CACHE_FORMAT_VERSION: u32 = 1;
+
+// Content caches persisted on disk, so that a later query on the same
+// (encrypted) content loads the comparisons of earlier queries instead of
+// recomputing them. Every content gets a file of its own, named after its
+// fingerprint, starting with the sha256 hash of the rest of the file; files
+// failing the check are discarded. Once the files add up to more than
+// max_bytes, the least recently saved ones are removed.
+pub struct CacheStore {
+    dir: PathBuf,
+    max_bytes: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct CachedComparisons {
+    version: u32,
+    fingerprint: Fingerprint,
+    comparisons: Vec<(Executed, RadixCiphertext)>,
+}
+
+impl CacheStore {
+    pub fn open<P: AsRef<Path>>(dir: P, max_bytes: u64) -> Result<Self> {
+        std::fs::create_dir_all(dir.as_ref())
+            .map_err(|e| anyhow!("failed to create content cache {:?}: {}", dir.as_ref(), e))?;
+        Ok(Self {
+            dir: dir.as_ref().to_path_buf(),
+            max_bytes,
+        })
+    }
+
+    // adds the comparisons stored for the content cache is bound to, results
+    // in the number of comparisons loaded. Unreadable files are removed.
+    pub fn load(&self, cache: &mut ContentCache) -> Result<usize> {
+        let fingerprint = cache
+            .fingerprint()
+            .ok_or_else(|| anyhow!("the content cache is not bound to any content"))?;
+        let path = self.path(&fingerprint);
+        let serialized = match std::fs::read(&path) {
+            Ok(serialized) => serialized,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(anyhow!("failed to read content cache entry {:?}: {}", path, e)),
+        };
+        match decode(&serialized, fingerprint) {
+            Ok(comparisons) => {
+                debug!("loaded {} comparisons from {:?}", comparisons.len(), path);
+                let n = comparisons.len();
+                cache.store(comparisons.into_iter().collect());
+                Ok(n)
+            }
+            Err(e) => {
+                warn!("discarding content cache entry {:?}: {}", path, e);
+                std::fs::remove_file(&path)?;
+                Ok(0)
+            }
+        }
+    }
+
+    // stores the comparisons of cache for the content it is bound to, then
+    // removes the oldest files while the store exceeds its size
+    pub fn save(&self, cache: &ContentCache) -> Result<()> {
+        let fingerprint = cache
+            .fingerprint()
+            .ok_or_else(|| anyhow!("the content cache is not bound to any content"))?;
+        let cached = CachedComparisons {
+            version: CACHE_FORMAT_VERSION,
+            fingerprint,
+            comparisons: cache
+                .comparisons
+                .iter()
+                .map(|(node, ct)| (node.clone(), ct.clone()))
+                .collect(),
+        };
+        let payload = bincode::serialize(&cached)?;
+        let mut serialized = Sha256::digest(&payload).to_vec();
+        serialized.extend(payload);
+
+        // written to a temporary file first, so that concurrent readers never
+        // see a partially written entry
+        let path = self.path(&fingerprint);
+        let tmp_path = path.with_extension("tmp");
+        std::fs::write(&tmp_path, serialized)
+            .and_then(|_| std::fs::rename(&tmp_path, &path))
+            .map_err(|e| anyhow!("failed to write content cache entry {:?}: {}", path, e))?;
+        self.gc(&path)
+    }
+
+    // the total size of the stored files, in bytes
+    pub fn size(&self) -> Result<u64> {
+        Ok(self.entries()?.iter().map(|(_, size, _)| size).sum())
+    }
+
+    fn gc(&self, keep: &Path) -> Result<()> {
+        let mut entries = self.entries()?;
+        let mut total: u64 = entries.iter().map(|(_, size, _)| size).sum();
+        entries.sort_by_key(|(_, _, modified)| *modified);
+        for (path, size, _) in entries {
+            if total <= self.max_bytes {
+                break;
+            }
+            if path == keep {
+                continue;
+            }
+            debug!("content cache exceeds {} bytes, removing {:?}", self.max_bytes, path);
+            std::fs::remove_file(&path)?;
+            total -= size;
+        }
+        Ok(())
+    }
+
+    fn entries(&self) -> Result<Vec<(PathBuf, u64, std::time::SystemTime)>> {
+        let mut entries = vec![];
+        for entry in std::fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if path.extension().map_or(false, |ext| ext == "cmp") {
+                let metadata = std::fs::metadata(&path)?;
+                entries.push((path, metadata.len(), metadata.modified()?));
+            }
+        }
+        Ok(entries)
+    }
+
+    fn path(&self, fingerprint: &Fingerprint) -> PathBuf {
+        self.dir.join(fingerprint.to_string()).with_extension("cmp")
+    }
+}
+
+// checks the hash, version and fingerprint of a serialized entry
+fn decode(serialized: &[u8], fingerprint: Fingerprint) -> Result<Vec<(Executed, RadixCiphertext)>> {
+    if serialized.len() < 32 {
+        return Err(anyhow!("truncated"));
+    }
+    let (hash, payload) = serialized.split_at(32);
+    if Sha256::digest(payload).as_slice() != hash {
+        return Err(anyhow!("hash mismatch"));
+    }
+    let cached: CachedComparisons = bincode::deserialize(payload)?;
+    if cached.version != CACHE_FORMAT_VERSION {
+        return Err(anyhow!("written by format version {}", cached.version));
+    }
+    if cached.fingerprint != fingerprint {
+        return Err(anyhow!("belongs to content {}", cached.fingerprint));
+    }
+    if cached.comparisons.iter().any(|(node, _)| !node.is_comparison()) {
+        return Err(anyhow!("holds results other than comparisons"));
+    }
+    Ok(cached.comparisons)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::regex::cache::{CacheStore, ContentCache};
+    use crate::regex::ciphertext::fingerprint;
+    use crate::regex::engine::{has_match_cached, MatchOptions};
+    use crate::regex::engine::tests::{encrypt_trivial, KEYS};
+    use crate::regex::test_keys::temp_path;
+
+    #[test]
+    fn test_comparisons_persisted() {
+        let dir = temp_path("content_cache");
+        let _ = std::fs::remove_dir_all(&dir);
+        let store = CacheStore::open(&dir, u64::MAX).unwrap();
+        let ct_content = encrypt_trivial("abc");
+
+        let mut cache = ContentCache::new();
+        has_match_cached(&KEYS.1, &ct_content, "/bc/", &MatchOptions::default(), &mut cache).unwrap();
+        store.save(&cache).unwrap();
+
+        let mut reloaded = ContentCache::new();
+        reloaded.bind(fingerprint(&ct_content).unwrap());
+        assert_eq!(cache.len(), store.load(&mut reloaded).unwrap());
+        let ct_res = has_match_cached(&KEYS.1, &ct_content, "/bc$/", &MatchOptions::default(), &mut reloaded).unwrap();
+        assert_eq!(1, KEYS.0.decrypt(&ct_res));
+
+        // content without a file loads nothing
+        let mut other = ContentCache::new();
+        other.bind(fingerprint(&encrypt_trivial("abd")).unwrap());
+        assert_eq!(0, store.load(&mut other).unwrap());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_corrupted_entry_discarded() {
+        let dir = temp_path("content_cache_corrupted");
+        let _ = std::fs::remove_dir_all(&dir);
+        let store = CacheStore::open(&dir, u64::MAX).unwrap();
+        let ct_content = encrypt_trivial("ab");
+
+        let mut cache = ContentCache::new();
+        has_match_cached(&KEYS.1, &ct_content, "/b/", &MatchOptions::default(), &mut cache).unwrap();
+        store.save(&cache).unwrap();
+        let path = std::fs::read_dir(&dir).unwrap().next().unwrap().unwrap().path();
+        let mut serialized = std::fs::read(&path).unwrap();
+        let last = serialized.len() - 1;
+        serialized[last] ^= 1;
+        std::fs::write(&path, serialized).unwrap();
+
+        let mut reloaded = ContentCache::new();
+        reloaded.bind(cache.fingerprint().unwrap());
+        assert_eq!(0, store.load(&mut reloaded).unwrap());
+        assert!(!path.exists());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_size_capped() {
+        let dir = temp_path("content_cache_gc");
+        let _ = std::fs::remove_dir_all(&dir);
+        let store = CacheStore::open(&dir, 1).unwrap();
+
+        for content in ["ab", "ba"] {
+            let mut cache = ContentCache::new();
+            has_match_cached(&KEYS.1, &encrypt_trivial(content), "/b/", &MatchOptions::default(), &mut cache).unwrap();
+            store.save(&cache).unwrap();
+        }
+        // only the file saved last is kept, even though it exceeds the cap
+        assert_eq!(1, std::fs::read_dir(&dir).unwrap().count());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
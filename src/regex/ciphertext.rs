@@ -1,24 +1,265 @@
 use tfhe::shortint::parameters::PARAM_MESSAGE_2_CARRY_2;
 use tfhe::integer::gen_keys_radix;
-use tfhe::integer::{RadixCiphertext, RadixClientKey, ServerKey};
+use tfhe::integer::{CompressedServerKey, RadixCiphertext, RadixClientKey, ServerKey};
 use anyhow::{Result, anyhow};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::ops::{Deref, DerefMut};
+use tfhe::core_crypto::prelude::{ActivatedRandomGenerator, DeterministicSeeder, Seed};
+use tfhe::shortint::engine::ShortintEngine;
+use tfhe::shortint::parameters::Parameters;
+use zeroize::Zeroize;
 
-pub type StringCiphertext = Vec<RadixCiphertext>;
+use crate::regex::profile::Profile;
 
+#[cfg(feature = "server")]
+use std::collections::HashMap;
+#[cfg(feature = "server")]
+use tfhe::shortint::server_key::Accumulator;
+#[cfg(feature = "server")]
+use tfhe::shortint::Ciphertext;
+
+#[cfg(feature = "server")]
+use crate::regex::class::CharClass;
+
+// How the characters of content are mapped to the plaintexts encrypted for
+// them.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Encoding {
+    // every character is encrypted as its ascii byte
+    #[default]
+    Ascii,
+}
+
+// Identifies the parameters (and block layout) content was encrypted with, by
+// hashing them. Content of which the parameters are not known (e.g. content
+// converted from bare ciphertexts) has the unknown fingerprint.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct ParamsFingerprint([u8; 8]);
+
+impl ParamsFingerprint {
+    pub fn of(params: &Parameters) -> Result<Self> {
+        let mut hasher = Sha256::new();
+        hasher.update(bincode::serialize(params)?);
+        hasher.update((NUM_BLOCKS as u64).to_le_bytes());
+        let hash: [u8; 32] = hasher.finalize().into();
+        let mut res = [0; 8];
+        res.copy_from_slice(&hash[..8]);
+        Ok(Self(res))
+    }
+
+    pub fn of_profile(profile: Profile) -> Result<Self> {
+        Self::of(&profile.parameters())
+    }
+
+    pub fn unknown() -> Self {
+        Self([0; 8])
+    }
+
+    pub fn is_known(&self) -> bool {
+        *self != Self::unknown()
+    }
+}
+
+impl fmt::Display for ParamsFingerprint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if !self.is_known() {
+            return write!(f, "unknown");
+        }
+        for byte in self.0 {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+// Encrypted content: the ciphertext of every character, along with what is
+// known about how they were encrypted. Content can be padded to hide its
+// length, the trailing padding characters are then part of the ciphertexts
+// but not of the content.
+//
+// It dereferences to the ciphertexts of its characters, padding included,
+// which is what the engine works on. The engine has no notion of padding, it
+// would match the padding characters as content, so padded content is
+// rejected where it is matched (see check_unpadded).
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct StringCiphertext {
+    // the order of the fields is the serialized layout, the characters come
+    // last so that content can be serialized while it is being encrypted
+    // (see client::encrypt_content_to_writer) and read lazily (see
+    // storage::MappedContent)
+    encoding: Encoding,
+    params: ParamsFingerprint,
+    padding: u64,
+    chars: Vec<RadixCiphertext>,
+}
+
+// Everything a serialized StringCiphertext starts with, up to and including
+// the number of characters. Serializes to HEADER_SIZE bytes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct Header {
+    pub encoding: Encoding,
+    pub params: ParamsFingerprint,
+    pub padding: u64,
+    pub len: u64,
+}
+
+// an enum variant tag, the params fingerprint, and two u64
+pub(crate) const HEADER_SIZE: usize = 4 + 8 + 8 + 8;
+
+impl StringCiphertext {
+    pub fn new(chars: Vec<RadixCiphertext>, encoding: Encoding, params: ParamsFingerprint) -> Self {
+        Self {
+            encoding,
+            params,
+            padding: 0,
+            chars,
+        }
+    }
+
+    // marks the last padding characters as padding
+    pub fn with_padding(mut self, padding: usize) -> Result<Self> {
+        if padding > self.chars.len() {
+            return Err(anyhow!(
+                "cannot pad {} of {} characters",
+                padding,
+                self.chars.len()
+            ));
+        }
+        self.padding = padding as u64;
+        Ok(self)
+    }
+
+    pub fn encoding(&self) -> Encoding {
+        self.encoding
+    }
+
+    pub fn params(&self) -> ParamsFingerprint {
+        self.params
+    }
+
+    // number of trailing characters that are padding
+    pub fn padding(&self) -> usize {
+        self.padding as usize
+    }
+
+    // number of characters of the content, the padding excluded
+    pub fn content_len(&self) -> usize {
+        self.chars.len() - self.padding()
+    }
+
+    pub fn chars(&self) -> &[RadixCiphertext] {
+        &self.chars
+    }
+
+    pub fn into_chars(self) -> Vec<RadixCiphertext> {
+        self.chars
+    }
+
+    // rejects content that is known to be encrypted with other parameters
+    // than expected
+    pub fn check_params(&self, expected: ParamsFingerprint) -> Result<()> {
+        if self.params.is_known() && expected.is_known() && self.params != expected {
+            return Err(anyhow!(
+                "content is encrypted with parameters {}, expected {}",
+                self.params,
+                expected
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn check_unpadded(&self) -> Result<()> {
+        if self.padding > 0 {
+            return Err(anyhow!(
+                "content is padded with {} characters, padded content cannot be matched",
+                self.padding
+            ));
+        }
+        Ok(())
+    }
+
+    pub(crate) fn from_header(header: Header, chars: Vec<RadixCiphertext>) -> Self {
+        Self {
+            encoding: header.encoding,
+            params: header.params,
+            padding: header.padding,
+            chars,
+        }
+    }
+}
+
+impl Deref for StringCiphertext {
+    type Target = [RadixCiphertext];
+
+    fn deref(&self) -> &Self::Target {
+        &self.chars
+    }
+}
+
+impl DerefMut for StringCiphertext {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.chars
+    }
+}
+
+// bare ciphertexts, of which the encoding is assumed to be ascii and the
+// parameters are unknown
+impl From<Vec<RadixCiphertext>> for StringCiphertext {
+    fn from(chars: Vec<RadixCiphertext>) -> Self {
+        Self::new(chars, Encoding::Ascii, ParamsFingerprint::unknown())
+    }
+}
+
+impl FromIterator<RadixCiphertext> for StringCiphertext {
+    fn from_iter<I: IntoIterator<Item = RadixCiphertext>>(iter: I) -> Self {
+        Self::from(iter.into_iter().collect::<Vec<_>>())
+    }
+}
+
+impl IntoIterator for StringCiphertext {
+    type Item = RadixCiphertext;
+    type IntoIter = std::vec::IntoIter<RadixCiphertext>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.chars.into_iter()
+    }
+}
+
+// every character is encrypted as a radix of NUM_BLOCKS blocks, each holding
+// BLOCK_SIZE bits of the character's byte
+pub(crate) const BLOCK_SIZE: usize = 2;
+pub(crate) const NUM_BLOCKS: usize = 4;
+
+// number of verdicts (encryptions of 0 or 1) packed into a single radix, one
+// per bit of the message space
+pub const VERDICTS_PER_RADIX: usize = NUM_BLOCKS * BLOCK_SIZE;
+
+// number of freshly bootstrapped blocks that can be added together before
+// the result must be bootstrapped again
+#[cfg(feature = "server")]
+pub(crate) const MAX_UNCHECKED_ADDS: usize = 4;
+
+#[cfg(feature = "server")]
 pub fn create_trivial_radix(
     server_key: &ServerKey,
     msg: u64,
 ) -> RadixCiphertext {
-    let block_size = 2;
-    let num_blocks = 4;
-
     let shortkey = tfhe::shortint::ServerKey::from(server_key.clone());
+    trivial_radix(&shortkey, msg)
+}
 
-    let mut vec_res = Vec::with_capacity(num_blocks);
-    for block in 0..num_blocks {
+// like create_trivial_radix, for callers that already hold the shortint key
+// (converting the server key into it means cloning the whole key)
+#[cfg(feature = "server")]
+pub(crate) fn trivial_radix(shortkey: &tfhe::shortint::ServerKey, msg: u64) -> RadixCiphertext {
+    let mut vec_res = Vec::with_capacity(NUM_BLOCKS);
+    for block in 0..NUM_BLOCKS {
         let mut block_value: usize = 0;
-        for bit in 0..block_size {
-            if msg & (1 << (block * block_size + bit)) != 0 {
+        for bit in 0..BLOCK_SIZE {
+            if msg & (1 << (block * BLOCK_SIZE + bit)) != 0 {
                 let base: usize = 2;
                 block_value += base.pow(bit as u32);
             }
@@ -29,17 +270,510 @@ pub fn create_trivial_radix(
     RadixCiphertext::from(vec_res)
 }
 
+// the value of a trivial encryption (one with an all zero mask, as
+// create_trivial_radix makes), None for actual encryptions. Operations on
+// trivial encryptions result in trivial encryptions, so this reads results
+// without the client key.
+#[cfg(feature = "server")]
+pub(crate) fn trivial_value(ct: &RadixCiphertext) -> Option<u64> {
+    let mut value = 0;
+    for (i, block) in ct.blocks().iter().enumerate() {
+        if block.ct.get_mask().as_ref().iter().any(|a| *a != 0) {
+            return None;
+        }
+        let modulus = (block.message_modulus.0 * block.carry_modulus.0) as u64;
+        // the top bit is padding
+        let delta = (1u64 << 63) / modulus;
+        let body = *block.ct.get_body().0;
+        let block_value = (body.wrapping_add(delta / 2) / delta) % modulus;
+        value += (block_value % block.message_modulus.0 as u64) << (i * BLOCK_SIZE);
+    }
+    Some(value)
+}
+
+// encrypts content of at most DEFAULT_MAX_CONTENT_LEN characters
 pub fn encrypt_str(client_key: &RadixClientKey, s: &str) -> Result<StringCiphertext> {
+    encrypt_str_within(client_key, s, DEFAULT_MAX_CONTENT_LEN)
+}
+
+// like encrypt_str, for content of at most max_len characters, for servers
+// that accept longer content than the default
+pub fn encrypt_str_within(client_key: &RadixClientKey, s: &str, max_len: usize) -> Result<StringCiphertext> {
     if !s.is_ascii() {
         return Err(anyhow!("content contains non-ascii characters"));
     }
-    Ok(s.as_bytes()
-        .iter()
+    check_content_len(s.len(), max_len)?;
+    let chars = s
+        .as_bytes()
+        .par_iter()
         .map(|byte| client_key.encrypt(*byte as u64))
-        .collect())
+        .collect();
+    Ok(StringCiphertext::new(
+        chars,
+        Encoding::Ascii,
+        ParamsFingerprint::of(&client_key.parameters())?,
+    ))
+}
+
+// appends the encryption of more content, see engine::has_match_appended for
+// matching the appended content without a full re-scan. Both must be
+// encrypted the same way, and padding can only be at the end of the result,
+// which is at most max_len characters long.
+pub fn append_encrypted(existing: &mut StringCiphertext, more: StringCiphertext, max_len: usize) -> Result<()> {
+    if existing.padding() > 0 {
+        return Err(anyhow!("cannot append to padded content"));
+    }
+    if existing.encoding != more.encoding {
+        return Err(anyhow!(
+            "cannot append {:?} encoded content to {:?} encoded content",
+            more.encoding,
+            existing.encoding
+        ));
+    }
+    more.check_params(existing.params)?;
+    check_content_len(existing.len() + more.len(), max_len)?;
+    if !existing.params.is_known() {
+        existing.params = more.params;
+    }
+    existing.padding = more.padding;
+    existing.chars.extend(more.chars);
+    Ok(())
+}
+
+// The longest content, in characters (padding included), that is encrypted
+// or matched by default. The plans the engine builds grow with the length of
+// the content (for some patterns quadratically), so without a bound a single
+// request could exhaust the memory of the server before any ciphertext
+// operation is run. A match takes its bound from its options (see
+// MatchOptions::max_content_len), servers hold every request to their own
+// (see serve::ServerLimits).
+pub const DEFAULT_MAX_CONTENT_LEN: usize = 1 << 16;
+
+// the error encryption and matching result in for content exceeding the
+// maximum content length, which callers can tell apart from other errors by
+// downcasting
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ContentTooLong {
+    pub len: usize,
+    pub max: usize,
+}
+
+impl fmt::Display for ContentTooLong {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "content too long: {} characters, at most {} are supported",
+            self.len, self.max
+        )
+    }
+}
+
+impl std::error::Error for ContentTooLong {}
+
+pub(crate) fn check_content_len(len: usize, max: usize) -> Result<(), ContentTooLong> {
+    if len > max {
+        return Err(ContentTooLong { len, max });
+    }
+    Ok(())
+}
+
+// The engine's constants and comparisons assume every character to be a
+// radix of NUM_BLOCKS blocks holding BLOCK_SIZE bits each, with at least as
+// many carry bits. Content encrypted with another layout would not fail, but
+// silently compare wrong, so it is rejected up front, as is content of more
+// than max_len characters.
+pub fn validate_layout(content: &[RadixCiphertext], max_len: usize) -> Result<()> {
+    check_content_len(content.len(), max_len)?;
+    for (i, ct) in content.iter().enumerate() {
+        if ct.blocks().len() != NUM_BLOCKS {
+            return Err(anyhow!(
+                "character {} is encrypted in {} blocks, expected {}",
+                i,
+                ct.blocks().len(),
+                NUM_BLOCKS,
+            ));
+        }
+        for block in ct.blocks() {
+            if block.message_modulus.0 != 1 << BLOCK_SIZE {
+                return Err(anyhow!(
+                    "character {} is encrypted with message modulus {}, expected {}",
+                    i,
+                    block.message_modulus.0,
+                    1 << BLOCK_SIZE,
+                ));
+            }
+            if block.carry_modulus.0 < 1 << BLOCK_SIZE {
+                return Err(anyhow!(
+                    "character {} is encrypted with carry modulus {}, expected at least {}",
+                    i,
+                    block.carry_modulus.0,
+                    1 << BLOCK_SIZE,
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+// Identifies encrypted content, by hashing the serialized ciphertexts of its
+// characters. Two encryptions of the same text have different fingerprints.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Fingerprint([u8; 32]);
+
+impl fmt::Display for Fingerprint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for byte in self.0 {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+pub fn fingerprint(content: &[RadixCiphertext]) -> Result<Fingerprint> {
+    let mut hasher = Sha256::new();
+    hasher.update((content.len() as u64).to_le_bytes());
+    hasher.update((NUM_BLOCKS as u64).to_le_bytes());
+    hasher.update((BLOCK_SIZE as u64).to_le_bytes());
+    for ct in content {
+        hasher.update(bincode::serialize(ct)?);
+    }
+    Ok(Fingerprint(hasher.finalize().into()))
+}
+
+// identifies a server key, by hashing it serialized
+pub fn key_fingerprint(sk: &ServerKey) -> Result<Fingerprint> {
+    Ok(Fingerprint(Sha256::digest(bincode::serialize(sk)?).into()))
+}
+
+// the fingerprint of the parameters a server key was generated with, when
+// those are the parameters of a profile (told apart by their lwe dimension,
+// the only parameter they differ in), unknown otherwise
+pub fn key_params(shortkey: &tfhe::shortint::ServerKey) -> Result<ParamsFingerprint> {
+    let lwe_dimension = shortkey.bootstrapping_key.input_lwe_dimension();
+    for profile in [Profile::Demo, Profile::Balanced, Profile::Paranoid] {
+        let params = profile.parameters();
+        if params.lwe_dimension == lwe_dimension
+            && params.message_modulus == shortkey.message_modulus
+            && params.carry_modulus == shortkey.carry_modulus
+        {
+            return ParamsFingerprint::of(&params);
+        }
+    }
+    Ok(ParamsFingerprint::unknown())
 }
 
 pub fn gen_keys() -> (RadixClientKey, ServerKey) {
-    let num_block = 4;
-    gen_keys_radix(&PARAM_MESSAGE_2_CARRY_2, num_block)
+    gen_keys_radix(&PARAM_MESSAGE_2_CARRY_2, NUM_BLOCKS)
+}
+
+pub fn gen_keys_with_profile(profile: Profile) -> (RadixClientKey, ServerKey) {
+    gen_keys_radix(&profile.parameters(), NUM_BLOCKS)
+}
+
+// like gen_keys_with_profile, with all randomness drawn from seed rather than
+// from the os, so that the same seed always results in the same keys
+pub fn gen_keys_from_seed(profile: Profile, seed: &[u8]) -> (RadixClientKey, ServerKey) {
+    with_seed(seed, || gen_keys_with_profile(profile))
+}
+
+// runs f with all randomness of tfhe on this thread drawn from seed (any
+// number of bytes, e.g. a master secret, hashed into tfhe's 128 bit seed).
+// Afterwards this thread draws from the os again.
+pub fn with_seed<T, F: FnOnce() -> T>(seed: &[u8], f: F) -> T {
+    let mut hash = Sha256::digest(seed);
+    let mut seed_bytes = [0; 16];
+    seed_bytes.copy_from_slice(&hash[..16]);
+    let mut seeder =
+        DeterministicSeeder::<ActivatedRandomGenerator>::new(Seed(u128::from_le_bytes(seed_bytes)));
+    // the seed is as secret as the keys derived from it
+    hash.as_mut_slice().zeroize();
+    seed_bytes.zeroize();
+    ShortintEngine::replace_thread_local(ShortintEngine::new_from_seeder(&mut seeder));
+    let res = f();
+    ShortintEngine::replace_thread_local(ShortintEngine::new());
+    res
+}
+
+// Reported while generating keys (and while warming up a server key, see
+// warmup.rs): stage names the step that just finished, done out of total
+// steps have finished.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Progress {
+    pub stage: &'static str,
+    pub done: usize,
+    pub total: usize,
+}
+
+// like gen_keys, reporting progress after each of the two keys. Generating
+// the server key takes by far the most time.
+pub fn gen_keys_with_progress<F: FnMut(Progress)>(
+    profile: Profile,
+    mut progress: F,
+) -> (RadixClientKey, ServerKey) {
+    let client_key = gen_client_key(profile);
+    progress(Progress {
+        stage: "client key",
+        done: 1,
+        total: 2,
+    });
+    let server_key = gen_server_key(&client_key);
+    progress(Progress {
+        stage: "server key",
+        done: 2,
+        total: 2,
+    });
+    (client_key, server_key)
+}
+
+pub fn gen_client_key(profile: Profile) -> RadixClientKey {
+    RadixClientKey::new(profile.parameters(), NUM_BLOCKS)
+}
+
+// The server key can only be derived from the client key, so whoever holds
+// the client key generates it and ships it to the server. It can be shipped
+// either as is, or compressed: the compressed key only holds the seeds of its
+// random parts, making it a fraction of the size, and the server decompresses
+// it on receival (see deserialize_compressed_server_key).
+
+pub fn gen_server_key(client_key: &RadixClientKey) -> ServerKey {
+    ServerKey::new(client_key)
+}
+
+pub fn gen_compressed_server_key(client_key: &RadixClientKey) -> CompressedServerKey {
+    CompressedServerKey::new(client_key.as_ref())
+}
+
+pub fn serialize_server_key(server_key: &ServerKey) -> Result<Vec<u8>> {
+    bincode::serialize(server_key).map_err(|e| anyhow!("failed to serialize server key: {}", e))
+}
+
+pub fn deserialize_server_key(serialized: &[u8]) -> Result<ServerKey> {
+    bincode::deserialize(serialized).map_err(|e| anyhow!("failed to deserialize server key: {}", e))
+}
+
+pub fn serialize_compressed_server_key(server_key: &CompressedServerKey) -> Result<Vec<u8>> {
+    bincode::serialize(server_key)
+        .map_err(|e| anyhow!("failed to serialize compressed server key: {}", e))
+}
+
+// deserializes a compressed server key, and decompresses it into a server key
+pub fn deserialize_compressed_server_key(serialized: &[u8]) -> Result<ServerKey> {
+    let compressed: CompressedServerKey = bincode::deserialize(serialized)
+        .map_err(|e| anyhow!("failed to deserialize compressed server key: {}", e))?;
+    Ok(ServerKey::from(compressed))
+}
+
+// tests class membership of a character with lookup tables, resulting in a
+// single block holding 0 or 1. A block's message and carry space together
+// hold 4 bits, so the character is first recombined into its two nibbles. Per
+// distinct high nibble h of the class, one lookup tests hi == h and one tests
+// whether (h, lo) is a member; at most one of these terms can hold.
+#[cfg(feature = "server")]
+pub(crate) fn class_flag(
+    shortkey: &tfhe::shortint::ServerKey,
+    ct: &RadixCiphertext,
+    class: &CharClass,
+) -> Ciphertext {
+    class_flag_with(shortkey, ct, class, None)
+}
+
+// as class_flag, taking the lookup tables from tables where they were
+// precomputed and generating the others
+#[cfg(feature = "server")]
+pub(crate) fn class_flag_with(
+    shortkey: &tfhe::shortint::ServerKey,
+    ct: &RadixCiphertext,
+    class: &CharClass,
+    tables: Option<&ClassAccumulators>,
+) -> Ciphertext {
+    let blocks = ct.blocks();
+    let nibble = |lo: usize| {
+        let mut res = shortkey.unchecked_scalar_mul(&blocks[lo + 1], 1 << BLOCK_SIZE);
+        shortkey.unchecked_add_assign(&mut res, &blocks[lo]);
+        res
+    };
+    let ct_lo = nibble(0);
+    let ct_hi = nibble(2);
+
+    let high_nibbles = class.high_nibbles();
+    if high_nibbles.is_empty() {
+        return shortkey.create_trivial(0);
+    }
+    let mut terms = high_nibbles.iter().map(|h| {
+        let h = *h;
+        let built;
+        let hi_eq = match tables {
+            Some(tables) => &tables.hi_eq[h as usize],
+            None => {
+                built = hi_eq_accumulator(shortkey, h);
+                &built
+            }
+        };
+        let mut res = shortkey.keyswitch_programmable_bootstrap(&ct_hi, hi_eq);
+        if class.contains_nibble(h) {
+            return res;
+        }
+
+        let built;
+        let lo_in = match tables.and_then(|tables| tables.lo_in.get(&(*class, h))) {
+            Some(acc) => acc,
+            None => {
+                built = lo_in_accumulator(shortkey, class, h);
+                &built
+            }
+        };
+        let ct_lo_in = shortkey.keyswitch_programmable_bootstrap(&ct_lo, lo_in);
+        shortkey.unchecked_add_assign(&mut res, &ct_lo_in);
+        let built;
+        let both = match tables {
+            Some(tables) => &tables.both,
+            None => {
+                built = both_accumulator(shortkey);
+                &built
+            }
+        };
+        shortkey.keyswitch_programmable_bootstrap(&res, both)
+    });
+
+    // terms are summed in chunks to keep the noise growth of the additions
+    // within what the parameters tolerate
+    let built;
+    let any = match tables {
+        Some(tables) => &tables.any,
+        None => {
+            built = any_accumulator(shortkey);
+            &built
+        }
+    };
+    let mut res = terms.next().unwrap();
+    let mut summed = 1;
+    for term in terms {
+        if summed == MAX_UNCHECKED_ADDS {
+            res = shortkey.keyswitch_programmable_bootstrap(&res, any);
+            summed = 1;
+        }
+        shortkey.unchecked_add_assign(&mut res, &term);
+        summed += 1;
+    }
+    if high_nibbles.len() > 1 {
+        res = shortkey.keyswitch_programmable_bootstrap(&res, any);
+    }
+    res
+}
+
+// the lookup tables of class_flag: those that do not depend on the class for
+// every high nibble, and those that do for a set of classes
+#[cfg(feature = "server")]
+pub(crate) struct ClassAccumulators {
+    hi_eq: Vec<Accumulator>,
+    both: Accumulator,
+    any: Accumulator,
+    lo_in: HashMap<(CharClass, u8), Accumulator>,
+}
+
+#[cfg(feature = "server")]
+impl ClassAccumulators {
+    pub(crate) fn new(shortkey: &tfhe::shortint::ServerKey, classes: &[CharClass]) -> Self {
+        let hi_eq = (0..8).map(|h| hi_eq_accumulator(shortkey, h)).collect();
+        let lo_in = classes
+            .iter()
+            .flat_map(|class| {
+                class
+                    .high_nibbles()
+                    .into_iter()
+                    .filter(|h| !class.contains_nibble(*h))
+                    .map(move |h| (*class, h))
+            })
+            .map(|(class, h)| ((class, h), lo_in_accumulator(shortkey, &class, h)))
+            .collect();
+        Self {
+            hi_eq,
+            both: both_accumulator(shortkey),
+            any: any_accumulator(shortkey),
+            lo_in,
+        }
+    }
+
+    // the number of class specific lookup tables
+    pub(crate) fn len(&self) -> usize {
+        self.lo_in.len()
+    }
+}
+
+#[cfg(feature = "server")]
+fn hi_eq_accumulator(shortkey: &tfhe::shortint::ServerKey, h: u8) -> Accumulator {
+    shortkey.generate_accumulator(|x| (x == h as u64) as u64)
+}
+
+#[cfg(feature = "server")]
+fn lo_in_accumulator(shortkey: &tfhe::shortint::ServerKey, class: &CharClass, h: u8) -> Accumulator {
+    shortkey.generate_accumulator(|x| class.contains(h << 4 | x as u8) as u64)
+}
+
+#[cfg(feature = "server")]
+fn both_accumulator(shortkey: &tfhe::shortint::ServerKey) -> Accumulator {
+    shortkey.generate_accumulator(|x| (x == 2) as u64)
+}
+
+#[cfg(feature = "server")]
+fn any_accumulator(shortkey: &tfhe::shortint::ServerKey) -> Accumulator {
+    shortkey.generate_accumulator(|x| (x > 0) as u64)
+}
+
+// places a 0 or 1 block in the least significant block of a radix
+#[cfg(feature = "server")]
+pub(crate) fn flag_to_radix(shortkey: &tfhe::shortint::ServerKey, flag: Ciphertext) -> RadixCiphertext {
+    let mut blocks = vec![flag];
+    blocks.extend((1..NUM_BLOCKS).map(|_| shortkey.create_trivial(0)));
+    RadixCiphertext::from(blocks)
+}
+
+// bootstraps every block of ct through the identity, so that the noise of the
+// result is that of a fresh bootstrap whichever operations produced ct. The
+// server holds no public key, so it cannot add fresh randomness: results
+// known without any ciphertext operation (trivial ones) still come out the
+// same every time.
+#[cfg(feature = "server")]
+pub(crate) fn sanitize(shortkey: &tfhe::shortint::ServerKey, ct: &RadixCiphertext) -> RadixCiphertext {
+    let identity = shortkey.generate_accumulator(|x| x);
+    let blocks: Vec<Ciphertext> = ct
+        .blocks()
+        .iter()
+        .map(|block| shortkey.keyswitch_programmable_bootstrap(block, &identity))
+        .collect();
+    RadixCiphertext::from(blocks)
+}
+
+// replaces, block by block, the character with f(block index, block) where
+// flag (a 0 or 1 block) is set, keeping it unchanged elsewhere. The flag and
+// block are combined into a single value for one bivariate lookup per block.
+#[cfg(feature = "server")]
+pub(crate) fn substitute_where<F>(
+    shortkey: &tfhe::shortint::ServerKey,
+    ct: &RadixCiphertext,
+    flag: &Ciphertext,
+    f: F,
+) -> RadixCiphertext
+where
+    F: Fn(usize, u64) -> u64,
+{
+    let block_mask = (1 << BLOCK_SIZE) - 1;
+    let blocks: Vec<Ciphertext> = ct
+        .blocks()
+        .iter()
+        .enumerate()
+        .map(|(i, block)| {
+            let acc = shortkey.generate_accumulator(|x| {
+                if x >> BLOCK_SIZE == 1 {
+                    f(i, x & block_mask) & block_mask
+                } else {
+                    x & block_mask
+                }
+            });
+            let mut combined = shortkey.unchecked_scalar_mul(flag, 1 << BLOCK_SIZE);
+            shortkey.unchecked_add_assign(&mut combined, block);
+            shortkey.keyswitch_programmable_bootstrap(&combined, &acc)
+        })
+        .collect();
+    RadixCiphertext::from(blocks)
 }
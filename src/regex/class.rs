@@ -0,0 +1,161 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+use crate::regex::alphabet::Alphabet;
+use crate::regex::parser::{u8_to_char, RegExpr};
+
+// A set of ascii characters, stored as a bitmap. Content is always ascii
+// (see encrypt_str), so characters outside of the ascii range can never be
+// members.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub(crate) struct CharClass {
+    bitmap: u128,
+}
+
+impl CharClass {
+    pub(crate) fn from_chars(cs: &[u8]) -> Self {
+        let mut class = Self::default();
+        for c in cs {
+            class.insert(*c);
+        }
+        class
+    }
+
+    pub(crate) fn from_range(from: u8, to: u8) -> Self {
+        let mut class = Self::default();
+        for c in from..=to {
+            class.insert(c);
+        }
+        class
+    }
+
+    pub(crate) fn insert(&mut self, c: u8) {
+        if c < 128 {
+            self.bitmap |= 1 << c;
+        }
+    }
+
+    pub(crate) fn union(&self, other: &Self) -> Self {
+        Self {
+            bitmap: self.bitmap | other.bitmap,
+        }
+    }
+
+    // the class of characters matched by a single character test of the
+    // pattern, None for any other construct
+    pub(crate) fn from_regex(re: &RegExpr) -> Option<Self> {
+        match re {
+            RegExpr::Char { c } => Some(Self::from_chars(&[*c])),
+            RegExpr::Range { cs } => Some(Self::from_chars(cs)),
+            RegExpr::Between { from, to } => Some(Self::from_range(*from, *to)),
+            RegExpr::Not { not_re } => Self::from_regex(not_re).map(|class| class.complement()),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn from_alphabet(alphabet: &Alphabet) -> Self {
+        Self {
+            bitmap: alphabet.bitmap(),
+        }
+    }
+
+    pub(crate) fn intersection(&self, other: &Self) -> Self {
+        Self {
+            bitmap: self.bitmap & other.bitmap,
+        }
+    }
+
+    pub(crate) fn complement(&self) -> Self {
+        Self {
+            bitmap: !self.bitmap,
+        }
+    }
+
+    pub(crate) fn contains(&self, c: u8) -> bool {
+        c < 128 && self.bitmap & (1 << c) != 0
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.bitmap.count_ones() as usize
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.bitmap == 0
+    }
+
+    pub(crate) fn chars(&self) -> impl Iterator<Item = u8> + '_ {
+        (0..128u8).filter(move |c| self.contains(*c))
+    }
+
+    // if the members are consecutive characters, returns the first and last
+    pub(crate) fn as_range(&self) -> Option<(u8, u8)> {
+        let from = self.chars().next()?;
+        let to = self.chars().last()?;
+        if self.len() == (to - from) as usize + 1 {
+            Some((from, to))
+        } else {
+            None
+        }
+    }
+
+    // whether all 16 characters with upper 4 bits h are members
+    pub(crate) fn contains_nibble(&self, h: u8) -> bool {
+        (0..16).all(|lo| self.contains(h << 4 | lo))
+    }
+
+    // the distinct upper 4 bits of the members
+    pub(crate) fn high_nibbles(&self) -> Vec<u8> {
+        let mut res: Vec<u8> = self.chars().map(|c| c >> 4).collect();
+        res.dedup();
+        res
+    }
+}
+
+impl fmt::Debug for CharClass {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "[{}]",
+            self.chars().map(u8_to_char).collect::<String>(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::regex::class::CharClass;
+
+    #[test]
+    fn test_from_range() {
+        let class = CharClass::from_range(b'a', b'd');
+        assert_eq!(vec![b'a', b'b', b'c', b'd'], class.chars().collect::<Vec<u8>>());
+        assert!(!class.contains(b'e'));
+    }
+
+    #[test]
+    fn test_non_ascii_never_member() {
+        let class = CharClass::from_chars(&[b'a', 200]);
+        assert_eq!(1, class.len());
+        assert!(!class.contains(200));
+    }
+
+    #[test]
+    fn test_as_range() {
+        assert_eq!(Some((b'a', b'c')), CharClass::from_chars(b"cab").as_range());
+        assert_eq!(None, CharClass::from_chars(b"ac").as_range());
+    }
+
+    #[test]
+    fn test_complement() {
+        let class = CharClass::from_chars(b"a").complement();
+        assert_eq!(127, class.len());
+        assert!(!class.contains(b'a'));
+        assert!(!class.contains(200));
+    }
+
+    #[test]
+    fn test_high_nibbles() {
+        let class = CharClass::from_chars(b"aeiou0_");
+        assert_eq!(vec![0x3, 0x5, 0x6, 0x7], class.high_nibbles());
+    }
+}
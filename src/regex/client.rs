@@ -0,0 +1,543 @@
+use anyhow::{anyhow, Result};
+use argon2::Argon2;
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::io::{Read, Write};
+use std::path::Path;
+use tfhe::integer::{PublicKey, RadixCiphertext, RadixClientKey};
+use zeroize::Zeroizing;
+
+use crate::regex::ciphertext::{
+    check_content_len, encrypt_str, Encoding, Header, ParamsFingerprint, Progress, StringCiphertext,
+    DEFAULT_MAX_CONTENT_LEN, HEADER_SIZE, NUM_BLOCKS, VERDICTS_PER_RADIX,
+};
+use crate::regex::storage::MappedContent;
+
+// number of characters encrypted (in parallel) between progress reports
+const ENCRYPT_CHUNK_SIZE: usize = 256;
+
+// The client side: everything needed by whoever holds the client key, none of
+// which requires the server key. Content is encrypted within the default
+// maximum length (see ciphertext::DEFAULT_MAX_CONTENT_LEN).
+
+pub fn encrypt_content(client_key: &RadixClientKey, content: &str) -> Result<StringCiphertext> {
+    encrypt_str(client_key, content)
+}
+
+// like encrypt_content, reporting progress in number of characters after
+// every chunk of characters
+pub fn encrypt_content_with_progress<F: FnMut(Progress)>(
+    client_key: &RadixClientKey,
+    content: &str,
+    mut progress: F,
+) -> Result<StringCiphertext> {
+    let mut res = Vec::with_capacity(content.len());
+    encrypt_chunks(client_key, content, |chunk| {
+        res.extend(chunk);
+        progress(Progress {
+            stage: "encrypting",
+            done: res.len(),
+            total: content.len(),
+        });
+        Ok(())
+    })?;
+    Ok(StringCiphertext::new(
+        res,
+        Encoding::Ascii,
+        ParamsFingerprint::of(&client_key.parameters())?,
+    ))
+}
+
+// encrypts content chunk by chunk, writing each chunk to writer as soon as it
+// is encrypted, so that the encryption of large content never has to be held
+// in memory. The written format is the one of a serialized StringCiphertext,
+// so it can be read back with read_content.
+pub fn encrypt_content_to_writer<W: Write, F: FnMut(Progress)>(
+    client_key: &RadixClientKey,
+    content: &str,
+    mut writer: W,
+    mut progress: F,
+) -> Result<()> {
+    let header = Header {
+        encoding: Encoding::Ascii,
+        params: ParamsFingerprint::of(&client_key.parameters())?,
+        padding: 0,
+        len: content.len() as u64,
+    };
+    bincode::serialize_into(&mut writer, &header)?;
+    let mut done = 0;
+    encrypt_chunks(client_key, content, |chunk| {
+        for ct in chunk.iter() {
+            bincode::serialize_into(&mut writer, ct)?;
+        }
+        done += chunk.len();
+        progress(Progress {
+            stage: "encrypting",
+            done,
+            total: content.len(),
+        });
+        Ok(())
+    })?;
+    writer
+        .flush()
+        .map_err(|e| anyhow!("failed to write encrypted content: {}", e))
+}
+
+// The encrypt only role: a public key derived from a client key, that
+// encrypts content for it but decrypts nothing. It is handed to the
+// producers of content, while the client key, and with it decrypting
+// verdicts, stays with whoever audits them. Content encrypted with it is
+// indistinguishable to the server from content encrypted with the client key.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct EncryptionKey {
+    public_key: PublicKey,
+    params: ParamsFingerprint,
+}
+
+impl EncryptionKey {
+    pub fn new(client_key: &RadixClientKey) -> Result<Self> {
+        Ok(Self {
+            public_key: PublicKey::new(client_key.as_ref()),
+            params: ParamsFingerprint::of(&client_key.parameters())?,
+        })
+    }
+
+    // like encrypt_content
+    pub fn encrypt(&self, content: &str) -> Result<StringCiphertext> {
+        if !content.is_ascii() {
+            return Err(anyhow!("content contains non-ascii characters"));
+        }
+        check_content_len(content.len(), DEFAULT_MAX_CONTENT_LEN)?;
+        let chars = content
+            .as_bytes()
+            .par_iter()
+            .map(|byte| self.public_key.encrypt_radix(*byte as u64, NUM_BLOCKS))
+            .collect();
+        Ok(StringCiphertext::new(chars, Encoding::Ascii, self.params))
+    }
+
+    // the fingerprint of the parameters of the client key it belongs to
+    pub fn params(&self) -> ParamsFingerprint {
+        self.params
+    }
+}
+
+pub fn save_encryption_key<P: AsRef<Path>>(key: &EncryptionKey, path: P) -> Result<()> {
+    std::fs::write(path.as_ref(), bincode::serialize(key)?)
+        .map_err(|e| anyhow!("failed to write encryption key to {:?}: {}", path.as_ref(), e))
+}
+
+pub fn load_encryption_key<P: AsRef<Path>>(path: P) -> Result<EncryptionKey> {
+    let serialized = std::fs::read(path.as_ref())
+        .map_err(|e| anyhow!("failed to read encryption key from {:?}: {}", path.as_ref(), e))?;
+    bincode::deserialize(&serialized)
+        .map_err(|e| anyhow!("{:?} is not an encryption key: {}", path.as_ref(), e))
+}
+
+// Moves encrypted content to a new client key: every character is decrypted
+// with the old key and encrypted with the new one, chunk by chunk, writing
+// each chunk to writer as encrypt_content_to_writer does. tfhe offers no way
+// to switch radix ciphertexts between the keys of different client keys, so
+// rotation takes whoever holds both keys.
+pub fn reencrypt_content_to_writer<W: Write, F: FnMut(Progress)>(
+    old_key: &RadixClientKey,
+    new_key: &RadixClientKey,
+    content: &MappedContent,
+    mut writer: W,
+    mut progress: F,
+) -> Result<()> {
+    let old_params = ParamsFingerprint::of(&old_key.parameters())?;
+    let header = content.header();
+    if header.params.is_known() && header.params != old_params {
+        return Err(anyhow!(
+            "content is encrypted with parameters {}, the old key has {}",
+            header.params,
+            old_params
+        ));
+    }
+    let header = Header {
+        params: ParamsFingerprint::of(&new_key.parameters())?,
+        ..header
+    };
+    bincode::serialize_into(&mut writer, &header)?;
+
+    for start in (0..content.len()).step_by(ENCRYPT_CHUNK_SIZE) {
+        let end = (start + ENCRYPT_CHUNK_SIZE).min(content.len());
+        let chunk = (start..end)
+            .into_par_iter()
+            .map(|i| Ok(new_key.encrypt(old_key.decrypt(&content.get(i)?))))
+            .collect::<Result<Vec<RadixCiphertext>>>()?;
+        for ct in chunk.iter() {
+            bincode::serialize_into(&mut writer, ct)?;
+        }
+        progress(Progress {
+            stage: "re-encrypting",
+            done: end,
+            total: content.len(),
+        });
+    }
+    writer
+        .flush()
+        .map_err(|e| anyhow!("failed to write encrypted content: {}", e))
+}
+
+pub fn read_content<R: Read>(reader: R) -> Result<StringCiphertext> {
+    bincode::deserialize_from(reader)
+        .map_err(|e| anyhow!("failed to read encrypted content: {}", e))
+}
+
+fn encrypt_chunks<F: FnMut(StringCiphertext) -> Result<()>>(
+    client_key: &RadixClientKey,
+    content: &str,
+    mut on_chunk: F,
+) -> Result<()> {
+    if !content.is_ascii() {
+        return Err(anyhow!("content contains non-ascii characters"));
+    }
+    check_content_len(content.len(), DEFAULT_MAX_CONTENT_LEN)?;
+    // content is ascii, so every byte boundary is a char boundary
+    for chunk in content.as_bytes().chunks(ENCRYPT_CHUNK_SIZE) {
+        on_chunk(encrypt_str(client_key, std::str::from_utf8(chunk)?)?)?;
+    }
+    Ok(())
+}
+
+// decrypts a match result, true if the pattern matched
+pub fn decrypt_result(client_key: &RadixClientKey, ct_res: &RadixCiphertext) -> bool {
+    client_key.decrypt(ct_res) != 0
+}
+
+// unpacks count verdicts from radixes packed by verdict::fhe_pack
+pub fn unpack_verdicts(client_key: &RadixClientKey, packed: &[RadixCiphertext], count: usize) -> Vec<bool> {
+    packed
+        .iter()
+        .flat_map(|ct| {
+            let bits: u64 = client_key.decrypt(ct);
+            (0..VERDICTS_PER_RADIX).map(move |i| bits >> i & 1 == 1)
+        })
+        .take(count)
+        .collect()
+}
+
+// estimates the size in bytes of the serialized encryption of content_len
+// characters, the size of every character's ciphertext is the same
+pub fn estimate_content_size(client_key: &RadixClientKey, content_len: usize) -> Result<u64> {
+    let ct = client_key.encrypt(0);
+    Ok(HEADER_SIZE as u64 + content_len as u64 * bincode::serialized_size(&ct)?)
+}
+
+// Only the buffers holding a serialized client key (and the keys derived from
+// a password) are zeroed when dropped, nothing else is wiped: not the client
+// key itself, whose memory tfhe does not expose, nor memory the buffers were
+// moved out of while growing, nor copies made by bincode, tfhe or the os.
+pub fn save_client_key<P: AsRef<Path>>(client_key: &RadixClientKey, path: P) -> Result<()> {
+    let serialized = Zeroizing::new(bincode::serialize(client_key)?);
+    write_secret(path.as_ref(), &serialized)
+        .map_err(|e| anyhow!("failed to write client key to {:?}: {}", path.as_ref(), e))
+}
+
+pub fn load_client_key<P: AsRef<Path>>(path: P) -> Result<RadixClientKey> {
+    let serialized = Zeroizing::new(
+        std::fs::read(path.as_ref())
+            .map_err(|e| anyhow!("failed to read client key from {:?}: {}", path.as_ref(), e))?,
+    );
+    Ok(bincode::deserialize(&serialized)?)
+}
+
+// writes secret to path, readable and writable by its owner only. It is
+// written in place, never through a temporary file that is renamed, so that
+// no copy is left behind elsewhere when writing fails. The mode a file is
+// created with does not apply to a file that already exists, whose
+// permissions are narrowed (once it is truncated) before anything is written.
+fn write_secret(path: &Path, secret: &[u8]) -> std::io::Result<()> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(path)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        file.set_permissions(std::fs::Permissions::from_mode(0o600))?;
+    }
+    file.write_all(secret)
+}
+
+// bump whenever the layout of key backups changes
+const KEY_BACKUP_VERSION: u32 = 1;
+
+// A client key encrypted with a key derived from a password (argon2id with a
+// random salt), with chacha20poly1305. Losing the client key makes everything
+// encrypted with it unreadable, backups make it safe to keep copies of.
+#[derive(Serialize, Deserialize)]
+struct KeyBackup {
+    version: u32,
+    salt: [u8; 16],
+    nonce: [u8; 12],
+    ciphertext: Vec<u8>,
+}
+
+pub fn export_client_key<P: AsRef<Path>>(
+    client_key: &RadixClientKey,
+    password: &[u8],
+    path: P,
+) -> Result<()> {
+    let mut salt = [0; 16];
+    OsRng.fill_bytes(&mut salt);
+    let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let serialized = Zeroizing::new(bincode::serialize(client_key)?);
+    let ciphertext = backup_cipher(password, &salt)?
+        .encrypt(&nonce, serialized.as_slice())
+        .map_err(|_| anyhow!("failed to encrypt client key"))?;
+    let backup = KeyBackup {
+        version: KEY_BACKUP_VERSION,
+        salt,
+        nonce: nonce.into(),
+        ciphertext,
+    };
+    write_secret(path.as_ref(), &bincode::serialize(&backup)?)
+        .map_err(|e| anyhow!("failed to write key backup to {:?}: {}", path.as_ref(), e))
+}
+
+pub fn import_client_key<P: AsRef<Path>>(path: P, password: &[u8]) -> Result<RadixClientKey> {
+    let serialized = std::fs::read(path.as_ref())
+        .map_err(|e| anyhow!("failed to read key backup from {:?}: {}", path.as_ref(), e))?;
+    let backup: KeyBackup = bincode::deserialize(&serialized)
+        .map_err(|e| anyhow!("{:?} is not a key backup: {}", path.as_ref(), e))?;
+    if backup.version != KEY_BACKUP_VERSION {
+        return Err(anyhow!(
+            "key backup {:?} has version {}, expected {}",
+            path.as_ref(),
+            backup.version,
+            KEY_BACKUP_VERSION
+        ));
+    }
+    let serialized = Zeroizing::new(
+        backup_cipher(password, &backup.salt)?
+            .decrypt(Nonce::from_slice(&backup.nonce), backup.ciphertext.as_slice())
+            .map_err(|_| anyhow!("wrong password, or the key backup is corrupted"))?,
+    );
+    Ok(bincode::deserialize(&serialized)?)
+}
+
+fn backup_cipher(password: &[u8], salt: &[u8]) -> Result<ChaCha20Poly1305> {
+    let mut key = Zeroizing::new([0; 32]);
+    Argon2::default()
+        .hash_password_into(password, salt, key.as_mut())
+        .map_err(|e| anyhow!("failed to derive key backup key: {}", e))?;
+    Ok(ChaCha20Poly1305::new(Key::from_slice(key.as_ref())))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::regex::ciphertext::{
+        check_content_len, gen_client_key, with_seed, ContentTooLong, Encoding, ParamsFingerprint,
+        StringCiphertext, DEFAULT_MAX_CONTENT_LEN,
+    };
+    use crate::regex::client::{
+        decrypt_result, encrypt_content, encrypt_content_to_writer, encrypt_content_with_progress,
+        estimate_content_size, export_client_key, import_client_key, load_client_key,
+        load_encryption_key, read_content, reencrypt_content_to_writer, save_client_key,
+        save_encryption_key, EncryptionKey, ENCRYPT_CHUNK_SIZE,
+    };
+    use crate::regex::storage::MappedContent;
+    use crate::regex::profile::Profile;
+    use crate::regex::test_keys::{temp_path, test_client_key};
+
+    #[test]
+    fn test_seeded_client_key() {
+        let key = |seed: &[u8]| {
+            let client_key = with_seed(seed, || gen_client_key(Profile::Demo));
+            bincode::serialize(&client_key).unwrap()
+        };
+        assert_eq!(key(b"master secret"), key(b"master secret"));
+        assert_ne!(key(b"master secret"), key(b"other secret"));
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let client_key = test_client_key();
+
+        let ct_content = encrypt_content(&client_key, "ab").unwrap();
+        assert!(decrypt_result(&client_key, &ct_content[1]));
+        assert_eq!(b'b' as u64, client_key.decrypt(&ct_content[1]));
+
+        let size = estimate_content_size(&client_key, ct_content.len()).unwrap();
+        assert_eq!(bincode::serialized_size(&ct_content).unwrap(), size);
+    }
+
+    #[test]
+    fn test_metadata_roundtrip() {
+        let client_key = test_client_key();
+        let ct_content = encrypt_content(&client_key, "abc").unwrap().with_padding(1).unwrap();
+        assert!(ct_content.params().is_known());
+
+        let serialized = bincode::serialize(&ct_content).unwrap();
+        let ct_content: StringCiphertext = bincode::deserialize(&serialized).unwrap();
+        assert_eq!(3, ct_content.len());
+        assert_eq!(2, ct_content.content_len());
+        assert_eq!(Encoding::Ascii, ct_content.encoding());
+        assert_eq!(
+            ParamsFingerprint::of(&client_key.parameters()).unwrap(),
+            ct_content.params()
+        );
+    }
+
+    #[test]
+    fn test_save_load_client_key() {
+        let client_key = test_client_key();
+        let path = temp_path("client_key");
+        save_client_key(&client_key, &path).unwrap();
+
+        let ct = client_key.encrypt(42);
+        assert_eq!(42, load_client_key(&path).unwrap().decrypt(&ct));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_saved_client_key_owner_only() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = temp_path("client_key_mode");
+        save_client_key(&test_client_key(), &path).unwrap();
+
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(0o600, mode & 0o777);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    // overwriting a key file readable by others narrows its permissions
+    #[cfg(unix)]
+    #[test]
+    fn test_overwritten_client_key_owner_only() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = temp_path("client_key_overwritten");
+        std::fs::write(&path, b"stale").unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644)).unwrap();
+        save_client_key(&test_client_key(), &path).unwrap();
+
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(0o600, mode & 0o777);
+        assert!(load_client_key(&path).is_ok());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_reencrypt_content() {
+        let old_key = test_client_key();
+        let new_key = with_seed(b"new key", || gen_client_key(Profile::Demo));
+        let old_path = temp_path("rotate_old");
+        let new_path = temp_path("rotate_new");
+        let file = std::fs::File::create(&old_path).unwrap();
+        encrypt_content_to_writer(&old_key, "abc", file, |_| ()).unwrap();
+
+        let content = MappedContent::open(&old_path).unwrap();
+        let file = std::fs::File::create(&new_path).unwrap();
+        reencrypt_content_to_writer(&old_key, &new_key, &content, file, |_| ()).unwrap();
+
+        let rotated = MappedContent::open(&new_path).unwrap().load().unwrap();
+        let chars: Vec<u64> = rotated.iter().map(|ct| new_key.decrypt(ct)).collect();
+        assert_eq!(vec![b'a' as u64, b'b' as u64, b'c' as u64], chars);
+        assert_eq!(ParamsFingerprint::of(&new_key.parameters()).unwrap(), rotated.params());
+
+        // the new key is not the key the rotated content was encrypted with
+        let content = MappedContent::open(&new_path).unwrap();
+        let mut written: Vec<u8> = vec![];
+        assert!(reencrypt_content_to_writer(&old_key, &new_key, &content, &mut written, |_| ()).is_err());
+        std::fs::remove_file(old_path).unwrap();
+        std::fs::remove_file(new_path).unwrap();
+    }
+
+    #[test]
+    fn test_export_import_client_key() {
+        let client_key = test_client_key();
+        let path = temp_path("key_backup");
+        export_client_key(&client_key, b"correct horse", &path).unwrap();
+
+        let ct = client_key.encrypt(42);
+        assert_eq!(42, import_client_key(&path, b"correct horse").unwrap().decrypt(&ct));
+        assert!(import_client_key(&path, b"battery staple").is_err());
+
+        let mut serialized = std::fs::read(&path).unwrap();
+        let last = serialized.len() - 1;
+        serialized[last] ^= 1;
+        std::fs::write(&path, serialized).unwrap();
+        assert!(import_client_key(&path, b"correct horse").is_err());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_non_ascii_rejected() {
+        let client_key = test_client_key();
+        assert!(encrypt_content(&client_key, "é").is_err());
+    }
+
+    #[test]
+    fn test_encryption_key() {
+        let client_key = test_client_key();
+        let path = temp_path("encryption_key");
+        save_encryption_key(&EncryptionKey::new(&client_key).unwrap(), &path).unwrap();
+        let key = load_encryption_key(&path).unwrap();
+        std::fs::remove_file(path).unwrap();
+
+        let ct_content = key.encrypt("ab").unwrap();
+        let decrypted: Vec<u64> = ct_content.iter().map(|ct| client_key.decrypt(ct)).collect();
+        assert_eq!(vec![b'a' as u64, b'b' as u64], decrypted);
+        assert_eq!(ParamsFingerprint::of(&client_key.parameters()).unwrap(), ct_content.params());
+        assert!(key.encrypt("é").is_err());
+    }
+
+    #[test]
+    fn test_content_too_long_rejected() {
+        let client_key = test_client_key();
+        let content = "a".repeat(DEFAULT_MAX_CONTENT_LEN + 1);
+        for res in [
+            encrypt_content(&client_key, &content),
+            encrypt_content_with_progress(&client_key, &content, |_| ()),
+        ] {
+            let err = res.err().unwrap();
+            let exp = ContentTooLong {
+                len: content.len(),
+                max: DEFAULT_MAX_CONTENT_LEN,
+            };
+            assert_eq!(Some(&exp), err.downcast_ref::<ContentTooLong>());
+        }
+        assert_eq!(Ok(()), check_content_len(3, 3));
+        assert_eq!(Err(ContentTooLong { len: 4, max: 3 }), check_content_len(4, 3));
+    }
+
+    #[test]
+    fn test_encrypt_with_progress() {
+        let client_key = test_client_key();
+        let content = "a".repeat(ENCRYPT_CHUNK_SIZE + 1);
+
+        let mut reported = vec![];
+        let ct_content =
+            encrypt_content_with_progress(&client_key, &content, |p| reported.push(p.done)).unwrap();
+        assert_eq!(content.len(), ct_content.len());
+        assert_eq!(vec![ENCRYPT_CHUNK_SIZE, ENCRYPT_CHUNK_SIZE + 1], reported);
+    }
+
+    #[test]
+    fn test_encrypt_to_writer() {
+        let client_key = test_client_key();
+        let mut written = vec![];
+        encrypt_content_to_writer(&client_key, "abc", &mut written, |_| ()).unwrap();
+
+        let ct_content = read_content(written.as_slice()).unwrap();
+        assert_eq!(
+            ParamsFingerprint::of(&client_key.parameters()).unwrap(),
+            ct_content.params()
+        );
+        let decrypted: Vec<u64> = ct_content.iter().map(|ct| client_key.decrypt(ct)).collect();
+        assert_eq!(vec![b'a' as u64, b'b' as u64, b'c' as u64], decrypted);
+    }
+}
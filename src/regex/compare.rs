@@ -0,0 +1,98 @@
+use tfhe::integer::{RadixCiphertext, ServerKey};
+
+use crate::regex::engine::evaluate;
+use crate::regex::execution::{Executed, Execution};
+
+// Comparisons between two encrypted strings. The lengths of both strings are
+// known (as they are for all content), and are used as is: strings of
+// different lengths are never equal.
+
+// encrypted results of fhe_cmp
+pub const CMP_LESS: u64 = 0;
+pub const CMP_EQUAL: u64 = 1;
+pub const CMP_GREATER: u64 = 2;
+
+// whether a and b are equal
+pub fn fhe_eq(sk: &ServerKey, a: &[RadixCiphertext], b: &[RadixCiphertext]) -> RadixCiphertext {
+    let content = [a, b].concat();
+    let plan = if a.len() == b.len() {
+        (0..a.len())
+            .map(|i| char_eq(i, a.len() + i))
+            .fold(Executed::ct_true(), Executed::and)
+    } else {
+        Executed::ct_false()
+    };
+
+    let mut exec = Execution::new(sk.clone());
+    evaluate(&mut exec, &content, &[plan]).remove(0)
+}
+
+// compares a and b lexicographically (by byte value, a string that is a
+// strict prefix of another string is less), resulting in an encryption of
+// CMP_LESS, CMP_EQUAL or CMP_GREATER
+pub fn fhe_cmp(sk: &ServerKey, a: &[RadixCiphertext], b: &[RadixCiphertext]) -> RadixCiphertext {
+    let content = [a, b].concat();
+    let b_at = a.len();
+
+    // prefix holds whether the strings are equal up to the current position
+    let mut prefix = Executed::ct_true();
+    let mut greater = Executed::ct_false();
+    for i in 0..std::cmp::min(a.len(), b.len()) {
+        let a_char = Executed::ct_pos(i);
+        let b_char = Executed::ct_pos(b_at + i);
+        let char_gt = Executed::not(Executed::le(a_char, b_char));
+        greater = Executed::or(greater, Executed::and(prefix.clone(), char_gt));
+        prefix = Executed::and(prefix, char_eq(i, b_at + i));
+    }
+    if a.len() > b.len() {
+        greater = Executed::or(greater, prefix.clone());
+    }
+    let equal = if a.len() == b.len() {
+        prefix
+    } else {
+        Executed::ct_false()
+    };
+
+    let mut exec = Execution::new(sk.clone());
+    let mut res = evaluate(&mut exec, &content, &[equal, greater]);
+    let mut greater = res.pop().unwrap();
+    let mut equal = res.pop().unwrap();
+
+    // CMP_EQUAL * equal + CMP_GREATER * greater
+    let mut greater = sk.smart_scalar_mul(&mut greater, CMP_GREATER);
+    let mut res = sk.smart_add(&mut equal, &mut greater);
+    sk.full_propagate(&mut res);
+    res
+}
+
+fn char_eq(a_at: usize, b_at: usize) -> Executed {
+    Executed::eq(Executed::ct_pos(a_at), Executed::ct_pos(b_at))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::regex::compare::{fhe_cmp, fhe_eq, CMP_EQUAL, CMP_GREATER, CMP_LESS};
+    use crate::regex::engine::tests::{encrypt_trivial, KEYS};
+    use test_case::test_case;
+
+    #[test_case("ab", "ab", 1)]
+    #[test_case("ab", "ac", 0)]
+    #[test_case("ab", "abc", 0 ; "different lengths")]
+    #[test_case("", "", 1 ; "empty")]
+    fn test_fhe_eq(a: &str, b: &str, exp: u64) {
+        let ct_res = fhe_eq(&KEYS.1, &encrypt_trivial(a), &encrypt_trivial(b));
+        assert_eq!(exp, KEYS.0.decrypt(&ct_res));
+    }
+
+    #[test_case("ab", "ac", CMP_LESS)]
+    #[test_case("ac", "ab", CMP_GREATER)]
+    #[test_case("ab", "ab", CMP_EQUAL)]
+    #[test_case("b", "abc", CMP_GREATER ; "first character decides")]
+    #[test_case("ab", "abc", CMP_LESS ; "prefix is less")]
+    #[test_case("abc", "ab", CMP_GREATER ; "extension is greater")]
+    #[test_case("", "", CMP_EQUAL ; "empty")]
+    fn test_fhe_cmp(a: &str, b: &str, exp: u64) {
+        let ct_res = fhe_cmp(&KEYS.1, &encrypt_trivial(a), &encrypt_trivial(b));
+        assert_eq!(exp, KEYS.0.decrypt(&ct_res));
+    }
+}
@@ -0,0 +1,273 @@
+use anyhow::{anyhow, Result};
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::thread::JoinHandle;
+use std::time::{Duration, SystemTime};
+
+use crate::regex::serve::ServerLimits;
+
+// how often a watched configuration file is checked for changes
+pub const RELOAD_INTERVAL: Duration = Duration::from_secs(1);
+
+// The configuration of a long running server (see serve), kept in a file
+// (toml or json, told apart by extension) along with the deployment. It holds
+// everything that would otherwise be passed to `fhe-regex serve` on the
+// command line. Relative paths in the file are relative to the file itself.
+// For example:
+//
+//   uds = "/run/fhe-regex.sock"
+//   compressed_server_key = "server.key"
+//   key_slots = 4
+//   planner_threads = 2
+//
+//   [limits]
+//   max_content_len = 4096
+//   max_bootstraps = 200000
+//
+// The limits are reloaded while the server runs (see ConfigWatcher), the
+// rest only takes effect when the server is started again.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ServeConfig {
+    pub uds: PathBuf,
+    pub server_key: Option<PathBuf>,
+    pub compressed_server_key: Option<PathBuf>,
+    #[serde(default)]
+    pub key_slots: usize,
+    pub planner_threads: Option<usize>,
+    pub batch_window_ms: Option<u64>,
+    #[serde(default)]
+    pub shard_workers: Vec<PathBuf>,
+    pub shard_len: Option<usize>,
+    pub registry_uds: Option<PathBuf>,
+    pub register_with: Option<PathBuf>,
+    #[serde(default)]
+    pub limits: LimitsConfig,
+}
+
+// the command line flags of the same name, the defaults of ServerLimits when
+// absent
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    pub max_pattern_len: Option<usize>,
+    pub max_nesting_depth: Option<usize>,
+    pub max_repetitions: Option<usize>,
+    pub max_content_len: Option<usize>,
+    pub max_memory_mib: Option<u64>,
+    pub max_bootstraps: Option<usize>,
+    pub max_threads: Option<usize>,
+}
+
+impl ServeConfig {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let serialized = std::fs::read_to_string(path)
+            .map_err(|e| anyhow!("failed to read serve config {:?}: {}", path, e))?;
+        let config = match path.extension().and_then(|ext| ext.to_str()) {
+            Some("toml") => Self::from_toml(&serialized)?,
+            Some("json") => Self::from_json(&serialized)?,
+            _ => return Err(anyhow!("serve config {:?} is neither a .toml nor a .json file", path)),
+        };
+        // the limits are checked up front, so that a config that loads also
+        // reloads
+        config.limits.server_limits()?;
+        Ok(config.relative_to(path.parent().unwrap_or_else(|| Path::new(""))))
+    }
+
+    pub fn from_toml(serialized: &str) -> Result<Self> {
+        toml::from_str(serialized).map_err(|e| anyhow!("failed to parse serve config: {}", e))
+    }
+
+    pub fn from_json(serialized: &str) -> Result<Self> {
+        serde_json::from_str(serialized).map_err(|e| anyhow!("failed to parse serve config: {}", e))
+    }
+
+    fn relative_to(mut self, dir: &Path) -> Self {
+        let paths = [
+            Some(&mut self.uds),
+            self.server_key.as_mut(),
+            self.compressed_server_key.as_mut(),
+            self.registry_uds.as_mut(),
+            self.register_with.as_mut(),
+        ]
+        .into_iter()
+        .flatten()
+        .chain(self.shard_workers.iter_mut());
+        for path in paths {
+            *path = dir.join(&*path);
+        }
+        self
+    }
+}
+
+impl LimitsConfig {
+    pub fn server_limits(&self) -> Result<ServerLimits> {
+        let mut limits = ServerLimits::default();
+        if let Some(max_len) = self.max_pattern_len {
+            limits.parse.max_len = max_len;
+        }
+        if let Some(max_depth) = self.max_nesting_depth {
+            limits.parse.max_depth = max_depth;
+        }
+        if let Some(max_repetitions) = self.max_repetitions {
+            limits.parse.max_repetitions = max_repetitions;
+        }
+        if let Some(max_content_len) = self.max_content_len {
+            limits.max_content_len = max_content_len;
+        }
+        if let Some(mib) = self.max_memory_mib {
+            let bytes = mib
+                .checked_mul(1 << 20)
+                .ok_or_else(|| anyhow!("max_memory_mib of {} does not fit in 64 bits of bytes", mib))?;
+            limits.max_memory = Some(bytes);
+        }
+        limits.max_bootstraps = self.max_bootstraps;
+        limits.max_threads = self.max_threads;
+        Ok(limits)
+    }
+}
+
+// Reloads the limits of a running server whenever its configuration file
+// changes, as told by its modification time. A file that fails to load keeps
+// the limits as they were. Changes to anything but the limits are logged,
+// they take a restart.
+pub struct ConfigWatcher {
+    path: PathBuf,
+    config: ServeConfig,
+    modified: Option<SystemTime>,
+    limits: Arc<RwLock<ServerLimits>>,
+}
+
+impl ConfigWatcher {
+    // config as loaded from path, and the limits of the server it configures
+    // (see serve::UdsServer::limits)
+    pub fn new<P: AsRef<Path>>(path: P, config: ServeConfig, limits: Arc<RwLock<ServerLimits>>) -> Self {
+        let path = path.as_ref().to_path_buf();
+        Self {
+            modified: modified(&path),
+            path,
+            config,
+            limits,
+        }
+    }
+
+    // checks the file every interval, until the process exits
+    pub fn spawn(mut self, interval: Duration) -> JoinHandle<()> {
+        std::thread::spawn(move || loop {
+            std::thread::sleep(interval);
+            if let Err(e) = self.reload_if_changed() {
+                warn!("kept the limits as they were: {}", e);
+            }
+        })
+    }
+
+    // whether the file changed since it was last loaded, in which case the
+    // limits are those of the file from then on
+    pub fn reload_if_changed(&mut self) -> Result<bool> {
+        let modified = modified(&self.path);
+        if modified == self.modified {
+            return Ok(false);
+        }
+        self.modified = modified;
+        let config = ServeConfig::load(&self.path)?;
+        let limits = config.limits.server_limits()?;
+        // what the server runs with from now on
+        let running = ServeConfig {
+            limits: config.limits.clone(),
+            ..self.config.clone()
+        };
+        if config != running {
+            warn!("changes to {:?} other than to the limits take a restart", self.path);
+        }
+        *self.limits.write().unwrap() = limits;
+        info!("reloaded the limits from {:?}: {:?}", self.path, limits);
+        self.config = running;
+        Ok(true)
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::regex::config::{ConfigWatcher, ServeConfig};
+    use crate::regex::serve::ServerLimits;
+    use crate::regex::test_keys::temp_path;
+    use std::sync::{Arc, RwLock};
+    use std::time::{Duration, SystemTime};
+
+    #[test]
+    fn test_toml_and_json_agree() {
+        let toml = ServeConfig::from_toml(
+            r#"
+            uds = "fhe-regex.sock"
+            compressed_server_key = "server.key"
+            shard_workers = ["worker.sock"]
+            shard_len = 64
+
+            [limits]
+            max_repetitions = 8
+            max_memory_mib = 2
+            "#,
+        )
+        .unwrap();
+        let json = ServeConfig::from_json(
+            r#"{
+                "uds": "fhe-regex.sock",
+                "compressed_server_key": "server.key",
+                "shard_workers": ["worker.sock"],
+                "shard_len": 64,
+                "limits": { "max_repetitions": 8, "max_memory_mib": 2 }
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(toml, json);
+
+        let limits = toml.limits.server_limits().unwrap();
+        assert_eq!(8, limits.parse.max_repetitions);
+        assert_eq!(Some(2 << 20), limits.max_memory);
+        assert_eq!(ServerLimits::default().max_content_len, limits.max_content_len);
+    }
+
+    #[test]
+    fn test_invalid_config() {
+        assert!(ServeConfig::from_toml("uds = \"a.sock\"\nworkers = 2\n").is_err());
+        // 2^44 mib is 2^64 bytes
+        let config = ServeConfig::from_toml("uds = \"a.sock\"\n[limits]\nmax_memory_mib = 17592186044416\n").unwrap();
+        assert!(config.limits.server_limits().is_err());
+    }
+
+    #[test]
+    fn test_reload() {
+        let dir = temp_path("serve_config");
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("serve.toml");
+        let write = |max_content_len: &str| {
+            std::fs::write(&path, format!("uds = \"a.sock\"\n[limits]\nmax_content_len = {}\n", max_content_len)).unwrap();
+            // the modification time as a change, whatever the resolution of
+            // the file system
+            let file = std::fs::File::options().write(true).open(&path).unwrap();
+            file.set_modified(SystemTime::now() + Duration::from_secs(60)).unwrap_or(());
+        };
+
+        write("16");
+        let config = ServeConfig::load(&path).unwrap();
+        assert_eq!(dir.join("a.sock"), config.uds);
+        let limits = Arc::new(RwLock::new(config.limits.server_limits().unwrap()));
+        let mut watcher = ConfigWatcher::new(&path, config, limits.clone());
+        assert!(!watcher.reload_if_changed().unwrap());
+
+        write("8");
+        assert!(watcher.reload_if_changed().unwrap());
+        assert_eq!(8, limits.read().unwrap().max_content_len);
+
+        // a config that fails to load keeps the limits
+        write("\"eight\"");
+        assert!(watcher.reload_if_changed().is_err());
+        assert_eq!(8, limits.read().unwrap().max_content_len);
+    }
+}
@@ -0,0 +1,342 @@
+use crate::regex::ciphertext::NUM_BLOCKS;
+use crate::regex::class::CharClass;
+use crate::regex::execution::Executed;
+use crate::regex::parser::RegExpr;
+
+// Estimates the cost of the available lowerings in number of programmable
+// bootstraps, which dominate the runtime of every homomorphic operation, for
+// the active parameter set.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct CostModel {
+    num_blocks: usize,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum ClassLowering {
+    // one equality per member, combined with ors
+    EqChain,
+    // a >= and a <= comparison, only for classes of consecutive characters
+    Interval,
+    // lookup tables over the nibbles of the character
+    Lut,
+}
+
+impl CostModel {
+    pub(crate) fn active() -> Self {
+        Self {
+            num_blocks: NUM_BLOCKS,
+        }
+    }
+
+    // equality against a constant, one lookup per block and one to combine
+    pub(crate) fn eq(&self) -> usize {
+        self.num_blocks + 1
+    }
+
+    // ordered comparison, per block a comparison and a carry propagation
+    pub(crate) fn cmp(&self) -> usize {
+        2 * self.num_blocks + 1
+    }
+
+    // and, or, xor and not, a bivariate lookup per block
+    pub(crate) fn bitop(&self) -> usize {
+        self.num_blocks
+    }
+
+    pub(crate) fn class(&self, class: &CharClass, lowering: ClassLowering) -> Option<usize> {
+        if class.is_empty() {
+            return Some(0);
+        }
+        match lowering {
+            ClassLowering::EqChain => Some(class.len() * self.eq() + (class.len() - 1) * self.bitop()),
+            ClassLowering::Interval => class.as_range().map(|(from, to)| {
+                if from == to {
+                    self.eq()
+                } else {
+                    2 * self.cmp() + self.bitop()
+                }
+            }),
+            ClassLowering::Lut => {
+                let high_nibbles = class.high_nibbles();
+                let terms: usize = high_nibbles
+                    .iter()
+                    .map(|h| if class.contains_nibble(*h) { 1 } else { 3 })
+                    .sum();
+                let combine = if high_nibbles.len() > 1 {
+                    1 + (high_nibbles.len() - 1) / 4
+                } else {
+                    0
+                };
+                Some(terms + combine)
+            }
+        }
+    }
+
+    pub(crate) fn class_lowering(&self, class: &CharClass) -> ClassLowering {
+        [
+            ClassLowering::EqChain,
+            ClassLowering::Interval,
+            ClassLowering::Lut,
+        ]
+        .into_iter()
+        .filter_map(|lowering| self.class(class, lowering).map(|cost| (cost, lowering)))
+        .min_by_key(|(cost, _)| *cost)
+        .map(|(_, lowering)| lowering)
+        .unwrap()
+    }
+
+    pub(crate) fn class_lowering_cost(&self, class: &CharClass) -> usize {
+        self.class(class, self.class_lowering(class)).unwrap()
+    }
+
+    // a class can also be tested by testing its complement and negating the
+    // result, whichever is cheaper
+    pub(crate) fn class_test(&self, class: &CharClass) -> usize {
+        std::cmp::min(self.class_lowering_cost(class), self.negated_cost(class))
+    }
+
+    pub(crate) fn negate_class(&self, class: &CharClass) -> bool {
+        self.negated_cost(class) < self.class_lowering_cost(class)
+    }
+
+    // testing the complement and negating, a complement of one character is
+    // tested for inequality instead, which costs no more than equality
+    fn negated_cost(&self, class: &CharClass) -> usize {
+        let complement = class.complement();
+        if complement.len() == 1 {
+            return self.eq();
+        }
+        self.class_lowering_cost(&complement) + self.bitop()
+    }
+
+    // estimated cost of testing re from a single start position
+    pub(crate) fn regex(&self, re: &RegExpr, content_len: usize) -> usize {
+        match re {
+            RegExpr::SOF | RegExpr::EOF | RegExpr::AnyChar => 0,
+            RegExpr::Char { .. }
+            | RegExpr::Range { .. }
+            | RegExpr::Between { .. }
+            | RegExpr::Not { .. } => self.class_test(&CharClass::from_regex(re).unwrap()),
+            RegExpr::Either { alts } => {
+                alts.iter().map(|re| self.regex(re, content_len)).sum::<usize>()
+                    + self.bitop() * (alts.len() - 1)
+            }
+            RegExpr::Optional { opt_re } => self.regex(opt_re, content_len),
+            RegExpr::Repeated {
+                repeat_re,
+                at_most,
+                ..
+            } => self.repetition(
+                self.regex(repeat_re, content_len),
+                at_most.unwrap_or(content_len),
+            ),
+            RegExpr::Seq { re_xs } => re_xs
+                .iter()
+                .map(|re_x| self.regex(re_x, content_len) + self.bitop())
+                .sum::<usize>()
+                .saturating_sub(self.bitop()),
+        }
+    }
+
+    // estimated cost of testing re from every start position it can match
+    // at, a pattern anchored at the start is only tested from the first
+    pub(crate) fn pattern(&self, re: &RegExpr, content_len: usize) -> usize {
+        let starts = if anchored_at_start(re) { 1 } else { content_len };
+        starts * self.regex(re, content_len)
+    }
+
+    // rewrites of re that the cost model estimates to be cheaper, along with
+    // their estimated cost, cheapest first
+    pub(crate) fn suggest_rewrites(&self, re: &RegExpr, content_len: usize) -> Vec<Rewrite> {
+        let current = self.pattern(re, content_len);
+        let mut res = vec![];
+
+        let mut unbounded = vec![];
+        collect_unbounded(re, &mut unbounded);
+        for repeated in unbounded {
+            if let RegExpr::Repeated {
+                repeat_re,
+                at_least,
+                ..
+            } = repeated
+            {
+                let at_most = std::cmp::max(at_least.unwrap_or(0), SUGGESTED_REPEAT_BOUND);
+                if at_most >= content_len {
+                    continue;
+                }
+                // an explicit lower bound, so that it is suggested as {0,n}
+                let bounded = RegExpr::Repeated {
+                    repeat_re: repeat_re.clone(),
+                    at_least: Some(at_least.unwrap_or(0)),
+                    at_most: Some(at_most),
+                };
+                let rewritten = replace(re, repeated, &bounded);
+                res.push(Rewrite {
+                    suggestion: format!(
+                        "replace `{}` with `{}`",
+                        repeated.to_pattern(),
+                        bounded.to_pattern()
+                    ),
+                    cost: self.pattern(&rewritten, content_len),
+                });
+            }
+        }
+
+        if !anchored_at_start(re) && content_len > 1 {
+            res.push(Rewrite {
+                suggestion: format!(
+                    "anchor the pattern at the start (`^`) to cut the start positions by {}x",
+                    content_len
+                ),
+                cost: self.regex(re, content_len),
+            });
+        }
+
+        res.retain(|rewrite| rewrite.cost < current);
+        res.sort_by_key(|rewrite| rewrite.cost);
+        res
+    }
+
+    // estimated cost of evaluating the operation of node, once its operands
+    // are at hand
+    pub(crate) fn operation(&self, node: &Executed) -> usize {
+        match node {
+            Executed::Constant { .. } | Executed::CtPos { .. } => 0,
+            Executed::Equal { .. } | Executed::NotEqual { .. } => self.eq(),
+            Executed::GreaterOrEqual { .. } | Executed::LessOrEqual { .. } | Executed::GreaterThan { .. } => {
+                self.cmp()
+            }
+            // the sum, and a carry propagation per block
+            Executed::Add { .. } => self.bitop() + self.num_blocks,
+            Executed::And { .. } | Executed::Or { .. } | Executed::Xor { .. } | Executed::Not { .. } => {
+                self.bitop()
+            }
+            Executed::InClass { class, .. } => self.class(class, ClassLowering::Lut).unwrap(),
+            Executed::Select { .. } => 2 * self.num_blocks,
+        }
+    }
+
+    // repetitions are unrolled into one branch per repeat count, which share
+    // their common prefix, so each additional repeat costs one test of the
+    // repeated expression and one and
+    pub(crate) fn repetition(&self, repeat_cost: usize, at_most: usize) -> usize {
+        at_most * (repeat_cost + self.bitop())
+    }
+}
+
+// unbounded repetitions are suggested to be bounded to this many repeats
+const SUGGESTED_REPEAT_BOUND: usize = 32;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Rewrite {
+    pub(crate) suggestion: String,
+    pub(crate) cost: usize,
+}
+
+fn anchored_at_start(re: &RegExpr) -> bool {
+    match re {
+        RegExpr::SOF => true,
+        RegExpr::Seq { re_xs } => re_xs.first().map_or(false, anchored_at_start),
+        _ => false,
+    }
+}
+
+fn collect_unbounded<'a>(re: &'a RegExpr, res: &mut Vec<&'a RegExpr>) {
+    match re {
+        RegExpr::Repeated {
+            repeat_re, at_most, ..
+        } => {
+            if at_most.is_none() {
+                res.push(re);
+            }
+            collect_unbounded(repeat_re, res);
+        }
+        RegExpr::Not { not_re: re_x } | RegExpr::Optional { opt_re: re_x } => {
+            collect_unbounded(re_x, res)
+        }
+        RegExpr::Either { alts } => alts.iter().for_each(|re| collect_unbounded(re, res)),
+        RegExpr::Seq { re_xs } => re_xs.iter().for_each(|re_x| collect_unbounded(re_x, res)),
+        _ => (),
+    }
+}
+
+// re with the sub expression at the address of target replaced by with
+fn replace(re: &RegExpr, target: &RegExpr, with: &RegExpr) -> RegExpr {
+    if std::ptr::eq(re, target) {
+        return with.clone();
+    }
+    let sub = |re_x: &RegExpr| Box::new(replace(re_x, target, with));
+    match re {
+        RegExpr::Not { not_re } => RegExpr::Not { not_re: sub(not_re) },
+        RegExpr::Optional { opt_re } => RegExpr::Optional { opt_re: sub(opt_re) },
+        RegExpr::Repeated {
+            repeat_re,
+            at_least,
+            at_most,
+        } => RegExpr::Repeated {
+            repeat_re: sub(repeat_re),
+            at_least: *at_least,
+            at_most: *at_most,
+        },
+        RegExpr::Either { alts } => RegExpr::Either {
+            alts: alts.iter().map(|re| replace(re, target, with)).collect(),
+        },
+        RegExpr::Seq { re_xs } => RegExpr::Seq {
+            re_xs: re_xs.iter().map(|re_x| replace(re_x, target, with)).collect(),
+        },
+        _ => re.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::regex::class::CharClass;
+    use crate::regex::cost::{ClassLowering, CostModel};
+    use crate::regex::parser::parse;
+    use test_case::test_case;
+
+    #[test_case(b"a", ClassLowering::Lut ; "single char")]
+    #[test_case(b"aA", ClassLowering::Lut ; "case insensitive char")]
+    #[test_case(b"abcdefg", ClassLowering::Lut ; "range within one nibble")]
+    #[test_case(b"aeiou0123456789_", ClassLowering::Lut ; "scattered class")]
+    fn test_class_lowering(cs: &[u8], exp: ClassLowering) {
+        let class = CharClass::from_chars(cs);
+        assert_eq!(exp, CostModel::active().class_lowering(&class));
+    }
+
+    #[test]
+    fn test_negated_class_tests_complement() {
+        let cost = CostModel::active();
+        let class = CharClass::from_chars(b"a").complement();
+        assert!(cost.negate_class(&class));
+        assert!(!cost.negate_class(&class.complement()));
+    }
+
+    #[test]
+    fn test_interval_only_for_consecutive() {
+        let cost = CostModel::active();
+        assert!(cost.class(&CharClass::from_chars(b"ac"), ClassLowering::Interval).is_none());
+        assert!(cost.class(&CharClass::from_range(b'a', b'c'), ClassLowering::Interval).is_some());
+    }
+
+    #[test]
+    fn test_suggest_rewrites() {
+        let cost = CostModel::active();
+        let re = parse("/a.*b/").unwrap();
+        let suggestions: Vec<String> = cost
+            .suggest_rewrites(&re, 100)
+            .into_iter()
+            .map(|rewrite| rewrite.suggestion)
+            .collect();
+
+        assert_eq!(2, suggestions.len());
+        assert!(suggestions.contains(&"replace `.*` with `.{0,32}`".to_string()));
+        assert!(suggestions.iter().any(|s| s.contains("by 100x")));
+    }
+
+    #[test]
+    fn test_no_rewrites_for_anchored_bounded() {
+        let re = parse("/^ab{0,4}$/").unwrap();
+        assert!(CostModel::active().suggest_rewrites(&re, 100).is_empty());
+    }
+}
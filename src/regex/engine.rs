@@ -1,64 +1,1081 @@
-use crate::regex::parser::{parse, RegExpr};
-use anyhow::Result;
-use std::rc::Rc;
+use crate::regex::parser::{parse_with, AnchorPrecedence, ParseLimits, RegExpr};
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tfhe::integer::{RadixCiphertext, ServerKey};
 
-use crate::regex::execution::{Executed, Execution, LazyExecution};
+use crate::regex::alphabet::{Alphabet, RestrictedContent};
+use crate::regex::cache::ContentCache;
+use crate::regex::ciphertext::{
+    check_content_len, create_trivial_radix, fingerprint, sanitize, validate_layout, StringCiphertext,
+    DEFAULT_MAX_CONTENT_LEN,
+};
+use crate::regex::class::CharClass;
+use crate::regex::cost::{ClassLowering, CostModel};
+use crate::regex::execution::{Executed, Execution};
+use crate::regex::factor::factor;
+use crate::regex::literal::{required_chars, without_required, Literal, LiteralPrefix};
+use crate::regex::observe::Observer;
+use crate::regex::plan_cache::PlanCache;
+use crate::regex::preprocess::fhe_to_lower;
+use crate::regex::scheduler::{EvalOrder, Schedule};
+use crate::regex::trace::OpTrace;
+use crate::regex::verdict::{fhe_count, fhe_count_saturating, MAX_COUNT};
+use crate::regex::warmup::WarmKey;
 
 pub fn has_match(
     sk: &ServerKey,
     content: &[RadixCiphertext],
     pattern: &str,
+    opts: &MatchOptions,
 ) -> Result<RadixCiphertext> {
-    let re = parse(pattern)?;
+    has_match_warm(&WarmKey::cold(sk.clone()), content, pattern, opts)
+}
+
+// like has_match, with a server key that was warmed up beforehand (see
+// warmup), so that no setup cost is absorbed by the call
+pub fn has_match_warm(
+    key: &WarmKey,
+    content: &[RadixCiphertext],
+    pattern: &str,
+    opts: &MatchOptions,
+) -> Result<RadixCiphertext> {
+    Ok(has_match_with(key, content, pattern, opts)?.0)
+}
+
+// The evaluators a pattern can be matched with: the general branch based
+// one, and the literal one that only applies to plain strings (see
+// literal.rs). Auto picks the literal engine whenever the pattern allows it.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Engine {
+    Branches,
+    Literal,
+    #[default]
+    Auto,
+}
+
+impl FromStr for Engine {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "branches" => Ok(Self::Branches),
+            "literal" => Ok(Self::Literal),
+            "auto" => Ok(Self::Auto),
+            "nfa" => Err(anyhow!("the nfa engine is not available yet")),
+            _ => Err(anyhow!(
+                "unknown engine {:?} (expected branches, literal or auto)",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Engine {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Self::Branches => "branches",
+            Self::Literal => "literal",
+            Self::Auto => "auto",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+// what a has_match_engine call did: the engine that matched the pattern (auto
+// resolved), and its work
+#[derive(Clone, Debug)]
+pub struct EngineStats {
+    pub engine: Engine,
+    pub ct_operations: usize,
+    pub cache_hits: usize,
+    pub elapsed: Duration,
+    // the threads the operations were spread over, and the time they spent
+    // in ciphertext operations summed over all of them
+    pub threads: usize,
+    pub busy: Duration,
+}
+
+impl EngineStats {
+    // the fraction of the available thread time spent in ciphertext
+    // operations, 1 when no thread was ever idle
+    pub fn parallel_efficiency(&self) -> f64 {
+        let available = self.elapsed.as_secs_f64() * self.threads as f64;
+        if available == 0.0 {
+            return 1.0;
+        }
+        (self.busy.as_secs_f64() / available).min(1.0)
+    }
+}
+
+impl fmt::Display for EngineStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "engine {}: {} ciphertext operations, {} cache hits in {:.2?} on {} threads ({:.0}% parallel efficiency)",
+            self.engine,
+            self.ct_operations,
+            self.cache_hits,
+            self.elapsed,
+            self.threads,
+            100.0 * self.parallel_efficiency(),
+        )
+    }
+}
+
+// The knobs of a match, in one place so that the public api (and the cli)
+// does not grow an argument per feature. Outside the crate it is built from
+// the default with the setters below, so that adding a knob is not a breaking
+// change.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[non_exhaustive]
+pub struct MatchOptions {
+    pub engine: Engine,
+    // as if the pattern was given with the i flag
+    pub case_insensitive: bool,
+    pub precedence: AnchorPrecedence,
+    // patterns estimated to take more bootstraps are rejected before any
+    // ciphertext operation (see check_budget)
+    pub max_bootstraps: Option<usize>,
+    // what to do with content shorter than any match of the pattern
+    #[serde(default)]
+    pub short_content: ShortContent,
+    // the number of threads ciphertext operations are spread over, all
+    // cores when None
+    #[serde(default)]
+    pub threads: Option<usize>,
+    // the order the operations of a level are dispatched in
+    #[serde(default)]
+    pub eval_order: EvalOrder,
+    // bootstrap the result once more, so that its noise does not depend on
+    // the operations that computed it (see ciphertext::sanitize)
+    #[serde(default)]
+    pub sanitize_output: bool,
+    // matches whose ciphertexts are estimated to take more bytes than this
+    // at once are rejected before any ciphertext operation (see
+    // ExecutionBuilder::max_memory)
+    #[serde(default)]
+    pub max_memory: Option<u64>,
+    // patterns exceeding these are rejected before they are parsed
+    #[serde(default)]
+    pub parse_limits: ParseLimits,
+    // longer content is rejected before any plan is built for it (see
+    // ciphertext::ContentTooLong)
+    #[serde(default = "default_max_content_len")]
+    pub max_content_len: usize,
+}
 
-    let branches: Vec<LazyExecution> = (0..content.len())
-        .flat_map(|i| build_branches(content, &re, i))
-        .map(|(lazy_branch_res, _)| lazy_branch_res)
+fn default_max_content_len() -> usize {
+    DEFAULT_MAX_CONTENT_LEN
+}
+
+// Content shorter than the minimum width of a pattern can never match it.
+// Either way no branch is built for it: it can result in an encryption of
+// false without any ciphertext operation, or be rejected, for callers that
+// would rather not pay the round trip for a result known up front.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ShortContent {
+    #[default]
+    NoMatch,
+    Reject,
+}
+
+impl Default for MatchOptions {
+    fn default() -> Self {
+        Self {
+            engine: Engine::default(),
+            case_insensitive: false,
+            precedence: AnchorPrecedence::default(),
+            max_bootstraps: None,
+            short_content: ShortContent::default(),
+            threads: None,
+            eval_order: EvalOrder::default(),
+            sanitize_output: false,
+            max_memory: None,
+            parse_limits: ParseLimits::default(),
+            max_content_len: DEFAULT_MAX_CONTENT_LEN,
+        }
+    }
+}
+
+impl MatchOptions {
+    pub fn engine(mut self, engine: Engine) -> Self {
+        self.engine = engine;
+        self
+    }
+
+    pub fn case_insensitive(mut self, case_insensitive: bool) -> Self {
+        self.case_insensitive = case_insensitive;
+        self
+    }
+
+    pub fn precedence(mut self, precedence: AnchorPrecedence) -> Self {
+        self.precedence = precedence;
+        self
+    }
+
+    pub fn max_bootstraps(mut self, max_bootstraps: usize) -> Self {
+        self.max_bootstraps = Some(max_bootstraps);
+        self
+    }
+
+    pub fn short_content(mut self, short_content: ShortContent) -> Self {
+        self.short_content = short_content;
+        self
+    }
+
+    pub fn threads(mut self, threads: usize) -> Self {
+        self.threads = Some(threads);
+        self
+    }
+
+    pub fn eval_order(mut self, eval_order: EvalOrder) -> Self {
+        self.eval_order = eval_order;
+        self
+    }
+
+    pub fn sanitize_output(mut self, sanitize_output: bool) -> Self {
+        self.sanitize_output = sanitize_output;
+        self
+    }
+
+    // in bytes
+    pub fn max_memory(mut self, max_memory: u64) -> Self {
+        self.max_memory = Some(max_memory);
+        self
+    }
+
+    pub fn parse_limits(mut self, parse_limits: ParseLimits) -> Self {
+        self.parse_limits = parse_limits;
+        self
+    }
+
+    // in characters
+    pub fn max_content_len(mut self, max_content_len: usize) -> Self {
+        self.max_content_len = max_content_len;
+        self
+    }
+}
+
+// matches pattern on content with everything configurable set by opts
+pub fn has_match_with(
+    key: &WarmKey,
+    content: &[RadixCiphertext],
+    pattern: &str,
+    opts: &MatchOptions,
+) -> Result<(RadixCiphertext, EngineStats)> {
+    validate_layout(content, opts.max_content_len)?;
+    let start = Instant::now();
+    let re = prepare(pattern, content.len(), opts)?;
+    let (engine, plan) = lower(&re, pattern, content.len(), opts.engine)?;
+    run_lowered(key, content, engine, plan, opts, start, &Watchers::default())
+}
+
+// like has_match_warm, with the engine chosen by the caller. Forcing the
+// literal engine on a pattern that is not a plain string is an error.
+pub fn has_match_engine(
+    key: &WarmKey,
+    content: &[RadixCiphertext],
+    pattern: &str,
+    engine: Engine,
+) -> Result<(RadixCiphertext, EngineStats)> {
+    let opts = MatchOptions {
+        engine,
+        ..MatchOptions::default()
+    };
+    has_match_with(key, content, pattern, &opts)
+}
+
+// like has_match_with, recording every ciphertext operation to trace
+pub fn has_match_traced(
+    key: &WarmKey,
+    content: &[RadixCiphertext],
+    pattern: &str,
+    opts: &MatchOptions,
+    trace: &OpTrace,
+) -> Result<(RadixCiphertext, EngineStats)> {
+    validate_layout(content, opts.max_content_len)?;
+    let start = Instant::now();
+    let re = prepare(pattern, content.len(), opts)?;
+    let (engine, plan) = lower(&re, pattern, content.len(), opts.engine)?;
+    let watchers = Watchers {
+        trace: Some(trace),
+        ..Watchers::default()
+    };
+    let res = run_lowered(key, content, engine, plan, opts, start, &watchers)?;
+    trace.flush()?;
+    Ok(res)
+}
+
+// like has_match_with, reporting its work to observer as it goes
+pub fn has_match_observed(
+    key: &WarmKey,
+    content: &[RadixCiphertext],
+    pattern: &str,
+    opts: &MatchOptions,
+    observer: Arc<dyn Observer>,
+) -> Result<(RadixCiphertext, EngineStats)> {
+    validate_layout(content, opts.max_content_len)?;
+    let start = Instant::now();
+    let re = prepare(pattern, content.len(), opts)?;
+    let (engine, plan) = lower(&re, pattern, content.len(), opts.engine)?;
+    let watchers = Watchers {
+        observer: Some(observer),
+        ..Watchers::default()
+    };
+    run_lowered(key, content, engine, plan, opts, start, &watchers)
+}
+
+// like has_match_with, but takes the plan from the plan cache when the same
+// pattern was lowered before, with the same options, for content of the same
+// length
+pub fn has_match_plan_cached(
+    key: &WarmKey,
+    content: &[RadixCiphertext],
+    pattern: &str,
+    opts: &MatchOptions,
+    plans: &mut PlanCache,
+) -> Result<(RadixCiphertext, EngineStats)> {
+    validate_layout(content, opts.max_content_len)?;
+    let start = Instant::now();
+    // parsing and the budget check are cheap compared to lowering, and a
+    // cached plan must still be within the budget of this call
+    let re = prepare(pattern, content.len(), opts)?;
+    let (engine, plan) = plans.get_or_insert_with(pattern, content.len(), opts, || {
+        lower(&re, pattern, content.len(), opts.engine)
+    })?;
+    run_lowered(key, content, engine, plan, opts, start, &Watchers::default())
+}
+
+// the plan has_match_with evaluates for pattern on content of content_len
+// characters, along with the engine it was built for
+pub(crate) fn plan_for(
+    pattern: &str,
+    content_len: usize,
+    opts: &MatchOptions,
+) -> Result<(Engine, Executed)> {
+    let re = prepare(pattern, content_len, opts)?;
+    lower(&re, pattern, content_len, opts.engine)
+}
+
+// parses pattern as opts specify, rejecting it if it exceeds the budget, or
+// the content if it is too long
+fn prepare(pattern: &str, content_len: usize, opts: &MatchOptions) -> Result<RegExpr> {
+    check_content_len(content_len, opts.max_content_len)?;
+    let re = parse_opts(pattern, opts)?;
+    if opts.short_content == ShortContent::Reject && re.min_width() > content_len {
+        return Err(anyhow!(
+            "content of length {} is shorter than any match of {} (at least {} characters)",
+            content_len,
+            pattern,
+            re.min_width()
+        ));
+    }
+    if let Some(max_bootstraps) = opts.max_bootstraps {
+        check_parsed_budget(&re, pattern, content_len, max_bootstraps)?;
+    }
+    Ok(re)
+}
+
+// parses pattern as opts specify, without checking it against any content
+fn parse_opts(pattern: &str, opts: &MatchOptions) -> Result<RegExpr> {
+    let mut re = parse_with(pattern, opts.precedence, opts.parse_limits)?;
+    if opts.case_insensitive {
+        re = re.case_insensitive();
+    }
+    Ok(factor(&re))
+}
+
+// builds the plan of the parsed pattern with the requested engine, resulting
+// in the engine that was used (auto resolved) and the plan
+fn lower(
+    re: &RegExpr,
+    pattern: &str,
+    content_len: usize,
+    engine: Engine,
+) -> Result<(Engine, Executed)> {
+    match (engine, Literal::from_regex(re)) {
+        (Engine::Literal | Engine::Auto, Some(literal)) => {
+            Ok((Engine::Literal, literal.plan(content_len)))
+        }
+        (Engine::Literal, None) => Err(anyhow!("pattern {} is not a plain string", pattern)),
+        (Engine::Branches | Engine::Auto, _) => Ok((Engine::Branches, build_plan(re, content_len))),
+    }
+}
+
+// what follows the evaluation of a match, besides the log
+#[derive(Default)]
+struct Watchers<'a> {
+    trace: Option<&'a OpTrace>,
+    observer: Option<Arc<dyn Observer>>,
+}
+
+fn run_lowered(
+    key: &WarmKey,
+    content: &[RadixCiphertext],
+    engine: Engine,
+    plan: Executed,
+    opts: &MatchOptions,
+    start: Instant,
+    watchers: &Watchers,
+) -> Result<(RadixCiphertext, EngineStats)> {
+    let (mut res, stats) = run_plans(key, content, engine, &[plan], opts, start, watchers)?;
+    Ok((res.remove(0), stats))
+}
+
+// evaluates the plans level by level (see scheduler.rs), the operations of a
+// level are spread over the threads by rayon's work stealing. With a number
+// of threads set, the plans are evaluated in a pool of its own of that size.
+fn run_plans(
+    key: &WarmKey,
+    content: &[RadixCiphertext],
+    engine: Engine,
+    plans: &[Executed],
+    opts: &MatchOptions,
+    start: Instant,
+    watchers: &Watchers,
+) -> Result<(Vec<RadixCiphertext>, EngineStats)> {
+    let mut exec = execution(key, opts, watchers)?;
+    exec.check_budget(content, plans)?;
+    Ok(run_in(&mut exec, content, engine, plans, start))
+}
+
+// the execution a match with opts is evaluated by
+fn execution(key: &WarmKey, opts: &MatchOptions, watchers: &Watchers) -> Result<Execution> {
+    let mut builder = Execution::builder(key.clone())
+        .eval_order(opts.eval_order)
+        .sanitize_output(opts.sanitize_output);
+    if let Some(threads) = opts.threads {
+        builder = builder.threads(threads);
+    }
+    if let Some(observer) = &watchers.observer {
+        builder = builder.observer(observer.clone());
+    }
+    if let Some(max_memory) = opts.max_memory {
+        builder = builder.max_memory(max_memory);
+    }
+    let mut exec = builder.build()?;
+    if let Some(trace) = watchers.trace {
+        exec.set_trace(trace.clone());
+    }
+    Ok(exec)
+}
+
+// evaluates plans with exec, in its pool when it has one of its own. The
+// stats only count the work of this evaluation, exec may have done more.
+fn run_in(
+    exec: &mut Execution,
+    content: &[RadixCiphertext],
+    engine: Engine,
+    plans: &[Executed],
+    start: Instant,
+) -> (Vec<RadixCiphertext>, EngineStats) {
+    let (ops_before, hits_before, busy_before) =
+        (exec.ct_operations_count(), exec.cache_hits(), exec.busy());
+    let (mut res, threads) = match exec.pool() {
+        Some(pool) => pool.install(|| (evaluate(exec, content, plans), rayon::current_num_threads())),
+        None => (evaluate(exec, content, plans), rayon::current_num_threads()),
+    };
+    let mut ct_operations = exec.ct_operations_count() - ops_before;
+    if exec.sanitizes_output() {
+        res = res.iter().map(|ct| sanitize(exec.key().shortkey(), ct)).collect();
+        ct_operations += res.len();
+    }
+    let stats = EngineStats {
+        engine,
+        ct_operations,
+        cache_hits: exec.cache_hits() - hits_before,
+        elapsed: start.elapsed(),
+        threads,
+        busy: exec.busy() - busy_before,
+    };
+    (res, stats)
+}
+
+// like has_match_with, evaluated by exec (see ExecutionBuilder) rather than
+// by an execution set up for the match: the threads, evaluation order,
+// sanitization, observer and operation budget of exec apply, instead of
+// those of opts. An execution can match any number of patterns, one after
+// the other: the results it holds are reused for as long as the content
+// stays the same, and dropped when it changes.
+pub fn has_match_in(
+    exec: &mut Execution,
+    content: &[RadixCiphertext],
+    pattern: &str,
+    opts: &MatchOptions,
+) -> Result<(RadixCiphertext, EngineStats)> {
+    validate_layout(content, opts.max_content_len)?;
+    exec.bind_content(fingerprint(content)?);
+    match_with(exec, content, pattern, opts)
+}
+
+// matches every pattern on content in a single pass, resulting in an
+// encrypted verdict per pattern. The plans of all patterns are scheduled as
+// one, and plans share every node they have in common (see Executed), so that
+// whatever patterns have in common, from comparisons of content characters
+// to whole subexpressions, is evaluated once for all of them. The stats name
+// the engine all patterns were lowered to, auto when they differ.
+pub fn has_match_patterns(
+    key: &WarmKey,
+    content: &[RadixCiphertext],
+    patterns: &[&str],
+    opts: &MatchOptions,
+) -> Result<(Vec<RadixCiphertext>, EngineStats)> {
+    let (engines, plans): (Vec<Engine>, Vec<Executed>) = patterns
+        .iter()
+        .map(|pattern| plan_for(pattern, content.len(), opts))
+        .collect::<Result<Vec<_>>>()?
+        .into_iter()
+        .unzip();
+    has_match_plans(key, content, combined_engine(engines), &plans, opts)
+}
+
+// evaluates plans (built by plan_for, with engine) in a single pass, as
+// has_match_patterns
+pub(crate) fn has_match_plans(
+    key: &WarmKey,
+    content: &[RadixCiphertext],
+    engine: Engine,
+    plans: &[Executed],
+    opts: &MatchOptions,
+) -> Result<(Vec<RadixCiphertext>, EngineStats)> {
+    validate_layout(content, opts.max_content_len)?;
+    let start = Instant::now();
+    run_plans(key, content, engine, plans, opts, start, &Watchers::default())
+}
+
+// the engine that plans lowered by engines were lowered with, auto when they
+// differ
+pub(crate) fn combined_engine(engines: Vec<Engine>) -> Engine {
+    match engines.first() {
+        Some(first) if engines.iter().all(|engine| engine == first) => *first,
+        _ => Engine::Auto,
+    }
+}
+
+// matches pattern on content that had characters appended to it, given the
+// result of matching the same pattern on its first old_len characters. Any
+// match that was not found before contains an appended character, so it
+// starts at most max width - 1 characters before the old end; only those
+// start positions are evaluated and combined with the previous result. A
+// pattern that tests for the end of the content anywhere may have matched at
+// the old end only (e.g. /a$|b/ with standard precedence), so the previous
+// result is discarded for it and every start position is evaluated, as it is
+// when nothing was appended.
+pub fn has_match_appended(
+    sk: &ServerKey,
+    content: &[RadixCiphertext],
+    old_len: usize,
+    previous: &RadixCiphertext,
+    pattern: &str,
+    opts: &MatchOptions,
+) -> Result<RadixCiphertext> {
+    if old_len > content.len() {
+        return Err(anyhow!(
+            "previous content length {} exceeds the content length {}",
+            old_len,
+            content.len(),
+        ));
+    }
+    validate_layout(content, opts.max_content_len)?;
+    let start = Instant::now();
+    let re = prepare(pattern, content.len(), opts)?;
+    let key = WarmKey::cold(sk.clone());
+
+    if re.contains_eof() || old_len == content.len() {
+        let (engine, plan) = lower(&re, pattern, content.len(), opts.engine)?;
+        let (res, _) = run_lowered(&key, content, engine, plan, opts, start, &Watchers::default())?;
+        return Ok(res);
+    }
+
+    let window_start = match re.max_width() {
+        Some(width) => old_len.saturating_sub(width.saturating_sub(1)),
+        None => 0,
+    };
+    debug!(
+        "re-evaluating start positions {}..{} of {}",
+        window_start,
+        content.len(),
+        content.len(),
+    );
+    let plan = (window_start..=content.len())
+        .map(|i| build_start_plan(&re, content.len(), i, &Alphabet::ascii()))
+        .reduce(Executed::or)
+        .unwrap_or_else(Executed::ct_false);
+    let (mut res, _) = run_lowered(&key, content, Engine::Branches, plan, opts, start, &Watchers::default())?;
+    Ok(sk.smart_bitor(&mut res, &mut previous.clone()))
+}
+
+// estimates the number of bootstraps matching pattern as opts specify on
+// content of length content_len takes, erroring when it exceeds
+// opts.max_bootstraps. The error lists rewrites of the pattern that the cost
+// model estimates to be cheaper.
+pub fn check_budget(pattern: &str, content_len: usize, opts: &MatchOptions) -> Result<usize> {
+    let re = parse_opts(pattern, opts)?;
+    check_parsed_budget(&re, pattern, content_len, opts.max_bootstraps.unwrap_or(usize::MAX))
+}
+
+fn check_parsed_budget(
+    re: &RegExpr,
+    pattern: &str,
+    content_len: usize,
+    max_bootstraps: usize,
+) -> Result<usize> {
+    let cost = CostModel::active();
+    let estimate = cost.pattern(re, content_len);
+    if estimate <= max_bootstraps {
+        return Ok(estimate);
+    }
+
+    let mut msg = format!(
+        "pattern {} needs an estimated {} bootstraps on {} characters, exceeding the budget of {}",
+        pattern, estimate, content_len, max_bootstraps,
+    );
+    let rewrites = cost.suggest_rewrites(re, content_len);
+    if !rewrites.is_empty() {
+        msg.push_str(", consider to:");
+        for rewrite in rewrites {
+            msg.push_str(&format!(
+                "\n - {} (estimated {} bootstraps)",
+                rewrite.suggestion, rewrite.cost
+            ));
+        }
+    }
+    Err(anyhow!(msg))
+}
+
+// like has_match, for content that was encrypted with a declared alphabet
+// (see alphabet.rs), which the plan is specialized for
+pub fn has_match_restricted(
+    sk: &ServerKey,
+    content: &RestrictedContent,
+    pattern: &str,
+    opts: &MatchOptions,
+) -> Result<RadixCiphertext> {
+    validate_layout(&content.content, opts.max_content_len)?;
+    let start = Instant::now();
+    let re = prepare(pattern, content.content.len(), opts)?;
+    let plan = build_plan_in(&re, content.content.len(), &content.alphabet);
+    let key = WarmKey::cold(sk.clone());
+    let (res, _) = run_lowered(&key, &content.content, Engine::Branches, plan, opts, start, &Watchers::default())?;
+    Ok(res)
+}
+
+// matches pattern ignoring case, like the i flag, but by lowercasing the
+// content once (see preprocess::fhe_to_lower) and matching the lowercased
+// pattern, instead of testing every character of the pattern for both of its
+// cases. This pays off for long patterns on short content.
+pub fn has_match_ignore_case(
+    sk: &ServerKey,
+    content: &[RadixCiphertext],
+    pattern: &str,
+    opts: &MatchOptions,
+) -> Result<RadixCiphertext> {
+    validate_layout(content, opts.max_content_len)?;
+    let start = Instant::now();
+    let re = prepare(pattern, content.len(), opts)?.lowercased();
+    let content = fhe_to_lower(sk, content);
+    let plan = build_plan(&re, content.len());
+    let key = WarmKey::cold(sk.clone());
+    let (res, _) = run_lowered(&key, &content, Engine::Branches, plan, opts, start, &Watchers::default())?;
+    Ok(res)
+}
+
+// like has_match, but reuses the comparisons of content positions that were
+// cached by earlier queries on the same content, and adds the comparisons of
+// this query to the cache
+pub fn has_match_cached(
+    sk: &ServerKey,
+    content: &[RadixCiphertext],
+    pattern: &str,
+    opts: &MatchOptions,
+    cache: &mut ContentCache,
+) -> Result<RadixCiphertext> {
+    cache.bind(fingerprint(content)?);
+
+    let mut exec = execution(&WarmKey::cold(sk.clone()), opts, &Watchers::default())?;
+    exec.import_cache(cache.take());
+    let res = match_with(&mut exec, content, pattern, opts);
+    cache.store(exec.into_comparisons());
+    Ok(res?.0)
+}
+
+// applies the pattern to each line separately (^ and $ match at the start
+// and end of every line), resulting in an encrypted match result per line.
+// The lines are evaluated as one document: plans are only built once per
+// distinct line length, and all lines share one execution.
+pub fn has_match_lines(
+    sk: &ServerKey,
+    lines: &[StringCiphertext],
+    pattern: &str,
+    opts: &MatchOptions,
+) -> Result<Vec<RadixCiphertext>> {
+    let start = Instant::now();
+    let (content, line_plans) = part_plans(lines, pattern, opts)?;
+    let key = WarmKey::cold(sk.clone());
+    let (res, _) = run_plans(&key, &content, Engine::Branches, &line_plans, opts, start, &Watchers::default())?;
+    Ok(res)
+}
+
+// like has_match_lines, but results in whether any of the lines matched
+pub fn has_match_any_line(
+    sk: &ServerKey,
+    lines: &[StringCiphertext],
+    pattern: &str,
+    opts: &MatchOptions,
+) -> Result<RadixCiphertext> {
+    has_match_any_document(&WarmKey::cold(sk.clone()), lines, pattern, opts)
+}
+
+// whether the pattern matched in at least k of the lines. Only the outcome of
+// the comparison is encrypted and returned, neither which lines matched nor
+// how many did is revealed to the client. At most verdict::MAX_COUNT lines
+// can be counted.
+pub fn at_least_k_matches(
+    sk: &ServerKey,
+    lines: &[StringCiphertext],
+    pattern: &str,
+    opts: &MatchOptions,
+    k: usize,
+) -> Result<RadixCiphertext> {
+    if k > lines.len() {
+        // still rejecting invalid patterns, whatever k is
+        prepare_parts(lines, pattern, opts)?;
+        return Ok(create_trivial_radix(sk, 0));
+    }
+    let verdicts = has_match_lines(sk, lines, pattern, opts)?;
+    let mut count = fhe_count(sk, &verdicts)?;
+    let mut ct_k = create_trivial_radix(sk, k as u64);
+    Ok(sk.smart_ge(&mut count, &mut ct_k))
+}
+
+// Matching a pattern over many documents at once, resulting in a single
+// encrypted result for all of them, so that a client polling for matches
+// downloads (and decrypts) one ciphertext instead of one per document. The
+// documents are evaluated like the lines of has_match_lines.
+
+// whether the pattern matched in any of the documents
+pub fn has_match_any_document(
+    key: &WarmKey,
+    documents: &[StringCiphertext],
+    pattern: &str,
+    opts: &MatchOptions,
+) -> Result<RadixCiphertext> {
+    let start = Instant::now();
+    let (content, document_plans) = part_plans(documents, pattern, opts)?;
+    let any_plan = document_plans.into_iter().fold(Executed::ct_false(), Executed::or);
+    let (res, _) = run_lowered(key, &content, Engine::Branches, any_plan, opts, start, &Watchers::default())?;
+    Ok(res)
+}
+
+// the number of documents the pattern matched in, at most verdict::MAX_COUNT
+// documents can be counted
+pub fn count_matching_documents(
+    key: &WarmKey,
+    documents: &[StringCiphertext],
+    pattern: &str,
+    opts: &MatchOptions,
+) -> Result<RadixCiphertext> {
+    if documents.len() > MAX_COUNT {
+        return Err(anyhow!(
+            "cannot count matches in {} documents, at most {} can be counted",
+            documents.len(),
+            MAX_COUNT
+        ));
+    }
+    let start = Instant::now();
+    let (content, document_plans) = part_plans(documents, pattern, opts)?;
+    let (verdicts, _) = run_plans(key, &content, Engine::Branches, &document_plans, opts, start, &Watchers::default())?;
+    fhe_count(key.server_key(), &verdicts)
+}
+
+// concatenates the parts (lines or documents) into one content, and builds
+// the plan of pattern for every part, anchored to the part's range of the
+// content. Plans are only built once per distinct part length.
+fn part_plans(
+    parts: &[StringCiphertext],
+    pattern: &str,
+    opts: &MatchOptions,
+) -> Result<(Vec<RadixCiphertext>, Vec<Executed>)> {
+    let re = prepare_parts(parts, pattern, opts)?;
+
+    let mut plans: HashMap<usize, Executed> = HashMap::new();
+    let mut part_plans = Vec::with_capacity(parts.len());
+    let mut offset = 0;
+    for part in parts {
+        let plan = plans
+            .entry(part.len())
+            .or_insert_with(|| build_plan(&re, part.len()));
+        part_plans.push(plan.offset(offset));
+        offset += part.len();
+    }
+    let content = parts.iter().flat_map(|part| part.iter().cloned()).collect();
+    Ok((content, part_plans))
+}
+
+// parses pattern for matching on every one of the parts. A part shorter than
+// the pattern does not match it, whatever opts.short_content says, rather
+// than failing the match on all others. The budget is checked as if the
+// parts were a single content, which over-estimates their cost.
+fn prepare_parts(parts: &[StringCiphertext], pattern: &str, opts: &MatchOptions) -> Result<RegExpr> {
+    for part in parts {
+        part.check_unpadded()?;
+        validate_layout(part, opts.max_content_len)?;
+    }
+    let opts = MatchOptions {
+        short_content: ShortContent::NoMatch,
+        ..opts.clone()
+    };
+    prepare(pattern, parts.iter().map(|part| part.len()).sum(), &opts)
+}
+
+// flags, per content position, whether a match of the delimiter pattern
+// starts at that position
+pub fn split_points(
+    sk: &ServerKey,
+    content: &[RadixCiphertext],
+    delimiter_pattern: &str,
+    opts: &MatchOptions,
+) -> Result<Vec<RadixCiphertext>> {
+    validate_layout(content, opts.max_content_len)?;
+    let start = Instant::now();
+    let re = prepare(delimiter_pattern, content.len(), opts)?;
+    let plans: Vec<Executed> = (0..content.len())
+        .map(|i| build_start_plan(&re, content.len(), i, &Alphabet::ascii()))
         .collect();
+    let key = WarmKey::cold(sk.clone());
+    let (res, _) = run_plans(&key, content, Engine::Branches, &plans, opts, start, &Watchers::default())?;
+    Ok(res)
+}
+
+// flags, per content position, whether the character is part of some match
+// of pattern, e.g. for the client to highlight the matches. Matches may
+// overlap, and empty matches cover no character. Like the verdict of a
+// match, the flags reveal nothing to the server.
+pub fn match_mask(
+    sk: &ServerKey,
+    content: &[RadixCiphertext],
+    pattern: &str,
+    opts: &MatchOptions,
+) -> Result<Vec<RadixCiphertext>> {
+    validate_layout(content, opts.max_content_len)?;
+    let start = Instant::now();
+    let re = prepare(pattern, content.len(), opts)?;
+    let plans = mask_plans(&re, content.len());
+    let key = WarmKey::cold(sk.clone());
+    let (res, _) = run_plans(&key, content, Engine::Branches, &plans, opts, start, &Watchers::default())?;
+    Ok(res)
+}
 
-    let mut exec = Execution::new(sk.clone());
+// the plans of match_mask. The branches of re from every start position are
+// merged by the position they end at; the character at c is covered by the
+// matches that start at or before c and end after it. Per start position,
+// the ors over the ends past c are built from the furthest end down, so
+// that every end is or-ed in once, rather than once per covered character.
+fn mask_plans(re: &RegExpr, content_len: usize) -> Vec<Executed> {
+    let alphabet = CharClass::from_alphabet(&Alphabet::ascii());
+    let mut covering: Vec<Vec<Executed>> = vec![vec![]; content_len];
+    let last_start = match content_len.checked_sub(re.min_width()) {
+        Some(last_start) => last_start,
+        None => return vec![Executed::ct_false(); content_len],
+    };
+    for start in 0..=last_start {
+        let ends = merge_by_position(build_branches(content_len, re, start, 0, &alphabet));
+        let mut covered = Executed::ct_false();
+        let mut covered_below = None;
+        for (matched, end) in ends.into_iter().rev() {
+            if let Some(below) = covered_below {
+                (end..below).for_each(|c| covering[c].push(covered.clone()));
+            }
+            covered = Executed::or(covered, matched);
+            covered_below = Some(end);
+        }
+        if let Some(below) = covered_below {
+            (start..below).for_each(|c| covering[c].push(covered.clone()));
+        }
+    }
+    covering.into_iter().map(Executed::any).collect()
+}
 
-    let res = if branches.len() <= 1 {
-        branches
-            .get(0)
-            .map_or(exec.ct_false(), |branch| branch(&mut exec))
-            .0
+// counts the positions at which a match of the delimiter pattern starts
+// (overlapping matches are each counted). The count is encrypted in the
+// same radix as the characters, so it wraps around past 255.
+pub fn split_count(
+    sk: &ServerKey,
+    content: &[RadixCiphertext],
+    delimiter_pattern: &str,
+    opts: &MatchOptions,
+) -> Result<RadixCiphertext> {
+    let mut res = split_points(sk, content, delimiter_pattern, opts)?
+        .into_iter()
+        .reduce(|mut count, mut point| sk.smart_add(&mut count, &mut point))
+        .unwrap_or_else(|| create_trivial_radix(sk, 0));
+    sk.full_propagate(&mut res);
+    Ok(res)
+}
+
+// like split_count, but the count saturates at cap (at most
+// verdict::MAX_SATURATING_COUNT) instead of wrapping around, for when whether
+// there are none, a few or many matches is enough. Cheaper than split_count
+// on long content, and correct however long it is.
+pub fn split_count_saturating(
+    sk: &ServerKey,
+    content: &[RadixCiphertext],
+    delimiter_pattern: &str,
+    opts: &MatchOptions,
+    cap: usize,
+) -> Result<RadixCiphertext> {
+    let points = split_points(sk, content, delimiter_pattern, opts)?;
+    fhe_count_saturating(sk, &points, cap)
+}
+
+// matches pattern on content with exec, as opts specify
+fn match_with(
+    exec: &mut Execution,
+    content: &[RadixCiphertext],
+    pattern: &str,
+    opts: &MatchOptions,
+) -> Result<(RadixCiphertext, EngineStats)> {
+    validate_layout(content, opts.max_content_len)?;
+    let start = Instant::now();
+    let re = prepare(pattern, content.len(), opts)?;
+    let (engine, plan) = lower(&re, pattern, content.len(), opts.engine)?;
+    exec.check_budget(content, std::slice::from_ref(&plan))?;
+    let (mut res, stats) = run_in(exec, content, engine, &[plan], start);
+    Ok((res.remove(0), stats))
+}
+
+// the plan of re matching anywhere in content of length content_len
+pub(crate) fn build_plan(re: &RegExpr, content_len: usize) -> Executed {
+    build_plan_in(re, content_len, &Alphabet::ascii())
+}
+
+// like build_plan, for content that only consists of characters of alphabet
+pub(crate) fn build_plan_in(re: &RegExpr, content_len: usize, alphabet: &Alphabet) -> Executed {
+    let min_width = re.min_width();
+    if min_width > content_len {
+        debug!(
+            "content of length {} is shorter than the minimum width {} of the pattern",
+            content_len, min_width,
+        );
+        return Executed::ct_false();
+    }
+    debug!(
+        "estimated {} bootstraps per start position",
+        CostModel::active().regex(re, content_len),
+    );
+    // a match can also start at the end of the content, if it is empty
+    // there, but never closer to the end than its minimum width. A match that
+    // has to end at the end of the content neither starts further from it
+    // than its maximum width.
+    let first_start = match re.max_width() {
+        Some(max_width) if re.anchored_at_end() => content_len.saturating_sub(max_width),
+        _ => 0,
+    };
+    (first_start..=content_len - min_width)
+        .map(|i| build_start_plan(re, content_len, i, alphabet))
+        .reduce(Executed::or)
+        .unwrap_or_else(Executed::ct_false)
+}
+
+// the plan of re matching from start position c_pos. A literal prefix of re
+// is tested first, and guards the branches of the rest of re as a whole
+// (g & x | g & y is built as g & (x | y)), so that the prefix is not part of
+// every branch.
+fn build_start_plan(re: &RegExpr, content_len: usize, c_pos: usize, alphabet: &Alphabet) -> Executed {
+    let alphabet = CharClass::from_alphabet(alphabet);
+    let prefix = match LiteralPrefix::from_regex(re) {
+        Some(prefix) => prefix,
+        None => return start_branches(content_len, re, c_pos, &alphabet),
+    };
+    let rest_at = c_pos + prefix.bytes.len();
+    let rest_min_width = prefix.rest.as_ref().map_or(0, RegExpr::min_width);
+    if (prefix.sof && c_pos != 0) || rest_at + rest_min_width > content_len {
+        return Executed::ct_false();
+    }
+
+    let guard = prefix
+        .bytes
+        .iter()
+        .enumerate()
+        .map(|(i, c)| class_test(c_pos + i, CharClass::from_chars(&[*c]), &alphabet))
+        .reduce(Executed::and)
+        .unwrap();
+    let rest = match &prefix.rest {
+        Some(rest) => start_branches(content_len, rest, rest_at, &alphabet),
+        None => Executed::ct_true(),
+    };
+    Executed::and(guard, rest)
+}
+
+// the branches of re from c_pos, or-ed. The characters every match contains
+// at a fixed offset (see literal::required_chars) are likewise tested once,
+// as a guard of all branches, which then no longer test them.
+fn start_branches(content_len: usize, re: &RegExpr, c_pos: usize, alphabet: &CharClass) -> Executed {
+    let required = required_chars(re);
+    if required.iter().any(|(offset, _)| c_pos + offset >= content_len) {
+        return Executed::ct_false();
+    }
+    let guard = required
+        .iter()
+        .map(|(offset, c)| class_test(c_pos + offset, CharClass::from_chars(&[*c]), alphabet))
+        .fold(Executed::ct_true(), Executed::and);
+    let re = if required.is_empty() {
+        re.clone()
     } else {
-        branches[1..]
-            .into_iter()
-            .fold(branches[0](&mut exec), |res, branch| {
-                let branch_res = branch(&mut exec);
-                exec.ct_or(res, branch_res)
-            })
-            .0
+        without_required(re, &required)
     };
+    let branches = build_branches(content_len, &re, c_pos, 0, alphabet)
+        .into_iter()
+        .map(|(branch, _)| branch)
+        .collect();
+    Executed::and(guard, Executed::any(branches))
+}
+
+pub(crate) fn evaluate(
+    exec: &mut Execution,
+    content: &[RadixCiphertext],
+    plans: &[Executed],
+) -> Vec<RadixCiphertext> {
+    let schedule = Schedule::from_roots_ordered(plans, exec.eval_order());
+    info!("schedule: {}", schedule.stats());
+
+    exec.run_schedule(content, &schedule);
+    let res = plans.iter().map(|plan| exec.eval(content, plan)).collect();
     info!(
         "{} ciphertext operations, {} cache hits",
         exec.ct_operations_count(),
         exec.cache_hits(),
     );
-    Ok(res)
+    res
 }
 
-// this is a list monad procedure
+// this is a list monad procedure. after is the minimum number of characters
+// whatever follows re in the pattern consumes, branches that cannot leave
+// that many characters are never built.
 fn build_branches(
-    content: &[RadixCiphertext],
+    content_len: usize,
     re: &RegExpr,
     c_pos: usize,
-) -> Vec<(LazyExecution, usize)> {
+    after: usize,
+    alphabet: &CharClass,
+) -> Vec<(Executed, usize)> {
     trace!("program pointer: regex={:?}, content pos={}", re, c_pos);
     match re {
         RegExpr::SOF => {
             if c_pos == 0 {
-                return vec![(Rc::new(|exec| exec.ct_true()), c_pos)];
+                return vec![(Executed::ct_true(), c_pos)];
             } else {
                 return vec![];
             }
         }
         RegExpr::EOF => {
-            if c_pos == content.len() {
-                return vec![(Rc::new(|exec| exec.ct_true()), c_pos)];
+            if c_pos == content_len {
+                return vec![(Executed::ct_true(), c_pos)];
             } else {
                 return vec![];
             }
@@ -66,191 +1083,249 @@ fn build_branches(
         _ => (),
     };
 
-    if c_pos >= content.len() {
+    // This includes expressions that consume a character past the last
+    // content position. The others still have to be followed to the end of
+    // the content, as they may match the empty string there (e.g. a? or
+    // (x)*).
+    if c_pos.saturating_add(re.min_width()).saturating_add(after) > content_len {
         return vec![];
     }
 
-    match re.clone() {
-        RegExpr::Char { c } => {
-            let c_char = (content[c_pos].clone(), Executed::ct_pos(c_pos));
-            vec![(
-                Rc::new(move |exec| exec.ct_eq(c_char.clone(), exec.ct_constant(c))),
-                c_pos + 1,
-            )]
+    match re {
+        RegExpr::Char { .. }
+        | RegExpr::Range { .. }
+        | RegExpr::Between { .. }
+        | RegExpr::Not { .. } => {
+            let class = CharClass::from_regex(re)
+                .unwrap_or_else(|| panic!("negation of a non character class: {:?}", re));
+            vec![(class_test(c_pos, class, alphabet), c_pos + 1)]
         }
-        RegExpr::AnyChar => vec![(Rc::new(|exec| exec.ct_true()), c_pos + 1)],
-        RegExpr::Not { not_re } => build_branches(content, &not_re, c_pos)
-            .into_iter()
-            .map(|(branch, c_pos)| {
-                (
-                    Rc::new(move |exec: &mut Execution| {
-                        let branch_res = branch(exec);
-                        exec.ct_not(branch_res)
-                    }) as LazyExecution,
-                    c_pos,
-                )
-            })
+        RegExpr::AnyChar => vec![(Executed::ct_true(), c_pos + 1)],
+        RegExpr::Either { alts } => alts
+            .iter()
+            .flat_map(|re| build_branches(content_len, re, c_pos, after, alphabet))
             .collect(),
-        RegExpr::Either { l_re, r_re } => {
-            let mut res = build_branches(content, &l_re, c_pos);
-            res.append(&mut build_branches(content, &r_re, c_pos));
-            res
-        }
-        RegExpr::Between { from, to } => {
-            let c_char = (content[c_pos].clone(), Executed::ct_pos(c_pos));
-            vec![(
-                Rc::new(move |exec| {
-                    let ct_from = exec.ct_constant(from);
-                    let ct_to = exec.ct_constant(to);
-                    let ge_from = exec.ct_ge(c_char.clone(), ct_from);
-                    let le_to = exec.ct_le(c_char.clone(), ct_to);
-                    exec.ct_and(ge_from, le_to)
-                }),
-                c_pos + 1,
-            )]
-        }
-        RegExpr::Range { cs } => {
-            let c_char = (content[c_pos].clone(), Executed::ct_pos(c_pos));
-            vec![(
-                Rc::new(move |exec| {
-                    cs[1..].iter().fold(
-                        exec.ct_eq(c_char.clone(), exec.ct_constant(cs[0])),
-                        |res, c| {
-                            let ct_c_char_eq = exec.ct_eq(c_char.clone(), exec.ct_constant(*c));
-                            exec.ct_or(res, ct_c_char_eq)
-                        },
-                    )
-                }),
-                c_pos + 1,
-            )]
-        }
         RegExpr::Repeated {
             repeat_re,
             at_least,
             at_most,
         } => {
             let at_least = at_least.unwrap_or(0);
-            let at_most = at_most.unwrap_or(content.len() - c_pos);
+            // an unbounded repetition never needs more repetitions than there
+            // are characters left, but at least as many as it must have, which
+            // a nullable element (e.g. (a?){3}) can have without any left
+            let at_most = at_most.unwrap_or(std::cmp::max(at_least, content_len.saturating_sub(c_pos)));
 
             if at_least > at_most {
                 return vec![];
             }
 
-            let mut res = vec![
-                if at_least == 0 {
-                    vec![(
-                        Rc::new(|exec: &mut Execution| exec.ct_true()) as LazyExecution,
-                        c_pos,
-                    )]
-                } else {
-                    vec![]
-                },
-                build_branches(
-                    content,
+            // the branches of 0 repetitions, if allowed, and of the first
+            // number of repetitions that is not 0
+            let first = std::cmp::max(1, at_least);
+            let mut res = vec![if at_least == 0 {
+                vec![(Executed::ct_true(), c_pos)]
+            } else {
+                vec![]
+            }];
+            if first <= at_most {
+                res.push(build_branches(
+                    content_len,
                     &(RegExpr::Seq {
-                        re_xs: std::iter::repeat(*repeat_re.clone())
-                            .take(std::cmp::max(1, at_least))
-                            .collect(),
+                        re_xs: std::iter::repeat(*repeat_re.clone()).take(first).collect(),
                     }),
                     c_pos,
-                ),
-            ];
+                    after,
+                    alphabet,
+                ));
+            }
 
-            for _ in (at_least + 1)..(at_most + 1) {
-                res.push(
+            for _ in first..at_most {
+                // no more repetitions fit in the content
+                if res.last().unwrap().is_empty() {
+                    break;
+                }
+                res.push(merge_by_position(
                     res.last()
                         .unwrap()
                         .iter()
                         .flat_map(|(branch_prev, branch_c_pos)| {
-                            build_branches(content, &repeat_re, *branch_c_pos)
+                            element_branches(content_len, repeat_re, *branch_c_pos, after, alphabet)
                                 .into_iter()
                                 .map(move |(branch_x, branch_x_c_pos)| {
-                                    let branch_prev = branch_prev.clone();
-                                    (
-                                        Rc::new(move |exec: &mut Execution| {
-                                            let res_prev = branch_prev(exec);
-                                            let res_x = branch_x(exec);
-                                            exec.ct_and(res_prev, res_x)
-                                        }) as LazyExecution,
-                                        branch_x_c_pos,
-                                    )
+                                    (Executed::and(branch_prev.clone(), branch_x), branch_x_c_pos)
                                 })
                         })
                         .collect(),
-                );
+                ));
             }
             res.into_iter().flatten().collect()
         }
         RegExpr::Optional { opt_re } => {
-            let mut res = build_branches(content, &opt_re, c_pos);
-            res.push((Rc::new(|exec| exec.ct_true()), c_pos));
+            let mut res = build_branches(content_len, opt_re, c_pos, after, alphabet);
+            res.push((Executed::ct_true(), c_pos));
             res
         }
-        RegExpr::Seq { re_xs } => re_xs[1..].iter().fold(
-            build_branches(content, &re_xs[0], c_pos),
-            |continuations, re_x| {
-                continuations
-                    .into_iter()
-                    .flat_map(|(branch_prev, branch_prev_c_pos)| {
-                        build_branches(content, re_x, branch_prev_c_pos)
+        RegExpr::Seq { re_xs } => {
+            // the empty sequence (e.g. of /^$/ or /a|/) matches without
+            // consuming anything
+            if re_xs.is_empty() {
+                return vec![(Executed::ct_true(), c_pos)];
+            }
+            // the minimum width of what follows each element of the sequence
+            let mut afters = vec![after; re_xs.len()];
+            for i in (0..re_xs.len() - 1).rev() {
+                afters[i] = afters[i + 1] + re_xs[i + 1].min_width();
+            }
+            re_xs[1..].iter().zip(&afters[1..]).fold(
+                element_branches(content_len, &re_xs[0], c_pos, afters[0], alphabet),
+                |continuations, (re_x, after_x)| {
+                    merge_by_position(
+                        continuations
                             .into_iter()
-                            .map(move |(branch_x, branch_x_c_pos)| {
-                                let branch_prev = branch_prev.clone();
-                                (
-                                    Rc::new(move |exec: &mut Execution| {
-                                        let res_prev = branch_prev(exec);
-                                        let res_x = branch_x(exec);
-                                        exec.ct_and(res_prev, res_x)
-                                    }) as LazyExecution,
-                                    branch_x_c_pos,
-                                )
+                            .flat_map(|(branch_prev, branch_prev_c_pos)| {
+                                element_branches(content_len, re_x, branch_prev_c_pos, *after_x, alphabet)
+                                    .into_iter()
+                                    .map(move |(branch_x, branch_x_c_pos)| {
+                                        (Executed::and(branch_prev.clone(), branch_x), branch_x_c_pos)
+                                    })
                             })
-                    })
-                    .collect()
-            },
-        ),
+                            .collect(),
+                    )
+                },
+            )
+        }
         _ => panic!("unmatched regex variant"),
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use crate::regex::engine::has_match;
-    use test_case::test_case;
-
-    use tfhe::integer::{ServerKey, RadixClientKey};
-    use crate::regex::ciphertext::{create_trivial_radix, gen_keys, StringCiphertext};
-    use bincode;
-    use lazy_static::lazy_static;
-    use std::io::Write;
+// the branches of an element of a sequence or of a repetition, merged by
+// position before they are and-ed with what precedes them. An element with
+// several branches (e.g. the alternation of (foo|bar){3}) then results in a
+// single sub-plan per start and end position, which is the same node
+// whatever precedes it: each repetition, and each start position of the
+// pattern, reuses it, and only adds one and per reached position. Without
+// this, every branch of the element would be and-ed separately with every
+// continuation reaching its start, multiplying the work per repetition.
+fn element_branches(
+    content_len: usize,
+    re: &RegExpr,
+    c_pos: usize,
+    after: usize,
+    alphabet: &CharClass,
+) -> Vec<(Executed, usize)> {
+    merge_by_position(build_branches(content_len, re, c_pos, after, alphabet))
+}
 
-    lazy_static! {
-        pub static ref KEYS: (RadixClientKey, ServerKey) = setup_test_keys();
+// combines the branches that continue at the same content position into one,
+// as whatever follows is the same for all of them. Without this, the number
+// of branches multiplies with every repetition of an expression that has
+// multiple branches (e.g. (a|bc){16}), while with it, it is bounded by the
+// number of content positions.
+fn merge_by_position(branches: Vec<(Executed, usize)>) -> Vec<(Executed, usize)> {
+    let mut merged: BTreeMap<usize, Executed> = BTreeMap::new();
+    for (branch, c_pos) in branches {
+        let branch = match merged.remove(&c_pos) {
+            Some(prev) => Executed::or(prev, branch),
+            None => branch,
+        };
+        merged.insert(c_pos, branch);
     }
+    merged.into_iter().map(|(c_pos, branch)| (branch, c_pos)).collect()
+}
 
-    fn setup_test_keys() -> (RadixClientKey, ServerKey) {
-        #[cfg(feature = "gen_test_keys")]
-        generate_test_keys();
-        read_test_keys()
+// lowers a test of the character at c_pos for membership of class, using the
+// lowering the cost model estimates to be cheapest. That includes testing for
+// membership of the complement and negating, as a negated class ([^a]) has
+// far more members than its complement. Characters outside of the alphabet
+// never occur, so whether the tested class contains them is free to choose:
+// a test that no character of the alphabet passes (or fails) needs no
+// ciphertext operation at all, and otherwise adding them may make the test
+// cheaper.
+fn class_test(c_pos: usize, class: CharClass, alphabet: &CharClass) -> Executed {
+    let cost = CostModel::active();
+    let class = class.intersection(alphabet);
+    if class.is_empty() {
+        return Executed::ct_false();
+    }
+    if class == *alphabet {
+        return Executed::ct_true();
     }
+    let widened = class.union(&alphabet.complement());
+    let class = if cost.class_test(&widened) < cost.class_test(&class) {
+        widened
+    } else {
+        class
+    };
 
-    #[allow(dead_code)]
-    fn generate_test_keys() {
-        let (client_key, _) = gen_keys();
+    if cost.negate_class(&class) {
+        let complement = class.complement();
+        if complement.len() == 1 {
+            let c = complement.chars().next().unwrap();
+            return Executed::ne(Executed::ct_pos(c_pos), Executed::constant(c));
+        }
+        return Executed::not(lower_class(c_pos, complement));
+    }
+    lower_class(c_pos, class)
+}
 
-        let mut serialized_data = Vec::new();
-        bincode::serialize_into(&mut serialized_data, &client_key).unwrap();
-        let mut file = std::fs::File::create("test_data/client_key")
-            .unwrap();
-        file.write_all(&serialized_data).unwrap();
+fn lower_class(c_pos: usize, class: CharClass) -> Executed {
+    let c_char = Executed::ct_pos(c_pos);
+    match CostModel::active().class_lowering(&class) {
+        ClassLowering::EqChain => class
+            .chars()
+            .map(|c| Executed::eq(c_char.clone(), Executed::constant(c)))
+            .reduce(Executed::or)
+            .unwrap_or_else(Executed::ct_false),
+        ClassLowering::Interval => {
+            let (from, to) = class.as_range().unwrap();
+            Executed::and(
+                Executed::ge(c_char.clone(), Executed::constant(from)),
+                Executed::le(c_char, Executed::constant(to)),
+            )
+        }
+        ClassLowering::Lut => Executed::in_class(c_char, class),
     }
+}
 
-    fn read_test_keys() -> (RadixClientKey, ServerKey) {
-        let serialized_data = std::fs::read("test_data/client_key").unwrap();
-        let client_key: RadixClientKey = bincode::deserialize_from(serialized_data.as_slice()).unwrap();
+#[cfg(test)]
+pub(crate) mod tests {
+    use crate::regex::alphabet::{Alphabet, RestrictedContent};
+    use crate::regex::cache::ContentCache;
+    use crate::regex::engine::{
+        at_least_k_matches, build_plan, build_plan_in, check_budget, count_matching_documents, evaluate, has_match,
+        has_match_any_document, has_match_ignore_case, has_match_restricted, has_match_any_line, has_match_appended, has_match_cached,
+        has_match_engine, has_match_in, has_match_lines, has_match_patterns, has_match_warm, has_match_with, match_mask, match_with,
+        split_count, split_count_saturating, split_points, Engine, MatchOptions, ShortContent,
+    };
+    use crate::regex::execution::{CachePolicy, Executed, Execution};
+    use crate::regex::parser::{parse, parse_with, AnchorPrecedence, ParseLimits};
+    use crate::regex::scheduler::Schedule;
+    use crate::regex::test_keys::test_keys;
+    use crate::regex::warmup::warmup;
+    use test_case::test_case;
+
+    use tfhe::integer::{RadixCiphertext, RadixClientKey, ServerKey};
+    use crate::regex::ciphertext::{
+        append_encrypted, create_trivial_radix, deserialize_compressed_server_key, deserialize_server_key, fingerprint,
+        gen_compressed_server_key, serialize_compressed_server_key, StringCiphertext,
+    };
+    use lazy_static::lazy_static;
+
+    lazy_static! {
+        pub static ref KEYS: (RadixClientKey, ServerKey) = test_keys();
+    }
 
-        let server_key = ServerKey::new(&client_key);
-        (client_key, server_key)
+    // Content in the tests is encrypted trivially, so that only the server key
+    // is needed to run the engine and none of the tests pay for encryption.
+    // The client key is only needed to decrypt results (see test_keys for
+    // where both come from).
+    pub(crate) fn encrypt_trivial(content: &str) -> StringCiphertext {
+        content
+            .as_bytes()
+            .iter()
+            .map(|byte| create_trivial_radix(&KEYS.1, *byte as u64))
+            .collect()
     }
 
     #[test_case("ab", "/ab/", 1)]
@@ -276,17 +1351,629 @@ mod tests {
     #[test_case("Ab", "/ab/", 0 ; "ab case sensitive")]
     #[test_case("cD", "/ab|cd/i", 1)]
     #[test_case("cD", "/cD/", 1)]
+    #[test_case("c", "/^[abcdefg]$/", 1 ; "class lookup member")]
+    #[test_case("h", "/^[abcdefg]$/", 0 ; "class lookup non member")]
+    #[test_case("Ay", "/^[xyzAB]+$/", 1 ; "class lookup multiple nibbles")]
+    #[test_case("Ayc", "/^[xyzAB]+$/", 0 ; "class lookup multiple nibbles non member")]
     #[test_case("de", "/^ab|cd|de$/", 1 ; "multiple or")]
     #[test_case(" de", "/^ab|cd|de$/", 0 ; "multiple or nests below ^")]
+    #[test_case("xabd", "/abc|abd/", 1 ; "common prefix factored")]
+    #[test_case("xrat", "/cat|bat|rat/", 1 ; "common suffix factored")]
+    #[test_case("xbct", "/cat|bat|rat/", 0 ; "common suffix factored no match")]
+    #[test_case("xcd", "/ab|(cd|ef)|gh|ij/", 1 ; "many alternatives balanced")]
+    #[test_case("xdc", "/ab|(cd|ef)|gh|ij/", 0 ; "many alternatives balanced no match")]
+    #[test_case("ab", "/[^a]$/", 1 ; "negated class at end")]
+    #[test_case("ba", "/[^a]$/", 0 ; "negated class at end no match")]
+    #[test_case("a", "/a[^a]/", 0 ; "negated class never matches past end")]
+    #[test_case("ab", "/a[^a]/", 1 ; "negated class at last position")]
+    #[test_case("aa", "/^[^a-c]/", 0 ; "negated range")]
+    #[test_case("za", "/^[^a-c]/", 1 ; "negated range no member")]
+    #[test_case("ab;c", "/^[^;]*;/", 1 ; "up to a delimiter")]
+    #[test_case("abc", "/^[^;]*$/", 1 ; "no delimiter")]
+    #[test_case("a;c", "/^[^;]*$/", 0 ; "delimiter present")]
+    #[test_case("x\"ab\"", "/\"[^\"]+\"/", 1 ; "quoted string")]
+    #[test_case("\"\"", "/\"[^\"]+\"/", 0 ; "empty quoted string")]
+    #[test_case("aabbcab", "/^(ab|bc|a){4}$/", 1 ; "repeated group with multiple branches")]
+    #[test_case("aabbcabx", "/^(ab|bc|a){4}$/", 0 ; "repeated group with multiple branches no match")]
+    #[test_case("xfoo", "/foo$|^bar/", 1 ; "mid pattern end anchor")]
+    #[test_case("foox", "/foo$|^bar/", 0 ; "mid pattern end anchor no match")]
+    #[test_case("barx", "/foo$|^bar/", 1 ; "mid pattern start anchor")]
+    #[test_case("xbar", "/foo$|^bar/", 0 ; "mid pattern start anchor no match")]
+    #[test_case("ab", "/a(b$|c)/", 1 ; "end anchor within group")]
+    #[test_case("abx", "/a(b$|c)/", 0 ; "end anchor within group no match")]
+    #[test_case("ac", "/a(^b|c)/", 1 ; "start anchor within group")]
+    #[test_case("ab", "/a(^b|c)/", 0 ; "start anchor within group never matches mid content")]
+    #[test_case("a", "/^.$/", 1 ; "any char")]
+    #[test_case("ab", "/^.$/", 0 ; "any char is a single char")]
+    #[test_case("xb7", "/[a-c][0-9]/", 1 ; "consecutive ranges")]
+    #[test_case("xd7", "/[a-c][0-9]/", 0 ; "consecutive ranges no match")]
+    #[test_case("aa", "/^a{2}$/", 1 ; "exact repetition")]
+    #[test_case("aaa", "/^a{2}$/", 0 ; "exact repetition too many")]
+    #[test_case("aab", "/^a{1,2}b/", 1 ; "bounded repetition")]
+    #[test_case("aaab", "/^a{1,2}b/", 0 ; "bounded repetition too many")]
+    #[test_case("aaab", "/^a{2,}b$/", 1 ; "repetition with lower bound")]
+    #[test_case("ab", "/^a{2,}b$/", 0 ; "repetition with lower bound too few")]
+    #[test_case("xzy", "/x.{0,1}y/", 1 ; "optional any char")]
+    #[test_case("xzzy", "/x.{0,1}y/", 0 ; "optional any char too many")]
+    #[test_case("xy", "/x[^a]{0,1}y/", 1 ; "bounded negated class")]
+    #[test_case("xay", "/x[^a]{0,1}y/", 0 ; "bounded negated class member")]
+    #[test_case("b", "/a?$/", 1 ; "optional empty at end")]
+    #[test_case("ab", "/(x)*$/", 1 ; "star empty at end")]
+    #[test_case("ab", "/b(x)*$/", 1 ; "star empty after last char")]
+    #[test_case("ab", "/b(c|d?)$/", 1 ; "nullable alternative at end")]
+    #[test_case("ab", "/b(c|d)$/", 0 ; "non nullable alternative at end")]
+    #[test_case("ab", "/a(x){0,2}$/", 0 ; "bounded repetition empty not at end")]
+    #[test_case("ab", "/^ab(x)?(y)*$/", 1 ; "nullable sequence at end")]
+    #[test_case("xabcde", "/abcde/", 1 ; "match at last possible start")]
+    #[test_case("abcdx", "/abcde/", 0 ; "no room for the last char")]
+    #[test_case("xxab", "/a?b$/", 1 ; "end anchored within max width")]
+    #[test_case("abxx", "/ab$/", 0 ; "end anchored too far from the end")]
+    #[test_case("abcd", "/a(bc|b)d$/", 1 ; "alternatives of different widths")]
+    #[test_case("aaab", "/^a*ab$/", 1 ; "repetition leaves room for what follows")]
+    #[test_case("xcbx", "/(ab|cb)x/", 1 ; "required chars after alternation")]
+    #[test_case("xcax", "/(ab|cb)x/", 0 ; "required chars after alternation no match")]
+    #[test_case("abab", "/^(ab){2}$/", 1 ; "required chars of exact repetition")]
+    #[test_case("abac", "/^(ab){2}$/", 0 ; "required chars of exact repetition no match")]
+    #[test_case("", "/^$/", 1 ; "empty content anchored")]
+    #[test_case("", "/a?/", 1 ; "empty content optional")]
+    #[test_case("", "/a*$/", 1 ; "empty content star")]
+    #[test_case("", "/a/", 0 ; "empty content char")]
+    #[test_case("", "/^.$/", 0 ; "empty content any char")]
     fn test_has_match(content: &str, pattern: &str, exp: u64) {
-        let ct_content: StringCiphertext = content
-            .as_bytes()
-            .iter()
-            .map(|byte| create_trivial_radix(&KEYS.1, *byte as u64))
-            .collect();
-        let ct_res = has_match(&KEYS.1, &ct_content, pattern).unwrap();
+        let ct_content = encrypt_trivial(content);
+        let ct_res = has_match(&KEYS.1, &ct_content, pattern, &MatchOptions::default()).unwrap();
 
         let got = KEYS.0.decrypt(&ct_res);
         assert_eq!(exp, got);
     }
+
+    // anchors next to expressions that may match the empty string, where
+    // the anchors' positions coincide
+    #[test_case("", "/^a?$/", 1 ; "optional on empty content")]
+    #[test_case("a", "/^a?$/", 1 ; "optional taken")]
+    #[test_case("b", "/^a?$/", 0 ; "optional other char")]
+    #[test_case("aa", "/^a?$/", 0 ; "optional twice")]
+    #[test_case("", "/^$/", 1 ; "empty pattern on empty content")]
+    #[test_case("a", "/^$/", 0 ; "empty pattern on content")]
+    #[test_case("", "/^a*$/", 1 ; "star on empty content")]
+    #[test_case("aaaaaaaaaaaa", "/^a*$/", 1 ; "star on long content")]
+    #[test_case("aaaaaaaaaaab", "/^a*$/", 0 ; "star on long content other last char")]
+    #[test_case("baaaaaaaaaaa", "/^a*$/", 0 ; "star on long content other first char")]
+    #[test_case("", "/^a{0}$/", 1 ; "no repetitions on empty content")]
+    #[test_case("a", "/^a{0}$/", 0 ; "no repetitions")]
+    #[test_case("aa", "/^a{0,1}$/", 0 ; "bounded repetition from zero too many")]
+    #[test_case("", "/^(a?){3}$/", 1 ; "repeated optional on empty content")]
+    #[test_case("aa", "/^(a?){3}$/", 1 ; "repeated optional fewer than repetitions")]
+    #[test_case("aaaa", "/^(a?){3}$/", 0 ; "repeated optional too many")]
+    #[test_case("", "/^(a?)*$/", 1 ; "starred optional on empty content")]
+    #[test_case("b", "/^(a?)+$/", 0 ; "repeated optional other char")]
+    #[test_case("", "//", 1 ; "empty pattern unanchored on empty content")]
+    #[test_case("ab", "//", 1 ; "empty pattern unanchored")]
+    #[test_case("", "/a|/", 1 ; "empty alternative on empty content")]
+    #[test_case("b", "/a|/", 1 ; "empty alternative")]
+    #[test_case("b", "/^(a|)$/", 0 ; "empty alternative anchored other char")]
+    #[test_case("", "/^(a|)$/", 1 ; "empty alternative anchored on empty content")]
+    fn test_has_match_nullable_anchored(content: &str, pattern: &str, exp: u64) {
+        let ct_content = encrypt_trivial(content);
+        let ct_res = has_match(&KEYS.1, &ct_content, pattern, &MatchOptions::default()).unwrap();
+        assert_eq!(exp, KEYS.0.decrypt(&ct_res));
+    }
+
+    #[test_case("abc", "/^a[bx]c$/", 1)]
+    #[test_case("abd", "/^a[bx]c$/", 0)]
+    #[test_case("xAbx", "/ab/i", 1)]
+    fn test_has_match_warm(content: &str, pattern: &str, exp: u64) {
+        let key = warmup(&KEYS.1);
+        let ct_content = encrypt_trivial(content);
+        let ct_res = has_match_warm(&key, &ct_content, pattern, &MatchOptions::default()).unwrap();
+
+        assert_eq!(exp, KEYS.0.decrypt(&ct_res));
+    }
+
+    #[test_case("abx", "/^ab|cd$/", 1)]
+    #[test_case("xcd", "/^ab|cd$/", 1)]
+    #[test_case("xabx", "/^ab|cd$/", 0)]
+    #[test_case("abx", "/^(ab|cd)$/", 0 ; "group anchored as a whole")]
+    fn test_standard_anchor_precedence(content: &str, pattern: &str, exp: u64) {
+        let re = parse_with(pattern, AnchorPrecedence::Standard, ParseLimits::default()).unwrap();
+        let ct_content = encrypt_trivial(content);
+        let mut exec = Execution::new(KEYS.1.clone());
+        let ct_res = evaluate(&mut exec, &ct_content, &[build_plan(&re, content.len())]).remove(0);
+
+        assert_eq!(exp, KEYS.0.decrypt(&ct_res));
+    }
+
+    #[test_case("xabcx", "/abc/", Engine::Auto, Engine::Literal, 1)]
+    #[test_case("xabcx", "/^abc/", Engine::Auto, Engine::Literal, 0)]
+    #[test_case("xabcx", "/abc/", Engine::Branches, Engine::Branches, 1)]
+    #[test_case("xabcx", "/a.c/", Engine::Auto, Engine::Branches, 1)]
+    #[test_case("abc", "/abc$/", Engine::Literal, Engine::Literal, 1)]
+    fn test_has_match_engine(content: &str, pattern: &str, engine: Engine, exp_engine: Engine, exp: u64) {
+        let key = warmup(&KEYS.1);
+        let ct_content = encrypt_trivial(content);
+        let (ct_res, stats) = has_match_engine(&key, &ct_content, pattern, engine).unwrap();
+
+        assert_eq!(exp_engine, stats.engine);
+        assert_eq!(exp, KEYS.0.decrypt(&ct_res));
+    }
+
+    #[test_case("xAbx", "/ab/", MatchOptions { case_insensitive: true, ..MatchOptions::default() }, 1 ;
+        "case insensitive")]
+    #[test_case("abx", "/^ab|cd$/",
+        MatchOptions { precedence: AnchorPrecedence::Standard, ..MatchOptions::default() }, 1 ;
+        "standard precedence")]
+    #[test_case("abx", "/^ab|cd$/",
+        MatchOptions { precedence: AnchorPrecedence::Legacy, ..MatchOptions::default() }, 0 ;
+        "legacy precedence")]
+    fn test_has_match_with(content: &str, pattern: &str, opts: MatchOptions, exp: u64) {
+        let key = warmup(&KEYS.1);
+        let ct_content = encrypt_trivial(content);
+        let (ct_res, _) = has_match_with(&key, &ct_content, pattern, &opts).unwrap();
+
+        assert_eq!(exp, KEYS.0.decrypt(&ct_res));
+    }
+
+    #[test]
+    fn test_has_match_patterns() {
+        let key = warmup(&KEYS.1);
+        let ct_content = encrypt_trivial("abcd");
+        let patterns = ["/ab?c/", "/b[cd]/", "/^x/", "/a.c$/", "/ab?c?d/"];
+        let opts = MatchOptions::default();
+        let (ct_res, stats) = has_match_patterns(&key, &ct_content, &patterns, &opts).unwrap();
+
+        let res: Vec<u64> = ct_res.iter().map(|ct| KEYS.0.decrypt(ct)).collect();
+        assert_eq!(vec![1, 1, 0, 0, 1], res);
+        let separately: usize = patterns
+            .iter()
+            .map(|pattern| has_match_with(&key, &ct_content, pattern, &opts).unwrap().1.ct_operations)
+            .sum();
+        assert!(stats.ct_operations < separately);
+    }
+
+    #[test]
+    fn test_has_match_with_threads() {
+        let key = warmup(&KEYS.1);
+        let ct_content = encrypt_trivial("xabx");
+        let opts = MatchOptions {
+            threads: Some(2),
+            ..MatchOptions::default()
+        };
+        let (ct_res, stats) = has_match_with(&key, &ct_content, "/a[bc]/", &opts).unwrap();
+
+        assert_eq!(1, KEYS.0.decrypt(&ct_res));
+        assert_eq!(2, stats.threads);
+        assert!(stats.parallel_efficiency() <= 1.0);
+    }
+
+    #[test_case("xabx", "/a[bc]/", 1)]
+    #[test_case("xacx", "/a[b]/", 0)]
+    // known without any ciphertext operation, as the content is too short
+    #[test_case("x", "/ab/", 0)]
+    fn test_has_match_sanitized(content: &str, pattern: &str, exp: u64) {
+        let key = warmup(&KEYS.1);
+        let ct_content = encrypt_trivial(content);
+        let opts = MatchOptions {
+            sanitize_output: true,
+            ..MatchOptions::default()
+        };
+        let (ct_res, _) = has_match_with(&key, &ct_content, pattern, &MatchOptions::default()).unwrap();
+        let (ct_sanitized, _) = has_match_with(&key, &ct_content, pattern, &opts).unwrap();
+
+        assert_eq!(exp, KEYS.0.decrypt(&ct_sanitized));
+        assert_ne!(
+            bincode::serialize(&ct_res).unwrap(),
+            bincode::serialize(&ct_sanitized).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_has_match_with_budget() {
+        let key = warmup(&KEYS.1);
+        let ct_content = encrypt_trivial("abc");
+        let opts = MatchOptions {
+            max_bootstraps: Some(1),
+            ..MatchOptions::default()
+        };
+        assert!(has_match_with(&key, &ct_content, "/a.*c/", &opts).is_err());
+    }
+
+    #[test]
+    fn test_short_content() {
+        let key = warmup(&KEYS.1);
+        let ct_content = encrypt_trivial("ab");
+        let (ct_res, stats) =
+            has_match_with(&key, &ct_content, "/a.c/", &MatchOptions::default()).unwrap();
+        assert_eq!(0, KEYS.0.decrypt(&ct_res));
+        assert_eq!(0, stats.ct_operations);
+
+        let opts = MatchOptions {
+            short_content: ShortContent::Reject,
+            ..MatchOptions::default()
+        };
+        assert!(has_match_with(&key, &ct_content, "/a.c/", &opts).is_err());
+        assert!(has_match_with(&key, &ct_content, "/a.?/", &opts).is_ok());
+    }
+
+    #[test]
+    fn test_literal_engine_rejects_non_literal() {
+        let key = warmup(&KEYS.1);
+        let ct_content = encrypt_trivial("abc");
+        assert!(has_match_engine(&key, &ct_content, "/a.c/", Engine::Literal).is_err());
+        assert!("nfa".parse::<Engine>().is_err());
+    }
+
+    #[test_case("xxa", "b", "/ab/", 1 ; "match across the boundary")]
+    #[test_case("xab", "x", "/ab/", 1 ; "previous match kept")]
+    #[test_case("xab", "c", "/ab$/", 0 ; "previous end match discarded")]
+    #[test_case("ab", "c", "/^abc/", 1 ; "start anchor in window")]
+    #[test_case("xxa", "b", "/x+ab/", 1 ; "unbounded width")]
+    #[test_case("xxa", "c", "/ab/", 0)]
+    #[test_case("xab", "", "/ab$/", 1 ; "nothing appended")]
+    #[test_case("xab", "", "/cd/", 0 ; "nothing appended no match")]
+    fn test_has_match_appended(old: &str, more: &str, pattern: &str, exp: u64) {
+        let opts = MatchOptions::default();
+        let mut ct_content = encrypt_trivial(old);
+        let previous = has_match(&KEYS.1, &ct_content, pattern, &opts).unwrap();
+        append_encrypted(&mut ct_content, encrypt_trivial(more), opts.max_content_len).unwrap();
+
+        let ct_res = has_match_appended(&KEYS.1, &ct_content, old.len(), &previous, pattern, &opts).unwrap();
+        assert_eq!(exp, KEYS.0.decrypt(&ct_res));
+    }
+
+    // /a$|b/ tests for the end in one alternative only: a b before the window
+    // still matches, an a at the old end no longer does
+    #[test_case("bxxxx", "x", 1 ; "match before the window")]
+    #[test_case("xxxxa", "x", 0 ; "end match of the old content")]
+    #[test_case("xxxxx", "a", 1 ; "end match of the new content")]
+    fn test_has_match_appended_standard_precedence(old: &str, more: &str, exp: u64) {
+        let opts = MatchOptions {
+            precedence: AnchorPrecedence::Standard,
+            ..MatchOptions::default()
+        };
+        let key = warmup(&KEYS.1);
+        let mut ct_content = encrypt_trivial(old);
+        let (previous, _) = has_match_with(&key, &ct_content, "/a$|b/", &opts).unwrap();
+        append_encrypted(&mut ct_content, encrypt_trivial(more), opts.max_content_len).unwrap();
+
+        let ct_res = has_match_appended(&KEYS.1, &ct_content, old.len(), &previous, "/a$|b/", &opts).unwrap();
+        assert_eq!(exp, KEYS.0.decrypt(&ct_res));
+    }
+
+    #[test]
+    fn test_repeated_group_plan_size() {
+        let op_count = |repeat: usize| {
+            let re = parse(&format!("/^(ab|a){{{}}}/", repeat)).unwrap();
+            Schedule::new(&build_plan(&re, 32)).stats().operations
+        };
+        // every repetition adds at most one branch per content position, while
+        // without merging branches the plan would hold 2^16 branches
+        assert!(op_count(16) < 6 * op_count(8));
+    }
+
+    #[test]
+    fn test_literal_prefix_guards_branches() {
+        // per start position: the guard (two comparisons and their and), the
+        // alternation (two comparisons and their or) and the and of both,
+        // instead of the prefix being and-ed into both alternatives. Two
+        // start positions fit the content, and are or-ed.
+        let re = parse("/ab(c|d)/").unwrap();
+        let ops = Schedule::new(&build_plan(&re, 4)).stats().operations;
+        assert!(ops <= 15, "{} operations", ops);
+    }
+
+    #[test]
+    fn test_required_chars_guard_branches() {
+        // the b and the x are tested once, and-ed with the or of the
+        // comparisons of the first character
+        let re = parse("/(ab|cb)x/").unwrap();
+        let ops = Schedule::new(&build_plan(&re, 3)).stats().operations;
+        assert!(ops <= 7, "{} operations", ops);
+    }
+
+    #[test]
+    fn test_repeated_alternation_shares_sub_plans() {
+        // the alternation at every position is planned once, each start
+        // position of the 3 repetitions only adds 2 ands on top of that
+        let ops = |pattern: &str| {
+            let re = parse(pattern).unwrap();
+            Schedule::new(&build_plan(&re, 12)).stats().operations
+        };
+        let (once, thrice) = (ops("/(foo|bar)/"), ops("/(foo|bar){3}/"));
+        assert!(thrice <= once + 2 * 4, "{} operations, {} for a single repetition", thrice, once);
+    }
+
+    // The ciphertext operations of the branches engine, so that changes to
+    // the lowering or the execution that make matching more expensive fail
+    // here instead of going unnoticed. The counts are those of the plans built
+    // for each case: one operation per distinct class test, and and or, e.g.
+    // for /abc/ on 5 characters 3 start positions of 3 class tests and 2 ands,
+    // or-ed by 2 more. /^ab|cd$/ leaves only start position 2, where ^ fails, so no
+    // operation is left. The budget is the count plus OPS_MARGIN; lower the
+    // counts when a change saves operations, raise them only with a good reason.
+    const OPS_MARGIN: usize = 2;
+
+    #[test_case("xabcx", "/abc/", 17 ; "literal")]
+    #[test_case("abcd", "/^ab|cd$/", 0 ; "anchored alternation")]
+    #[test_case("aaaa", "/a*b/", 25 ; "unbounded repetition")]
+    #[test_case("abcdef", "/[a-c]x/", 19 ; "class")]
+    #[test_case("ab", "/ab/i", 3 ; "case insensitive")]
+    fn test_ct_operations_budget(content: &str, pattern: &str, exp_ops: usize) {
+        let ct_content = encrypt_trivial(content);
+        let mut exec = Execution::new(KEYS.1.clone());
+        let opts = MatchOptions {
+            engine: Engine::Branches,
+            ..MatchOptions::default()
+        };
+        match_with(&mut exec, &ct_content, pattern, &opts).unwrap();
+
+        let ops = exec.ct_operations_count();
+        assert!(
+            ops <= exp_ops + OPS_MARGIN,
+            "{} ciphertext operations, expected {} (at most {} more)",
+            ops,
+            exp_ops,
+            OPS_MARGIN
+        );
+    }
+
+    // The lowered plans of representative patterns, rendered, along with the
+    // number of operations and levels they are scheduled in, so that changes
+    // to the lowering show up in review as changes to these. Every character
+    // is tested with a lookup table (in), cheaper than an equality.
+    #[test_case("/abc/", 3, "(((ct_0 in [a])/\\(ct_1 in [b]))/\\(ct_2 in [c]))", 5, 3 ; "literal")]
+    #[test_case("/^ab$/", 2, "((ct_0 in [a])/\\(ct_1 in [b]))", 3, 2 ; "anchored")]
+    #[test_case("/^ab$/", 3, "f", 0, 0 ; "anchored on longer content")]
+    #[test_case(
+        "/ab|cd/",
+        2,
+        "(((ct_0 in [a])/\\(ct_1 in [b]))\\/((ct_0 in [c])/\\(ct_1 in [d])))",
+        7,
+        3 ;
+        "alternation"
+    )]
+    fn test_lowered_plan(pattern: &str, content_len: usize, exp: &str, exp_operations: usize, exp_levels: usize) {
+        let plan = build_plan(&parse(pattern).unwrap(), content_len);
+        assert_eq!(exp, format!("{:?}", plan));
+        let stats = Schedule::new(&plan).stats().clone();
+        assert_eq!((exp_operations, exp_levels), (stats.operations, stats.levels));
+    }
+
+    #[test]
+    fn test_check_budget() {
+        let opts = MatchOptions {
+            max_bootstraps: Some(1000),
+            ..MatchOptions::default()
+        };
+        assert!(check_budget("/^ab$/", 100, &opts).is_ok());
+
+        let err = check_budget("/a.*b/", 100, &opts).unwrap_err().to_string();
+        assert!(err.contains("replace `.*` with `.{0,32}`"), "{}", err);
+        assert!(err.contains("anchor the pattern"), "{}", err);
+    }
+
+    #[test_case("0123", "/12/", 1)]
+    #[test_case("0123", "/13/", 0)]
+    #[test_case("45", "/^[0-4]/", 1 ; "class within alphabet")]
+    #[test_case("59", "/[0-4]/", 0 ; "class within alphabet no match")]
+    #[test_case("59", "/^[^a]9/", 1 ; "negated class outside alphabet")]
+    fn test_has_match_restricted(content: &str, pattern: &str, exp: u64) {
+        let ct_content = RestrictedContent {
+            alphabet: Alphabet::digits(),
+            content: encrypt_trivial(content),
+        };
+        let ct_res = has_match_restricted(&KEYS.1, &ct_content, pattern, &MatchOptions::default()).unwrap();
+        assert_eq!(exp, KEYS.0.decrypt(&ct_res));
+    }
+
+    #[test]
+    fn test_impossible_characters_rejected_by_plan() {
+        let re = parse("/1a/").unwrap();
+        assert_eq!(Executed::ct_false(), build_plan_in(&re, 8, &Alphabet::digits()));
+
+        let re = parse("/[^a]/").unwrap();
+        assert_eq!(Executed::ct_true(), build_plan_in(&re, 8, &Alphabet::digits()));
+    }
+
+    #[test]
+    fn test_negated_char_lowered_to_ne() {
+        let plan = build_plan(&parse("/x[^a]/").unwrap(), 3);
+        let schedule = Schedule::new(&plan);
+        let kinds: Vec<&str> = schedule.levels().iter().flatten().map(|node| node.kind()).collect();
+        assert!(kinds.contains(&"ne"));
+        assert!(!kinds.contains(&"not"));
+    }
+
+    #[test_case("HeLLo", "/hello/", 1)]
+    #[test_case("hello", "/HELLO/", 1)]
+    #[test_case("Hxllo", "/h[A-E]llo/", 0)]
+    #[test_case("HEllo", "/h[A-E]llo/", 1)]
+    #[test_case("A", "/[^a]/", 0 ; "negated class")]
+    fn test_has_match_ignore_case(content: &str, pattern: &str, exp: u64) {
+        let ct_content = encrypt_trivial(content);
+        let ct_res = has_match_ignore_case(&KEYS.1, &ct_content, pattern, &MatchOptions::default()).unwrap();
+        assert_eq!(exp, KEYS.0.decrypt(&ct_res));
+    }
+
+    #[test]
+    fn test_has_match_cached() {
+        let ct_content = encrypt_trivial("abcab");
+        let mut cache = ContentCache::new();
+
+        let ct_res = has_match_cached(&KEYS.1, &ct_content, "/bc/", &MatchOptions::default(), &mut cache).unwrap();
+        assert_eq!(1, KEYS.0.decrypt(&ct_res));
+        let cached = cache.len();
+        assert!(cached > 0);
+
+        let ct_res = has_match_cached(&KEYS.1, &ct_content, "/^cab/", &MatchOptions::default(), &mut cache).unwrap();
+        assert_eq!(0, KEYS.0.decrypt(&ct_res));
+        let ct_res = has_match_cached(&KEYS.1, &ct_content, "/cab$/", &MatchOptions::default(), &mut cache).unwrap();
+        assert_eq!(1, KEYS.0.decrypt(&ct_res));
+        assert!(cache.len() > cached);
+    }
+
+    #[test]
+    fn test_has_match_cached_content_changed() {
+        let mut cache = ContentCache::new();
+
+        let ct_res = has_match_cached(&KEYS.1, &encrypt_trivial("ab"), "/^ab$/", &MatchOptions::default(), &mut cache).unwrap();
+        assert_eq!(1, KEYS.0.decrypt(&ct_res));
+        let ct_res = has_match_cached(&KEYS.1, &encrypt_trivial("ba"), "/^ab$/", &MatchOptions::default(), &mut cache).unwrap();
+        assert_eq!(0, KEYS.0.decrypt(&ct_res));
+    }
+
+    #[test]
+    fn test_fingerprint() {
+        assert_eq!(
+            fingerprint(&encrypt_trivial("ab")).unwrap(),
+            fingerprint(&encrypt_trivial("ab")).unwrap()
+        );
+        assert_ne!(
+            fingerprint(&encrypt_trivial("ab")).unwrap(),
+            fingerprint(&encrypt_trivial("ba")).unwrap()
+        );
+    }
+
+    #[test_case(&["ab", "cd"], "/^cd$/", &[0, 1] ; "anchors per line")]
+    #[test_case(&["ab", "cd"], "/^abcd$/", &[0, 0] ; "no match across lines")]
+    #[test_case(&["ab", "", "xab"], "/ab$/", &[1, 0, 1] ; "shared plan for same length")]
+    #[test_case(&["ab", "", "x"], "/^$/", &[0, 1, 0] ; "empty line")]
+    fn test_has_match_lines(lines: &[&str], pattern: &str, exp: &[u64]) {
+        let ct_lines: Vec<StringCiphertext> = lines.iter().map(|line| encrypt_trivial(line)).collect();
+
+        let got: Vec<u64> = has_match_lines(&KEYS.1, &ct_lines, pattern, &MatchOptions::default())
+            .unwrap()
+            .iter()
+            .map(|ct_res| KEYS.0.decrypt(ct_res))
+            .collect();
+        assert_eq!(exp, got.as_slice());
+
+        let ct_any = has_match_any_line(&KEYS.1, &ct_lines, pattern, &MatchOptions::default()).unwrap();
+        assert_eq!(exp.iter().any(|res| *res == 1) as u64, KEYS.0.decrypt(&ct_any));
+    }
+
+    #[test_case(&["ab", "xab", "cd"], "/ab/", 2, 1 ; "exactly k")]
+    #[test_case(&["ab", "xab", "cd"], "/ab/", 3, 0 ; "fewer than k")]
+    #[test_case(&["ab", "xab", "ab"], "/ab/", 1, 1 ; "more than k")]
+    #[test_case(&["cd"], "/ab/", 0, 1 ; "k of zero always holds")]
+    #[test_case(&["ab"], "/ab/", 2, 0 ; "k beyond the number of lines")]
+    fn test_at_least_k_matches(lines: &[&str], pattern: &str, k: usize, exp: u64) {
+        let ct_lines: Vec<StringCiphertext> = lines.iter().map(|line| encrypt_trivial(line)).collect();
+        let ct_res = at_least_k_matches(&KEYS.1, &ct_lines, pattern, &MatchOptions::default(), k).unwrap();
+        assert_eq!(exp, KEYS.0.decrypt(&ct_res));
+    }
+
+    #[test_case(&["xab", "ab", "cd"], "/^ab/", 1, 1 ; "one document")]
+    #[test_case(&["ab", "abx", "b"], "/ab/", 1, 2 ; "multiple documents")]
+    #[test_case(&["ba", "a"], "/ab/", 0, 0 ; "no document")]
+    #[test_case(&[], "/ab/", 0, 0 ; "no documents")]
+    fn test_match_documents(documents: &[&str], pattern: &str, exp_any: u64, exp_count: u64) {
+        let key = warmup(&KEYS.1);
+        let ct_documents: Vec<StringCiphertext> = documents.iter().map(|doc| encrypt_trivial(doc)).collect();
+
+        let ct_any = has_match_any_document(&key, &ct_documents, pattern, &MatchOptions::default()).unwrap();
+        assert_eq!(exp_any, KEYS.0.decrypt(&ct_any));
+        let ct_count = count_matching_documents(&key, &ct_documents, pattern, &MatchOptions::default()).unwrap();
+        assert_eq!(exp_count, KEYS.0.decrypt(&ct_count));
+    }
+
+    #[test_case("a,b,,c", "/,/", &[0, 1, 0, 1, 1, 0] ; "single char delimiter")]
+    #[test_case("a, b,c", "/, ?/", &[0, 1, 0, 0, 1, 0] ; "optional space")]
+    #[test_case("aaa", "/aa/", &[1, 1, 0] ; "overlapping")]
+    #[test_case("", "/,/", &[] ; "empty content")]
+    fn test_split(content: &str, delimiter_pattern: &str, exp: &[u64]) {
+        let ct_content = encrypt_trivial(content);
+
+        let got: Vec<u64> = split_points(&KEYS.1, &ct_content, delimiter_pattern, &MatchOptions::default())
+            .unwrap()
+            .iter()
+            .map(|ct_res| KEYS.0.decrypt(ct_res))
+            .collect();
+        assert_eq!(exp, got.as_slice());
+
+        let ct_count = split_count(&KEYS.1, &ct_content, delimiter_pattern, &MatchOptions::default()).unwrap();
+        assert_eq!(exp.iter().sum::<u64>(), KEYS.0.decrypt(&ct_count));
+    }
+
+    #[test_case("a,b,,c", 5, 3 ; "below the cap")]
+    #[test_case("a,b,,c", 2, 2 ; "saturated")]
+    #[test_case("abc", 2, 0 ; "no matches")]
+    fn test_split_count_saturating(content: &str, cap: usize, exp: u64) {
+        let ct_content = encrypt_trivial(content);
+        let ct_count = split_count_saturating(&KEYS.1, &ct_content, "/,/", &MatchOptions::default(), cap).unwrap();
+        assert_eq!(exp, KEYS.0.decrypt(&ct_count));
+    }
+
+    #[test_case("xabyab", "/ab/", &[0, 1, 1, 0, 1, 1] ; "every match")]
+    #[test_case("baab", "/a+/", &[0, 1, 1, 0] ; "longest match covered")]
+    #[test_case("abab", "/^ab/", &[1, 1, 0, 0] ; "anchored")]
+    #[test_case("aaba", "/aab|ba/", &[1, 1, 1, 1] ; "overlapping")]
+    #[test_case("ab", "/x?/", &[0, 0] ; "empty matches")]
+    #[test_case("ab", "/abc/", &[0, 0] ; "pattern longer than content")]
+    fn test_match_mask(content: &str, pattern: &str, exp: &[u64]) {
+        let ct_content = encrypt_trivial(content);
+
+        let got: Vec<u64> = match_mask(&KEYS.1, &ct_content, pattern, &MatchOptions::default())
+            .unwrap()
+            .iter()
+            .map(|ct_res| KEYS.0.decrypt(ct_res))
+            .collect();
+        assert_eq!(exp, got.as_slice());
+    }
+
+    #[test]
+    fn test_has_match_in_execution() {
+        let mut exec = Execution::builder(warmup(&KEYS.1))
+            .cache_policy(CachePolicy::KeepAll)
+            .threads(2)
+            .build()
+            .unwrap();
+        let opts = MatchOptions::default();
+        let ct_content = encrypt_trivial("xaby");
+
+        let (ct_res, stats) = has_match_in(&mut exec, &ct_content, "/ab?y/", &opts).unwrap();
+        assert_eq!(1, KEYS.0.decrypt(&ct_res));
+        assert_eq!(2, stats.threads);
+        // every result of the first match is kept, the second only adds to it
+        let (ct_res, stats) = has_match_in(&mut exec, &ct_content, "/ab?y$/", &opts).unwrap();
+        assert_eq!(1, KEYS.0.decrypt(&ct_res));
+        assert!(stats.cache_hits > 0);
+
+        // results of other content are not reused
+        let (ct_res, _) = has_match_in(&mut exec, &encrypt_trivial("xbay"), "/ab?y/", &opts).unwrap();
+        assert_eq!(0, KEYS.0.decrypt(&ct_res));
+    }
+
+    #[test]
+    fn test_has_match_in_over_budget() {
+        let mut exec = Execution::builder(warmup(&KEYS.1)).max_operations(2).build().unwrap();
+        let ct_content = encrypt_trivial("xab");
+        assert!(has_match_in(&mut exec, &ct_content, "/a?b/", &MatchOptions::default()).is_err());
+        assert!(has_match_in(&mut exec, &ct_content, "/^x/", &MatchOptions::default()).is_ok());
+    }
+
+    #[test]
+    fn test_compressed_server_key() {
+        let compressed = gen_compressed_server_key(&KEYS.0);
+        let serialized = serialize_compressed_server_key(&compressed).unwrap();
+        let server_key = deserialize_compressed_server_key(&serialized).unwrap();
+
+        let ct_content = encrypt_trivial("ab");
+        let ct_res = has_match(&server_key, &ct_content, "/^ab$/", &MatchOptions::default()).unwrap();
+        assert_eq!(1, KEYS.0.decrypt(&ct_res));
+    }
+
+    #[test]
+    fn test_deserialize_server_key_garbage() {
+        assert!(deserialize_server_key(&[1, 2, 3]).is_err());
+        assert!(deserialize_compressed_server_key(&[1, 2, 3]).is_err());
+    }
+
+    #[test]
+    fn test_has_match_rejects_other_layout() {
+        let mut ct_content = encrypt_trivial("ab");
+        ct_content[1] = RadixCiphertext::from(ct_content[1].blocks()[..2].to_vec());
+
+        assert!(has_match(&KEYS.1, &ct_content, "/ab/", &MatchOptions::default()).is_err());
+        assert!(split_points(&KEYS.1, &ct_content, "/b/", &MatchOptions::default()).is_err());
+    }
 }
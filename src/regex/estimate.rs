@@ -0,0 +1,247 @@
+use anyhow::Result;
+use std::fmt;
+use std::time::Duration;
+
+use crate::regex::ciphertext::gen_client_key;
+use crate::regex::client::estimate_content_size;
+use crate::regex::cost::CostModel;
+use crate::regex::engine::{plan_for, Engine, MatchOptions};
+use crate::regex::execution::Executed;
+use crate::regex::parser::parse;
+use crate::regex::profile::Profile;
+use crate::regex::scheduler::Schedule;
+
+// the time a single bootstrap takes on one core with the lwe dimension of
+// tfhe's default parameters (742), from a fast to a slow machine. Bootstraps
+// take time linear in the lwe dimension.
+const BOOTSTRAP_TIME: (Duration, Duration) = (Duration::from_millis(8), Duration::from_millis(30));
+const BOOTSTRAP_TIME_LWE_DIMENSION: usize = 742;
+
+// What running the protocol for a pattern on content of some length takes:
+// the bytes the client uploads (the encrypted content, and the server key
+// once), the time the server computes for and the bytes it sends back. The
+// sizes are exact, the compute time is a range: from all cores of a fast
+// machine to a single core of a slow one.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ProtocolEstimate {
+    pub content_bytes: u64,
+    pub server_key_bytes: u64,
+    pub compressed_server_key_bytes: u64,
+    pub bootstraps: usize,
+    pub compute_time: (Duration, Duration),
+    pub result_bytes: u64,
+}
+
+pub fn estimate_protocol(
+    content_len: usize,
+    pattern: &str,
+    profile: Profile,
+) -> Result<ProtocolEstimate> {
+    let re = parse(pattern)?;
+    // client keys are cheap to generate, unlike server keys, so ciphertext
+    // sizes are measured and key sizes are computed
+    let client_key = gen_client_key(profile);
+    let ct_bytes = estimate_content_size(&client_key, 1)? - estimate_content_size(&client_key, 0)?;
+    let (server_key_bytes, compressed_server_key_bytes) = server_key_bytes(profile);
+
+    let bootstraps = CostModel::active().pattern(&re, content_len);
+
+    Ok(ProtocolEstimate {
+        content_bytes: estimate_content_size(&client_key, content_len)?,
+        server_key_bytes,
+        compressed_server_key_bytes,
+        bootstraps,
+        compute_time: compute_time(bootstraps, profile),
+        result_bytes: ct_bytes,
+    })
+}
+
+// from all cores of a fast machine to a single core of a slow one
+fn compute_time(bootstraps: usize, profile: Profile) -> (Duration, Duration) {
+    let params = profile.parameters();
+    let scale = |t: Duration| {
+        t.mul_f64(params.lwe_dimension.0 as f64 / BOOTSTRAP_TIME_LWE_DIMENSION as f64)
+    };
+    let cores = std::thread::available_parallelism().map_or(1, |n| n.get());
+    (
+        scale(BOOTSTRAP_TIME.0).mul_f64(bootstraps as f64 / cores as f64),
+        scale(BOOTSTRAP_TIME.1).mul_f64(bootstraps as f64),
+    )
+}
+
+// The plan a match would evaluate, summarized before any key is generated or
+// anything is evaluated, for sanity checking a pattern before committing a
+// server to it. Unlike ProtocolEstimate, it is computed from the plan itself
+// rather than from the pattern: the operations that are left once shared
+// subexpressions are deduplicated.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PlanSummary {
+    pub engine: Engine,
+    // the alternatives the plan ors together, e.g. a branch per start
+    // position of the pattern and per way of matching from it
+    pub branches: usize,
+    pub operations: usize,
+    pub shared_operations: usize,
+    pub levels: usize,
+    pub bootstraps: usize,
+    pub compute_time: (Duration, Duration),
+    // the ciphertexts the match holds at once: the content and the most
+    // intermediate results held at once, as MatchOptions::max_memory limits
+    pub peak_memory_bytes: u64,
+    // held by the server on top, but shared by all matches
+    pub server_key_bytes: u64,
+}
+
+pub fn plan_summary(
+    content_len: usize,
+    pattern: &str,
+    opts: &MatchOptions,
+    profile: Profile,
+) -> Result<PlanSummary> {
+    let (engine, plan) = plan_for(pattern, content_len, opts)?;
+    let schedule = Schedule::new(&plan);
+    let stats = schedule.stats();
+    let cost = CostModel::active();
+    let bootstraps = schedule
+        .levels()
+        .iter()
+        .flatten()
+        .map(|node| cost.operation(node))
+        .sum();
+
+    let client_key = gen_client_key(profile);
+    let ct_bytes = estimate_content_size(&client_key, 1)? - estimate_content_size(&client_key, 0)?;
+    let (server_key_bytes, _) = server_key_bytes(profile);
+    let peak_memory_bytes = (content_len + stats.peak_results) as u64 * ct_bytes;
+
+    Ok(PlanSummary {
+        engine,
+        branches: branches(&plan),
+        operations: stats.operations,
+        shared_operations: stats.shared_operations,
+        levels: stats.levels,
+        bootstraps,
+        compute_time: compute_time(bootstraps, profile),
+        peak_memory_bytes,
+        server_key_bytes,
+    })
+}
+
+fn branches(plan: &Executed) -> usize {
+    match plan {
+        Executed::Or { a, b } => branches(a) + branches(b),
+        plan if *plan == Executed::ct_false() => 0,
+        _ => 1,
+    }
+}
+
+// the serialized sizes of the server key and of the compressed server key:
+// the bootstrapping key (an encryption of every lwe key bit under the glwe
+// key) and the key switching key (an encryption of every glwe key bit under
+// the lwe key), of 8 byte coefficients. A compressed key only holds the
+// bodies of its ciphertexts, their masks are regenerated from a seed.
+fn server_key_bytes(profile: Profile) -> (u64, u64) {
+    let params = profile.parameters();
+    let lwe_dimension = params.lwe_dimension.0 as u64;
+    let glwe_size = params.glwe_dimension.0 as u64 + 1;
+    let polynomial_size = params.polynomial_size.0 as u64;
+    let big_lwe_dimension = params.glwe_dimension.0 as u64 * polynomial_size;
+    let pbs_level = params.pbs_level.0 as u64;
+    let ks_level = params.ks_level.0 as u64;
+
+    let bsk = lwe_dimension * pbs_level * glwe_size * glwe_size * polynomial_size;
+    let ksk = big_lwe_dimension * ks_level * (lwe_dimension + 1);
+    let compressed_bsk = lwe_dimension * pbs_level * glwe_size * polynomial_size;
+    let compressed_ksk = big_lwe_dimension * ks_level;
+    (8 * (bsk + ksk), 8 * (compressed_bsk + compressed_ksk))
+}
+
+impl fmt::Display for ProtocolEstimate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "encrypted content (upload): {}", bytes(self.content_bytes))?;
+        writeln!(
+            f,
+            "server key (upload once): {} ({} compressed)",
+            bytes(self.server_key_bytes),
+            bytes(self.compressed_server_key_bytes)
+        )?;
+        writeln!(
+            f,
+            "server compute: ~{} bootstraps, {:.1?} to {:.1?}",
+            self.bootstraps, self.compute_time.0, self.compute_time.1
+        )?;
+        write!(f, "encrypted result (download): {}", bytes(self.result_bytes))
+    }
+}
+
+impl fmt::Display for PlanSummary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "engine: {}", self.engine)?;
+        writeln!(f, "branches: {}", self.branches)?;
+        writeln!(
+            f,
+            "operations: {} after deduplication ({} shared), in {} levels",
+            self.operations, self.shared_operations, self.levels
+        )?;
+        writeln!(
+            f,
+            "server compute: ~{} bootstraps, {:.1?} to {:.1?}",
+            self.bootstraps, self.compute_time.0, self.compute_time.1
+        )?;
+        write!(
+            f,
+            "peak memory: ~{} of ciphertexts, and the server key ({})",
+            bytes(self.peak_memory_bytes),
+            bytes(self.server_key_bytes)
+        )
+    }
+}
+
+fn bytes(n: u64) -> String {
+    match n {
+        n if n >= 1 << 30 => format!("{:.1} GiB", n as f64 / (1u64 << 30) as f64),
+        n if n >= 1 << 20 => format!("{:.1} MiB", n as f64 / (1u64 << 20) as f64),
+        n if n >= 1 << 10 => format!("{:.1} KiB", n as f64 / (1u64 << 10) as f64),
+        n => format!("{} B", n),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::regex::engine::{Engine, MatchOptions};
+    use crate::regex::estimate::{estimate_protocol, plan_summary};
+    use crate::regex::profile::Profile;
+
+    #[test]
+    fn test_estimate_protocol() {
+        let short = estimate_protocol(8, "/ab/", Profile::Demo).unwrap();
+        let long = estimate_protocol(16, "/ab/", Profile::Demo).unwrap();
+
+        assert_eq!(8 * short.result_bytes, long.content_bytes - short.content_bytes);
+        assert!(long.bootstraps > short.bootstraps);
+        assert!(short.compute_time.0 <= short.compute_time.1);
+        assert!(short.compressed_server_key_bytes < short.server_key_bytes);
+        assert_eq!(short.result_bytes, long.result_bytes);
+
+        let paranoid = estimate_protocol(8, "/ab/", Profile::Paranoid).unwrap();
+        assert!(paranoid.server_key_bytes > short.server_key_bytes);
+        assert!(paranoid.compute_time.1 > short.compute_time.1);
+    }
+
+    #[test]
+    fn test_plan_summary() {
+        let opts = MatchOptions::default();
+        let short = plan_summary(4, "/a?b/", &opts, Profile::Demo).unwrap();
+        let long = plan_summary(8, "/a?b/", &opts, Profile::Demo).unwrap();
+        assert_eq!(Engine::Branches, short.engine);
+        assert!(long.branches > short.branches);
+        assert!(long.operations > short.operations);
+        assert!(long.bootstraps > short.bootstraps);
+        assert!(long.peak_memory_bytes > short.peak_memory_bytes);
+
+        let none = plan_summary(1, "/abc/", &opts, Profile::Demo).unwrap();
+        assert_eq!(0, none.branches);
+        assert_eq!(0, none.bootstraps);
+        assert!(plan_summary(4, "/(/", &opts, Profile::Demo).is_err());
+    }
+}
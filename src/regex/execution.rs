@@ -1,28 +1,381 @@
-use std::collections::HashMap;
-use std::rc::Rc;
+use anyhow::{anyhow, Result};
+use rayon::prelude::*;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{Hash, Hasher};
+use std::ops::Deref;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tfhe::integer::{RadixCiphertext, ServerKey};
+use tfhe::shortint::server_key::Accumulator;
 
+use crate::regex::ciphertext::{flag_to_radix, trivial_value, Fingerprint, BLOCK_SIZE, NUM_BLOCKS};
+use crate::regex::class::CharClass;
+use crate::regex::kernel::{fhe_ne_char, fhe_select, in_class};
+use crate::regex::observe::Observer;
 use crate::regex::parser::u8_to_char;
-use crate::regex::ciphertext::create_trivial_radix;
+use crate::regex::scheduler::{EvalOrder, Schedule};
+use crate::regex::trace::OpTrace;
+use crate::regex::warmup::WarmKey;
 
-#[derive(Clone, PartialEq, Eq, Hash)]
+#[derive(Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub(crate) enum Executed {
     Constant { c: u8 },
     CtPos { at: usize },
-    And { a: Box<Executed>, b: Box<Executed> },
-    Or { a: Box<Executed>, b: Box<Executed> },
-    Equal { a: Box<Executed>, b: Box<Executed> },
-    GreaterOrEqual { a: Box<Executed>, b: Box<Executed> },
-    LessOrEqual { a: Box<Executed>, b: Box<Executed> },
-    Not { a: Box<Executed> },
+    And { a: Node, b: Node },
+    Or { a: Node, b: Node },
+    Equal { a: Node, b: Node },
+    NotEqual { a: Node, b: Node },
+    Xor { a: Node, b: Node },
+    GreaterOrEqual { a: Node, b: Node },
+    LessOrEqual { a: Node, b: Node },
+    Not { a: Node },
+    InClass { a: Node, class: CharClass },
+    // a where cond is set and b elsewhere
+    Select { cond: Node, a: Node, b: Node },
+    // a + b, modulo 256
+    Add { a: Node, b: Node },
+    GreaterThan { a: Node, b: Node },
+}
+
+// An operand of a node. Plans share their subtrees (between the start
+// positions of a pattern, and between the plans of a batch), and are keyed
+// by in the maps of executions and schedules, so operands are shared rather
+// than owned, and hashed once when built: cloning a node then takes a
+// reference count per operand rather than a copy of its subtree, and hashing
+// it hashes the hashes of its operands rather than their subtrees.
+#[derive(Clone)]
+pub(crate) struct Node {
+    hash: u64,
+    node: Arc<Executed>,
+}
+
+impl Node {
+    fn new(node: Executed) -> Self {
+        let mut hasher = DefaultHasher::new();
+        node.hash(&mut hasher);
+        Self {
+            hash: hasher.finish(),
+            node: Arc::new(node),
+        }
+    }
+}
+
+impl Deref for Node {
+    type Target = Executed;
+
+    fn deref(&self) -> &Executed {
+        &self.node
+    }
+}
+
+impl AsRef<Executed> for Node {
+    fn as_ref(&self) -> &Executed {
+        &self.node
+    }
+}
+
+impl PartialEq for Node {
+    // nodes with different hashes differ, and shared nodes are equal,
+    // whatever their subtrees
+    fn eq(&self, other: &Self) -> bool {
+        self.hash == other.hash && (Arc::ptr_eq(&self.node, &other.node) || self.node == other.node)
+    }
+}
+
+impl Eq for Node {}
+
+impl Hash for Node {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        state.write_u64(self.hash);
+    }
+}
+
+// serialized as the node itself, the hash is recomputed when deserialized
+impl Serialize for Node {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        self.node.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Node {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        Executed::deserialize(deserializer).map(Node::new)
+    }
+}
+
+impl std::fmt::Debug for Node {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        std::fmt::Debug::fmt(&*self.node, f)
+    }
 }
-type ExecutedResult = (RadixCiphertext, Executed);
 
 impl Executed {
     pub(crate) fn ct_pos(at: usize) -> Self {
         Executed::CtPos { at }
     }
 
+    pub(crate) fn constant(c: u8) -> Self {
+        Executed::Constant { c }
+    }
+
+    pub(crate) fn ct_false() -> Self {
+        Self::constant(CT_FALSE)
+    }
+
+    pub(crate) fn ct_true() -> Self {
+        Self::constant(CT_TRUE)
+    }
+
+    pub(crate) fn eq(a: Self, b: Self) -> Self {
+        Executed::Equal {
+            a: Node::new(a),
+            b: Node::new(b),
+        }
+    }
+
+    // the negation of eq in one operation: against a constant, it costs the
+    // same as eq does, rather than an eq and a not
+    pub(crate) fn ne(a: Self, b: Self) -> Self {
+        Executed::NotEqual {
+            a: Node::new(a),
+            b: Node::new(b),
+        }
+    }
+
+    pub(crate) fn ge(a: Self, b: Self) -> Self {
+        Executed::GreaterOrEqual {
+            a: Node::new(a),
+            b: Node::new(b),
+        }
+    }
+
+    pub(crate) fn le(a: Self, b: Self) -> Self {
+        Executed::LessOrEqual {
+            a: Node::new(a),
+            b: Node::new(b),
+        }
+    }
+
+    pub(crate) fn and(a: Self, b: Self) -> Self {
+        match (a.get_trivial_constant(), b.get_trivial_constant()) {
+            (Some(CT_TRUE), _) => b,
+            (Some(CT_FALSE), _) => a,
+            (_, Some(CT_TRUE)) => a,
+            (_, Some(CT_FALSE)) => b,
+            _ => Executed::And {
+                a: Node::new(a),
+                b: Node::new(b),
+            },
+        }
+    }
+
+    pub(crate) fn or(a: Self, b: Self) -> Self {
+        match (a.get_trivial_constant(), b.get_trivial_constant()) {
+            (Some(CT_TRUE), _) => a,
+            (_, Some(CT_TRUE)) => b,
+            (Some(CT_FALSE), _) => b,
+            (_, Some(CT_FALSE)) => a,
+            _ => Executed::Or {
+                a: Node::new(a),
+                b: Node::new(b),
+            },
+        }
+    }
+
+    pub(crate) fn xor(a: Self, b: Self) -> Self {
+        match (a.get_trivial_constant(), b.get_trivial_constant()) {
+            (Some(CT_FALSE), _) => b,
+            (_, Some(CT_FALSE)) => a,
+            (Some(CT_TRUE), _) => Self::not(b),
+            (_, Some(CT_TRUE)) => Self::not(a),
+            _ => Executed::Xor {
+                a: Node::new(a),
+                b: Node::new(b),
+            },
+        }
+    }
+
+    // the or of xs as a balanced tree, so that its depth grows
+    // logarithmically rather than linearly with the number of xs
+    pub(crate) fn any(mut xs: Vec<Self>) -> Self {
+        match xs.len() {
+            0 => Self::ct_false(),
+            1 => xs.pop().unwrap(),
+            n => {
+                let right = xs.split_off(n / 2);
+                Self::or(Self::any(xs), Self::any(right))
+            }
+        }
+    }
+
+    pub(crate) fn not(a: Self) -> Self {
+        match a.get_trivial_constant() {
+            Some(CT_TRUE) => Self::ct_false(),
+            Some(CT_FALSE) => Self::ct_true(),
+            _ => Executed::Not { a: Node::new(a) },
+        }
+    }
+
+    pub(crate) fn in_class(a: Self, class: CharClass) -> Self {
+        if class.is_empty() {
+            return Self::ct_false();
+        }
+        Executed::InClass {
+            a: Node::new(a),
+            class,
+        }
+    }
+
+    // a where cond holds and b elsewhere, for whatever needs to pick a value
+    // (e.g. a character, or the state of an automaton) on an encrypted
+    // condition without learning it
+    pub(crate) fn select(cond: Self, a: Self, b: Self) -> Self {
+        match cond.get_trivial_constant() {
+            Some(CT_TRUE) => a,
+            Some(CT_FALSE) => b,
+            _ if a == b => a,
+            _ => Executed::Select {
+                cond: Node::new(cond),
+                a: Node::new(a),
+                b: Node::new(b),
+            },
+        }
+    }
+
+    // a + b, for counters (e.g. of matches, or of mismatched characters).
+    // Counters are held in a radix like characters are, so they wrap around
+    // past 255: callers that could count further must cap what they add up.
+    pub(crate) fn add(a: Self, b: Self) -> Self {
+        match (a.get_trivial_constant(), b.get_trivial_constant()) {
+            (Some(0), _) => b,
+            (_, Some(0)) => a,
+            _ => Executed::Add {
+                a: Node::new(a),
+                b: Node::new(b),
+            },
+        }
+    }
+
+    // the sum of xs as a balanced tree, like any
+    pub(crate) fn sum(mut xs: Vec<Self>) -> Self {
+        match xs.len() {
+            0 => Self::constant(0),
+            1 => xs.pop().unwrap(),
+            n => {
+                let right = xs.split_off(n / 2);
+                Self::add(Self::sum(xs), Self::sum(right))
+            }
+        }
+    }
+
+    // whether a exceeds k, e.g. whether a counter reached k + 1
+    pub(crate) fn gt_scalar(a: Self, k: u8) -> Self {
+        match a.get_trivial_constant() {
+            _ if k == u8::MAX => Self::ct_false(),
+            Some(c) if c > k => Self::ct_true(),
+            Some(_) => Self::ct_false(),
+            None => Executed::GreaterThan {
+                a: Node::new(a),
+                b: Node::new(Self::constant(k)),
+            },
+        }
+    }
+
+    // the same node, but testing content positions shifted by offset
+    pub(crate) fn offset(&self, offset: usize) -> Self {
+        let shift = |a: &Executed| Node::new(a.offset(offset));
+        match self {
+            Self::Constant { .. } => self.clone(),
+            Self::CtPos { at } => Self::CtPos { at: at + offset },
+            Self::And { a, b } => Self::And { a: shift(a), b: shift(b) },
+            Self::Or { a, b } => Self::Or { a: shift(a), b: shift(b) },
+            Self::Equal { a, b } => Self::Equal { a: shift(a), b: shift(b) },
+            Self::NotEqual { a, b } => Self::NotEqual { a: shift(a), b: shift(b) },
+            Self::Xor { a, b } => Self::Xor { a: shift(a), b: shift(b) },
+            Self::GreaterOrEqual { a, b } => Self::GreaterOrEqual { a: shift(a), b: shift(b) },
+            Self::LessOrEqual { a, b } => Self::LessOrEqual { a: shift(a), b: shift(b) },
+            Self::Add { a, b } => Self::Add { a: shift(a), b: shift(b) },
+            Self::GreaterThan { a, b } => Self::GreaterThan { a: shift(a), b: shift(b) },
+            Self::Not { a } => Self::Not { a: shift(a) },
+            Self::InClass { a, class } => Self::InClass {
+                a: shift(a),
+                class: *class,
+            },
+            Self::Select { cond, a, b } => Self::Select {
+                cond: shift(cond),
+                a: shift(a),
+                b: shift(b),
+            },
+        }
+    }
+
+    // operands that must be available before this node can be evaluated,
+    // trivial constants and content positions have none
+    pub(crate) fn operands(&self) -> Vec<&Executed> {
+        match self {
+            Self::Constant { .. } | Self::CtPos { .. } => vec![],
+            Self::And { a, b }
+            | Self::Or { a, b }
+            | Self::Equal { a, b }
+            | Self::NotEqual { a, b }
+            | Self::Xor { a, b }
+            | Self::GreaterOrEqual { a, b }
+            | Self::LessOrEqual { a, b }
+            | Self::Add { a, b }
+            | Self::GreaterThan { a, b } => vec![a, b],
+            Self::Not { a } | Self::InClass { a, .. } => vec![a],
+            Self::Select { cond, a, b } => vec![cond, a, b],
+        }
+    }
+
+    // if this is a comparison of a content position against a constant,
+    // returns (position, constant)
+    pub(crate) fn as_position_eq(&self) -> Option<(usize, u8)> {
+        match self {
+            Self::Equal { a, b } => match (a.as_ref(), b.as_ref()) {
+                (Self::CtPos { at }, Self::Constant { c }) | (Self::Constant { c }, Self::CtPos { at }) => {
+                    Some((*at, *c))
+                }
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    // whether this tests a content position against constants, the result of
+    // which only depends on the content
+    pub(crate) fn is_comparison(&self) -> bool {
+        !self.is_leaf()
+            && !matches!(self, Self::Select { .. } | Self::Add { .. })
+            && self.operands().iter().all(|operand| operand.is_leaf())
+    }
+
+    // name of the operation of this node, as written to op traces
+    pub(crate) fn kind(&self) -> &'static str {
+        match self {
+            Self::Constant { .. } => "constant",
+            Self::CtPos { .. } => "ct_pos",
+            Self::And { .. } => "and",
+            Self::Or { .. } => "or",
+            Self::Equal { .. } => "eq",
+            Self::NotEqual { .. } => "ne",
+            Self::Xor { .. } => "xor",
+            Self::GreaterOrEqual { .. } => "ge",
+            Self::LessOrEqual { .. } => "le",
+            Self::Not { .. } => "not",
+            Self::InClass { .. } => "in_class",
+            Self::Select { .. } => "select",
+            Self::Add { .. } => "add",
+            Self::GreaterThan { .. } => "gt",
+        }
+    }
+
+    pub(crate) fn is_leaf(&self) -> bool {
+        matches!(self, Self::Constant { .. } | Self::CtPos { .. })
+    }
+
     fn get_trivial_constant(&self) -> Option<u8> {
         match self {
             Self::Constant { c } => Some(*c),
@@ -34,191 +387,599 @@ impl Executed {
 const CT_FALSE: u8 = 0;
 const CT_TRUE: u8 = 1;
 
-pub(crate) struct Execution {
-    sk: ServerKey,
+// whether the result of every operation on trivial encryptions is checked
+// against the same operation on plaintexts (see Execution::check_invariant)
+const CHECK_INVARIANTS: bool = cfg!(any(debug_assertions, feature = "check-invariants"));
+
+// What an execution keeps of the results of the operations it evaluated.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CachePolicy {
+    // intermediate results are dropped as soon as no later operation uses
+    // them, only comparisons of the content and the results of the plans are
+    // kept
+    #[default]
+    ReleaseEarly,
+    // every result is kept, so that later matches on the same content reuse
+    // all of them, at the cost of holding them all in memory
+    KeepAll,
+}
+
+// Evaluates plans on content, holding the results of the operations it
+// evaluated (see CachePolicy) and counting its work. Set up by an
+// ExecutionBuilder, for callers that control the resources matches use (see
+// engine::has_match_in); the engine otherwise sets one up per match from its
+// MatchOptions.
+pub struct Execution {
+    key: WarmKey,
     cache: HashMap<Executed, RadixCiphertext>,
+    cache_policy: CachePolicy,
+    // the content the cached results are of, when known
+    content: Option<Fingerprint>,
+    trace: Option<OpTrace>,
+    observer: Option<Arc<dyn Observer>>,
+    eval_order: EvalOrder,
+    pool: Option<Arc<rayon::ThreadPool>>,
+    sanitize_output: bool,
+    max_operations: Option<usize>,
+    max_memory: Option<u64>,
 
     ct_ops: usize,
     cache_hits: usize,
+    // summed time spent in ciphertext operations, over all threads
+    busy: Duration,
 }
-pub(crate) type LazyExecution = Rc<dyn Fn(&mut Execution) -> ExecutedResult>;
 
-impl Execution {
-    pub(crate) fn new(sk: ServerKey) -> Self {
-        Self {
-            sk,
+// The settings of an Execution, all optional: by default operations are
+// spread over rayon's global pool, intermediate results are released early,
+// results are not sanitized and no budget applies.
+pub struct ExecutionBuilder {
+    key: WarmKey,
+    cache_policy: CachePolicy,
+    eval_order: EvalOrder,
+    threads: Option<usize>,
+    sanitize_output: bool,
+    observer: Option<Arc<dyn Observer>>,
+    max_operations: Option<usize>,
+    max_memory: Option<u64>,
+}
+
+impl ExecutionBuilder {
+    pub fn cache_policy(mut self, cache_policy: CachePolicy) -> Self {
+        self.cache_policy = cache_policy;
+        self
+    }
+
+    pub fn eval_order(mut self, eval_order: EvalOrder) -> Self {
+        self.eval_order = eval_order;
+        self
+    }
+
+    // the operations are spread over a pool of this many threads of the
+    // execution's own, started once when it is built
+    pub fn threads(mut self, threads: usize) -> Self {
+        self.threads = Some(threads);
+        self
+    }
+
+    // bootstrap results once more (see ciphertext::sanitize)
+    pub fn sanitize_output(mut self, sanitize_output: bool) -> Self {
+        self.sanitize_output = sanitize_output;
+        self
+    }
+
+    pub fn observer(mut self, observer: Arc<dyn Observer>) -> Self {
+        self.observer = Some(observer);
+        self
+    }
+
+    // matches whose plans take more ciphertext operations than this are
+    // rejected before any is evaluated
+    pub fn max_operations(mut self, max_operations: usize) -> Self {
+        self.max_operations = Some(max_operations);
+        self
+    }
+
+    // matches whose ciphertexts are estimated to take more bytes than this
+    // at once (the content, and the most results held at once) are rejected
+    // before any operation is evaluated
+    pub fn max_memory(mut self, max_memory: u64) -> Self {
+        self.max_memory = Some(max_memory);
+        self
+    }
+
+    pub fn build(self) -> Result<Execution> {
+        let pool = match self.threads {
+            Some(threads) => Some(Arc::new(
+                rayon::ThreadPoolBuilder::new()
+                    .num_threads(threads)
+                    .build()
+                    .map_err(|e| anyhow!("failed to start {} threads: {}", threads, e))?,
+            )),
+            None => None,
+        };
+        Ok(Execution {
+            key: self.key,
             cache: HashMap::new(),
+            cache_policy: self.cache_policy,
+            content: None,
+            trace: None,
+            observer: self.observer,
+            eval_order: self.eval_order,
+            pool,
+            sanitize_output: self.sanitize_output,
+            max_operations: self.max_operations,
+            max_memory: self.max_memory,
             ct_ops: 0,
             cache_hits: 0,
+            busy: Duration::ZERO,
+        })
+    }
+}
+
+impl Execution {
+    // the constants, lookup tables and key of the execution come with key:
+    // precomputed when it is warmed up (see warmup), else derived on demand
+    pub fn builder(key: WarmKey) -> ExecutionBuilder {
+        ExecutionBuilder {
+            key,
+            cache_policy: CachePolicy::default(),
+            eval_order: EvalOrder::default(),
+            threads: None,
+            sanitize_output: false,
+            observer: None,
+            max_operations: None,
+            max_memory: None,
         }
     }
 
-    pub(crate) fn ct_operations_count(&self) -> usize {
-        self.ct_ops
+    pub(crate) fn new(sk: ServerKey) -> Self {
+        Self::with_key(WarmKey::cold(sk))
     }
 
-    pub(crate) fn cache_hits(&self) -> usize {
-        self.cache_hits
+    pub(crate) fn with_key(key: WarmKey) -> Self {
+        // without threads of its own, building can't fail
+        Self::builder(key).build().unwrap()
     }
 
-    pub(crate) fn ct_eq(&mut self, a: ExecutedResult, b: ExecutedResult) -> ExecutedResult {
-        let ctx = Executed::Equal {
-            a: Box::new(a.1.clone()),
-            b: Box::new(b.1.clone()),
-        };
-        self.with_cache(
-            ctx.clone(),
-            Rc::new(move |exec: &mut Execution| {
-                exec.ct_ops += 1;
-
-                let mut ct_a = a.0.clone();
-                let mut ct_b = b.0.clone();
-                (exec.sk.smart_eq(&mut ct_a, &mut ct_b), ctx.clone())
-            }),
-        )
+    pub(crate) fn key(&self) -> &WarmKey {
+        &self.key
     }
 
-    pub(crate) fn ct_ge(&mut self, a: ExecutedResult, b: ExecutedResult) -> ExecutedResult {
-        let ctx = Executed::GreaterOrEqual {
-            a: Box::new(a.1.clone()),
-            b: Box::new(b.1.clone()),
-        };
-        self.with_cache(
-            ctx.clone(),
-            Rc::new(move |exec| {
-                exec.ct_ops += 1;
-
-                let mut ct_a = a.0.clone();
-                let mut ct_b = b.0.clone();
-                (exec.sk.smart_gt(&mut ct_a, &mut ct_b), ctx.clone())
-            }),
-        )
+    pub(crate) fn pool(&self) -> Option<Arc<rayon::ThreadPool>> {
+        self.pool.clone()
     }
 
-    pub(crate) fn ct_le(&mut self, a: ExecutedResult, b: ExecutedResult) -> ExecutedResult {
-        let ctx = Executed::LessOrEqual {
-            a: Box::new(a.1.clone()),
-            b: Box::new(b.1.clone()),
-        };
-        self.with_cache(
-            ctx.clone(),
-            Rc::new(move |exec| {
-                exec.ct_ops += 1;
-
-                let mut ct_a = a.0.clone();
-                let mut ct_b = b.0.clone();
-                (exec.sk.smart_le(&mut ct_a, &mut ct_b), ctx.clone())
-            }),
-        )
+    pub(crate) fn sanitizes_output(&self) -> bool {
+        self.sanitize_output
     }
 
-    pub(crate) fn ct_and(&mut self, a: ExecutedResult, b: ExecutedResult) -> ExecutedResult {
-        let ctx = Executed::And {
-            a: Box::new(a.1.clone()),
-            b: Box::new(b.1.clone()),
-        };
+    // drops the cached results when content is not the content they are of
+    pub(crate) fn bind_content(&mut self, content: Fingerprint) {
+        if self.content != Some(content) {
+            self.cache.clear();
+            self.content = Some(content);
+        }
+    }
 
-        let c_a = a.1.get_trivial_constant();
-        let c_b = b.1.get_trivial_constant();
-        if c_a == Some(CT_TRUE) {
-            return (b.0, ctx);
+    // rejects plans taking more operations than the budget, counting the
+    // operations whose results are cached as free
+    pub(crate) fn check_budget(&self, content: &[RadixCiphertext], plans: &[Executed]) -> Result<()> {
+        if self.max_operations.is_none() && self.max_memory.is_none() {
+            return Ok(());
         }
-        if c_a == Some(CT_FALSE) {
-            return (a.0, ctx);
+        let schedule = Schedule::from_roots(plans);
+        if let Some(max_operations) = self.max_operations {
+            let operations = schedule
+                .levels()
+                .iter()
+                .flatten()
+                .filter(|node| !self.cache.contains_key(*node))
+                .count();
+            if operations > max_operations {
+                return Err(anyhow!(
+                    "the plan takes {} ciphertext operations, over the budget of {}",
+                    operations,
+                    max_operations
+                ));
+            }
         }
-        if c_b == Some(CT_TRUE) {
-            return (a.0, ctx);
+        if let Some(max_memory) = self.max_memory {
+            let peak = self.peak_memory(content, &schedule);
+            if peak > max_memory {
+                return Err(anyhow!(
+                    "the plan is estimated to hold {} bytes of ciphertexts at once, over the limit of {}",
+                    peak,
+                    max_memory
+                ));
+            }
         }
-        if c_b == Some(CT_FALSE) {
-            return (b.0, ctx);
+        Ok(())
+    }
+
+    // the most bytes the ciphertexts of evaluating schedule on content hold
+    // at once: the content, the results already cached, and the results the
+    // schedule holds at its peak (all of them, when the cache keeps all).
+    // Every result is a radix of the same size as a character.
+    pub(crate) fn peak_memory(&self, content: &[RadixCiphertext], schedule: &Schedule) -> u64 {
+        let ct_bytes = match content.first() {
+            Some(ct) => bincode::serialized_size(ct).unwrap_or(0),
+            None => return 0,
+        };
+        let stats = schedule.stats();
+        let held = match self.cache_policy {
+            CachePolicy::ReleaseEarly => stats.peak_results,
+            CachePolicy::KeepAll => stats.operations,
+        };
+        (content.len() + self.cache.len() + held) as u64 * ct_bytes
+    }
+
+    // records every ciphertext operation (and cache hit) of this execution
+    pub(crate) fn set_trace(&mut self, trace: OpTrace) {
+        self.trace = Some(trace);
+    }
+
+    pub(crate) fn eval_order(&self) -> EvalOrder {
+        self.eval_order
+    }
+
+    // The work of the execution so far, over every match it evaluated since
+    // it was built or its stats were last reset.
+
+    pub fn ct_operations_count(&self) -> usize {
+        self.ct_ops
+    }
+
+    pub fn cache_hits(&self) -> usize {
+        self.cache_hits
+    }
+
+    pub fn busy(&self) -> Duration {
+        self.busy
+    }
+
+    pub fn reset_stats(&mut self) {
+        self.ct_ops = 0;
+        self.cache_hits = 0;
+        self.busy = Duration::ZERO;
+    }
+
+    // The results the execution holds, reused by later matches on the same
+    // content. They are dropped when it matches other content, these drop
+    // them (or some of them) before then, e.g. to bound memory.
+
+    // the number of results held
+    pub fn cached_results(&self) -> usize {
+        self.cache.len()
+    }
+
+    pub fn clear_cache(&mut self) {
+        self.cache.clear();
+        self.content = None;
+    }
+
+    // keeps only the comparisons of content characters against constants,
+    // the results most likely to be reused by other patterns
+    pub fn retain_comparisons(&mut self) {
+        self.cache.retain(|node, _| node.is_comparison());
+    }
+
+    pub(crate) fn import_cache(&mut self, cache: HashMap<Executed, RadixCiphertext>) {
+        self.cache.extend(cache);
+    }
+
+    pub(crate) fn into_comparisons(self) -> HashMap<Executed, RadixCiphertext> {
+        self.cache
+            .into_iter()
+            .filter(|(node, _)| node.is_comparison())
+            .collect()
+    }
+
+    // evaluates the schedule level by level, all operations of one level are
+    // independent of each other and are dispatched to the thread pool together.
+    // Intermediate results are dropped as soon as no later level uses them,
+    // unless the cache policy keeps them.
+    pub(crate) fn run_schedule(&mut self, content: &[RadixCiphertext], schedule: &Schedule) {
+        let n_levels = schedule.levels().len();
+        if let Some(observer) = &self.observer {
+            observer.plan_built(schedule.stats().operations, n_levels);
         }
+        for (i, level) in schedule.levels().iter().enumerate() {
+            let (hits, todo): (Vec<&Executed>, Vec<&Executed>) =
+                level.iter().partition(|node| self.cache.contains_key(*node));
+            self.cache_hits += hits.len();
+            for node in hits {
+                self.record(node, &self.cache[node], Duration::ZERO, true);
+            }
 
-        self.with_cache(
-            ctx.clone(),
-            Rc::new(move |exec| {
-                exec.ct_ops += 1;
+            let (batchable, rest): (Vec<&Executed>, Vec<&Executed>) = todo
+                .into_iter()
+                .partition(|node| node.as_position_eq().is_some());
 
-                let mut ct_a = a.0.clone();
-                let mut ct_b = b.0.clone();
-                (exec.sk.smart_bitand(&mut ct_a, &mut ct_b), ctx.clone())
-            }),
-        )
+            // the comparisons of a level are dispatched before its other
+            // operations, they are the bulk of the first level
+            let mut results = self.eq_batched(content, &batchable);
+            results.par_extend(rest.par_iter().map(|node| {
+                debug!("evaluation for: {:?}", node);
+                self.started(node);
+                let start = Instant::now();
+                let res = self.apply(content, node);
+                let elapsed = start.elapsed();
+                self.check_invariant(content, node, &res);
+                self.record(node, &res, elapsed, false);
+                (*node, res, elapsed)
+            }));
+
+            self.ct_ops += results.len();
+            for (node, res, elapsed) in results {
+                self.busy += elapsed;
+                self.cache.insert(node.clone(), res);
+            }
+            if self.cache_policy == CachePolicy::ReleaseEarly {
+                for node in schedule.released_after(i) {
+                    self.cache.remove(node);
+                }
+            }
+            if let Some(observer) = &self.observer {
+                observer.level_done(i + 1, n_levels);
+            }
+        }
     }
 
-    pub(crate) fn ct_or(&mut self, a: ExecutedResult, b: ExecutedResult) -> ExecutedResult {
-        let ctx = Executed::Or {
-            a: Box::new(a.1.clone()),
-            b: Box::new(b.1.clone()),
-        };
+    // evaluates comparisons of content positions against constants, grouped
+    // per constant so that the lookup tables are only generated once for all
+    // positions compared against the same constant
+    fn eq_batched<'a>(
+        &self,
+        content: &[RadixCiphertext],
+        nodes: &[&'a Executed],
+    ) -> Vec<(&'a Executed, RadixCiphertext, Duration)> {
+        let mut batches: BTreeMap<u8, Vec<(usize, &'a Executed)>> = BTreeMap::new();
+        for node in nodes {
+            let (at, c) = node.as_position_eq().unwrap();
+            batches.entry(c).or_default().push((at, *node));
+        }
+
+        batches
+            .into_iter()
+            .flat_map(|(c, batch)| {
+                debug!(
+                    "batched evaluation of {} comparisons against {:?}",
+                    batch.len(),
+                    u8_to_char(c),
+                );
+                let shortkey = self.key.shortkey();
+                let built;
+                let accs = match self.key.eq_accumulators(c) {
+                    Some(accs) => accs,
+                    None => {
+                        built = EqAccumulators::new(shortkey, c);
+                        &built
+                    }
+                };
+                batch
+                    .into_par_iter()
+                    .map(|(at, node)| {
+                        self.started(node);
+                        let start = Instant::now();
+                        let res = accs.eq(shortkey, &content[at]);
+                        let elapsed = start.elapsed();
+                        self.check_invariant(content, node, &res);
+                        self.record(node, &res, elapsed, false);
+                        (node, res, elapsed)
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    }
 
-        let c_a = a.1.get_trivial_constant();
-        let c_b = b.1.get_trivial_constant();
-        if c_a == Some(CT_TRUE) {
-            return (a.0, ctx);
+    pub(crate) fn eval(&mut self, content: &[RadixCiphertext], node: &Executed) -> RadixCiphertext {
+        match node {
+            Executed::Constant { c } => return self.key.constant(*c),
+            Executed::CtPos { at } => return content[*at].clone(),
+            _ => (),
         }
-        if c_b == Some(CT_TRUE) {
-            return (b.0, ctx);
+        if let Some(res) = self.cache.get(node) {
+            trace!("cache hit: {:?}", node);
+            self.cache_hits += 1;
+            self.record(node, res, Duration::ZERO, true);
+            return res.clone();
         }
-        if c_a == Some(CT_FALSE) && c_b == Some(CT_FALSE) {
-            return (a.0, ctx);
+        for operand in node.operands() {
+            self.eval(content, operand);
         }
+        debug!("evaluation for: {:?}", node);
+        self.started(node);
+        let start = Instant::now();
+        let res = self.apply(content, node);
+        let elapsed = start.elapsed();
+        self.check_invariant(content, node, &res);
+        self.record(node, &res, elapsed, false);
+        self.busy += elapsed;
+        self.ct_ops += 1;
+        self.cache.insert(node.clone(), res.clone());
+        res
+    }
 
-        self.with_cache(
-            ctx.clone(),
-            Rc::new(move |exec| {
-                exec.ct_ops += 1;
+    fn started(&self, node: &Executed) {
+        if let Some(observer) = &self.observer {
+            observer.op_started(node.kind());
+        }
+    }
 
-                let mut ct_a = a.0.clone();
-                let mut ct_b = b.0.clone();
-                (exec.sk.smart_bitor(&mut ct_a, &mut ct_b), ctx.clone())
-            }),
-        )
+    fn record(&self, node: &Executed, res: &RadixCiphertext, duration: Duration, cache_hit: bool) {
+        if let Some(trace) = &self.trace {
+            trace.record(node, res, duration, cache_hit);
+        }
+        match &self.observer {
+            Some(observer) if cache_hit => observer.cache_hit(node.kind()),
+            Some(observer) => observer.op_finished(node.kind(), duration),
+            None => (),
+        }
     }
 
-    pub(crate) fn ct_not(&mut self, a: ExecutedResult) -> ExecutedResult {
-        let ctx = Executed::Not {
-            a: Box::new(a.1.clone()),
+    // when node's operands are trivial encryptions, decodes them and its
+    // result res, and panics unless res is what the operation results in on
+    // the plaintexts. Only done when CHECK_INVARIANTS is set, a harness for
+    // changes to how operations are evaluated.
+    fn check_invariant(&self, content: &[RadixCiphertext], node: &Executed, res: &RadixCiphertext) {
+        if !CHECK_INVARIANTS {
+            return;
+        }
+        let operands: Option<Vec<u64>> = node
+            .operands()
+            .into_iter()
+            .map(|operand| trivial_value(&self.operand(content, operand)))
+            .collect();
+        let (operands, value) = match (operands, trivial_value(res)) {
+            (Some(operands), Some(value)) => (operands, value),
+            _ => return,
         };
-        self.with_cache(
-            ctx.clone(),
-            Rc::new(move |exec| {
-                exec.ct_ops += 1;
-
-                let mut ct_a = a.0.clone();
-                let mut ct_b = exec.ct_constant(1).0;
-                (
-                    exec.sk.smart_bitxor(&mut ct_a, &mut ct_b),
-                    ctx.clone(),
-                )
-            }),
-        )
+        let expected = match node {
+            Executed::And { .. } => operands[0] & operands[1],
+            Executed::Or { .. } => operands[0] | operands[1],
+            Executed::Equal { .. } => (operands[0] == operands[1]) as u64,
+            Executed::NotEqual { .. } => (operands[0] != operands[1]) as u64,
+            Executed::Xor { .. } => operands[0] ^ operands[1],
+            Executed::GreaterOrEqual { .. } => (operands[0] >= operands[1]) as u64,
+            Executed::LessOrEqual { .. } => (operands[0] <= operands[1]) as u64,
+            Executed::Add { .. } => (operands[0] + operands[1]) % (1 << (NUM_BLOCKS * BLOCK_SIZE)),
+            Executed::GreaterThan { .. } => (operands[0] > operands[1]) as u64,
+            Executed::Not { .. } => operands[0] ^ CT_TRUE as u64,
+            Executed::InClass { class, .. } => class.contains(operands[0] as u8) as u64,
+            Executed::Select { .. } => {
+                if operands[0] != 0 {
+                    operands[1]
+                } else {
+                    operands[2]
+                }
+            }
+            Executed::Constant { .. } | Executed::CtPos { .. } => return,
+        };
+        if value != expected {
+            panic!(
+                "invariant violated: {:?} on {:?} resulted in {}, expected {}",
+                node, operands, value, expected
+            );
+        }
     }
 
-    pub(crate) fn ct_false(&self) -> ExecutedResult {
-        self.ct_constant(CT_FALSE)
+    // applies the operation of node, its operands must already be cached
+    fn apply(&self, content: &[RadixCiphertext], node: &Executed) -> RadixCiphertext {
+        let mut operands: Vec<RadixCiphertext> = node
+            .operands()
+            .into_iter()
+            .map(|operand| self.operand(content, operand))
+            .collect();
+
+        match node {
+            Executed::Not { .. } => {
+                let mut ct_a = operands.pop().unwrap();
+                let mut ct_b = self.key.constant(CT_TRUE);
+                return self.key.server_key().smart_bitxor(&mut ct_a, &mut ct_b);
+            }
+            Executed::InClass { class, .. } => {
+                return in_class(&self.key, &operands[0], class);
+            }
+            Executed::Select { .. } => {
+                return fhe_select(&self.key, &operands[0], &operands[1], &operands[2]);
+            }
+            Executed::NotEqual { a, b } => match (a.get_trivial_constant(), b.get_trivial_constant()) {
+                (None, Some(c)) => return fhe_ne_char(&self.key, &operands[0], c),
+                (Some(c), None) => return fhe_ne_char(&self.key, &operands[1], c),
+                _ => (),
+            },
+            _ => (),
+        }
+
+        let mut ct_b = operands.pop().unwrap();
+        let mut ct_a = operands.pop().unwrap();
+        let sk = self.key.server_key();
+        match node {
+            Executed::And { .. } => sk.smart_bitand(&mut ct_a, &mut ct_b),
+            Executed::Or { .. } => sk.smart_bitor(&mut ct_a, &mut ct_b),
+            Executed::Equal { .. } => sk.smart_eq(&mut ct_a, &mut ct_b),
+            Executed::NotEqual { .. } => {
+                let mut eq = sk.smart_eq(&mut ct_a, &mut ct_b);
+                sk.smart_bitxor(&mut eq, &mut self.key.constant(CT_TRUE))
+            }
+            Executed::Xor { .. } => sk.smart_bitxor(&mut ct_a, &mut ct_b),
+            Executed::GreaterOrEqual { .. } => sk.smart_ge(&mut ct_a, &mut ct_b),
+            Executed::LessOrEqual { .. } => sk.smart_le(&mut ct_a, &mut ct_b),
+            Executed::GreaterThan { .. } => sk.smart_gt(&mut ct_a, &mut ct_b),
+            Executed::Add { .. } => {
+                // the carries are propagated into the next blocks, so that
+                // every block holds its digit alone, as operations on the
+                // sum (and trivial_value) expect, and the carry out of the
+                // last block is dropped
+                let mut res = sk.smart_add(&mut ct_a, &mut ct_b);
+                sk.full_propagate(&mut res);
+                res
+            }
+            _ => unreachable!("leaf nodes are not applied"),
+        }
     }
 
-    pub(crate) fn ct_true(&self) -> ExecutedResult {
-        self.ct_constant(CT_TRUE)
+    fn operand(&self, content: &[RadixCiphertext], operand: &Executed) -> RadixCiphertext {
+        match operand {
+            Executed::Constant { c } => self.key.constant(*c),
+            Executed::CtPos { at } => content[*at].clone(),
+            _ => self.cache[operand].clone(),
+        }
     }
+}
 
-    pub(crate) fn ct_constant(&self, c: u8) -> ExecutedResult {
-        (
-            create_trivial_radix(&self.sk, c as u64),
-            Executed::Constant { c },
-        )
+// lookup tables for testing a radix ciphertext for equality against a
+// constant: one per block that maps the block to 1 iff it equals the
+// constant's block, and one that maps the sum of these to 1 iff all blocks
+// were equal (or, for inequality, iff any was not)
+pub(crate) struct EqAccumulators {
+    blocks: Vec<Accumulator>,
+    all_eq: Accumulator,
+    any_ne: Accumulator,
+}
+
+impl EqAccumulators {
+    pub(crate) fn new(shortkey: &tfhe::shortint::ServerKey, c: u8) -> Self {
+        let block_mask = (1 << BLOCK_SIZE) - 1;
+        let blocks = (0..NUM_BLOCKS)
+            .map(|block| {
+                let c_block = (c as u64 >> (block * BLOCK_SIZE)) & block_mask;
+                shortkey.generate_accumulator(|x| (x == c_block) as u64)
+            })
+            .collect();
+        let all_eq = shortkey.generate_accumulator(|x| (x == NUM_BLOCKS as u64) as u64);
+        let any_ne = shortkey.generate_accumulator(|x| (x != NUM_BLOCKS as u64) as u64);
+        Self { blocks, all_eq, any_ne }
     }
 
-    fn with_cache(&mut self, ctx: Executed, f: LazyExecution) -> ExecutedResult {
-        if let Some(res) = self.cache.get(&ctx) {
-            trace!("cache hit: {:?}", &ctx);
-            self.cache_hits += 1;
-            return (res.clone(), ctx);
+    pub(crate) fn eq(&self, shortkey: &tfhe::shortint::ServerKey, ct: &RadixCiphertext) -> RadixCiphertext {
+        self.combine(shortkey, ct, &self.all_eq)
+    }
+
+    pub(crate) fn ne(&self, shortkey: &tfhe::shortint::ServerKey, ct: &RadixCiphertext) -> RadixCiphertext {
+        self.combine(shortkey, ct, &self.any_ne)
+    }
+
+    fn combine(
+        &self,
+        shortkey: &tfhe::shortint::ServerKey,
+        ct: &RadixCiphertext,
+        combined: &Accumulator,
+    ) -> RadixCiphertext {
+        let mut block_eqs = ct
+            .blocks()
+            .iter()
+            .zip(self.blocks.iter())
+            .map(|(block, acc)| shortkey.keyswitch_programmable_bootstrap(block, acc));
+        let mut sum = block_eqs.next().unwrap();
+        for block_eq in block_eqs {
+            shortkey.unchecked_add_assign(&mut sum, &block_eq);
         }
-        debug!("evaluation for: {:?}", &ctx);
-        let res = f(self);
-        self.cache.insert(ctx, res.0.clone());
-        res
+
+        flag_to_radix(
+            shortkey,
+            shortkey.keyswitch_programmable_bootstrap(&sum, combined),
+        )
     }
 }
 
@@ -252,6 +1013,20 @@ impl std::fmt::Debug for Executed {
                 b.fmt(f)?;
                 write!(f, ")")
             }
+            Self::NotEqual { a, b } => {
+                write!(f, "(")?;
+                a.fmt(f)?;
+                write!(f, "!=")?;
+                b.fmt(f)?;
+                write!(f, ")")
+            }
+            Self::Xor { a, b } => {
+                write!(f, "(")?;
+                a.fmt(f)?;
+                write!(f, "^")?;
+                b.fmt(f)?;
+                write!(f, ")")
+            }
             Self::GreaterOrEqual { a, b } => {
                 write!(f, "(")?;
                 a.fmt(f)?;
@@ -266,11 +1041,269 @@ impl std::fmt::Debug for Executed {
                 b.fmt(f)?;
                 write!(f, ")")
             }
+            Self::GreaterThan { a, b } => {
+                write!(f, "(")?;
+                a.fmt(f)?;
+                write!(f, ">")?;
+                b.fmt(f)?;
+                write!(f, ")")
+            }
+            Self::Add { a, b } => {
+                write!(f, "(")?;
+                a.fmt(f)?;
+                write!(f, "+")?;
+                b.fmt(f)?;
+                write!(f, ")")
+            }
             Self::Not { a } => {
                 write!(f, "(!")?;
                 a.fmt(f)?;
                 write!(f, ")")
             }
+            Self::InClass { a, class } => {
+                write!(f, "(")?;
+                a.fmt(f)?;
+                write!(f, " in ")?;
+                class.fmt(f)?;
+                write!(f, ")")
+            }
+            Self::Select { cond, a, b } => {
+                write!(f, "(")?;
+                cond.fmt(f)?;
+                write!(f, "?")?;
+                a.fmt(f)?;
+                write!(f, ":")?;
+                b.fmt(f)?;
+                write!(f, ")")
+            }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::regex::ciphertext::trivial_value;
+    use crate::regex::engine::tests::KEYS;
+    use crate::regex::engine::tests::encrypt_trivial;
+    use crate::regex::engine::{evaluate, has_match_in, MatchOptions};
+    use crate::regex::execution::{CachePolicy, Executed, Execution};
+    use crate::regex::scheduler::Schedule;
+    use crate::regex::warmup::{warmup, WarmKey};
+    use std::collections::hash_map::DefaultHasher;
+    use std::hash::{Hash, Hasher};
+    use std::sync::Arc;
+    use test_case::test_case;
+
+    #[test]
+    fn test_shared_operands() {
+        let comparison = |at| Executed::eq(Executed::ct_pos(at), Executed::constant(b'a'));
+        let plan = Executed::and(comparison(0), comparison(1));
+        // built apart, equal and hashed the same, whatever shares what
+        let rebuilt = Executed::and(comparison(0), comparison(1));
+        assert!(plan == rebuilt);
+        assert!(plan != Executed::and(comparison(1), comparison(0)));
+        let hash = |node: &Executed| {
+            let mut hasher = DefaultHasher::new();
+            node.hash(&mut hasher);
+            hasher.finish()
+        };
+        assert_eq!(hash(&plan), hash(&rebuilt));
+        assert!(plan.offset(1) == Executed::and(comparison(1), comparison(2)));
+
+        // clones share the operands of the node
+        let cloned = plan.clone();
+        match (&plan, &cloned) {
+            (Executed::And { a, .. }, Executed::And { a: a_cloned, .. }) => {
+                assert!(Arc::ptr_eq(&a.node, &a_cloned.node))
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_reused_execution() {
+        let mut exec = Execution::builder(warmup(&KEYS.1))
+            .cache_policy(CachePolicy::KeepAll)
+            .build()
+            .unwrap();
+        let opts = MatchOptions::default();
+        let ct_content = encrypt_trivial("xaby");
+
+        has_match_in(&mut exec, &ct_content, "/a(b|c)y/", &opts).unwrap();
+        let (ops, held) = (exec.ct_operations_count(), exec.cached_results());
+        assert!(ops > 0);
+        exec.reset_stats();
+        assert_eq!(0, exec.ct_operations_count());
+
+        exec.retain_comparisons();
+        assert!(exec.cached_results() < held);
+        let (ct_res, _) = has_match_in(&mut exec, &ct_content, "/a(b|c)y/", &opts).unwrap();
+        assert_eq!(1, KEYS.0.decrypt(&ct_res));
+        // only what combines the comparisons is evaluated again
+        assert!(exec.cache_hits() > 0);
+        assert!(exec.ct_operations_count() < ops);
+
+        exec.clear_cache();
+        assert_eq!(0, exec.cached_results());
+        exec.reset_stats();
+        has_match_in(&mut exec, &ct_content, "/a(b|c)y/", &opts).unwrap();
+        assert_eq!(ops, exec.ct_operations_count());
+    }
+
+    #[test]
+    fn test_max_memory() {
+        let ct_content = encrypt_trivial("abcd");
+        let plan = Executed::any(
+            (0..4)
+                .map(|at| Executed::eq(Executed::ct_pos(at), Executed::constant(b'c')))
+                .collect(),
+        );
+        let schedule = Schedule::new(&plan);
+        let exec = Execution::with_key(warmup(&KEYS.1));
+        let peak = exec.peak_memory(&ct_content, &schedule);
+        assert!(peak > 0);
+
+        let kept = Execution::builder(warmup(&KEYS.1))
+            .cache_policy(CachePolicy::KeepAll)
+            .build()
+            .unwrap();
+        assert!(kept.peak_memory(&ct_content, &schedule) > peak);
+
+        let limited = |max_memory| {
+            Execution::builder(warmup(&KEYS.1))
+                .max_memory(max_memory)
+                .build()
+                .unwrap()
+        };
+        assert!(limited(peak).check_budget(&ct_content, &[plan.clone()]).is_ok());
+        assert!(limited(peak - 1).check_budget(&ct_content, &[plan]).is_err());
+    }
+
+    #[test_case("ab", b'b' ; "condition holds")]
+    #[test_case("xb", b'z' ; "condition does not hold")]
+    fn test_select(content: &str, exp: u8) {
+        let cond = Executed::eq(Executed::ct_pos(0), Executed::constant(b'a'));
+        let select = Executed::select(cond, Executed::ct_pos(1), Executed::constant(b'z'));
+        // the same selection twice is evaluated once
+        let plan = Executed::eq(select.clone(), select);
+
+        let mut exec = Execution::with_key(warmup(&KEYS.1));
+        let ct_content = encrypt_trivial(content);
+        let res = evaluate(&mut exec, &ct_content, &[plan.clone(), plan.operands()[0].clone()]);
+        assert_eq!(1, KEYS.0.decrypt(&res[0]));
+        assert_eq!(exp as u64, KEYS.0.decrypt(&res[1]));
+        assert_eq!(3, exec.ct_operations_count());
+    }
+
+    #[test]
+    fn test_select_trivial_condition() {
+        let (a, b) = (Executed::ct_pos(0), Executed::ct_pos(1));
+        assert_eq!(a, Executed::select(Executed::ct_true(), a.clone(), b.clone()));
+        assert_eq!(b, Executed::select(Executed::ct_false(), a.clone(), b.clone()));
+        assert_eq!(a, Executed::select(Executed::ct_pos(2), a.clone(), a.clone()));
+    }
+
+    #[test_case("aaa", 3, 1 ; "above")]
+    #[test_case("aba", 2, 0 ; "at")]
+    #[test_case("bbb", 0, 0 ; "none")]
+    fn test_sum(content: &str, exp_sum: u64, exp_gt: u64) {
+        let counted: Vec<Executed> = (0..content.len())
+            .map(|at| Executed::eq(Executed::ct_pos(at), Executed::constant(b'a')))
+            .collect();
+        let sum = Executed::sum(counted);
+        let plan = Executed::gt_scalar(sum.clone(), 2);
+
+        let mut exec = Execution::with_key(warmup(&KEYS.1));
+        let res = evaluate(&mut exec, &encrypt_trivial(content), &[sum, plan]);
+        assert_eq!(exp_sum, KEYS.0.decrypt(&res[0]));
+        assert_eq!(exp_gt, KEYS.0.decrypt(&res[1]));
+    }
+
+    #[test]
+    fn test_add_carries() {
+        // 0x7f + 0x7f carries out of every block but the last one, and
+        // 0xff + 0x02 out of the last one
+        let mut exec = Execution::with_key(warmup(&KEYS.1));
+        let ct_content = encrypt_trivial("\x7f");
+        let twice = Executed::add(Executed::ct_pos(0), Executed::ct_pos(0));
+        let wrapped = Executed::add(
+            Executed::add(twice.clone(), Executed::constant(1)),
+            Executed::constant(2),
+        );
+        let res = evaluate(&mut exec, &ct_content, &[twice.clone(), wrapped]);
+        assert_eq!(0xfe, KEYS.0.decrypt(&res[0]));
+        assert_eq!(1, KEYS.0.decrypt(&res[1]));
+        // the sum is a value like any other, comparisons on it hold
+        let res = evaluate(&mut exec, &ct_content, &[Executed::gt_scalar(twice, 0xfd)]);
+        assert_eq!(1, KEYS.0.decrypt(&res[0]));
+    }
+
+    #[test]
+    fn test_trivial_counters() {
+        let a = Executed::ct_pos(0);
+        assert_eq!(a, Executed::add(a.clone(), Executed::constant(0)));
+        assert_eq!(Executed::constant(0), Executed::sum(vec![]));
+        assert_eq!(Executed::ct_false(), Executed::gt_scalar(a, u8::MAX));
+        assert_eq!(Executed::ct_true(), Executed::gt_scalar(Executed::constant(3), 2));
+    }
+
+    #[test_case("aa", 0 ; "equal")]
+    #[test_case("ba", 1 ; "not equal")]
+    fn test_ne(content: &str, exp: u64) {
+        let ct_content = encrypt_trivial(content);
+        let against_constant = Executed::ne(Executed::ct_pos(0), Executed::constant(b'a'));
+        let between = Executed::ne(Executed::ct_pos(0), Executed::ct_pos(1));
+        for key in [warmup(&KEYS.1), WarmKey::cold(KEYS.1.clone())] {
+            let mut exec = Execution::with_key(key);
+            let res = evaluate(&mut exec, &ct_content, &[against_constant.clone(), between.clone()]);
+            assert_eq!(exp, KEYS.0.decrypt(&res[0]));
+            assert_eq!(exp, KEYS.0.decrypt(&res[1]));
+            assert_eq!(2, exec.ct_operations_count());
+        }
+    }
+
+    #[test_case(0, 1, 1)]
+    #[test_case(1, 1, 0)]
+    fn test_xor(a: u8, b: u8, exp: u64) {
+        let mut exec = Execution::with_key(warmup(&KEYS.1));
+        let ct_content: Vec<_> = [a, b].iter().map(|v| exec.key().constant(*v)).collect();
+        let plan = Executed::xor(Executed::ct_pos(0), Executed::ct_pos(1));
+        assert_eq!(exp, KEYS.0.decrypt(&evaluate(&mut exec, &ct_content, &[plan])[0]));
+        assert_eq!(
+            Executed::not(Executed::ct_pos(0)),
+            Executed::xor(Executed::ct_true(), Executed::ct_pos(0))
+        );
+    }
+
+    #[test]
+    fn test_trivial_value() {
+        let key = warmup(&KEYS.1);
+        for c in [0u8, 1, b'a', 127] {
+            assert_eq!(Some(c as u64), trivial_value(&key.constant(c)));
+        }
+        assert_eq!(None, trivial_value(&KEYS.0.encrypt(b'a' as u64)));
+    }
+
+    #[test]
+    fn test_any_balanced() {
+        let xs: Vec<Executed> = (0..4).map(Executed::ct_pos).collect();
+        let exp = Executed::or(
+            Executed::or(xs[0].clone(), xs[1].clone()),
+            Executed::or(xs[2].clone(), xs[3].clone()),
+        );
+        assert_eq!(exp, Executed::any(xs.clone()));
+        assert_eq!(xs[0], Executed::any(vec![xs[0].clone()]));
+        assert_eq!(Executed::ct_false(), Executed::any(vec![]));
+    }
+
+    #[test]
+    #[cfg(any(debug_assertions, feature = "check-invariants"))]
+    #[should_panic(expected = "invariant violated")]
+    fn test_invariant_violation_panics() {
+        let key = warmup(&KEYS.1);
+        let exec = Execution::with_key(key.clone());
+        let content = vec![key.constant(b'a')];
+        let node = Executed::eq(Executed::ct_pos(0), Executed::constant(b'a'));
+        exec.check_invariant(&content, &node, &key.constant(0));
+    }
+}
@@ -0,0 +1,222 @@
+use anyhow::{anyhow, Result};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::Path;
+use tfhe::integer::{RadixCiphertext, RadixClientKey};
+
+// A traced execution as the client sees it: the operations of an op trace
+// (see trace::OpTrace) along with the results the server captured for them.
+// With the client key and the plaintext content, every captured result can be
+// decrypted and compared against what the plaintext reference computes for
+// the same operation, to find where a wrong verdict came from.
+pub struct TracedRun {
+    nodes: BTreeMap<usize, TracedNode>,
+    results: BTreeMap<usize, RadixCiphertext>,
+}
+
+// an operation of an op trace, as written by trace::OpTrace
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+pub struct TracedNode {
+    pub id: usize,
+    pub kind: String,
+    pub inputs: Vec<usize>,
+    #[serde(default)]
+    pub at: Option<usize>,
+    #[serde(default)]
+    pub c: Option<u8>,
+    #[serde(default)]
+    pub class: Option<Vec<u8>>,
+}
+
+// an operation whose decrypted result differs from the plaintext reference.
+// It is where the divergence started if all its inputs agree with the
+// reference.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Divergence {
+    pub id: usize,
+    pub kind: String,
+    pub decrypted: u64,
+    pub expected: u64,
+    pub inputs_agree: bool,
+}
+
+impl TracedRun {
+    // an op trace and the results captured along with it (see
+    // trace::OpTrace::save_results)
+    pub fn load<P: AsRef<Path>, Q: AsRef<Path>>(trace: P, results: Q) -> Result<Self> {
+        let trace = std::fs::read_to_string(trace.as_ref())
+            .map_err(|e| anyhow!("failed to read op trace {:?}: {}", trace.as_ref(), e))?;
+        let results = std::fs::read(results.as_ref())
+            .map_err(|e| anyhow!("failed to read op results {:?}: {}", results.as_ref(), e))?;
+        let results = bincode::deserialize(&results)
+            .map_err(|e| anyhow!("failed to deserialize op results: {}", e))?;
+        Self::new(&trace, results)
+    }
+
+    pub fn new(trace: &str, results: BTreeMap<usize, RadixCiphertext>) -> Result<Self> {
+        let mut nodes = BTreeMap::new();
+        for (i, line) in trace.lines().enumerate() {
+            let node: TracedNode = serde_json::from_str(line)
+                .map_err(|e| anyhow!("op trace line {}: {}", i + 1, e))?;
+            // cache hits repeat the line of the operation
+            nodes.entry(node.id).or_insert(node);
+        }
+        Ok(Self { nodes, results })
+    }
+
+    pub fn node(&self, id: usize) -> Option<&TracedNode> {
+        self.nodes.get(&id)
+    }
+
+    pub fn decrypt(&self, client_key: &RadixClientKey, id: usize) -> Result<u64> {
+        let ct = self
+            .results
+            .get(&id)
+            .ok_or_else(|| anyhow!("no result was captured for operation {}", id))?;
+        Ok(client_key.decrypt(ct))
+    }
+
+    // the result of operation id on content (padding included) as the
+    // plaintext reference computes it
+    pub fn expected(&self, id: usize, content: &[u8]) -> Result<u64> {
+        let mut memo = BTreeMap::new();
+        self.reference(id, content, &mut memo)
+    }
+
+    // the operations whose decrypted result differs from the reference, in
+    // the order they were first traced
+    pub fn explain(&self, client_key: &RadixClientKey, content: &[u8]) -> Result<Vec<Divergence>> {
+        let mut memo = BTreeMap::new();
+        let mut diverged = BTreeMap::new();
+        let mut res = vec![];
+        for id in self.results.keys() {
+            let node = self.node(*id).ok_or_else(|| anyhow!("operation {} is not traced", id))?;
+            let decrypted = self.decrypt(client_key, *id)?;
+            let expected = self.reference(*id, content, &mut memo)?;
+            diverged.insert(*id, decrypted != expected);
+            if decrypted != expected {
+                let inputs_agree = node
+                    .inputs
+                    .iter()
+                    .all(|input| !diverged.get(input).copied().unwrap_or(false));
+                res.push(Divergence {
+                    id: *id,
+                    kind: node.kind.clone(),
+                    decrypted,
+                    expected,
+                    inputs_agree,
+                });
+            }
+        }
+        Ok(res)
+    }
+
+    fn reference(&self, id: usize, content: &[u8], memo: &mut BTreeMap<usize, u64>) -> Result<u64> {
+        if let Some(v) = memo.get(&id) {
+            return Ok(*v);
+        }
+        let node = self.node(id).ok_or_else(|| anyhow!("operation {} is not traced", id))?;
+        let inputs = node
+            .inputs
+            .iter()
+            .map(|input| self.reference(*input, content, memo))
+            .collect::<Result<Vec<u64>>>()?;
+        let arity = |n: usize| {
+            if inputs.len() != n {
+                return Err(anyhow!("operation {} ({}) has {} inputs", id, node.kind, inputs.len()));
+            }
+            Ok(())
+        };
+        let v = match node.kind.as_str() {
+            "ct_pos" => {
+                let at = node.at.ok_or_else(|| anyhow!("operation {} has no position", id))?;
+                *content
+                    .get(at)
+                    .ok_or_else(|| anyhow!("position {} is past the end of the content", at))?
+                    as u64
+            }
+            "constant" => node.c.ok_or_else(|| anyhow!("operation {} has no constant", id))? as u64,
+            "and" => arity(2).map(|_| inputs[0] & inputs[1])?,
+            "or" => arity(2).map(|_| inputs[0] | inputs[1])?,
+            "eq" => arity(2).map(|_| (inputs[0] == inputs[1]) as u64)?,
+            "ne" => arity(2).map(|_| (inputs[0] != inputs[1]) as u64)?,
+            "xor" => arity(2).map(|_| inputs[0] ^ inputs[1])?,
+            "ge" => arity(2).map(|_| (inputs[0] >= inputs[1]) as u64)?,
+            "le" => arity(2).map(|_| (inputs[0] <= inputs[1]) as u64)?,
+            "gt" => arity(2).map(|_| (inputs[0] > inputs[1]) as u64)?,
+            "add" => arity(2).map(|_| (inputs[0] + inputs[1]) & 0xff)?,
+            "not" => arity(1).map(|_| inputs[0] ^ 1)?,
+            "select" => arity(3).map(|_| if inputs[0] != 0 { inputs[1] } else { inputs[2] })?,
+            "in_class" => {
+                arity(1)?;
+                let class = node
+                    .class
+                    .as_ref()
+                    .ok_or_else(|| anyhow!("operation {} has no class", id))?;
+                class.contains(&(inputs[0] as u8)) as u64
+            }
+            kind => return Err(anyhow!("operation {} is of unknown kind {:?}", id, kind)),
+        };
+        memo.insert(id, v);
+        Ok(v)
+    }
+}
+
+#[cfg(all(test, feature = "server"))]
+mod tests {
+    use crate::regex::engine::tests::{encrypt_trivial, KEYS};
+    use crate::regex::engine::{has_match_traced, MatchOptions};
+    use crate::regex::explain::TracedRun;
+    use crate::regex::trace::OpTrace;
+    use crate::regex::warmup::warmup;
+    use std::io::Write;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn traced_run(content: &str, pattern: &str) -> TracedRun {
+        let written = Shared::default();
+        let trace = OpTrace::to_writer(written.clone());
+        trace.capture_results();
+        let ct_content = encrypt_trivial(content);
+        has_match_traced(&warmup(&KEYS.1), &ct_content, pattern, &MatchOptions::default(), &trace)
+            .unwrap();
+        let written = String::from_utf8(written.0.lock().unwrap().clone()).unwrap();
+        TracedRun::new(&written, trace.results()).unwrap()
+    }
+
+    #[test]
+    fn test_no_divergence() {
+        let run = traced_run("abc", "/b[a-c]/");
+        assert!(run.explain(&KEYS.0, b"abc").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_divergence_pinpointed() {
+        // explaining against other content than was encrypted makes the
+        // comparisons of the differing position diverge first
+        let run = traced_run("abc", "/bc/");
+        let divergences = run.explain(&KEYS.0, b"abd").unwrap();
+
+        assert!(!divergences.is_empty());
+        let first = &divergences[0];
+        assert!(first.inputs_agree);
+        assert_eq!("eq", first.kind);
+        let inputs = &run.node(first.id).unwrap().inputs;
+        assert!(inputs.iter().any(|input| run.node(*input).unwrap().at == Some(2)));
+        assert!(divergences[1..].iter().all(|d| !d.inputs_agree));
+        assert_eq!(first.decrypted, run.decrypt(&KEYS.0, first.id).unwrap());
+        assert_eq!(first.expected, run.expected(first.id, b"abd").unwrap());
+    }
+}
@@ -0,0 +1,180 @@
+use crate::regex::parser::RegExpr;
+
+// Factors the prefixes and suffixes that alternatives share out of the
+// alternations of re, e.g. abc|abd into ab(c|d) and cat|bat into (c|b)at.
+// The branch engine follows every alternative separately, so without this
+// the work of a shared part is repeated per alternative, which adds up for
+// lists of similar keywords. Only whether re matches is computed, not which
+// alternative did, so alternatives are grouped regardless of where they
+// occur in the alternation, and alternatives that occur twice are kept once.
+pub(crate) fn factor(re: &RegExpr) -> RegExpr {
+    match re {
+        RegExpr::Either { alts } => factor_alts(alts.iter().map(factor).collect()),
+        RegExpr::Seq { re_xs } => RegExpr::Seq {
+            re_xs: re_xs.iter().map(factor).collect(),
+        },
+        RegExpr::Optional { opt_re } => RegExpr::Optional {
+            opt_re: Box::new(factor(opt_re)),
+        },
+        RegExpr::Repeated {
+            repeat_re,
+            at_least,
+            at_most,
+        } => RegExpr::Repeated {
+            repeat_re: Box::new(factor(repeat_re)),
+            at_least: *at_least,
+            at_most: *at_most,
+        },
+        _ => re.clone(),
+    }
+}
+
+#[derive(Clone, Copy)]
+enum End {
+    Prefix,
+    Suffix,
+}
+
+fn factor_alts(alts: Vec<RegExpr>) -> RegExpr {
+    let mut unique: Vec<RegExpr> = vec![];
+    for re in alts {
+        if !unique.contains(&re) {
+            unique.push(re);
+        }
+    }
+    let alts = factor_end(unique, End::Prefix);
+    let alts = factor_end(alts, End::Suffix);
+    RegExpr::either(alts)
+}
+
+// groups alts by the element they start (or end) with, and factors the
+// longest common prefix (or suffix) out of each group, leaving at least one
+// element of each alternative behind
+fn factor_end(alts: Vec<RegExpr>, end: End) -> Vec<RegExpr> {
+    let mut groups: Vec<Vec<Vec<RegExpr>>> = vec![];
+    for re in alts {
+        let re_xs = elements(re);
+        let group = groups.iter_mut().find(|group| {
+            outer(&group[0], end).is_some() && outer(&group[0], end) == outer(&re_xs, end)
+        });
+        match group {
+            Some(group) => group.push(re_xs),
+            None => groups.push(vec![re_xs]),
+        }
+    }
+
+    groups
+        .into_iter()
+        .map(|mut group| {
+            if group.len() == 1 {
+                return seq(group.pop().unwrap());
+            }
+            let min_len = group.iter().map(|re_xs| re_xs.len()).min().unwrap();
+            let first = &group[0];
+            let shared = (0..min_len - 1)
+                .take_while(|i| match end {
+                    End::Prefix => group.iter().all(|re_xs| re_xs[*i] == first[*i]),
+                    End::Suffix => group
+                        .iter()
+                        .all(|re_xs| re_xs[re_xs.len() - 1 - i] == first[first.len() - 1 - i]),
+                })
+                .count();
+            if shared == 0 {
+                // only a common first (or last) element, which can't be
+                // factored out without leaving an alternative empty
+                return RegExpr::either(group.into_iter().map(seq).collect());
+            }
+
+            let (common, rests): (Vec<RegExpr>, Vec<RegExpr>) = match end {
+                End::Prefix => (
+                    group[0][..shared].to_vec(),
+                    group.into_iter().map(|re_xs| seq(re_xs[shared..].to_vec())).collect(),
+                ),
+                End::Suffix => (
+                    group[0][group[0].len() - shared..].to_vec(),
+                    group
+                        .into_iter()
+                        .map(|re_xs| seq(re_xs[..re_xs.len() - shared].to_vec()))
+                        .collect(),
+                ),
+            };
+            let rest = elements(factor_alts(rests));
+            seq(match end {
+                End::Prefix => [common, rest].concat(),
+                End::Suffix => [rest, common].concat(),
+            })
+        })
+        .flat_map(|re| match re {
+            RegExpr::Either { alts } => alts,
+            re => vec![re],
+        })
+        .collect()
+}
+
+fn outer(re_xs: &[RegExpr], end: End) -> Option<&RegExpr> {
+    match end {
+        End::Prefix => re_xs.first(),
+        End::Suffix => re_xs.last(),
+    }
+}
+
+// the elements of re as a sequence, nested sequences spliced in
+fn elements(re: RegExpr) -> Vec<RegExpr> {
+    match re {
+        RegExpr::Seq { re_xs } => re_xs.into_iter().flat_map(elements).collect(),
+        re => vec![re],
+    }
+}
+
+fn seq(mut re_xs: Vec<RegExpr>) -> RegExpr {
+    if re_xs.len() == 1 {
+        return re_xs.pop().unwrap();
+    }
+    RegExpr::Seq { re_xs }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::regex::engine::build_plan;
+    use crate::regex::factor::factor;
+    use crate::regex::parser::{parse, parse_with, AnchorPrecedence, ParseLimits, RegExpr};
+    use crate::regex::scheduler::Schedule;
+    use test_case::test_case;
+
+    #[test_case("/abc|abd/", "/ab(c|d)/" ; "common prefix")]
+    #[test_case("/cat|bat|rat/", "/(c|b|r)at/" ; "common suffix")]
+    #[test_case("/abxc|abyc/", "/ab(x|y)c/" ; "common prefix and suffix")]
+    #[test_case("/abc|xyz|abd/", "/ab(c|d)|xyz/" ; "grouped regardless of order")]
+    #[test_case("/abc|abd|aef/", "/a(b(c|d)|ef)/" ; "nested prefixes")]
+    #[test_case("/ab|abc/", "/a(b|bc)/" ; "alternative left with one element")]
+    #[test_case("/a|b|a/", "/a|b/" ; "duplicates kept once")]
+    #[test_case("/x(abc|abd)+/", "/x(ab(c|d))+/" ; "within a repetition")]
+    #[test_case("/ab|cd/", "/ab|cd/" ; "nothing in common")]
+    #[test_case("/a|ab/", "/a|ab/" ; "prefix is a whole alternative")]
+    fn test_factor(pattern: &str, exp: &str) {
+        assert_eq!(parse(exp).unwrap(), factor(&parse(pattern).unwrap()));
+    }
+
+    #[test]
+    fn test_factor_anchors() {
+        let re = parse_with("/^ab|^ac/", AnchorPrecedence::Standard, ParseLimits::default()).unwrap();
+        let exp = parse_with("/^a(b|c)/", AnchorPrecedence::Standard, ParseLimits::default()).unwrap();
+        assert_eq!(exp, factor(&re));
+    }
+
+    // the suffix is and-ed once with the or of the alternatives, rather than
+    // once per alternative
+    #[test_case("/a+cat|b+cat/", 6)]
+    #[test_case("/x[0-9]+yz|x[a-f]+yz/", 8)]
+    fn test_factor_saves_operations(pattern: &str, content_len: usize) {
+        let re = parse(pattern).unwrap();
+        let ops = |re: &RegExpr| Schedule::new(&build_plan(re, content_len)).stats().operations;
+        let (factored, unfactored) = (ops(&factor(&re)), ops(&re));
+        assert!(
+            factored < unfactored,
+            "{} operations factored, {} unfactored",
+            factored,
+            unfactored
+        );
+    }
+}
@@ -0,0 +1,73 @@
+use anyhow::{anyhow, Result};
+use tfhe::integer::{RadixCiphertext, ServerKey};
+
+use crate::regex::ciphertext::{validate_layout, DEFAULT_MAX_CONTENT_LEN};
+use crate::regex::engine::evaluate;
+use crate::regex::execution::{Executed, Execution};
+use crate::regex::verdict::{fhe_count, MAX_COUNT};
+
+// Encrypted character counts, for analytics on content that is never
+// decrypted. The per position comparisons are the ones the engine matches
+// characters with, batched per character (and warmed up, see warmup.rs).
+
+// the number of occurrences of each of chars in content, in the order of
+// chars. Counts are held in a single radix, so content of more than
+// verdict::MAX_COUNT characters is rejected rather than wrapping around.
+pub fn fhe_char_histogram(
+    sk: &ServerKey,
+    content: &[RadixCiphertext],
+    chars: &[u8],
+) -> Result<Vec<RadixCiphertext>> {
+    validate_layout(content, DEFAULT_MAX_CONTENT_LEN)?;
+    if content.len() > MAX_COUNT {
+        return Err(anyhow!(
+            "cannot count characters in content of length {}, at most {} fit in a radix",
+            content.len(),
+            MAX_COUNT
+        ));
+    }
+    if content.is_empty() {
+        return chars.iter().map(|_| fhe_count(sk, &[])).collect();
+    }
+    let plans: Vec<Executed> = chars
+        .iter()
+        .flat_map(|c| {
+            (0..content.len())
+                .map(move |i| Executed::eq(Executed::ct_pos(i), Executed::constant(*c)))
+        })
+        .collect();
+
+    let mut exec = Execution::new(sk.clone());
+    evaluate(&mut exec, content, &plans)
+        .chunks(content.len())
+        .map(|per_position| fhe_count(sk, per_position))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::regex::engine::tests::{encrypt_trivial, KEYS};
+    use crate::regex::histogram::fhe_char_histogram;
+    use crate::regex::verdict::MAX_COUNT;
+    use test_case::test_case;
+
+    #[test_case("abca", b"ac", &[2, 1])]
+    #[test_case("abca", b"x", &[0] ; "absent char")]
+    #[test_case("", b"ab", &[0, 0] ; "empty content")]
+    #[test_case("abc", b"", &[] ; "no chars")]
+    fn test_char_histogram(content: &str, chars: &[u8], exp: &[u64]) {
+        let ct_content = encrypt_trivial(content);
+        let counts: Vec<u64> = fhe_char_histogram(&KEYS.1, &ct_content, chars)
+            .unwrap()
+            .iter()
+            .map(|ct| KEYS.0.decrypt(ct))
+            .collect();
+        assert_eq!(exp, counts.as_slice());
+    }
+
+    #[test]
+    fn test_long_content_rejected() {
+        let ct_content = encrypt_trivial(&"a".repeat(MAX_COUNT + 1));
+        assert!(fhe_char_histogram(&KEYS.1, &ct_content, b"a").is_err());
+    }
+}
@@ -0,0 +1,135 @@
+use tfhe::integer::RadixCiphertext;
+use tfhe::shortint::server_key::Accumulator;
+use tfhe::shortint::Ciphertext;
+
+use crate::regex::ciphertext::{class_flag_with, flag_to_radix, BLOCK_SIZE};
+use crate::regex::class::CharClass;
+use crate::regex::execution::EqAccumulators;
+use crate::regex::warmup::WarmKey;
+
+// The per-character primitives the regex engine is built from, for building
+// other algorithms on encrypted text (e.g. tokenizers, suffix matching).
+// They know nothing about patterns.
+//
+// A character is a radix ciphertext as encrypt_str produces them (see
+// ciphertext::validate_layout), of an ascii character. A flag is a radix
+// ciphertext of 0 or 1, in the same layout, as the primitives result in;
+// verdict.rs combines flags (fhe_and, fhe_or, fhe_not, fhe_count, ..). The
+// primitives take a WarmKey: their lookup tables are taken from it where it
+// was warmed up (see warmup), and generated per call otherwise. Every
+// primitive is a fixed number of bootstraps, whatever the character.
+
+// whether ct is c: one bootstrap per block, and one to combine them
+pub fn fhe_eq_char(key: &WarmKey, ct: &RadixCiphertext, c: u8) -> RadixCiphertext {
+    match key.eq_accumulators(c) {
+        Some(accs) => accs.eq(key.shortkey(), ct),
+        None => EqAccumulators::new(key.shortkey(), c).eq(key.shortkey(), ct),
+    }
+}
+
+// whether ct is not c, in as many bootstraps as fhe_eq_char
+pub fn fhe_ne_char(key: &WarmKey, ct: &RadixCiphertext, c: u8) -> RadixCiphertext {
+    match key.eq_accumulators(c) {
+        Some(accs) => accs.ne(key.shortkey(), ct),
+        None => EqAccumulators::new(key.shortkey(), c).ne(key.shortkey(), ct),
+    }
+}
+
+// whether ct is within from..=to
+pub fn fhe_in_range(key: &WarmKey, ct: &RadixCiphertext, from: u8, to: u8) -> RadixCiphertext {
+    in_class(key, ct, &CharClass::from_range(from, to))
+}
+
+// whether ct is any of chars, in as many bootstraps as chars have distinct
+// high nibbles (see ciphertext::class_flag), rather than one equality test
+// per character
+pub fn fhe_in_set(key: &WarmKey, ct: &RadixCiphertext, chars: &[u8]) -> RadixCiphertext {
+    in_class(key, ct, &CharClass::from_chars(chars))
+}
+
+pub(crate) fn in_class(key: &WarmKey, ct: &RadixCiphertext, class: &CharClass) -> RadixCiphertext {
+    let flag = class_flag_with(key.shortkey(), ct, class, key.class_accumulators());
+    flag_to_radix(key.shortkey(), flag)
+}
+
+// if_true where flag is set and if_false elsewhere, without learning which:
+// per block, the flag is combined with either block into one value, looked
+// up to the block or 0, and the two are added (only one of them is non
+// zero). Two bootstraps per block.
+pub fn fhe_select(
+    key: &WarmKey,
+    flag: &RadixCiphertext,
+    if_true: &RadixCiphertext,
+    if_false: &RadixCiphertext,
+) -> RadixCiphertext {
+    let shortkey = key.shortkey();
+    let block_mask = (1 << BLOCK_SIZE) - 1;
+    let keep_if = |set: u64| {
+        shortkey.generate_accumulator(move |x| if x >> BLOCK_SIZE == set { x & block_mask } else { 0 })
+    };
+    let (keep_if_set, keep_if_unset) = (keep_if(1), keep_if(0));
+    let flag = &flag.blocks()[0];
+    let kept = |block: &Ciphertext, acc: &Accumulator| {
+        let mut combined = shortkey.unchecked_scalar_mul(flag, 1 << BLOCK_SIZE);
+        shortkey.unchecked_add_assign(&mut combined, block);
+        shortkey.keyswitch_programmable_bootstrap(&combined, acc)
+    };
+    let blocks: Vec<Ciphertext> = if_true
+        .blocks()
+        .iter()
+        .zip(if_false.blocks())
+        .map(|(block_true, block_false)| {
+            let mut res = kept(block_true, &keep_if_set);
+            shortkey.unchecked_add_assign(&mut res, &kept(block_false, &keep_if_unset));
+            res
+        })
+        .collect();
+    RadixCiphertext::from(blocks)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::regex::engine::tests::{encrypt_trivial, KEYS};
+    use crate::regex::kernel::{fhe_eq_char, fhe_in_range, fhe_in_set, fhe_ne_char, fhe_select};
+    use crate::regex::warmup::{warmup, WarmKey};
+    use test_case::test_case;
+
+    #[test_case('a', 'a', 1)]
+    #[test_case('a', 'b', 0)]
+    #[test_case('a', 'A', 0)]
+    fn test_eq_char(content: char, c: char, exp: u64) {
+        let ct = &encrypt_trivial(&content.to_string())[0];
+        for key in [warmup(&KEYS.1), WarmKey::cold(KEYS.1.clone())] {
+            assert_eq!(exp, KEYS.0.decrypt(&fhe_eq_char(&key, ct, c as u8)));
+            assert_eq!(1 - exp, KEYS.0.decrypt(&fhe_ne_char(&key, ct, c as u8)));
+        }
+    }
+
+    #[test_case('5', 1)]
+    #[test_case('0', 1)]
+    #[test_case('a', 0)]
+    fn test_in_range(content: char, exp: u64) {
+        let ct = &encrypt_trivial(&content.to_string())[0];
+        let ct_res = fhe_in_range(&warmup(&KEYS.1), ct, b'0', b'9');
+        assert_eq!(exp, KEYS.0.decrypt(&ct_res));
+    }
+
+    #[test_case(',', 1)]
+    #[test_case('!', 1)]
+    #[test_case('x', 0)]
+    fn test_in_set(content: char, exp: u64) {
+        let ct = &encrypt_trivial(&content.to_string())[0];
+        let ct_res = fhe_in_set(&warmup(&KEYS.1), ct, b",.;!?");
+        assert_eq!(exp, KEYS.0.decrypt(&ct_res));
+    }
+
+    #[test_case(1, b'x')]
+    #[test_case(0, b'y')]
+    fn test_select(flag: u64, exp: u8) {
+        let key = warmup(&KEYS.1);
+        let ct_flag = KEYS.0.encrypt(flag);
+        let ct_chars = encrypt_trivial("xy");
+        let ct_res = fhe_select(&key, &ct_flag, &ct_chars[0], &ct_chars[1]);
+        assert_eq!(exp as u64, KEYS.0.decrypt(&ct_res));
+    }
+}
@@ -0,0 +1,208 @@
+use anyhow::Result;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+use crate::regex::engine::{plan_for, Engine, MatchOptions};
+use crate::regex::execution::Executed;
+use crate::regex::scheduler::Schedule;
+
+// What the server learns from matching a pattern on content of some length
+// with one engine. The server holds the pattern and the options in
+// plaintext, sees how many characters the content has, and evaluates the
+// operations of a plan built from those alone: which operations it runs, on
+// which content positions and against which characters, is public. Computed
+// from the plan itself, so that it is what the engine does rather than what
+// it is believed to do.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LeakageReport {
+    pub engine: Engine,
+    // whether the auto engine picks this engine for the pattern
+    pub auto: bool,
+    pub pattern: String,
+    pub content_len: usize,
+    // the characters the content is compared against
+    pub constants: BTreeSet<u8>,
+    // the content positions any operation reads
+    pub positions_read: BTreeSet<usize>,
+    // the operations of the plan by kind, and the levels they are evaluated
+    // in
+    pub operations: BTreeMap<&'static str, usize>,
+    pub levels: usize,
+    // the result, when the plan is a constant: e.g. content shorter than any
+    // match can't match, which the server knows without an operation
+    pub known_result: Option<bool>,
+}
+
+// the report of every engine the pattern can be matched with, with opts
+// otherwise as given
+pub fn leakage_reports(
+    pattern: &str,
+    content_len: usize,
+    opts: &MatchOptions,
+) -> Result<Vec<LeakageReport>> {
+    let (auto_engine, _) = plan_for(pattern, content_len, &with_engine(opts, Engine::Auto))?;
+    let mut reports = vec![];
+    for engine in [Engine::Branches, Engine::Literal] {
+        // the literal engine only takes plain strings
+        let plan = match plan_for(pattern, content_len, &with_engine(opts, engine)) {
+            Ok((_, plan)) => plan,
+            Err(e) if engine == Engine::Literal => {
+                debug!("no literal engine report: {}", e);
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+        reports.push(report(pattern, content_len, engine, engine == auto_engine, &plan));
+    }
+    Ok(reports)
+}
+
+fn with_engine(opts: &MatchOptions, engine: Engine) -> MatchOptions {
+    MatchOptions {
+        engine,
+        ..opts.clone()
+    }
+}
+
+fn report(pattern: &str, content_len: usize, engine: Engine, auto: bool, plan: &Executed) -> LeakageReport {
+    let schedule = Schedule::new(plan);
+    let mut constants = BTreeSet::new();
+    let mut positions_read = BTreeSet::new();
+    let mut operations = BTreeMap::new();
+    for node in schedule.levels().iter().flatten() {
+        *operations.entry(node.kind()).or_default() += 1;
+        if let Executed::InClass { class, .. } = node {
+            constants.extend(class.chars());
+        }
+        for operand in node.operands() {
+            match operand {
+                // constants are also the trivial booleans of or and and,
+                // only those of comparisons are characters
+                Executed::Constant { c } if node.is_comparison() => {
+                    constants.insert(*c);
+                }
+                Executed::CtPos { at } => {
+                    positions_read.insert(*at);
+                }
+                _ => (),
+            }
+        }
+    }
+    let known_result = match plan {
+        plan if *plan == Executed::ct_true() => Some(true),
+        plan if *plan == Executed::ct_false() => Some(false),
+        _ => None,
+    };
+    LeakageReport {
+        engine,
+        auto,
+        pattern: pattern.to_string(),
+        content_len,
+        constants,
+        positions_read,
+        operations,
+        levels: schedule.stats().levels,
+        known_result,
+    }
+}
+
+impl fmt::Display for LeakageReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let auto = if self.auto { " (picked by auto)" } else { "" };
+        writeln!(f, "engine {}{}:", self.engine, auto)?;
+        writeln!(
+            f,
+            "  learned in plaintext: the pattern {}, the match options, and the content length ({})",
+            self.pattern, self.content_len
+        )?;
+        let constants: Vec<String> =
+            self.constants.iter().map(|c| format!("{:?}", *c as char)).collect();
+        writeln!(f, "  characters compared against: {}", list(&constants))?;
+        writeln!(
+            f,
+            "  content positions read: {} of {}",
+            self.positions_read.len(),
+            self.content_len
+        )?;
+        let kinds: Vec<String> =
+            self.operations.iter().map(|(kind, n)| format!("{} {}", kind, n)).collect();
+        writeln!(
+            f,
+            "  operations: {} in {} levels ({})",
+            self.operations.values().sum::<usize>(),
+            self.levels,
+            list(&kinds)
+        )?;
+        match self.known_result {
+            Some(matched) => writeln!(
+                f,
+                "  result: known up front, {}",
+                if matched { "matched" } else { "no match" }
+            )?,
+            None => writeln!(f, "  result: encrypted, not learned")?,
+        }
+        write!(
+            f,
+            "  not learned: the content, and which branch matched. The operations, and so \
+             their timing, are the same for all content of this length"
+        )
+    }
+}
+
+fn list(items: &[String]) -> String {
+    if items.is_empty() {
+        return "none".to_string();
+    }
+    items.join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::regex::engine::{Engine, MatchOptions};
+    use crate::regex::leakage::leakage_reports;
+
+    #[test]
+    fn test_literal_pattern() {
+        let reports = leakage_reports("/ab/", 3, &MatchOptions::default()).unwrap();
+        let engines: Vec<(Engine, bool)> = reports.iter().map(|r| (r.engine, r.auto)).collect();
+        assert_eq!(vec![(Engine::Branches, false), (Engine::Literal, true)], engines);
+        for report in reports {
+            assert_eq!(vec![b'a', b'b'], report.constants.into_iter().collect::<Vec<_>>());
+            assert_eq!(vec![0, 1, 2], report.positions_read.into_iter().collect::<Vec<_>>());
+            assert_eq!(None, report.known_result);
+        }
+    }
+
+    #[test]
+    fn test_pattern_only_branches() {
+        let reports = leakage_reports("/a?b/", 3, &MatchOptions::default()).unwrap();
+        assert_eq!(1, reports.len());
+        assert_eq!(Engine::Branches, reports[0].engine);
+        assert!(reports[0].auto);
+    }
+
+    #[test]
+    fn test_options_change_the_plan() {
+        let opts = MatchOptions {
+            case_insensitive: true,
+            ..MatchOptions::default()
+        };
+        let reports = leakage_reports("/ab/", 3, &opts).unwrap();
+        assert!(reports[0].constants.contains(&b'A'));
+    }
+
+    #[test]
+    fn test_short_content() {
+        let reports = leakage_reports("/abc/", 2, &MatchOptions::default()).unwrap();
+        for report in reports {
+            assert_eq!(Some(false), report.known_result);
+            assert!(report.operations.is_empty());
+            assert!(report.positions_read.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_invalid_pattern() {
+        assert!(leakage_reports("/(a/", 3, &MatchOptions::default()).is_err());
+    }
+}
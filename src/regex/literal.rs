@@ -0,0 +1,307 @@
+use std::collections::BTreeSet;
+use tfhe::integer::{RadixCiphertext, ServerKey};
+
+use crate::regex::engine::evaluate;
+use crate::regex::execution::{Executed, Execution};
+use crate::regex::parser::RegExpr;
+
+// Tests for a literal string, which do not go through the pattern parser (so
+// the literal needs no escaping).
+
+pub fn fhe_starts_with(sk: &ServerKey, content: &[RadixCiphertext], literal: &str) -> RadixCiphertext {
+    match_literal(sk, content, literal, true, false)
+}
+
+pub fn fhe_ends_with(sk: &ServerKey, content: &[RadixCiphertext], literal: &str) -> RadixCiphertext {
+    match_literal(sk, content, literal, false, true)
+}
+
+pub fn fhe_contains(sk: &ServerKey, content: &[RadixCiphertext], literal: &str) -> RadixCiphertext {
+    match_literal(sk, content, literal, false, false)
+}
+
+fn match_literal(
+    sk: &ServerKey,
+    content: &[RadixCiphertext],
+    literal: &str,
+    sof: bool,
+    eof: bool,
+) -> RadixCiphertext {
+    let mut exec = Execution::new(sk.clone());
+    if literal.is_empty() {
+        return exec.eval(content, &Executed::ct_true());
+    }
+
+    let literal = Literal {
+        bytes: literal.as_bytes().to_vec(),
+        sof,
+        eof,
+    };
+    let plan = literal.plan(content.len());
+    evaluate(&mut exec, content, &[plan]).remove(0)
+}
+
+// A pattern that is a plain string, optionally anchored at the start and/or
+// the end. These are matched by the literal engine: one equality comparison
+// per literal character and candidate position, rather than going through
+// the general branch construction. Equality comparisons against the same
+// character are batched, and their lookup tables precomputed by warmup.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Literal {
+    pub(crate) bytes: Vec<u8>,
+    pub(crate) sof: bool,
+    pub(crate) eof: bool,
+}
+
+impl Literal {
+    // recognizes re as a non empty literal, case insensitive patterns and
+    // any other construct are not literals
+    pub(crate) fn from_regex(re: &RegExpr) -> Option<Self> {
+        let mut re_xs = vec![];
+        flatten(re, &mut re_xs);
+        let sof = matches!(re_xs.first(), Some(RegExpr::SOF));
+        let eof = re_xs.len() > sof as usize && matches!(re_xs.last(), Some(RegExpr::EOF));
+        let inner = &re_xs[sof as usize..re_xs.len() - eof as usize];
+
+        let bytes = inner
+            .iter()
+            .map(|re_x| match re_x {
+                RegExpr::Char { c } => Some(*c),
+                _ => None,
+            })
+            .collect::<Option<Vec<u8>>>()?;
+        if bytes.is_empty() {
+            return None;
+        }
+        Some(Self { bytes, sof, eof })
+    }
+
+    pub(crate) fn plan(&self, content_len: usize) -> Executed {
+        if self.bytes.len() > content_len {
+            return Executed::ct_false();
+        }
+        let last_start = content_len - self.bytes.len();
+        (0..=last_start)
+            .filter(|start| !self.sof || *start == 0)
+            .filter(|start| !self.eof || *start == last_start)
+            .map(|start| {
+                self.bytes
+                    .iter()
+                    .enumerate()
+                    .map(|(i, c)| Executed::eq(Executed::ct_pos(start + i), Executed::constant(*c)))
+                    .reduce(Executed::and)
+                    .unwrap()
+            })
+            .reduce(Executed::or)
+            .unwrap_or_else(Executed::ct_false)
+    }
+}
+
+// The literal characters every match of a pattern starts with (right after a
+// start anchor, if any), and what has to match after them. The branch engine
+// tests the prefix once per start position, as a guard shared by all
+// branches of the rest of the pattern, instead of repeating the prefix in
+// every branch.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct LiteralPrefix {
+    pub(crate) sof: bool,
+    pub(crate) bytes: Vec<u8>,
+    pub(crate) rest: Option<RegExpr>,
+}
+
+impl LiteralPrefix {
+    // None if re does not start with a literal character
+    pub(crate) fn from_regex(re: &RegExpr) -> Option<Self> {
+        let mut re_xs = vec![];
+        flatten(re, &mut re_xs);
+        let sof = matches!(re_xs.first(), Some(RegExpr::SOF));
+        let bytes: Vec<u8> = re_xs[sof as usize..]
+            .iter()
+            .map_while(|re_x| match re_x {
+                RegExpr::Char { c } => Some(*c),
+                _ => None,
+            })
+            .collect();
+        if bytes.is_empty() {
+            return None;
+        }
+        let rest: Vec<RegExpr> = re_xs[sof as usize + bytes.len()..]
+            .iter()
+            .map(|re_x| (*re_x).clone())
+            .collect();
+        Some(Self {
+            sof,
+            bytes,
+            rest: if rest.is_empty() {
+                None
+            } else {
+                Some(RegExpr::Seq { re_xs: rest })
+            },
+        })
+    }
+}
+
+// The characters every match of re contains at a fixed offset from its start
+// (e.g. the b and the x of (ab|cb)x), as (offset, character). The branch
+// engine tests these once per start position, as a guard shared by all
+// branches, rather than in every branch.
+pub(crate) fn required_chars(re: &RegExpr) -> BTreeSet<(usize, u8)> {
+    match re {
+        RegExpr::Char { c } => BTreeSet::from([(0, *c)]),
+        RegExpr::Either { alts } => alts
+            .iter()
+            .map(required_chars)
+            .reduce(|a, b| a.intersection(&b).copied().collect())
+            .unwrap_or_default(),
+        RegExpr::Repeated {
+            repeat_re,
+            at_least: Some(n),
+            at_most: Some(m),
+        } if n == m => match fixed_width(repeat_re) {
+            Some(width) => {
+                let once = required_chars(repeat_re);
+                (0..*n)
+                    .flat_map(|i| once.iter().map(move |(offset, c)| (i * width + offset, *c)))
+                    .collect()
+            }
+            None => BTreeSet::new(),
+        },
+        RegExpr::Seq { re_xs } => {
+            let mut res = BTreeSet::new();
+            let mut offset = 0;
+            for re_x in re_xs {
+                res.extend(required_chars(re_x).into_iter().map(|(o, c)| (offset + o, c)));
+                // past an element of variable width, offsets are not fixed
+                match fixed_width(re_x) {
+                    Some(width) => offset += width,
+                    None => break,
+                }
+            }
+            res
+        }
+        _ => BTreeSet::new(),
+    }
+}
+
+// re with the characters of required (see required_chars) replaced by any
+// character, for when they are tested separately
+pub(crate) fn without_required(re: &RegExpr, required: &BTreeSet<(usize, u8)>) -> RegExpr {
+    strip_required(re, required, 0)
+}
+
+// mirrors the traversal of required_chars, so that only the characters it
+// found at offset are replaced
+fn strip_required(re: &RegExpr, required: &BTreeSet<(usize, u8)>, offset: usize) -> RegExpr {
+    match re {
+        RegExpr::Char { c } if required.contains(&(offset, *c)) => RegExpr::AnyChar,
+        RegExpr::Either { alts } => RegExpr::Either {
+            alts: alts.iter().map(|re| strip_required(re, required, offset)).collect(),
+        },
+        RegExpr::Repeated {
+            repeat_re,
+            at_least: Some(n),
+            at_most: Some(m),
+        } if n == m && *n > 0 && fixed_width(repeat_re).is_some() => {
+            let width = fixed_width(repeat_re).unwrap();
+            RegExpr::Seq {
+                re_xs: (0..*n)
+                    .map(|i| strip_required(repeat_re, required, offset + i * width))
+                    .collect(),
+            }
+        }
+        RegExpr::Seq { re_xs } => {
+            let mut offset = Some(offset);
+            RegExpr::Seq {
+                re_xs: re_xs
+                    .iter()
+                    .map(|re_x| match offset {
+                        Some(at) => {
+                            offset = fixed_width(re_x).map(|width| at + width);
+                            strip_required(re_x, required, at)
+                        }
+                        None => re_x.clone(),
+                    })
+                    .collect(),
+            }
+        }
+        _ => re.clone(),
+    }
+}
+
+fn fixed_width(re: &RegExpr) -> Option<usize> {
+    let width = re.max_width()?;
+    (width == re.min_width()).then_some(width)
+}
+
+// the parser nests the anchored part of a pattern in its own sequence
+fn flatten<'a>(re: &'a RegExpr, res: &mut Vec<&'a RegExpr>) {
+    match re {
+        RegExpr::Seq { re_xs } => re_xs.iter().for_each(|re_x| flatten(re_x, res)),
+        _ => res.push(re),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::regex::engine::tests::{encrypt_trivial, KEYS};
+    use crate::regex::literal::{
+        fhe_contains, fhe_ends_with, fhe_starts_with, required_chars, without_required, Literal, LiteralPrefix,
+    };
+    use crate::regex::parser::parse;
+    use test_case::test_case;
+
+    #[test_case("abc", "ab", 1, 0, 1)]
+    #[test_case("abc", "bc", 0, 1, 1)]
+    #[test_case("abc", "abc", 1, 1, 1)]
+    #[test_case("abc", "abcd", 0, 0, 0)]
+    #[test_case("abc", "", 1, 1, 1 ; "empty literal")]
+    #[test_case("a.c", ".", 0, 0, 1 ; "no escaping needed")]
+    #[test_case("abc", ".", 0, 0, 0 ; "no special characters")]
+    fn test_literal(content: &str, literal: &str, starts: u64, ends: u64, contains: u64) {
+        let ct_content = encrypt_trivial(content);
+        assert_eq!(starts, KEYS.0.decrypt(&fhe_starts_with(&KEYS.1, &ct_content, literal)));
+        assert_eq!(ends, KEYS.0.decrypt(&fhe_ends_with(&KEYS.1, &ct_content, literal)));
+        assert_eq!(contains, KEYS.0.decrypt(&fhe_contains(&KEYS.1, &ct_content, literal)));
+    }
+
+    #[test_case("/abc/", Some((b"abc" as &[u8], false, false)))]
+    #[test_case("/^abc$/", Some((b"abc" as &[u8], true, true)))]
+    #[test_case("/a$/", Some((b"a" as &[u8], false, true)))]
+    #[test_case("/ab/i", None ; "case insensitive")]
+    #[test_case("/a.c/", None ; "any char")]
+    #[test_case("/ab|cd/", None ; "alternation")]
+    fn test_literal_from_regex(pattern: &str, exp: Option<(&[u8], bool, bool)>) {
+        let got = Literal::from_regex(&parse(pattern).unwrap());
+        assert_eq!(
+            exp.map(|(bytes, sof, eof)| Literal {
+                bytes: bytes.to_vec(),
+                sof,
+                eof,
+            }),
+            got,
+        );
+    }
+
+    #[test_case("/abc/", true, b"abc", None)]
+    #[test_case("/^ab(c|d)/", true, b"ab", Some("(c|d)") ; "anchored with rest")]
+    #[test_case("/a+/", false, b"", None ; "no literal prefix")]
+    fn test_literal_prefix(pattern: &str, exp_some: bool, exp_bytes: &[u8], exp_rest: Option<&str>) {
+        let got = LiteralPrefix::from_regex(&parse(pattern).unwrap());
+        assert_eq!(exp_some, got.is_some());
+        if let Some(prefix) = got {
+            assert_eq!(exp_bytes, prefix.bytes);
+            assert_eq!(exp_rest.map(str::to_string), prefix.rest.map(|rest| rest.to_pattern()));
+        }
+    }
+
+    #[test_case("/(ab|cb)x/", &[(1, b'b'), (2, b'x')], "(.b|.b).")]
+    #[test_case("/(ab){2}/", &[(0, b'a'), (1, b'b'), (2, b'a'), (3, b'b')], "(..)(..)")]
+    #[test_case("/xa+b/", &[(0, b'x')], ".a+b" ; "variable width ends the fixed offsets")]
+    #[test_case("/a?b/", &[], "a?b")]
+    fn test_required_chars(pattern: &str, exp: &[(usize, u8)], exp_stripped: &str) {
+        let re = parse(pattern).unwrap();
+        let required = required_chars(&re);
+        assert_eq!(exp.iter().copied().collect::<Vec<_>>(), required.iter().copied().collect::<Vec<_>>());
+        assert_eq!(exp_stripped, without_required(&re, &required).to_pattern());
+    }
+}
@@ -1,19 +1,225 @@
+pub mod alphabet;
+pub mod api;
 pub mod ciphertext;
-pub mod engine;
+pub mod client;
+pub mod explain;
 pub mod parser;
+pub mod profile;
+pub mod storage;
+pub mod test_keys;
+
+// everything below requires the server key, and is left out of client builds
+#[cfg(feature = "server")]
+pub mod batch;
+#[cfg(feature = "server")]
+pub mod cache;
+#[cfg(feature = "server")]
+pub mod class;
+#[cfg(feature = "server")]
+pub mod compare;
+#[cfg(all(feature = "server", unix))]
+pub mod config;
+#[cfg(feature = "server")]
+pub mod cost;
+#[cfg(feature = "server")]
+pub mod engine;
+#[cfg(feature = "server")]
+pub mod histogram;
+#[cfg(feature = "server")]
+pub mod observe;
+#[cfg(feature = "server")]
+pub mod plan_cache;
+#[cfg(feature = "server")]
+pub mod preprocess;
+#[cfg(feature = "server")]
+pub mod estimate;
+#[cfg(feature = "server")]
 pub mod execution;
+#[cfg(feature = "server")]
+pub mod factor;
+#[cfg(feature = "server")]
+pub mod kernel;
+#[cfg(feature = "server")]
+pub mod leakage;
+#[cfg(feature = "server")]
+pub mod literal;
+#[cfg(feature = "server")]
+pub mod policy;
+#[cfg(feature = "server")]
+pub mod provenance;
+#[cfg(feature = "server")]
+pub mod query;
+#[cfg(feature = "server")]
+pub mod rules;
+#[cfg(feature = "server")]
+pub mod scheduler;
+#[cfg(all(feature = "server", unix))]
+pub mod serve;
+#[cfg(all(feature = "server", unix))]
+pub mod shard;
+#[cfg(feature = "server")]
+pub mod trace;
+#[cfg(feature = "server")]
+pub mod verdict;
+#[cfg(feature = "server")]
+pub mod warmup;
+
+#[cfg(feature = "server")]
+use crate::regex::ciphertext::{gen_keys_with_progress, encrypt_str_within, Progress};
+#[cfg(feature = "server")]
+use crate::regex::engine::{has_match_plan_cached, has_match_traced, has_match_with, MatchOptions};
+#[cfg(feature = "server")]
+use crate::regex::explain::TracedRun;
+#[cfg(feature = "server")]
+use crate::regex::plan_cache::PlanCache;
+#[cfg(feature = "server")]
+use crate::regex::preprocess::{preprocess, Preprocess};
+#[cfg(feature = "server")]
+use crate::regex::provenance::Provenance;
+#[cfg(feature = "server")]
+use crate::regex::profile::Profile;
+#[cfg(feature = "server")]
+use crate::regex::rules::RuleSet;
+#[cfg(feature = "server")]
+use crate::regex::trace::OpTrace;
+#[cfg(feature = "server")]
+use crate::regex::warmup::warmup_with_progress;
+#[cfg(feature = "server")]
+use std::path::Path;
+#[cfg(feature = "server")]
+use tfhe::integer::RadixClientKey;
 
-use crate::regex::ciphertext::{gen_keys, encrypt_str};
-use crate::regex::engine::has_match;
+#[cfg(feature = "server")]
+fn log_progress(p: Progress) {
+    info!("  {} done ({}/{})", p.stage, p.done, p.total);
+}
+
+// the files a run uses besides its content: the plan cache, and the records
+// it writes along with the result
+#[cfg(feature = "server")]
+#[derive(Clone, Copy, Default)]
+pub struct RunFiles<'a> {
+    pub plan_cache: Option<&'a Path>,
+    pub trace_ops: Option<&'a Path>,
+    // where the results of the traced operations are saved (see
+    // explain::TracedRun), only along with trace_ops
+    pub trace_results: Option<&'a Path>,
+    pub provenance: Option<&'a Path>,
+    // the key the provenance record is signed with, unsigned without one
+    pub provenance_key: Option<&'a [u8]>,
+}
 
-pub(crate) fn main(content: &str, pattern: &str) {
-    let (client_key, server_key) = gen_keys();
+#[cfg(feature = "server")]
+pub fn main(
+    content: &str,
+    pattern: &str,
+    opts: &Preprocess,
+    profile: Profile,
+    match_opts: &MatchOptions,
+    files: &RunFiles,
+) -> u64 {
+    if !profile.is_secure() {
+        warn!("the {} profile is INSECURE, only use it to try things out", profile);
+    }
+    info!("generating keys ({} profile)..", profile);
+    let (client_key, server_key) = gen_keys_with_progress(profile, log_progress);
+    info!("warming up server key..");
+    let key = warmup_with_progress(&server_key, log_progress);
 
     info!("encrypting content..");
-    let ct_content = encrypt_str(&client_key, content);
+    let mut ct_content = encrypt_str_within(&client_key, content, match_opts.max_content_len);
+
+    if !opts.is_noop() {
+        info!("preprocessing content..");
+        ct_content = ct_content.map(|ct| preprocess(&server_key, &ct, opts));
+    }
 
     info!("applying regex..");
-    let ct_res = has_match(&server_key, &ct_content.unwrap(), pattern).unwrap();
-    let res = client_key.decrypt(&ct_res);
-    println!("res: {:?}", res);
+    let ct_content = ct_content.unwrap();
+    let (ct_res, stats) = match (files.plan_cache, files.trace_ops) {
+        (plan_cache, Some(path)) => {
+            if plan_cache.is_some() {
+                warn!("the plan cache is not used while tracing ciphertext operations");
+            }
+            let trace = OpTrace::create(path).unwrap();
+            if files.trace_results.is_some() {
+                trace.capture_results();
+            }
+            let res = has_match_traced(&key, &ct_content, pattern, match_opts, &trace).unwrap();
+            if let Some(results) = files.trace_results {
+                trace.save_results(results).unwrap();
+                explain_run(&client_key, path, results, content, opts);
+            }
+            res
+        }
+        (Some(dir), None) => {
+            let mut plans = PlanCache::open(dir).unwrap();
+            has_match_plan_cached(&key, &ct_content, pattern, match_opts, &mut plans).unwrap()
+        }
+        (None, None) => has_match_with(&key, &ct_content, pattern, match_opts).unwrap(),
+    };
+    info!("{}", stats);
+
+    if let Some(path) = files.provenance {
+        let record = Provenance::new(pattern, &ct_content, match_opts, &stats, &ct_res).unwrap();
+        let record = match files.provenance_key {
+            Some(key) => record.sign(key).unwrap(),
+            None => record.unsigned(),
+        };
+        record.save(path).unwrap();
+    }
+    client_key.decrypt(&ct_res)
+}
+
+// logs the operations of a traced run whose decrypted results differ from
+// the plaintext reference
+#[cfg(feature = "server")]
+fn explain_run(
+    client_key: &RadixClientKey,
+    trace: &Path,
+    results: &Path,
+    content: &str,
+    opts: &Preprocess,
+) {
+    if !opts.is_noop() {
+        warn!("the plaintext reference does not preprocess, skipping the explanation");
+        return;
+    }
+    let run = TracedRun::load(trace, results).unwrap();
+    let divergences = run.explain(client_key, content.as_bytes()).unwrap();
+    if divergences.is_empty() {
+        info!("every traced result agrees with the plaintext reference");
+    }
+    for d in divergences.iter().filter(|d| d.inputs_agree) {
+        warn!(
+            "operation {} ({}) diverged: decrypted {}, expected {}",
+            d.id, d.kind, d.decrypted, d.expected
+        );
+    }
+}
+
+// like main, scanning content for every rule of rules at once, resulting in
+// the names of the rules along with whether they matched
+#[cfg(feature = "server")]
+pub fn scan(
+    content: &str,
+    rules: &RuleSet,
+    profile: Profile,
+    match_opts: &MatchOptions,
+) -> Vec<(String, bool)> {
+    if !profile.is_secure() {
+        warn!("the {} profile is INSECURE, only use it to try things out", profile);
+    }
+    info!("generating keys ({} profile)..", profile);
+    let (client_key, server_key) = gen_keys_with_progress(profile, log_progress);
+    info!("warming up server key..");
+    let key = warmup_with_progress(&server_key, log_progress);
+
+    info!("encrypting content..");
+    let ct_content = encrypt_str_within(&client_key, content, match_opts.max_content_len).unwrap();
+
+    info!("scanning for {} rules..", rules.rules.len());
+    let (verdicts, stats) = rules.scan(&key, &ct_content, match_opts).unwrap();
+    info!("{}", stats);
+    verdicts.decrypt(&client_key)
 }
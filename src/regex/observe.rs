@@ -0,0 +1,83 @@
+use std::time::Duration;
+
+// Callbacks on the work of a match, for applications that embed the engine
+// and drive their own progress displays or metrics from it, rather than
+// reading the log. Every callback does nothing unless implemented. They are
+// called from the threads operations are evaluated on, concurrently, so they
+// should return quickly. Operations are named by their kind, as in op traces
+// (e.g. "eq", "and"). The branches of a pattern are not evaluated one after
+// the other, but all together level by level (see scheduler.rs), so progress
+// is reported per level: a plan is done when its last level is.
+pub trait Observer: Send + Sync {
+    // the plan is scheduled, into operations to evaluate in levels
+    fn plan_built(&self, _operations: usize, _levels: usize) {}
+
+    fn op_started(&self, _kind: &str) {}
+
+    fn op_finished(&self, _kind: &str, _elapsed: Duration) {}
+
+    // an operation whose result was already at hand, and is not evaluated
+    fn cache_hit(&self, _kind: &str) {}
+
+    // done levels of levels are evaluated
+    fn level_done(&self, _done: usize, _levels: usize) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::regex::engine::tests::{encrypt_trivial, KEYS};
+    use crate::regex::engine::{has_match_observed, MatchOptions};
+    use crate::regex::observe::Observer;
+    use crate::regex::warmup::warmup;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    #[derive(Default)]
+    struct Counts {
+        planned: Mutex<Option<(usize, usize)>>,
+        started: AtomicUsize,
+        finished: AtomicUsize,
+        levels_done: AtomicUsize,
+    }
+
+    impl Observer for Counts {
+        fn plan_built(&self, operations: usize, levels: usize) {
+            *self.planned.lock().unwrap() = Some((operations, levels));
+        }
+
+        fn op_started(&self, _kind: &str) {
+            self.started.fetch_add(1, Ordering::Relaxed);
+        }
+
+        fn op_finished(&self, _kind: &str, _elapsed: Duration) {
+            self.finished.fetch_add(1, Ordering::Relaxed);
+        }
+
+        fn level_done(&self, done: usize, levels: usize) {
+            assert!(done <= levels);
+            self.levels_done.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    #[test]
+    fn test_observed_match() {
+        let counts = Arc::new(Counts::default());
+        let ct_content = encrypt_trivial("xab");
+        let (ct_res, stats) = has_match_observed(
+            &warmup(&KEYS.1),
+            &ct_content,
+            "/a?b/",
+            &MatchOptions::default(),
+            counts.clone(),
+        )
+        .unwrap();
+        assert_eq!(1, KEYS.0.decrypt(&ct_res));
+
+        let (operations, levels) = counts.planned.lock().unwrap().unwrap();
+        assert_eq!(stats.ct_operations, operations);
+        assert_eq!(operations, counts.started.load(Ordering::Relaxed));
+        assert_eq!(operations, counts.finished.load(Ordering::Relaxed));
+        assert_eq!(levels, counts.levels_done.load(Ordering::Relaxed));
+    }
+}
@@ -3,10 +3,11 @@ use combine::parser::byte;
 use combine::parser::byte::byte;
 use combine::*;
 
+use serde::{Deserialize, Serialize};
 use std::fmt;
 
 #[derive(Clone, PartialEq, Eq, Hash)]
-pub(crate) enum RegExpr {
+pub enum RegExpr {
     SOF,
     EOF,
     Char {
@@ -20,12 +21,15 @@ pub(crate) enum RegExpr {
     Range {
         cs: Vec<u8>,
     },
+    // the complement of a character class, not_re is always a Char, Range
+    // or Between. It consumes exactly one character, like the class itself.
     Not {
         not_re: Box<RegExpr>,
     },
+    // alternatives in the order of the pattern, at least two of them and
+    // none of them an Either itself (see RegExpr::either)
     Either {
-        l_re: Box<RegExpr>,
-        r_re: Box<RegExpr>,
+        alts: Vec<RegExpr>,
     },
     Optional {
         opt_re: Box<RegExpr>,
@@ -41,7 +45,24 @@ pub(crate) enum RegExpr {
 }
 
 impl RegExpr {
-    fn case_insensitive(self) -> Self {
+    // the alternation of alts, flattened: alternatives that are alternations
+    // themselves are spliced in, keeping the order of the pattern. A single
+    // alternative is returned as is.
+    pub(crate) fn either(alts: Vec<Self>) -> Self {
+        let mut flat = Vec::with_capacity(alts.len());
+        for re in alts {
+            match re {
+                Self::Either { alts } => flat.extend(alts),
+                re => flat.push(re),
+            }
+        }
+        if flat.len() == 1 {
+            return flat.pop().unwrap();
+        }
+        Self::Either { alts: flat }
+    }
+
+    pub(crate) fn case_insensitive(self) -> Self {
         match self {
             Self::Char { c } => Self::Range {
                 cs: case_insensitive(c),
@@ -49,9 +70,8 @@ impl RegExpr {
             Self::Not { not_re } => Self::Not {
                 not_re: Box::new(not_re.case_insensitive()),
             },
-            Self::Either { l_re, r_re } => Self::Either {
-                l_re: Box::new(l_re.case_insensitive()),
-                r_re: Box::new(r_re.case_insensitive()),
+            Self::Either { alts } => Self::Either {
+                alts: alts.into_iter().map(|re| re.case_insensitive()).collect(),
             },
             Self::Optional { opt_re } => Self::Optional {
                 opt_re: Box::new(opt_re.case_insensitive()),
@@ -67,6 +87,193 @@ impl RegExpr {
             _ => self,
         }
     }
+
+    // the expression with every upper case letter it tests for replaced by its
+    // lower case counterpart, for matching against lowercased content
+    pub(crate) fn lowercased(&self) -> Self {
+        let lower_all = |cs: &mut dyn Iterator<Item = u8>| {
+            let mut cs: Vec<u8> = cs.map(|c| c.to_ascii_lowercase()).collect();
+            cs.sort_unstable();
+            cs.dedup();
+            cs
+        };
+        match self {
+            Self::Char { c } => Self::Char {
+                c: c.to_ascii_lowercase(),
+            },
+            Self::Range { cs } => Self::Range {
+                cs: lower_all(&mut cs.iter().copied()),
+            },
+            Self::Between { from, to } if (*from..=*to).any(|c| c.is_ascii_uppercase()) => {
+                Self::Range {
+                    cs: lower_all(&mut (*from..=*to)),
+                }
+            }
+            Self::Not { not_re } => Self::Not {
+                not_re: Box::new(not_re.lowercased()),
+            },
+            Self::Either { alts } => Self::Either {
+                alts: alts.iter().map(|re| re.lowercased()).collect(),
+            },
+            Self::Optional { opt_re } => Self::Optional {
+                opt_re: Box::new(opt_re.lowercased()),
+            },
+            Self::Repeated {
+                repeat_re,
+                at_least,
+                at_most,
+            } => Self::Repeated {
+                repeat_re: Box::new(repeat_re.lowercased()),
+                at_least: *at_least,
+                at_most: *at_most,
+            },
+            Self::Seq { re_xs } => Self::Seq {
+                re_xs: re_xs.iter().map(|re_x| re_x.lowercased()).collect(),
+            },
+            _ => self.clone(),
+        }
+    }
+
+    // the maximum number of characters a match consumes, None if unbounded
+    // (or beyond usize)
+    pub(crate) fn max_width(&self) -> Option<usize> {
+        match self {
+            Self::SOF | Self::EOF => Some(0),
+            Self::Char { .. } | Self::AnyChar | Self::Between { .. } | Self::Range { .. } => Some(1),
+            Self::Not { not_re } => not_re.max_width(),
+            Self::Either { alts } => alts
+                .iter()
+                .map(|re| re.max_width())
+                .collect::<Option<Vec<usize>>>()?
+                .into_iter()
+                .max(),
+            Self::Optional { opt_re } => opt_re.max_width(),
+            Self::Repeated { repeat_re, at_most, .. } => match (repeat_re.max_width()?, at_most) {
+                (0, _) => Some(0),
+                (width, Some(at_most)) => width.checked_mul(*at_most),
+                (_, None) => None,
+            },
+            Self::Seq { re_xs } => re_xs
+                .iter()
+                .try_fold(0usize, |width, re_x| width.checked_add(re_x.max_width()?)),
+        }
+    }
+
+    // the minimum number of characters a match consumes, saturating at
+    // usize::MAX (which no content reaches)
+    pub(crate) fn min_width(&self) -> usize {
+        match self {
+            Self::SOF | Self::EOF => 0,
+            Self::Char { .. } | Self::AnyChar | Self::Between { .. } | Self::Range { .. } => 1,
+            Self::Not { not_re } => not_re.min_width(),
+            Self::Either { alts } => alts.iter().map(|re| re.min_width()).min().unwrap_or(0),
+            Self::Optional { .. } => 0,
+            Self::Repeated { repeat_re, at_least, .. } => {
+                repeat_re.min_width().saturating_mul(at_least.unwrap_or(0))
+            }
+            Self::Seq { re_xs } => re_xs
+                .iter()
+                .fold(0usize, |width, re_x| width.saturating_add(re_x.min_width())),
+        }
+    }
+
+    // renders the expression in pattern syntax (without the enclosing /)
+    pub(crate) fn to_pattern(&self) -> String {
+        let class_char = |c: &u8| match c {
+            b']' | b'\\' | b'-' | b'^' => format!("\\{}", u8_to_char(*c)),
+            _ => u8_to_char(*c).to_string(),
+        };
+        let class_body = |re: &RegExpr| match re {
+            Self::Between { from, to } => format!("{}-{}", class_char(from), class_char(to)),
+            Self::Range { cs } => cs.iter().map(class_char).collect(),
+            Self::Char { c } => class_char(c),
+            _ => unreachable!("not a character class"),
+        };
+        // quantifiers apply to a single atom, that is not quantified itself.
+        // Anchors are grouped as well, as a ^ or $ at the edge of the pattern
+        // is taken for the anchor of the pattern as a whole.
+        let atom = |re: &RegExpr| match re {
+            Self::Seq { .. } | Self::Optional { .. } | Self::Repeated { .. } | Self::SOF | Self::EOF => {
+                format!("({})", re.to_pattern())
+            }
+            _ => re.to_pattern(),
+        };
+        match self {
+            Self::SOF => "^".to_string(),
+            Self::EOF => "$".to_string(),
+            Self::Char { c } if c.is_ascii_alphabetic() || NON_ESCAPABLE_SYMBOLS.contains(c) => {
+                u8_to_char(*c).to_string()
+            }
+            Self::Char { c } => format!("\\{}", u8_to_char(*c)),
+            Self::AnyChar => ".".to_string(),
+            Self::Between { .. } | Self::Range { .. } => format!("[{}]", class_body(self)),
+            Self::Not { not_re } => format!("[^{}]", class_body(not_re)),
+            Self::Either { alts } => format!(
+                "({})",
+                alts.iter().map(|re| re.to_pattern()).collect::<Vec<_>>().join("|")
+            ),
+            Self::Optional { opt_re } => format!("{}?", atom(opt_re)),
+            Self::Repeated {
+                repeat_re,
+                at_least,
+                at_most,
+            } => {
+                // {0,} and {,m} parse to a lower bound of 0 and of None
+                // respectively, which are kept apart
+                let quantifier = match (at_least, at_most) {
+                    (None, None) => "*".to_string(),
+                    (Some(1), None) => "+".to_string(),
+                    (Some(n), None) => format!("{{{},}}", n),
+                    (Some(n), Some(m)) if n == m => format!("{{{}}}", n),
+                    (Some(n), Some(m)) => format!("{{{},{}}}", n, m),
+                    (None, Some(m)) => format!("{{,{}}}", m),
+                };
+                format!("{}{}", atom(repeat_re), quantifier)
+            }
+            // a sequence within a sequence is a group of its own
+            Self::Seq { re_xs } => re_xs
+                .iter()
+                .map(|re_x| match re_x {
+                    Self::Seq { .. } => format!("({})", re_x.to_pattern()),
+                    _ => re_x.to_pattern(),
+                })
+                .collect(),
+        }
+    }
+
+    // whether every match has to end at the end of the content
+    pub(crate) fn anchored_at_end(&self) -> bool {
+        match self {
+            Self::EOF => true,
+            Self::Either { alts } => alts.iter().all(|re| re.anchored_at_end()),
+            Self::Seq { re_xs } => re_xs.last().map_or(false, |re_x| re_x.anchored_at_end()),
+            _ => false,
+        }
+    }
+
+    pub(crate) fn contains_eof(&self) -> bool {
+        match self {
+            Self::EOF => true,
+            Self::Not { not_re: re } | Self::Optional { opt_re: re } | Self::Repeated { repeat_re: re, .. } => {
+                re.contains_eof()
+            }
+            Self::Either { alts } => alts.iter().any(|re| re.contains_eof()),
+            Self::Seq { re_xs } => re_xs.iter().any(|re_x| re_x.contains_eof()),
+            _ => false,
+        }
+    }
+
+    pub(crate) fn contains_sof(&self) -> bool {
+        match self {
+            Self::SOF => true,
+            Self::Not { not_re: re } | Self::Optional { opt_re: re } | Self::Repeated { repeat_re: re, .. } => {
+                re.contains_sof()
+            }
+            Self::Either { alts } => alts.iter().any(|re| re.contains_sof()),
+            Self::Seq { re_xs } => re_xs.iter().any(|re_x| re_x.contains_sof()),
+            _ => false,
+        }
+    }
 }
 
 fn case_insensitive(x: u8) -> Vec<u8> {
@@ -104,11 +311,14 @@ impl fmt::Debug for RegExpr {
                 "[{}]",
                 cs.iter().map(|c| u8_to_char(*c)).collect::<String>(),
             ),
-            Self::Either { l_re, r_re } => {
+            Self::Either { alts } => {
                 write!(f, "(")?;
-                l_re.fmt(f)?;
-                write!(f, "|")?;
-                r_re.fmt(f)?;
+                for (i, re) in alts.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, "|")?;
+                    }
+                    re.fmt(f)?;
+                }
                 write!(f, ")")
             }
             Self::Repeated {
@@ -143,29 +353,165 @@ impl fmt::Debug for RegExpr {
     }
 }
 
-pub(crate) fn parse(pattern: &str) -> Result<RegExpr> {
-    let (parsed, unparsed) = ((
-        between(
-            byte(b'/'),
-            byte(b'/'),
-            (optional(byte(b'^')), regex(), optional(byte(b'$'))),
-        )
-        .map(|(sof, re, eof)| {
-            if sof.is_none() && eof.is_none() {
-                return re;
+// How anchors at the edges of a pattern bind when the pattern is an
+// alternation. Legacy anchors the alternation as a whole (/^ab|cd$/ matches
+// only ab and cd), Standard follows mainstream regex engines, where ^ only
+// binds to the first alternative and $ only to the last (/^ab|cd$/ matches
+// anything starting with ab or ending with cd).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum AnchorPrecedence {
+    #[default]
+    Legacy,
+    Standard,
+}
+
+// Limits on the patterns parse accepts, so that adversarial patterns (e.g.
+// thousands of nested groups) are rejected before the recursive descent
+// parser, and everything else that recurses over the parsed pattern, can
+// exhaust the stack.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ParseLimits {
+    // in bytes
+    pub max_len: usize,
+    // of nested groups
+    pub max_depth: usize,
+    // the largest count of a repetition ({n}, {n,m})
+    pub max_repetitions: usize,
+}
+
+const DEFAULT_MAX_PATTERN_LEN: usize = 4096;
+const DEFAULT_MAX_NESTING_DEPTH: usize = 32;
+// as RE2 does
+const DEFAULT_MAX_REPETITIONS: usize = 1000;
+
+impl Default for ParseLimits {
+    fn default() -> Self {
+        Self {
+            max_len: DEFAULT_MAX_PATTERN_LEN,
+            max_depth: DEFAULT_MAX_NESTING_DEPTH,
+            max_repetitions: DEFAULT_MAX_REPETITIONS,
+        }
+    }
+}
+
+impl ParseLimits {
+    // the tighter of both limits, each
+    pub fn tighter(self, other: Self) -> Self {
+        Self {
+            max_len: self.max_len.min(other.max_len),
+            max_depth: self.max_depth.min(other.max_depth),
+            max_repetitions: self.max_repetitions.min(other.max_repetitions),
+        }
+    }
+}
+
+// the error parse results in for patterns exceeding the parse limits, which
+// callers can tell apart from syntax errors by downcasting
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PatternTooComplex {
+    Length { len: usize, max: usize },
+    Depth { depth: usize, max: usize },
+    // count is None when it does not even fit in a usize
+    Repetitions { count: Option<usize>, max: usize },
+}
+
+impl fmt::Display for PatternTooComplex {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Length { len, max } => write!(
+                f,
+                "pattern too complex to parse: {} bytes long, at most {} are allowed",
+                len, max
+            ),
+            Self::Depth { depth, max } => write!(
+                f,
+                "pattern too complex to parse: groups nested {} deep, at most {} are allowed",
+                depth, max
+            ),
+            Self::Repetitions { count: Some(count), max } => write!(
+                f,
+                "pattern too complex to parse: repeated {} times, at most {} are allowed",
+                count, max
+            ),
+            Self::Repetitions { count: None, max } => write!(
+                f,
+                "pattern too complex to parse: repetition count overflows, at most {} are allowed",
+                max
+            ),
+        }
+    }
+}
+
+impl std::error::Error for PatternTooComplex {}
+
+// checks pattern against limits without parsing it. Groups are the only
+// construct the parser recurses on; parentheses that are escaped or inside a
+// class are plain characters. A ] right after the opening [ (or [^) of a class
+// is a member, not its end. The counts of repetitions ({n}, {n,m}) are
+// checked here as well, so that the parser can take them as they are.
+fn check_limits(pattern: &str, limits: ParseLimits) -> Result<(), PatternTooComplex> {
+    if pattern.len() > limits.max_len {
+        return Err(PatternTooComplex::Length {
+            len: pattern.len(),
+            max: limits.max_len,
+        });
+    }
+    let mut depth = 0;
+    let mut in_class = false;
+    let mut bytes = pattern.bytes().peekable();
+    while let Some(c) = bytes.next() {
+        match c {
+            b'\\' => {
+                bytes.next();
             }
-            let mut re_xs = vec![];
-            if sof.is_some() {
-                re_xs.push(RegExpr::SOF);
+            b'[' if !in_class => {
+                in_class = true;
+                bytes.next_if_eq(&b'^');
+                bytes.next_if_eq(&b']');
             }
-            re_xs.push(re);
-            if eof.is_some() {
-                re_xs.push(RegExpr::EOF);
+            b']' if in_class => in_class = false,
+            b'(' if !in_class => {
+                depth += 1;
+                if depth > limits.max_depth {
+                    return Err(PatternTooComplex::Depth {
+                        depth,
+                        max: limits.max_depth,
+                    });
+                }
             }
-            RegExpr::Seq { re_xs }
-        }),
-        optional(byte(b'i')),
-    ))
+            b')' if !in_class => depth = depth.saturating_sub(1),
+            b'{' if !in_class => {
+                let mut count = vec![];
+                while let Some(c) = bytes.next_if(|c| c.is_ascii_digit() || *c == b',') {
+                    if c == b',' {
+                        parse_digits(&count, limits.max_repetitions)?;
+                        count.clear();
+                    } else {
+                        count.push(c);
+                    }
+                }
+                parse_digits(&count, limits.max_repetitions)?;
+            }
+            _ => (),
+        }
+    }
+    Ok(())
+}
+
+// parses pattern with the default (legacy) anchor precedence, within the
+// default limits
+pub fn parse(pattern: &str) -> Result<RegExpr> {
+    parse_with(pattern, AnchorPrecedence::default(), ParseLimits::default())
+}
+
+pub fn parse_with(pattern: &str, precedence: AnchorPrecedence, limits: ParseLimits) -> Result<RegExpr> {
+    check_limits(pattern, limits)?;
+    let body = match precedence {
+        AnchorPrecedence::Legacy => legacy_body().left(),
+        AnchorPrecedence::Standard => standard_body().right(),
+    };
+    let (parsed, unparsed) = ((between(byte(b'/'), byte(b'/'), body), optional(byte(b'i'))))
         .map(|(re, case_insensitive)| {
             if case_insensitive.is_some() {
                 re.case_insensitive()
@@ -184,16 +530,60 @@ pub(crate) fn parse(pattern: &str) -> Result<RegExpr> {
     Ok(parsed)
 }
 
+// the edge anchors apply to the whole pattern
+fn legacy_body<Input>() -> impl Parser<Input, Output = RegExpr>
+where
+    Input: Stream<Token = u8>,
+    Input::Error: ParseError<Input::Token, Input::Range, Input::Position>,
+{
+    (optional(byte(b'^')), regex(), optional(byte(b'$'))).map(|(sof, re, eof)| {
+        if sof.is_none() && eof.is_none() {
+            return re;
+        }
+        let mut re_xs = vec![];
+        if sof.is_some() {
+            re_xs.push(RegExpr::SOF);
+        }
+        re_xs.push(re);
+        if eof.is_some() {
+            re_xs.push(RegExpr::EOF);
+        }
+        RegExpr::Seq { re_xs }
+    })
+}
+
+// a leading ^ is parsed as part of the first alternative, and the trailing $
+// is added to the last alternative
+fn standard_body<Input>() -> impl Parser<Input, Output = RegExpr>
+where
+    Input: Stream<Token = u8>,
+    Input::Error: ParseError<Input::Token, Input::Range, Input::Position>,
+{
+    (
+        sep_by1::<Vec<RegExpr>, _, _, _>(term(), byte(b'|')),
+        optional(byte(b'$')),
+    )
+        .map(|(mut alternatives, eof)| {
+            if eof.is_some() {
+                let last = alternatives.pop().unwrap();
+                alternatives.push(RegExpr::Seq {
+                    re_xs: vec![last, RegExpr::EOF],
+                });
+            }
+            RegExpr::either(alternatives)
+        })
+}
+
 // based on grammar from: https://matt.might.net/articles/parsing-regex-with-recursive-descent/
 //
-//  <regex> ::= <term> '|' <regex>
-//           |  <term>
+//  <regex> ::= <term> { '|' <term> }
 //
 //  <term> ::= { <factor> }
 //
 //  <factor> ::= <base> { '*' }
 //
 //  <base> ::= <char>
+//          |  '^' | '$'
 //          |  '\' <char>
 //          |  '(' <regex> ')'
 
@@ -210,15 +600,7 @@ where
     Input: Stream<Token = u8>,
     Input::Error: ParseError<Input::Token, Input::Range, Input::Position>,
 {
-    choice((
-        attempt(
-            (term(), byte(b'|'), regex()).map(|(l_re, _, r_re)| RegExpr::Either {
-                l_re: Box::new(l_re),
-                r_re: Box::new(r_re),
-            }),
-        ),
-        term(),
-    ))
+    sep_by1::<Vec<RegExpr>, _, _, _>(term(), byte(b'|')).map(RegExpr::either)
 }
 
 fn term<Input>() -> impl Parser<Input, Output = RegExpr>
@@ -260,6 +642,11 @@ where
 {
     choice((
         byte(b'.').map(|_| RegExpr::AnyChar),
+        // anchors at the edges of the pattern are parsed in parse (and anchor
+        // the pattern as a whole), anchors anywhere else are zero width
+        // position checks
+        byte(b'^').map(|_| RegExpr::SOF),
+        attempt(byte(b'$').skip(not_followed_by(byte(b'/')))).map(|_| RegExpr::EOF),
         attempt(byte(b'\\').with(parser::token::any())).map(|c| RegExpr::Char { c }),
         choice((byte::letter(), parser::token::one_of(NON_ESCAPABLE_SYMBOLS)))
             .map(|c| RegExpr::Char { c }),
@@ -282,14 +669,71 @@ where
     Input::Error: ParseError<Input::Token, Input::Range, Input::Position>,
 {
     choice((
-        byte(b'^').with(range()).map(|re| RegExpr::Not {
+        byte(b'^').with(positive_range()).map(|re| RegExpr::Not {
             not_re: Box::new(re),
         }),
-        attempt(
-            (byte::letter(), byte(b'-'), byte::letter())
-                .map(|(from, _, to)| RegExpr::Between { from, to }),
-        ),
-        many1(byte::letter()).map(|cs| RegExpr::Range { cs }),
+        positive_range(),
+    ))
+}
+
+// a class of single characters and ranges of characters, e.g. [a-z0-9_].
+// Only a class of a single range is kept as such, any other class is
+// expanded into its characters.
+fn positive_range<Input>() -> impl Parser<Input, Output = RegExpr>
+where
+    Input: Stream<Token = u8>,
+    Input::Error: ParseError<Input::Token, Input::Range, Input::Position>,
+{
+    (class_item(true), many(class_item(false))).map(
+        |(first, mut items): ((u8, u8), Vec<(u8, u8)>)| {
+            items.insert(0, first);
+            match items[..] {
+                [(from, to)] if from != to => RegExpr::Between { from, to },
+                _ => RegExpr::Range {
+                    cs: items.iter().flat_map(|(from, to)| *from..=*to).collect(),
+                },
+            }
+        },
+    )
+}
+
+// a single character or a range of characters. A - that cannot be the
+// middle of a range (at either end of the class) stands for itself. The first
+// item of a class may start with an unescaped ], as a class cannot be empty.
+fn class_item<Input>(first: bool) -> impl Parser<Input, Output = (u8, u8)>
+where
+    Input: Stream<Token = u8>,
+    Input::Error: ParseError<Input::Token, Input::Range, Input::Position>,
+{
+    let start = move || {
+        if first {
+            choice((byte(b']'), class_char())).left()
+        } else {
+            class_char().right()
+        }
+    };
+    choice((
+        attempt((start(), byte(b'-'), class_char())).then(|(from, _, to)| {
+            if from <= to {
+                value((from, to)).left()
+            } else {
+                unexpected_any("reversed range in character class").right()
+            }
+        }),
+        start().map(|c| (c, c)),
+    ))
+}
+
+// within a class, every character except ] and \ stands for itself, those
+// two have to be escaped
+fn class_char<Input>() -> impl Parser<Input, Output = u8>
+where
+    Input: Stream<Token = u8>,
+    Input::Error: ParseError<Input::Token, Input::Range, Input::Position>,
+{
+    choice((
+        byte(b'\\').with(parser::token::any()),
+        satisfy(|c: u8| c != b']' && c != b'\\'),
     ))
 }
 
@@ -306,10 +750,10 @@ where
         }),
         attempt((
             atom(),
-            between(byte(b'{'), byte(b'}'), many::<Vec<u8>, _, _>(byte::digit())),
+            between(byte(b'{'), byte(b'}'), many1::<Vec<u8>, _, _>(byte::digit())),
         ))
         .map(|(re, repeat_digits)| {
-            let repeat = parse_digits(&repeat_digits);
+            let repeat = repetition_count(&repeat_digits);
             RegExpr::Repeated {
                 repeat_re: Box::new(re),
                 at_least: Some(repeat),
@@ -334,25 +778,40 @@ where
                     at_least: if at_least_digits.len() == 0 {
                         None
                     } else {
-                        Some(parse_digits(&at_least_digits))
+                        Some(repetition_count(&at_least_digits))
                     },
                     at_most: if at_most_digits.len() == 0 {
                         None
                     } else {
-                        Some(parse_digits(&at_most_digits))
+                        Some(repetition_count(&at_most_digits))
                     },
                 },
             ),
     ))
 }
 
-fn parse_digits(digits: &[u8]) -> usize {
-    std::str::from_utf8(digits).unwrap().parse().unwrap()
+// the count spelled by digits (0 for none), at most max
+fn parse_digits(digits: &[u8], max: usize) -> Result<usize, PatternTooComplex> {
+    let count = digits.iter().try_fold(0usize, |count, digit| {
+        count.checked_mul(10)?.checked_add((digit - b'0') as usize)
+    });
+    match count {
+        Some(count) if count <= max => Ok(count),
+        _ => Err(PatternTooComplex::Repetitions { count, max }),
+    }
+}
+
+// a repetition count, which check_limits found within the limits before the
+// pattern was parsed
+fn repetition_count(digits: &[u8]) -> usize {
+    parse_digits(digits, usize::MAX).unwrap_or(usize::MAX)
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::regex::parser::{parse, RegExpr};
+    use crate::regex::parser::{
+        parse, parse_with, AnchorPrecedence, ParseLimits, PatternTooComplex, RegExpr,
+    };
     use test_case::test_case;
 
     #[test_case("/h/", RegExpr::Char { c: b'h' }; "char")]
@@ -592,8 +1051,10 @@ mod tests {
     #[test_case("/(a|b)*/",
         RegExpr::Repeated {
             repeat_re: Box::new(RegExpr::Either {
-                l_re: Box::new(RegExpr::Char { c: b'a' }),
-                r_re: Box::new(RegExpr::Char { c: b'b' }),
+                alts: vec![
+                    RegExpr::Char { c: b'a' },
+                    RegExpr::Char { c: b'b' },
+                ],
             }),
             at_least: None,
             at_most: None,
@@ -602,8 +1063,10 @@ mod tests {
     #[test_case("/(a|b){3,7}/",
         RegExpr::Repeated {
             repeat_re: Box::new(RegExpr::Either {
-                l_re: Box::new(RegExpr::Char { c: b'a' }),
-                r_re: Box::new(RegExpr::Char { c: b'b' }),
+                alts: vec![
+                    RegExpr::Char { c: b'a' },
+                    RegExpr::Char { c: b'b' },
+                ],
             }),
             at_least: Some(3),
             at_most: Some(7),
@@ -613,28 +1076,32 @@ mod tests {
         RegExpr::Seq { re_xs: vec![
             RegExpr::SOF,
             RegExpr::Either {
-                l_re: Box::new(RegExpr::Seq { re_xs: vec![
-                    RegExpr::Char { c: b'a' },
-                    RegExpr::Char { c: b'b' },
-                ] }),
-                r_re: Box::new(RegExpr::Seq { re_xs: vec![
-                    RegExpr::Char { c: b'c' },
-                    RegExpr::Char { c: b'd' },
-                ]}),
+                alts: vec![
+                    RegExpr::Seq { re_xs: vec![
+                        RegExpr::Char { c: b'a' },
+                        RegExpr::Char { c: b'b' },
+                    ] },
+                    RegExpr::Seq { re_xs: vec![
+                        RegExpr::Char { c: b'c' },
+                        RegExpr::Char { c: b'd' },
+                    ]},
+                ],
             },
         ]};
         "SOF encapsulates full RHS")]
     #[test_case("/ab|cd$/",
         RegExpr::Seq {re_xs: vec![
             RegExpr::Either {
-                l_re: Box::new(RegExpr::Seq {re_xs: vec![
-                    RegExpr::Char { c: b'a' },
-                    RegExpr::Char { c: b'b' },
-                ]}),
-                r_re: Box::new(RegExpr::Seq {re_xs: vec![
-                    RegExpr::Char { c: b'c' },
-                    RegExpr::Char { c: b'd' },
-                ]}),
+                alts: vec![
+                    RegExpr::Seq {re_xs: vec![
+                        RegExpr::Char { c: b'a' },
+                        RegExpr::Char { c: b'b' },
+                    ]},
+                    RegExpr::Seq {re_xs: vec![
+                        RegExpr::Char { c: b'c' },
+                        RegExpr::Char { c: b'd' },
+                    ]},
+                ],
             },
             RegExpr::EOF,
         ]};
@@ -643,18 +1110,41 @@ mod tests {
         RegExpr::Seq {re_xs: vec![
             RegExpr::SOF,
             RegExpr::Either {
-                l_re: Box::new(RegExpr::Seq {re_xs: vec![
-                    RegExpr::Char { c: b'a' },
-                    RegExpr::Char { c: b'b' },
-                ]}),
-                r_re: Box::new(RegExpr::Seq {re_xs: vec![
-                    RegExpr::Char { c: b'c' },
-                    RegExpr::Char { c: b'd' },
-                ]}),
+                alts: vec![
+                    RegExpr::Seq {re_xs: vec![
+                        RegExpr::Char { c: b'a' },
+                        RegExpr::Char { c: b'b' },
+                    ]},
+                    RegExpr::Seq {re_xs: vec![
+                        RegExpr::Char { c: b'c' },
+                        RegExpr::Char { c: b'd' },
+                    ]},
+                ],
             },
             RegExpr::EOF,
         ]};
         "SOF + EOF both encapsulate full center")]
+    #[test_case("/a|b|c|d/",
+        RegExpr::Either {
+            alts: vec![
+                RegExpr::Char { c: b'a' },
+                RegExpr::Char { c: b'b' },
+                RegExpr::Char { c: b'c' },
+                RegExpr::Char { c: b'd' },
+            ],
+        };
+        "alternation is flat and in order")]
+    #[test_case("/(a|b)|c|(d|e)/",
+        RegExpr::Either {
+            alts: vec![
+                RegExpr::Char { c: b'a' },
+                RegExpr::Char { c: b'b' },
+                RegExpr::Char { c: b'c' },
+                RegExpr::Char { c: b'd' },
+                RegExpr::Char { c: b'e' },
+            ],
+        };
+        "grouped alternations are spliced in")]
     #[test_case("/\\^/",
         RegExpr::Char { c: b'^' };
         "escaping sof symbol")]
@@ -681,5 +1171,316 @@ mod tests {
             Ok(got) => assert_eq!(exp, got),
             Err(e) => panic!("got err: {}", e),
         }
+        assert_roundtrip(&exp, AnchorPrecedence::Legacy);
+    }
+
+    // to_pattern renders re as a pattern that parses back to re
+    fn assert_roundtrip(re: &RegExpr, precedence: AnchorPrecedence) {
+        let pattern = format!("/{}/", re.to_pattern());
+        assert_eq!(
+            *re,
+            parse_with(&pattern, precedence, ParseLimits::default()).unwrap(),
+            "rendered as {}",
+            pattern
+        );
+    }
+
+    #[test_case("/abc/", Some(3))]
+    #[test_case("/^ab?c$/", Some(3))]
+    #[test_case("/ab|cde/", Some(3))]
+    #[test_case("/a|bcd|ef/", Some(3))]
+    #[test_case("/a{2,4}/", Some(4))]
+    #[test_case("/a+/", None)]
+    #[test_case("/[^ab]/", Some(1))]
+    #[test_case("/(((((((a{1000}){1000}){1000}){1000}){1000}){1000}){1000}/", None ; "overflowing")]
+    fn test_max_width(pattern: &str, exp: Option<usize>) {
+        assert_eq!(exp, parse(pattern).unwrap().max_width());
+    }
+
+    #[test_case("/abc/", 3)]
+    #[test_case("/^ab?c$/", 2)]
+    #[test_case("/ab|cde/", 2)]
+    #[test_case("/abc|d|ef/", 1)]
+    #[test_case("/a{2,4}/", 2)]
+    #[test_case("/(ab)+/", 2)]
+    #[test_case("/a*/", 0)]
+    #[test_case("/[^ab]/", 1)]
+    #[test_case("/(((((((a{1000}){1000}){1000}){1000}){1000}){1000}){1000}/", usize::MAX ; "overflowing")]
+    fn test_min_width(pattern: &str, exp: usize) {
+        assert_eq!(exp, parse(pattern).unwrap().min_width());
+    }
+
+    #[test_case("/ab$/", true)]
+    #[test_case("/^(ab|cd$)/", false)]
+    #[test_case("/a$|b$/", true)]
+    #[test_case("/a$|b$|c/", false)]
+    #[test_case("/ab/", false)]
+    fn test_anchored_at_end(pattern: &str, exp: bool) {
+        assert_eq!(exp, parse(pattern).unwrap().anchored_at_end());
+    }
+
+    #[test_case("/foo$|^bar/",
+        RegExpr::Either {
+            alts: vec![
+                RegExpr::Seq {re_xs: vec![
+                    RegExpr::Char { c: b'f' },
+                    RegExpr::Char { c: b'o' },
+                    RegExpr::Char { c: b'o' },
+                    RegExpr::EOF,
+                ]},
+                RegExpr::Seq {re_xs: vec![
+                    RegExpr::SOF,
+                    RegExpr::Char { c: b'b' },
+                    RegExpr::Char { c: b'a' },
+                    RegExpr::Char { c: b'r' },
+                ]},
+            ],
+        };
+        "anchors within alternatives")]
+    #[test_case("/(a$)/",
+        RegExpr::Seq {re_xs: vec![
+            RegExpr::Char { c: b'a' },
+            RegExpr::EOF,
+        ]};
+        "end anchor within group")]
+    fn test_parser_mid_pattern_anchors(pattern: &str, exp: RegExpr) {
+        assert_eq!(exp, parse(pattern).unwrap());
+        assert_roundtrip(&exp, AnchorPrecedence::Legacy);
+    }
+
+    #[test_case("/^ab|cd$/",
+        RegExpr::Either {
+            alts: vec![
+                RegExpr::Seq {re_xs: vec![
+                    RegExpr::SOF,
+                    RegExpr::Char { c: b'a' },
+                    RegExpr::Char { c: b'b' },
+                ]},
+                RegExpr::Seq {re_xs: vec![
+                    RegExpr::Seq {re_xs: vec![
+                        RegExpr::Char { c: b'c' },
+                        RegExpr::Char { c: b'd' },
+                    ]},
+                    RegExpr::EOF,
+                ]},
+            ],
+        };
+        "anchors bind to the outer alternatives")]
+    #[test_case("/^(ab|cd)$/",
+        RegExpr::Seq {re_xs: vec![
+            RegExpr::Seq {re_xs: vec![
+                RegExpr::SOF,
+                RegExpr::Either {
+                    alts: vec![
+                        RegExpr::Seq {re_xs: vec![
+                            RegExpr::Char { c: b'a' },
+                            RegExpr::Char { c: b'b' },
+                        ]},
+                        RegExpr::Seq {re_xs: vec![
+                            RegExpr::Char { c: b'c' },
+                            RegExpr::Char { c: b'd' },
+                        ]},
+                    ],
+                },
+            ]},
+            RegExpr::EOF,
+        ]};
+        "group anchored as a whole")]
+    fn test_parser_standard_precedence(pattern: &str, exp: RegExpr) {
+        assert_eq!(exp, parse_with(pattern, AnchorPrecedence::Standard, ParseLimits::default()).unwrap());
+        assert_roundtrip(&exp, AnchorPrecedence::Standard);
+    }
+
+    #[test_case("/[^;]/", RegExpr::Not { not_re: Box::new(RegExpr::Range { cs: vec![b';'] }) };
+        "negated symbol")]
+    #[test_case("/[a-c0_]/", RegExpr::Range { cs: vec![b'a', b'b', b'c', b'0', b'_'] };
+        "range mixed with chars")]
+    #[test_case("/[\\]a-]/", RegExpr::Range { cs: vec![b']', b'a', b'-'] };
+        "escaped bracket and trailing dash")]
+    #[test_case("/[^\"]+/",
+        RegExpr::Repeated {
+            repeat_re: Box::new(RegExpr::Not { not_re: Box::new(RegExpr::Range { cs: vec![b'"'] }) }),
+            at_least: Some(1),
+            at_most: None,
+        };
+        "quantified negated class")]
+    fn test_parser_classes(pattern: &str, exp: RegExpr) {
+        assert_eq!(exp, parse(pattern).unwrap());
+        assert_roundtrip(&exp, AnchorPrecedence::Legacy);
+    }
+
+    // the members of a class, sorted
+    fn class_members(re: &RegExpr) -> Vec<u8> {
+        let mut cs = match re {
+            RegExpr::Char { c } => vec![*c],
+            RegExpr::Range { cs } => cs.clone(),
+            RegExpr::Between { from, to } => (*from..=*to).collect(),
+            RegExpr::Not { not_re } => {
+                let not = class_members(not_re);
+                (0..128).filter(|c| !not.contains(c)).collect()
+            }
+            _ => panic!("not a class: {:?}", re),
+        };
+        cs.sort();
+        cs.dedup();
+        cs
+    }
+
+    #[test_case("/[abc]/", "abc", false; "plain members")]
+    #[test_case("/[a-c]/", "abc", false; "range")]
+    #[test_case("/[a-a]/", "a", false; "single character range")]
+    #[test_case("/[\\]\\-\\^]/", "]-^", false; "escaped metacharacters")]
+    #[test_case("/[\\\\]/", "\\", false; "escaped backslash")]
+    #[test_case("/[\\a]/", "a", false; "escaped letter")]
+    #[test_case("/[]]/", "]", false; "bracket as only member")]
+    #[test_case("/[]a]/", "]a", false; "bracket as first member")]
+    #[test_case("/[]-a]/", "]^_`a", false; "bracket as start of range")]
+    #[test_case("/[\\]-a]/", "]^_`a", false; "escaped bracket as start of range")]
+    #[test_case("/[-a]/", "-a", false; "leading dash")]
+    #[test_case("/[a-]/", "-a", false; "trailing dash")]
+    #[test_case("/[-a-c-]/", "-abc", false; "dashes around a range")]
+    #[test_case("/[a\\-z]/", "-az", false; "escaped dash is no range")]
+    #[test_case("/[a-c-e]/", "-abce", false; "dash after a range")]
+    #[test_case("/[a^]/", "^a", false; "caret not first")]
+    #[test_case("/[[]/", "[", false; "opening bracket")]
+    #[test_case("/[.*+?(){}|$]/", "$()*+.?{|}", false; "metacharacters stand for themselves")]
+    #[test_case("/[^abc]/", "abc", true; "negated")]
+    #[test_case("/[^]a]/", "]a", true; "negated with bracket as first member")]
+    #[test_case("/[^-]/", "-", true; "negated dash")]
+    #[test_case("/[^a-]/", "-a", true; "negated with trailing dash")]
+    #[test_case("/[^\\^]/", "^", true; "negated escaped caret")]
+    #[test_case("/[^^]/", "^", true; "negated caret")]
+    fn test_class_syntax(pattern: &str, members: &str, negated: bool) {
+        let re = parse(pattern).unwrap();
+        assert_eq!(negated, matches!(re, RegExpr::Not { .. }));
+        let mut exp: Vec<u8> = members.bytes().collect();
+        if negated {
+            exp = (0..128).filter(|c| !exp.contains(c)).collect();
+        }
+        exp.sort();
+        assert_eq!(exp, class_members(&re));
+    }
+
+    #[test_case("/[]/"; "empty")]
+    #[test_case("/[^]/"; "negated empty")]
+    #[test_case("/[z-a]/"; "reversed range")]
+    #[test_case("/[abc/"; "unterminated")]
+    #[test_case("/[\\]/"; "escaped closing bracket")]
+    fn test_class_syntax_rejected(pattern: &str) {
+        assert!(parse(pattern).is_err());
+    }
+
+    #[test_case("/[a-c]+/", Some(1), None; "plus")]
+    #[test_case("/[]a]*/", None, None; "star")]
+    #[test_case("/[^\\]]+/", Some(1), None; "plus on negated")]
+    #[test_case("/[a-]{2,3}/", Some(2), Some(3); "bounded")]
+    fn test_quantified_class(pattern: &str, at_least_exp: Option<usize>, at_most_exp: Option<usize>) {
+        match parse(pattern).unwrap() {
+            RegExpr::Repeated {
+                repeat_re,
+                at_least,
+                at_most,
+            } => {
+                assert_eq!(at_least_exp, at_least);
+                assert_eq!(at_most_exp, at_most);
+                // the quantifier applies to the class as a whole
+                assert!(!class_members(&repeat_re).is_empty());
+            }
+            re => panic!("not repeated: {:?}", re),
+        }
+    }
+
+    #[test_case("/^ab?c$/")]
+    #[test_case("/a.*[^;]+/")]
+    #[test_case("/(ab|c\\1){2,4}[a-z]/")]
+    #[test_case("/x{3,}y{0,2}/")]
+    #[test_case("/[]a-]+[^]-a]/")]
+    #[test_case("/ab|c|(d|ef)g|h/")]
+    #[test_case("/(a?){3}/" ; "repeated optional")]
+    #[test_case("/(a*)?/" ; "optional repetition")]
+    #[test_case("/((ab)+)*/" ; "repeated repetition")]
+    #[test_case("/a(bc)d/" ; "group within a sequence")]
+    #[test_case("/a{0,}b{,2}/" ; "explicit bounds")]
+    #[test_case("/(^)*a($)?/" ; "quantified anchors")]
+    fn test_to_pattern_roundtrip(pattern: &str) {
+        assert_roundtrip(&parse(pattern).unwrap(), AnchorPrecedence::Legacy);
+    }
+
+    #[test]
+    fn test_deep_nesting_rejected() {
+        let limits = ParseLimits::default();
+        let nested = format!("/{}a{}/", "(".repeat(10_000), ")".repeat(10_000));
+        let err = parse(&nested).unwrap_err();
+        assert_eq!(
+            Some(&PatternTooComplex::Depth {
+                depth: limits.max_depth + 1,
+                max: limits.max_depth
+            }),
+            err.downcast_ref::<PatternTooComplex>()
+        );
+
+        let at_limit = format!("/{}a{}/", "(".repeat(limits.max_depth), ")".repeat(limits.max_depth));
+        assert!(parse(&at_limit).is_ok());
+        let escaped = format!("/{}/", "\\(".repeat(limits.max_depth + 1));
+        assert!(parse(&escaped).is_ok());
+        let in_class = format!("/[{}]/", "(".repeat(limits.max_depth + 1));
+        assert!(parse(&in_class).is_ok());
+        let after_bracket = format!("/[]{}]/", "(".repeat(limits.max_depth + 1));
+        assert!(parse(&after_bracket).is_ok());
+    }
+
+    #[test]
+    fn test_long_pattern_rejected() {
+        let long = format!("/{}/", "a".repeat(ParseLimits::default().max_len));
+        let err = parse(&long).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<PatternTooComplex>(),
+            Some(PatternTooComplex::Length { .. })
+        ));
+    }
+
+    #[test_case("/a{99999999999999999999}/", None ; "count overflowing usize")]
+    #[test_case("/a{1001}/", Some(1001) ; "count over the limit")]
+    #[test_case("/a{2,1001}/", Some(1001) ; "upper bound over the limit")]
+    #[test_case("/a{1001,}/", Some(1001) ; "lower bound over the limit")]
+    fn test_large_repetition_rejected(pattern: &str, count: Option<usize>) {
+        let err = parse(pattern).unwrap_err();
+        assert_eq!(
+            Some(&PatternTooComplex::Repetitions {
+                count,
+                max: ParseLimits::default().max_repetitions
+            }),
+            err.downcast_ref::<PatternTooComplex>()
+        );
+    }
+
+    #[test]
+    fn test_custom_limits() {
+        let limits = ParseLimits {
+            max_len: 8,
+            max_depth: 1,
+            max_repetitions: 4,
+        };
+        let parse_limited = |pattern: &str| parse_with(pattern, AnchorPrecedence::Legacy, limits);
+        assert!(parse_limited("/(a){4}/").is_ok());
+        for pattern in ["/a{5}/", "/((a))/", "/abcdefgh/"] {
+            let err = parse_limited(pattern).unwrap_err();
+            assert!(err.downcast_ref::<PatternTooComplex>().is_some(), "{}", pattern);
+            // within the default limits
+            assert!(parse(pattern).is_ok());
+        }
+
+        let tighter = ParseLimits::default().tighter(limits);
+        assert_eq!(limits, tighter);
+        assert_eq!(limits, limits.tighter(ParseLimits::default()));
+    }
+
+    #[test]
+    fn test_repetition_at_limit() {
+        assert!(parse("/a{1000}/").is_ok());
+        assert!(parse("/a{0,1000}/").is_ok());
+        // not repetitions
+        assert!(parse("/[{99999999999999999999}]/").is_ok());
+        assert!(parse("/a{}/").unwrap_err().downcast_ref::<PatternTooComplex>().is_none());
     }
 }
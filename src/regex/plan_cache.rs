@@ -0,0 +1,182 @@
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+
+use crate::regex::engine::{Engine, MatchOptions};
+use crate::regex::execution::Executed;
+use crate::regex::parser::AnchorPrecedence;
+
+// bump whenever the plans built for a pattern change (new lowerings, changes
+// to Executed), so that plans cached by older versions are not reused
+const PLAN_FORMAT_VERSION: u32 = 2;
+
+// Plans persisted on disk, keyed by the pattern, the content length and the
+// match options that affect lowering (engine, case insensitivity and anchor
+// precedence). Plans
+// do not depend on the content itself nor on the keys, so they can be shared
+// by all requests for content of the same length. Every plan is stored in its
+// own file, named after the hash of its key.
+pub struct PlanCache {
+    dir: PathBuf,
+    hits: usize,
+    misses: usize,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+struct PlanKey {
+    version: u32,
+    pattern: String,
+    content_len: usize,
+    engine: Engine,
+    case_insensitive: bool,
+    precedence: AnchorPrecedence,
+}
+
+#[derive(Serialize, Deserialize)]
+struct CachedPlan {
+    key: PlanKey,
+    engine: Engine,
+    plan: Executed,
+}
+
+impl PlanCache {
+    pub fn open<P: AsRef<Path>>(dir: P) -> Result<Self> {
+        std::fs::create_dir_all(dir.as_ref())
+            .map_err(|e| anyhow!("failed to create plan cache {:?}: {}", dir.as_ref(), e))?;
+        Ok(Self {
+            dir: dir.as_ref().to_path_buf(),
+            hits: 0,
+            misses: 0,
+        })
+    }
+
+    pub fn hits(&self) -> usize {
+        self.hits
+    }
+
+    pub fn misses(&self) -> usize {
+        self.misses
+    }
+
+    // removes all cached plans
+    pub fn clear(&mut self) -> Result<()> {
+        for entry in std::fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if path.extension().map_or(false, |ext| ext == "plan") {
+                std::fs::remove_file(path)?;
+            }
+        }
+        Ok(())
+    }
+
+    // returns the cached plan (and the engine it was built with), or builds
+    // it and stores it. Unreadable entries are rebuilt and overwritten.
+    pub(crate) fn get_or_insert_with<F>(
+        &mut self,
+        pattern: &str,
+        content_len: usize,
+        opts: &MatchOptions,
+        build: F,
+    ) -> Result<(Engine, Executed)>
+    where
+        F: FnOnce() -> Result<(Engine, Executed)>,
+    {
+        let key = PlanKey {
+            version: PLAN_FORMAT_VERSION,
+            pattern: pattern.to_string(),
+            content_len,
+            engine: opts.engine,
+            case_insensitive: opts.case_insensitive,
+            precedence: opts.precedence,
+        };
+        let path = self.path(&key)?;
+
+        if let Ok(serialized) = std::fs::read(&path) {
+            match bincode::deserialize::<CachedPlan>(&serialized) {
+                Ok(cached) if cached.key == key => {
+                    debug!("plan cache hit: {:?}", path);
+                    self.hits += 1;
+                    return Ok((cached.engine, cached.plan));
+                }
+                Ok(_) => warn!("plan cache entry {:?} belongs to another key", path),
+                Err(e) => warn!("plan cache entry {:?} is unreadable: {}", path, e),
+            }
+        }
+
+        self.misses += 1;
+        let (engine, plan) = build()?;
+        let cached = CachedPlan { key, engine, plan };
+        // written to a temporary file first, so that concurrent readers never
+        // see a partially written entry
+        let tmp_path = path.with_extension("tmp");
+        std::fs::write(&tmp_path, bincode::serialize(&cached)?)
+            .and_then(|_| std::fs::rename(&tmp_path, &path))
+            .map_err(|e| anyhow!("failed to write plan cache entry {:?}: {}", path, e))?;
+        Ok((cached.engine, cached.plan))
+    }
+
+    fn path(&self, key: &PlanKey) -> Result<PathBuf> {
+        let hash = Sha256::digest(bincode::serialize(key)?);
+        let name: String = hash.iter().map(|byte| format!("{:02x}", byte)).collect();
+        Ok(self.dir.join(name).with_extension("plan"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::regex::engine::{Engine, MatchOptions};
+    use crate::regex::execution::Executed;
+    use crate::regex::plan_cache::PlanCache;
+    use crate::regex::test_keys::temp_path;
+
+    fn plan() -> Executed {
+        Executed::eq(Executed::ct_pos(0), Executed::constant(b'a'))
+    }
+
+    #[test]
+    fn test_plan_reused() {
+        let dir = temp_path("plan_cache");
+        let mut cache = PlanCache::open(&dir).unwrap();
+        cache.clear().unwrap();
+
+        let built = cache
+            .get_or_insert_with("/a/", 1, &MatchOptions::default(), || Ok((Engine::Literal, plan())))
+            .unwrap();
+        let mut reopened = PlanCache::open(&dir).unwrap();
+        let cached = reopened
+            .get_or_insert_with("/a/", 1, &MatchOptions::default(), || panic!("plan was cached"))
+            .unwrap();
+
+        assert_eq!(built, cached);
+        assert_eq!((0, 1), (cache.hits(), cache.misses()));
+        assert_eq!((1, 0), (reopened.hits(), reopened.misses()));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_keyed_by_content_length() {
+        let dir = temp_path("plan_cache_len");
+        let mut cache = PlanCache::open(&dir).unwrap();
+        cache.clear().unwrap();
+
+        for content_len in [1, 2, 1] {
+            cache
+                .get_or_insert_with("/a/", content_len, &MatchOptions::default(), || {
+                    Ok((Engine::Literal, plan()))
+                })
+                .unwrap();
+        }
+        assert_eq!((1, 2), (cache.hits(), cache.misses()));
+
+        let case_insensitive = MatchOptions {
+            case_insensitive: true,
+            ..MatchOptions::default()
+        };
+        cache
+            .get_or_insert_with("/a/", 1, &case_insensitive, || Ok((Engine::Branches, plan())))
+            .unwrap();
+        assert_eq!((1, 3), (cache.hits(), cache.misses()));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
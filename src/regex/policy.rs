@@ -0,0 +1,180 @@
+use anyhow::{anyhow, Result};
+use combine::parser::char::{char, spaces, string};
+use combine::*;
+use std::collections::HashMap;
+
+use crate::regex::execution::Executed;
+
+// A boolean expression over the verdicts of the rules of a rule set (see
+// rules.rs), e.g. `api-key && !test-data || password`. Its plan combines the
+// plans of the rules it refers to, so that it is evaluated homomorphically in
+// the same execution as the rules themselves.
+//
+//  <or> ::= <and> { '||' <and> }
+//
+//  <and> ::= <not> { '&&' <not> }
+//
+//  <not> ::= '!' <not>
+//         |  <atom>
+//
+//  <atom> ::= '(' <or> ')'
+//          |  'true' | 'false'
+//          |  <rule name>
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PolicyExpr {
+    Rule(String),
+    Const(bool),
+    Not(Box<PolicyExpr>),
+    And(Box<PolicyExpr>, Box<PolicyExpr>),
+    Or(Box<PolicyExpr>, Box<PolicyExpr>),
+}
+
+impl PolicyExpr {
+    pub fn parse(expr: &str) -> Result<Self> {
+        let (parsed, unparsed) = spaces().with(or_expr()).parse(expr)?;
+        if !unparsed.is_empty() {
+            return Err(anyhow!(
+                "failed to parse policy, unexpected token at start of: {}",
+                unparsed
+            ));
+        }
+        Ok(parsed)
+    }
+
+    // the names of the rules the expression refers to
+    pub fn rules(&self) -> Vec<&str> {
+        match self {
+            Self::Rule(name) => vec![name.as_str()],
+            Self::Const(_) => vec![],
+            Self::Not(a) => a.rules(),
+            Self::And(a, b) | Self::Or(a, b) => [a.rules(), b.rules()].concat(),
+        }
+    }
+
+    // the plan of the expression, given the plans of the rules by name
+    pub(crate) fn plan(&self, rules: &HashMap<&str, &Executed>) -> Result<Executed> {
+        Ok(match self {
+            Self::Rule(name) => (*rules
+                .get(name.as_str())
+                .ok_or_else(|| anyhow!("policy refers to unknown rule {:?}", name))?)
+            .clone(),
+            Self::Const(true) => Executed::ct_true(),
+            Self::Const(false) => Executed::ct_false(),
+            Self::Not(a) => Executed::not(a.plan(rules)?),
+            Self::And(a, b) => Executed::and(a.plan(rules)?, b.plan(rules)?),
+            Self::Or(a, b) => Executed::or(a.plan(rules)?, b.plan(rules)?),
+        })
+    }
+}
+
+fn lex<Input, P>(p: P) -> impl Parser<Input, Output = P::Output>
+where
+    Input: Stream<Token = char>,
+    Input::Error: ParseError<Input::Token, Input::Range, Input::Position>,
+    P: Parser<Input>,
+{
+    p.skip(spaces())
+}
+
+parser! {
+    fn or_expr[Input]()(Input) -> PolicyExpr
+        where [Input: Stream<Token = char>]
+        {
+            or_expr_()
+        }
+}
+
+fn or_expr_<Input>() -> impl Parser<Input, Output = PolicyExpr>
+where
+    Input: Stream<Token = char>,
+    Input::Error: ParseError<Input::Token, Input::Range, Input::Position>,
+{
+    sep_by1::<Vec<PolicyExpr>, _, _, _>(and_expr(), lex(attempt(string("||")))).map(|xs| {
+        xs.into_iter()
+            .reduce(|a, b| PolicyExpr::Or(Box::new(a), Box::new(b)))
+            .unwrap()
+    })
+}
+
+fn and_expr<Input>() -> impl Parser<Input, Output = PolicyExpr>
+where
+    Input: Stream<Token = char>,
+    Input::Error: ParseError<Input::Token, Input::Range, Input::Position>,
+{
+    sep_by1::<Vec<PolicyExpr>, _, _, _>(not_expr(), lex(attempt(string("&&")))).map(|xs| {
+        xs.into_iter()
+            .reduce(|a, b| PolicyExpr::And(Box::new(a), Box::new(b)))
+            .unwrap()
+    })
+}
+
+parser! {
+    fn not_expr[Input]()(Input) -> PolicyExpr
+        where [Input: Stream<Token = char>]
+        {
+            not_expr_()
+        }
+}
+
+fn not_expr_<Input>() -> impl Parser<Input, Output = PolicyExpr>
+where
+    Input: Stream<Token = char>,
+    Input::Error: ParseError<Input::Token, Input::Range, Input::Position>,
+{
+    choice((
+        lex(char('!'))
+            .with(not_expr())
+            .map(|a| PolicyExpr::Not(Box::new(a))),
+        atom(),
+    ))
+}
+
+fn atom<Input>() -> impl Parser<Input, Output = PolicyExpr>
+where
+    Input: Stream<Token = char>,
+    Input::Error: ParseError<Input::Token, Input::Range, Input::Position>,
+{
+    let name = many1(satisfy(|c: char| c.is_ascii_alphanumeric() || c == '_' || c == '-'));
+    choice((
+        between(lex(char('(')), lex(char(')')), or_expr()),
+        lex(name).map(|name: String| match name.as_str() {
+            "true" => PolicyExpr::Const(true),
+            "false" => PolicyExpr::Const(false),
+            _ => PolicyExpr::Rule(name),
+        }),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::regex::policy::PolicyExpr;
+    use test_case::test_case;
+
+    fn rule(name: &str) -> Box<PolicyExpr> {
+        Box::new(PolicyExpr::Rule(name.to_string()))
+    }
+
+    #[test]
+    fn test_precedence() {
+        // ! binds tighter than &&, which binds tighter than ||
+        let exp = PolicyExpr::Or(
+            Box::new(PolicyExpr::And(rule("r1"), Box::new(PolicyExpr::Not(rule("r2"))))),
+            rule("r3"),
+        );
+        assert_eq!(exp, PolicyExpr::parse("r1 && !r2 || r3").unwrap());
+        assert_eq!(exp, PolicyExpr::parse(" (r1&&!r2) || (r3) ").unwrap());
+
+        let exp = PolicyExpr::And(rule("r1"), Box::new(PolicyExpr::Or(rule("r2"), rule("r3"))));
+        assert_eq!(exp, PolicyExpr::parse("r1 && (r2 || r3)").unwrap());
+        assert_eq!(vec!["r1", "r2", "r3"], exp.rules());
+    }
+
+    #[test_case("")]
+    #[test_case("r1 &&")]
+    #[test_case("r1 & r2")]
+    #[test_case("(r1 || r2")]
+    #[test_case("r1 r2")]
+    fn test_invalid(expr: &str) {
+        assert!(PolicyExpr::parse(expr).is_err());
+    }
+}
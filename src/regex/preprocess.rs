@@ -0,0 +1,109 @@
+use rayon::prelude::*;
+use tfhe::integer::{RadixCiphertext, ServerKey};
+
+use crate::regex::ciphertext::{class_flag, substitute_where, StringCiphertext, BLOCK_SIZE};
+use crate::regex::class::CharClass;
+
+// Normalizations applied homomorphically to encrypted content, before
+// matching. Each of them substitutes characters in place; dropping characters
+// (e.g. collapsing runs of whitespace) would change the content's length,
+// which the party applying them can observe.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Preprocess {
+    // A-Z become a-z
+    pub lowercase: bool,
+    // every whitespace character (tabs, newlines, ..) becomes a space
+    pub normalize_whitespace: bool,
+    // every punctuation character becomes a space
+    pub strip_punctuation: bool,
+}
+
+impl Preprocess {
+    pub fn is_noop(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+pub fn preprocess(sk: &ServerKey, content: &[RadixCiphertext], opts: &Preprocess) -> StringCiphertext {
+    if opts.is_noop() {
+        return content.to_vec().into();
+    }
+    let shortkey = tfhe::shortint::ServerKey::from(sk.clone());
+
+    let mut to_space = CharClass::default();
+    if opts.normalize_whitespace {
+        to_space = to_space.union(&CharClass::from_chars(b" \t\n\r\x0b\x0c"));
+    }
+    if opts.strip_punctuation {
+        to_space = to_space.union(&CharClass::from_chars(b"!\"#$%&'()*+,-./:;<=>?@[\\]^_`{|}~"));
+    }
+
+    content
+        .par_iter()
+        .map(|ct| {
+            let mut ct = ct.clone();
+            if !to_space.is_empty() {
+                let flag = class_flag(&shortkey, &ct, &to_space);
+                ct = substitute_where(&shortkey, &ct, &flag, |block, _| {
+                    (b' ' as u64) >> (block * BLOCK_SIZE)
+                });
+            }
+            if opts.lowercase {
+                ct = to_lower(&shortkey, &ct);
+            }
+            ct
+        })
+        .collect::<Vec<_>>()
+        .into()
+}
+
+// turns A-Z into a-z, leaving every other character unchanged
+pub fn fhe_to_lower(sk: &ServerKey, content: &[RadixCiphertext]) -> StringCiphertext {
+    let shortkey = tfhe::shortint::ServerKey::from(sk.clone());
+    content
+        .par_iter()
+        .map(|ct| to_lower(&shortkey, ct))
+        .collect::<Vec<_>>()
+        .into()
+}
+
+// a range test for A-Z, and where it holds adding 32. Bit 0x20 is never set
+// for A-Z, so the addition is done by setting that bit, which needs no carry
+// propagation.
+pub(crate) fn to_lower(shortkey: &tfhe::shortint::ServerKey, ct: &RadixCiphertext) -> RadixCiphertext {
+    let flag = class_flag(shortkey, ct, &CharClass::from_range(b'A', b'Z'));
+    substitute_where(shortkey, ct, &flag, |block, x| x | (0x20 >> (block * BLOCK_SIZE)))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::regex::engine::tests::{encrypt_trivial, KEYS};
+    use crate::regex::preprocess::{fhe_to_lower, preprocess, Preprocess};
+    use test_case::test_case;
+
+    #[test_case("Hello, World!", Preprocess { lowercase: true, ..Preprocess::default() }, "hello, world!")]
+    #[test_case("a\tb\nc", Preprocess { normalize_whitespace: true, ..Preprocess::default() }, "a b c")]
+    #[test_case("a,b.c", Preprocess { strip_punctuation: true, ..Preprocess::default() }, "a b c")]
+    #[test_case(
+        "Hi,\tYou",
+        Preprocess { lowercase: true, normalize_whitespace: true, strip_punctuation: true },
+        "hi  you" ; "all")]
+    fn test_preprocess(content: &str, opts: Preprocess, exp: &str) {
+        let ct_content = encrypt_trivial(content);
+        let got: Vec<u8> = preprocess(&KEYS.1, &ct_content, &opts)
+            .iter()
+            .map(|ct| KEYS.0.decrypt(ct) as u8)
+            .collect();
+        assert_eq!(exp.as_bytes(), got.as_slice());
+    }
+
+    #[test]
+    fn test_fhe_to_lower() {
+        let ct_content = encrypt_trivial("AZaz@[`{09");
+        let got: Vec<u8> = fhe_to_lower(&KEYS.1, &ct_content)
+            .iter()
+            .map(|ct| KEYS.0.decrypt(ct) as u8)
+            .collect();
+        assert_eq!(b"azaz@[`{09", got.as_slice());
+    }
+}
@@ -0,0 +1,98 @@
+use anyhow::{anyhow, Result};
+use std::fmt;
+use std::str::FromStr;
+use tfhe::shortint::parameters::{LweDimension, Parameters, PARAM_MESSAGE_2_CARRY_2};
+
+// Named parameter presets. All profiles share the block layout the engine
+// is built around (NUM_BLOCKS blocks of BLOCK_SIZE bits with equally sized
+// carries), they only differ in the lwe dimension, which trades security
+// for bootstrapping speed.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Profile {
+    // INSECURE: a lwe dimension far too small to offer any security, for
+    // seeing results in minutes while evaluating. Never use it on real data.
+    Demo,
+    // tfhe's default parameters for 2 bit messages, ~128 bits of security
+    #[default]
+    Balanced,
+    // the default parameters with a larger lwe dimension, adding security
+    // margin at the cost of slower bootstraps and slightly more noise
+    Paranoid,
+}
+
+impl Profile {
+    pub fn parameters(&self) -> Parameters {
+        match self {
+            Self::Demo => Parameters {
+                lwe_dimension: LweDimension(256),
+                ..PARAM_MESSAGE_2_CARRY_2
+            },
+            Self::Balanced => PARAM_MESSAGE_2_CARRY_2,
+            Self::Paranoid => Parameters {
+                lwe_dimension: LweDimension(800),
+                ..PARAM_MESSAGE_2_CARRY_2
+            },
+        }
+    }
+
+    pub fn is_secure(&self) -> bool {
+        *self != Self::Demo
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Demo => "demo",
+            Self::Balanced => "balanced",
+            Self::Paranoid => "paranoid",
+        }
+    }
+}
+
+impl FromStr for Profile {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "demo" => Ok(Self::Demo),
+            "balanced" => Ok(Self::Balanced),
+            "paranoid" => Ok(Self::Paranoid),
+            _ => Err(anyhow!(
+                "unknown profile {:?} (expected demo, balanced or paranoid)",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Profile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::regex::profile::Profile;
+    use test_case::test_case;
+
+    #[test_case(Profile::Demo)]
+    #[test_case(Profile::Balanced)]
+    #[test_case(Profile::Paranoid)]
+    fn test_name_roundtrip(profile: Profile) {
+        assert_eq!(profile, profile.name().parse::<Profile>().unwrap());
+    }
+
+    #[test]
+    fn test_unknown_profile() {
+        assert!("fast".parse::<Profile>().is_err());
+    }
+
+    #[test]
+    fn test_same_layout() {
+        for profile in [Profile::Demo, Profile::Balanced, Profile::Paranoid] {
+            let params = profile.parameters();
+            assert_eq!(Profile::Balanced.parameters().message_modulus, params.message_modulus);
+            assert_eq!(Profile::Balanced.parameters().carry_modulus, params.carry_modulus);
+        }
+    }
+}
@@ -0,0 +1,249 @@
+use anyhow::{anyhow, Result};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tfhe::integer::RadixCiphertext;
+
+use crate::regex::ciphertext::{fingerprint, StringCiphertext};
+use crate::regex::engine::{plan_for, Engine, EngineStats, MatchOptions};
+use crate::regex::execution::Executed;
+use crate::regex::scheduler::Schedule;
+
+type HmacSha256 = Hmac<Sha256>;
+
+// What a match was computed from, kept alongside its encrypted result so that
+// it can later be shown which query produced which result. Nothing in it
+// reveals the pattern or the content, only hashes of them. The op trace hash
+// identifies the ciphertext operations of the plan (not their timings), so
+// that it can be recomputed from the pattern, the content length and the
+// options.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Provenance {
+    pub pattern_hash: String,
+    pub content_fingerprint: String,
+    pub content_len: usize,
+    pub params: String,
+    pub opts: MatchOptions,
+    pub engine: Engine,
+    pub engine_version: String,
+    pub op_trace_hash: String,
+    pub result_hash: String,
+    pub ct_operations: usize,
+    pub elapsed_us: u64,
+    // seconds since the unix epoch
+    pub created_at: u64,
+}
+
+// A provenance record signed with an hmac-sha256 key shared between the
+// server and whoever audits it. Unsigned records have no signature.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SignedProvenance {
+    pub record: Provenance,
+    pub signature: Option<String>,
+}
+
+impl Provenance {
+    pub fn new(
+        pattern: &str,
+        content: &StringCiphertext,
+        opts: &MatchOptions,
+        stats: &EngineStats,
+        result: &RadixCiphertext,
+    ) -> Result<Self> {
+        let (engine, plan) = plan_for(pattern, content.len(), opts)?;
+        Ok(Self {
+            pattern_hash: hex(&Sha256::digest(pattern.as_bytes())),
+            content_fingerprint: fingerprint(content)?.to_string(),
+            content_len: content.len(),
+            params: content.params().to_string(),
+            opts: opts.clone(),
+            engine,
+            engine_version: env!("CARGO_PKG_VERSION").to_string(),
+            op_trace_hash: op_trace_hash(&plan),
+            result_hash: hex(&Sha256::digest(bincode::serialize(result)?)),
+            ct_operations: stats.ct_operations,
+            elapsed_us: stats.elapsed.as_micros() as u64,
+            created_at: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+        })
+    }
+
+    // rebuilds the plan of pattern for the recorded content length and
+    // options, and checks that it is the plan the record was made for.
+    // Plans only reproduce with the engine version that built them.
+    pub fn verify_plan(&self, pattern: &str) -> Result<()> {
+        if hex(&Sha256::digest(pattern.as_bytes())) != self.pattern_hash {
+            return Err(anyhow!("the record was made for another pattern"));
+        }
+        if self.engine_version != env!("CARGO_PKG_VERSION") {
+            warn!(
+                "the record was made by engine version {}, this is {}",
+                self.engine_version,
+                env!("CARGO_PKG_VERSION")
+            );
+        }
+        let (engine, plan) = plan_for(pattern, self.content_len, &self.opts)?;
+        if engine != self.engine {
+            return Err(anyhow!(
+                "the pattern lowers to the {} engine, the record claims {}",
+                engine,
+                self.engine
+            ));
+        }
+        let hash = op_trace_hash(&plan);
+        if hash != self.op_trace_hash {
+            return Err(anyhow!(
+                "the plan's op trace hash is {}, the record claims {}",
+                hash,
+                self.op_trace_hash
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn unsigned(self) -> SignedProvenance {
+        SignedProvenance {
+            record: self,
+            signature: None,
+        }
+    }
+
+    pub fn sign(self, key: &[u8]) -> Result<SignedProvenance> {
+        let signature = hex(&mac(&self, key)?.finalize().into_bytes());
+        Ok(SignedProvenance {
+            record: self,
+            signature: Some(signature),
+        })
+    }
+}
+
+impl SignedProvenance {
+    pub fn verify(&self, key: &[u8]) -> Result<()> {
+        let signature = self
+            .signature
+            .as_ref()
+            .ok_or_else(|| anyhow!("the provenance record is not signed"))?;
+        mac(&self.record, key)?
+            .verify_slice(&unhex(signature)?)
+            .map_err(|_| anyhow!("the provenance record does not match its signature"))
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let serialized = std::fs::read_to_string(path.as_ref())
+            .map_err(|e| anyhow!("failed to read provenance {:?}: {}", path.as_ref(), e))?;
+        serde_json::from_str(&serialized).map_err(|e| anyhow!("failed to parse provenance: {}", e))
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        std::fs::write(path.as_ref(), serde_json::to_string_pretty(self)?)
+            .map_err(|e| anyhow!("failed to write provenance {:?}: {}", path.as_ref(), e))
+    }
+}
+
+fn mac(record: &Provenance, key: &[u8]) -> Result<HmacSha256> {
+    let mut mac =
+        HmacSha256::new_from_slice(key).map_err(|e| anyhow!("invalid provenance key: {}", e))?;
+    mac.update(&serde_json::to_vec(record)?);
+    Ok(mac)
+}
+
+// hashes the ciphertext operations of plan in the order they are scheduled,
+// every operation by its kind and the ids of its inputs (numbered as in an
+// OpTrace), content positions and constants by their value
+pub(crate) fn op_trace_hash(plan: &Executed) -> String {
+    let mut hasher = Sha256::new();
+    let mut ids: HashMap<&Executed, u64> = HashMap::new();
+    let schedule = Schedule::new(plan);
+    for node in schedule.levels().iter().flatten() {
+        let mut inputs = vec![];
+        for operand in node.operands() {
+            if !ids.contains_key(operand) {
+                ids.insert(operand, ids.len() as u64);
+                hasher.update(operand.kind().as_bytes());
+                match operand {
+                    Executed::CtPos { at } => hasher.update((*at as u64).to_le_bytes()),
+                    Executed::Constant { c } => hasher.update([*c]),
+                    _ => (),
+                }
+            }
+            inputs.push(ids[operand]);
+        }
+        ids.insert(node, ids.len() as u64);
+        hasher.update(node.kind().as_bytes());
+        for input in inputs {
+            hasher.update(input.to_le_bytes());
+        }
+    }
+    hex(&hasher.finalize())
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn unhex(s: &str) -> Result<Vec<u8>> {
+    if s.len() % 2 != 0 {
+        return Err(anyhow!("malformed signature {:?}", s));
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| {
+            u8::from_str_radix(&s[i..i + 2], 16).map_err(|_| anyhow!("malformed signature {:?}", s))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::regex::engine::tests::{encrypt_trivial, KEYS};
+    use crate::regex::engine::{has_match_with, plan_for, MatchOptions};
+    use crate::regex::provenance::{op_trace_hash, Provenance};
+    use crate::regex::warmup::warmup;
+
+    fn provenance(content: &str, pattern: &str) -> Provenance {
+        let ct_content = encrypt_trivial(content);
+        let opts = MatchOptions::default();
+        let (ct_res, stats) = has_match_with(&warmup(&KEYS.1), &ct_content, pattern, &opts).unwrap();
+        Provenance::new(pattern, &ct_content, &opts, &stats, &ct_res).unwrap()
+    }
+
+    #[test]
+    fn test_signature() {
+        let signed = provenance("abc", "/b/").sign(b"secret").unwrap();
+        assert!(signed.verify(b"secret").is_ok());
+        assert!(signed.verify(b"other secret").is_err());
+
+        let mut tampered = signed.clone();
+        tampered.record.pattern_hash = provenance("abc", "/c/").pattern_hash;
+        assert!(tampered.verify(b"secret").is_err());
+        assert!(provenance("abc", "/b/").unsigned().verify(b"secret").is_err());
+    }
+
+    #[test]
+    fn test_verify_plan() {
+        let record = provenance("abc", "/a?b/");
+        assert!(record.verify_plan("/a?b/").is_ok());
+        assert!(record.verify_plan("/a?c/").is_err());
+
+        let mut other_len = record.clone();
+        other_len.content_len = 4;
+        assert!(other_len.verify_plan("/a?b/").is_err());
+        let mut other_opts = record;
+        other_opts.opts.case_insensitive = true;
+        assert!(other_opts.verify_plan("/a?b/").is_err());
+    }
+
+    #[test]
+    fn test_op_trace_hash() {
+        let hash = |pattern: &str, content_len: usize| {
+            let (_, plan) = plan_for(pattern, content_len, &MatchOptions::default()).unwrap();
+            op_trace_hash(&plan)
+        };
+        assert_eq!(hash("/a?b/", 3), hash("/a?b/", 3));
+        assert_ne!(hash("/a?b/", 3), hash("/a?b/", 4));
+        assert_ne!(hash("/a?b/", 3), hash("/a?c/", 3));
+        assert_eq!(hash("/b/", 3), provenance("abc", "/b/").op_trace_hash);
+    }
+}
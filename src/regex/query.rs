@@ -0,0 +1,215 @@
+use anyhow::{anyhow, Result};
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+
+use crate::regex::engine::MatchOptions;
+use crate::regex::parser::AnchorPrecedence;
+use crate::regex::preprocess::Preprocess;
+use crate::regex::profile::Profile;
+
+// A search job described in a file (toml or json, told apart by extension),
+// holding everything that would otherwise be passed on the command line, so
+// that jobs can be kept and rerun as they are. Relative paths in the file are
+// relative to the file itself. For example:
+//
+//   pattern = "/^ab?c/"
+//   output = "result.txt"
+//
+//   [content]
+//   file = "content.txt"
+//
+//   [options]
+//   engine = "branches"
+//   ignore_case = true
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Query {
+    pub pattern: String,
+    pub content: ContentRef,
+    #[serde(default)]
+    pub options: QueryOptions,
+    // where the result is written to, stdout when absent
+    pub output: Option<PathBuf>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub enum ContentRef {
+    Text(String),
+    File(PathBuf),
+}
+
+// the command line flags of the same name
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct QueryOptions {
+    pub profile: Option<String>,
+    pub engine: Option<String>,
+    pub ignore_case: bool,
+    pub standard_anchors: bool,
+    pub max_bootstraps: Option<usize>,
+    pub max_memory_mib: Option<u64>,
+    pub threads: Option<usize>,
+    pub eval_order: Option<String>,
+    pub sanitize_output: bool,
+    pub lowercase: bool,
+    pub normalize_whitespace: bool,
+    pub strip_punctuation: bool,
+    pub plan_cache: Option<PathBuf>,
+    pub trace_ops: Option<PathBuf>,
+    pub trace_results: Option<PathBuf>,
+    pub provenance: Option<PathBuf>,
+    pub provenance_key: Option<PathBuf>,
+}
+
+impl Query {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let serialized = std::fs::read_to_string(path)
+            .map_err(|e| anyhow!("failed to read query {:?}: {}", path, e))?;
+        let query = match path.extension().and_then(|ext| ext.to_str()) {
+            Some("toml") => Self::from_toml(&serialized)?,
+            Some("json") => Self::from_json(&serialized)?,
+            _ => return Err(anyhow!("query {:?} is neither a .toml nor a .json file", path)),
+        };
+        Ok(query.relative_to(path.parent().unwrap_or_else(|| Path::new(""))))
+    }
+
+    pub fn from_toml(serialized: &str) -> Result<Self> {
+        toml::from_str(serialized).map_err(|e| anyhow!("failed to parse query: {}", e))
+    }
+
+    pub fn from_json(serialized: &str) -> Result<Self> {
+        serde_json::from_str(serialized).map_err(|e| anyhow!("failed to parse query: {}", e))
+    }
+
+    // the content to search in, read from its file if it refers to one
+    pub fn content(&self) -> Result<String> {
+        match &self.content {
+            ContentRef::Text(text) => Ok(text.clone()),
+            ContentRef::File(path) => std::fs::read_to_string(path)
+                .map_err(|e| anyhow!("failed to read content {:?}: {}", path, e)),
+        }
+    }
+
+    pub fn profile(&self) -> Result<Profile> {
+        match &self.options.profile {
+            Some(name) => name.parse(),
+            None => Ok(Profile::default()),
+        }
+    }
+
+    pub fn match_options(&self) -> Result<MatchOptions> {
+        let mut opts = MatchOptions {
+            case_insensitive: self.options.ignore_case,
+            max_bootstraps: self.options.max_bootstraps,
+            threads: self.options.threads,
+            sanitize_output: self.options.sanitize_output,
+            max_memory: self.options.max_memory_mib.map(|mib| mib << 20),
+            ..MatchOptions::default()
+        };
+        if let Some(engine) = &self.options.engine {
+            opts.engine = engine.parse()?;
+        }
+        if let Some(order) = &self.options.eval_order {
+            opts.eval_order = order.parse()?;
+        }
+        if self.options.standard_anchors {
+            opts.precedence = AnchorPrecedence::Standard;
+        }
+        Ok(opts)
+    }
+
+    pub fn preprocess(&self) -> Preprocess {
+        Preprocess {
+            lowercase: self.options.lowercase,
+            normalize_whitespace: self.options.normalize_whitespace,
+            strip_punctuation: self.options.strip_punctuation,
+        }
+    }
+
+    fn relative_to(mut self, dir: &Path) -> Self {
+        let content = match &mut self.content {
+            ContentRef::File(path) => Some(path),
+            ContentRef::Text(_) => None,
+        };
+        let paths = [
+            content,
+            self.output.as_mut(),
+            self.options.plan_cache.as_mut(),
+            self.options.trace_ops.as_mut(),
+            self.options.trace_results.as_mut(),
+            self.options.provenance.as_mut(),
+            self.options.provenance_key.as_mut(),
+        ];
+        for path in paths.into_iter().flatten() {
+            *path = dir.join(&*path);
+        }
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::regex::engine::Engine;
+    use crate::regex::query::{ContentRef, Query};
+    use crate::regex::test_keys::temp_path;
+
+    #[test]
+    fn test_toml_and_json_agree() {
+        let toml = Query::from_toml(
+            r#"
+            pattern = "/ab/"
+            output = "res.txt"
+
+            [content]
+            file = "content.txt"
+
+            [options]
+            engine = "literal"
+            ignore_case = true
+            max_memory_mib = 2
+            "#,
+        )
+        .unwrap();
+        let json = Query::from_json(
+            r#"{
+                "pattern": "/ab/",
+                "output": "res.txt",
+                "content": { "file": "content.txt" },
+                "options": { "engine": "literal", "ignore_case": true, "max_memory_mib": 2 }
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(toml, json);
+
+        let opts = toml.match_options().unwrap();
+        assert_eq!(Engine::Literal, opts.engine);
+        assert!(opts.case_insensitive);
+        assert_eq!(Some(2 << 20), opts.max_memory);
+    }
+
+    #[test]
+    fn test_paths_relative_to_query() {
+        let dir = temp_path("query");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("content.txt"), "abc").unwrap();
+        std::fs::write(
+            dir.join("query.toml"),
+            "pattern = \"/b/\"\ncontent = { file = \"content.txt\" }\noutput = \"res.txt\"\n",
+        )
+        .unwrap();
+
+        let query = Query::load(dir.join("query.toml")).unwrap();
+        assert_eq!(ContentRef::File(dir.join("content.txt")), query.content);
+        assert_eq!(Some(dir.join("res.txt")), query.output);
+        assert_eq!("abc", query.content().unwrap());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_unknown_fields_rejected() {
+        assert!(Query::from_toml("pattern = \"/a/\"\ncontent = { text = \"a\" }\nengine = \"auto\"\n").is_err());
+        assert!(Query::from_toml("pattern = \"/a/\"\ncontent = { text = \"a\" }\n").is_ok());
+    }
+}
@@ -0,0 +1,334 @@
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::path::Path;
+use tfhe::integer::{RadixCiphertext, RadixClientKey};
+
+use crate::regex::api::{Decryptor, MatchCiphertext};
+use crate::regex::engine::{
+    combined_engine, has_match_plans, plan_for, Engine, EngineStats, MatchOptions,
+};
+use crate::regex::execution::Executed;
+use crate::regex::parser::AnchorPrecedence;
+use crate::regex::policy::PolicyExpr;
+use crate::regex::warmup::WarmKey;
+
+// A set of named patterns to scan content for, kept in a file (toml or json,
+// told apart by extension) so that it can be versioned. For example:
+//
+//   [[rule]]
+//   name = "api-key"
+//   pattern = "/key-[0-9a-f]{8}/"
+//   severity = "high"
+//
+//   [[rule]]
+//   name = "password"
+//   pattern = "/password/"
+//   options = { ignore_case = true }
+//
+//   [[policy]]
+//   name = "leak"
+//   expr = "api-key || password && !test-data"
+//
+// All rules are matched in a single pass (see engine::has_match_patterns),
+// resulting in an encrypted verdict per rule. Policies combine the verdicts
+// of rules (see policy::PolicyExpr) within the same pass, resulting in an
+// encrypted verdict per policy.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RuleSet {
+    #[serde(rename = "rule", default)]
+    pub rules: Vec<Rule>,
+    #[serde(rename = "policy", default)]
+    pub policies: Vec<Policy>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    pub name: String,
+    pub pattern: String,
+    #[serde(default)]
+    pub severity: Severity,
+    #[serde(default)]
+    pub options: RuleOptions,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Severity {
+    Low,
+    #[default]
+    Medium,
+    High,
+    Critical,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Policy {
+    pub name: String,
+    pub expr: String,
+}
+
+// the match options of the same name in query files (see query::QueryOptions)
+// that apply to a single pattern
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RuleOptions {
+    pub ignore_case: bool,
+    pub standard_anchors: bool,
+}
+
+// The encrypted verdicts of a scan, one per rule in the order of the rules,
+// followed by one per policy in the order of the policies.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct RuleVerdicts {
+    pub names: Vec<String>,
+    pub verdicts: Vec<RadixCiphertext>,
+}
+
+impl RuleSet {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let serialized = std::fs::read_to_string(path)
+            .map_err(|e| anyhow!("failed to read rules {:?}: {}", path, e))?;
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("toml") => Self::from_toml(&serialized),
+            Some("json") => Self::from_json(&serialized),
+            _ => Err(anyhow!("rules {:?} are neither a .toml nor a .json file", path)),
+        }
+    }
+
+    pub fn from_toml(serialized: &str) -> Result<Self> {
+        let rules: Self =
+            toml::from_str(serialized).map_err(|e| anyhow!("failed to parse rules: {}", e))?;
+        rules.validate()
+    }
+
+    pub fn from_json(serialized: &str) -> Result<Self> {
+        let rules: Self = serde_json::from_str(serialized)
+            .map_err(|e| anyhow!("failed to parse rules: {}", e))?;
+        rules.validate()
+    }
+
+    fn validate(self) -> Result<Self> {
+        let mut names = HashSet::new();
+        for rule in &self.rules {
+            if !names.insert(rule.name.as_str()) {
+                return Err(anyhow!("rule {:?} is defined more than once", rule.name));
+            }
+        }
+        let rules = names.clone();
+        for policy in &self.policies {
+            if !names.insert(policy.name.as_str()) {
+                return Err(anyhow!("policy {:?} is defined more than once", policy.name));
+            }
+            let expr = PolicyExpr::parse(&policy.expr)
+                .map_err(|e| anyhow!("policy {}: {}", policy.name, e))?;
+            if let Some(name) = expr.rules().into_iter().find(|name| !rules.contains(name)) {
+                return Err(anyhow!(
+                    "policy {} refers to unknown rule {:?}",
+                    policy.name,
+                    name
+                ));
+            }
+        }
+        Ok(self)
+    }
+
+    // matches every rule on content and evaluates every policy in a single
+    // pass. opts applies to all rules, except for the options a rule sets
+    // itself.
+    pub fn scan(
+        &self,
+        key: &WarmKey,
+        content: &[RadixCiphertext],
+        opts: &MatchOptions,
+    ) -> Result<(RuleVerdicts, EngineStats)> {
+        let plans = self
+            .rules
+            .iter()
+            .map(|rule| {
+                plan_for(&rule.pattern, content.len(), &rule.match_options(opts))
+                    .map_err(|e| anyhow!("rule {}: {}", rule.name, e))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let (engines, mut roots): (Vec<Engine>, Vec<Executed>) = plans.into_iter().unzip();
+
+        let policy_roots = {
+            let by_name: HashMap<&str, &Executed> = self
+                .rules
+                .iter()
+                .map(|rule| rule.name.as_str())
+                .zip(&roots)
+                .collect();
+            self.policies
+                .iter()
+                .map(|policy| {
+                    PolicyExpr::parse(&policy.expr)
+                        .and_then(|expr| expr.plan(&by_name))
+                        .map_err(|e| anyhow!("policy {}: {}", policy.name, e))
+                })
+                .collect::<Result<Vec<_>>>()?
+        };
+        roots.extend(policy_roots);
+
+        let (verdicts, stats) =
+            has_match_plans(key, content, combined_engine(engines), &roots, opts)?;
+        let names = self
+            .rules
+            .iter()
+            .map(|rule| rule.name.clone())
+            .chain(self.policies.iter().map(|policy| policy.name.clone()))
+            .collect();
+        Ok((RuleVerdicts { names, verdicts }, stats))
+    }
+}
+
+impl Rule {
+    pub fn match_options(&self, opts: &MatchOptions) -> MatchOptions {
+        let mut opts = opts.clone();
+        opts.case_insensitive |= self.options.ignore_case;
+        if self.options.standard_anchors {
+            opts.precedence = AnchorPrecedence::Standard;
+        }
+        opts
+    }
+}
+
+impl RuleVerdicts {
+    pub fn get(&self, name: &str) -> Option<&RadixCiphertext> {
+        let i = self.names.iter().position(|n| n == name)?;
+        Some(&self.verdicts[i])
+    }
+
+    // the names of the rules and policies along with whether they matched
+    pub fn decrypt(&self, client_key: &RadixClientKey) -> Vec<(String, bool)> {
+        self.names
+            .iter()
+            .cloned()
+            .zip(self.verdicts.iter().map(|ct| client_key.decrypt(ct) != 0))
+            .collect()
+    }
+
+    // like decrypt, with the verdicts decrypted by whoever can (see
+    // api::Decryptor), all in one go
+    pub fn decrypt_with<D: Decryptor + ?Sized>(&self, decryptor: &D) -> Result<Vec<(String, bool)>> {
+        let verdicts: Vec<MatchCiphertext> = self.verdicts.iter().cloned().map(MatchCiphertext::from).collect();
+        let verdicts = decryptor.decrypt_all(&verdicts)?;
+        Ok(self.names.iter().cloned().zip(verdicts).collect())
+    }
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Self::Low => "low",
+            Self::Medium => "medium",
+            Self::High => "high",
+            Self::Critical => "critical",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::regex::engine::tests::{encrypt_trivial, KEYS};
+    use crate::regex::engine::MatchOptions;
+    use crate::regex::rules::{RuleSet, Severity};
+    use crate::regex::warmup::warmup;
+    use test_case::test_case;
+
+    const RULES: &str = r#"
+        [[rule]]
+        name = "greeting"
+        pattern = "/^hello/"
+        severity = "low"
+
+        [[rule]]
+        name = "shout"
+        pattern = "/WORLD/"
+        options = { ignore_case = true }
+
+        [[rule]]
+        name = "farewell"
+        pattern = "/bye/"
+        severity = "critical"
+
+        [[policy]]
+        name = "polite"
+        expr = "greeting && !farewell"
+
+        [[policy]]
+        name = "any"
+        expr = "farewell || (shout && !greeting)"
+    "#;
+
+    #[test]
+    fn test_toml_and_json_agree() {
+        let json = RuleSet::from_json(
+            r#"{ "rule": [
+                { "name": "greeting", "pattern": "/^hello/", "severity": "low" },
+                { "name": "shout", "pattern": "/WORLD/", "options": { "ignore_case": true } },
+                { "name": "farewell", "pattern": "/bye/", "severity": "critical" }
+            ], "policy": [
+                { "name": "polite", "expr": "greeting && !farewell" },
+                { "name": "any", "expr": "farewell || (shout && !greeting)" }
+            ] }"#,
+        )
+        .unwrap();
+        let toml = RuleSet::from_toml(RULES).unwrap();
+        assert_eq!(toml, json);
+        assert_eq!(Severity::Medium, toml.rules[1].severity);
+    }
+
+    #[test]
+    fn test_duplicate_names_rejected() {
+        let rules = "[[rule]]\nname = \"a\"\npattern = \"/a/\"\n[[rule]]\nname = \"a\"\npattern = \"/b/\"\n";
+        assert!(RuleSet::from_toml(rules).is_err());
+    }
+
+    #[test_case("a" ; "rule name")]
+    #[test_case("b" ; "policy name")]
+    fn test_duplicate_policy_names_rejected(name: &str) {
+        let rules = format!(
+            "[[rule]]\nname = \"a\"\npattern = \"/a/\"\n[[policy]]\nname = \"b\"\nexpr = \"a\"\n[[policy]]\nname = \"{}\"\nexpr = \"!a\"\n",
+            name
+        );
+        assert!(RuleSet::from_toml(&rules).is_err());
+    }
+
+    #[test_case("a && unknown" ; "unknown rule")]
+    #[test_case("a &&" ; "invalid expression")]
+    fn test_invalid_policy_rejected(expr: &str) {
+        let rules = format!(
+            "[[rule]]\nname = \"a\"\npattern = \"/a/\"\n[[policy]]\nname = \"p\"\nexpr = \"{}\"\n",
+            expr
+        );
+        assert!(RuleSet::from_toml(&rules).is_err());
+    }
+
+    #[test]
+    fn test_scan() {
+        let rules = RuleSet::from_toml(RULES).unwrap();
+        let ct_content = encrypt_trivial("hello world");
+        let (verdicts, _) = rules
+            .scan(&warmup(&KEYS.1), &ct_content, &MatchOptions::default())
+            .unwrap();
+
+        let exp = vec![
+            ("greeting".to_string(), true),
+            ("shout".to_string(), true),
+            ("farewell".to_string(), false),
+            ("polite".to_string(), true),
+            ("any".to_string(), false),
+        ];
+        assert_eq!(exp, verdicts.decrypt(&KEYS.0));
+        assert_eq!(exp, verdicts.decrypt_with(&KEYS.0).unwrap());
+        assert_eq!(0, KEYS.0.decrypt(verdicts.get("farewell").unwrap()));
+        assert!(verdicts.get("unknown").is_none());
+    }
+}
@@ -0,0 +1,319 @@
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::str::FromStr;
+
+use crate::regex::execution::Executed;
+
+// A schedule orders the unique operations of a plan into levels. Every
+// operation in a level only depends on operations of earlier levels, so all
+// operations of a level can be evaluated in parallel. Along with every level
+// go the results that are no longer needed once it is evaluated, so that
+// they can be dropped instead of being held until the end.
+pub(crate) struct Schedule {
+    levels: Vec<Vec<Executed>>,
+    released: Vec<Vec<Executed>>,
+    stats: ScheduleStats,
+}
+
+// The order the operations within a level are dispatched in. All of them are
+// evaluated before the next level either way, so this only decides which
+// results are available first, for experimenting with the order.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum EvalOrder {
+    // the most reused operations first, so that the operations depending on
+    // them can start as early as possible
+    #[default]
+    ReuseFirst,
+    // the order in which the operations were planned
+    Planned,
+}
+
+impl FromStr for EvalOrder {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "reuse-first" => Ok(Self::ReuseFirst),
+            "planned" => Ok(Self::Planned),
+            _ => Err(anyhow!(
+                "unknown evaluation order {:?} (expected reuse-first or planned)",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub(crate) struct ScheduleStats {
+    pub(crate) operations: usize,
+    pub(crate) levels: usize,
+    pub(crate) max_width: usize,
+    pub(crate) shared_operations: usize,
+    pub(crate) comparison_batches: usize,
+    // the most results held at once, when results are dropped as soon as
+    // they are no longer needed
+    pub(crate) peak_results: usize,
+}
+
+struct NodeInfo {
+    level: usize,
+    uses: usize,
+    order: usize,
+}
+
+impl Schedule {
+    pub(crate) fn new(root: &Executed) -> Self {
+        Self::from_roots(std::slice::from_ref(root))
+    }
+
+    // schedules the operations of multiple plans together, operations shared
+    // between the plans are only scheduled once
+    pub(crate) fn from_roots(roots: &[Executed]) -> Self {
+        Self::from_roots_ordered(roots, EvalOrder::default())
+    }
+
+    pub(crate) fn from_roots_ordered(roots: &[Executed], order: EvalOrder) -> Self {
+        let mut infos: HashMap<Executed, NodeInfo> = HashMap::new();
+        for root in roots {
+            visit(root, &mut infos);
+        }
+
+        // the level after which a result is no longer needed: the level of
+        // the last operation using it. The results of the plans themselves
+        // are needed until the end, and comparisons are kept as they are
+        // what content caches are made of (see Execution::into_comparisons).
+        let roots: HashSet<&Executed> = roots.iter().collect();
+        let mut last_use: HashMap<&Executed, usize> = HashMap::new();
+        for (node, info) in infos.iter() {
+            for operand in node.operands() {
+                if !operand.is_leaf() {
+                    let last = last_use.entry(operand).or_default();
+                    *last = std::cmp::max(*last, info.level);
+                }
+            }
+        }
+
+        let n_levels = infos.values().map(|info| info.level).max().unwrap_or(0);
+        let mut released: Vec<Vec<Executed>> = (0..n_levels).map(|_| vec![]).collect();
+        for (node, level) in last_use {
+            if !roots.contains(node) && !node.is_comparison() {
+                released[level - 1].push(node.clone());
+            }
+        }
+        let mut levels: Vec<Vec<(Executed, NodeInfo)>> = (0..n_levels).map(|_| vec![]).collect();
+        for (node, info) in infos.into_iter() {
+            levels[info.level - 1].push((node, info));
+        }
+
+        let mut stats = ScheduleStats {
+            levels: n_levels,
+            ..ScheduleStats::default()
+        };
+        let levels: Vec<Vec<Executed>> = levels
+            .into_iter()
+            .map(|mut level| {
+                // comparisons against the same constant are placed next to
+                // each other, the remaining operations are ordered as order
+                // specifies
+                level.sort_by(|(node_a, info_a), (node_b, info_b)| {
+                    let by_order = match order {
+                        EvalOrder::ReuseFirst => info_b.uses.cmp(&info_a.uses),
+                        EvalOrder::Planned => std::cmp::Ordering::Equal,
+                    };
+                    comparison_key(node_a)
+                        .cmp(&comparison_key(node_b))
+                        .then(by_order)
+                        .then(info_a.order.cmp(&info_b.order))
+                });
+
+                stats.operations += level.len();
+                stats.max_width = std::cmp::max(stats.max_width, level.len());
+                stats.shared_operations += level.iter().filter(|(_, info)| info.uses > 1).count();
+                let mut batch_keys: Vec<(u8, u8)> = level
+                    .iter()
+                    .filter_map(|(node, _)| comparison_key(node))
+                    .collect();
+                batch_keys.dedup();
+                stats.comparison_batches += batch_keys.len();
+
+                level.into_iter().map(|(node, _)| node).collect()
+            })
+            .collect();
+
+        let mut held = 0;
+        for (level, released) in levels.iter().zip(&released) {
+            held += level.len();
+            stats.peak_results = std::cmp::max(stats.peak_results, held);
+            held -= released.len();
+        }
+
+        Self {
+            levels,
+            released,
+            stats,
+        }
+    }
+
+    pub(crate) fn levels(&self) -> &[Vec<Executed>] {
+        &self.levels
+    }
+
+    // the results no longer needed once level i is evaluated
+    pub(crate) fn released_after(&self, i: usize) -> &[Executed] {
+        &self.released[i]
+    }
+
+    pub(crate) fn stats(&self) -> &ScheduleStats {
+        &self.stats
+    }
+}
+
+// registers node and its (not yet seen) operands, returns the node's level
+// (leaves are at level 0, as they require no ciphertext operation)
+fn visit(node: &Executed, infos: &mut HashMap<Executed, NodeInfo>) -> usize {
+    if node.is_leaf() {
+        return 0;
+    }
+    if let Some(info) = infos.get_mut(node) {
+        info.uses += 1;
+        return info.level;
+    }
+
+    let level = 1 + node
+        .operands()
+        .into_iter()
+        .map(|operand| visit(operand, infos))
+        .max()
+        .unwrap_or(0);
+    let order = infos.len();
+    infos.insert(
+        node.clone(),
+        NodeInfo {
+            level,
+            uses: 1,
+            order,
+        },
+    );
+    level
+}
+
+// comparisons of a content position against a constant share the key
+// (comparison kind, constant), all other operations have no key
+fn comparison_key(node: &Executed) -> Option<(u8, u8)> {
+    let (kind, a, b) = match node {
+        Executed::Equal { a, b } => (0, a, b),
+        Executed::GreaterOrEqual { a, b } => (1, a, b),
+        Executed::LessOrEqual { a, b } => (2, a, b),
+        Executed::GreaterThan { a, b } => (3, a, b),
+        Executed::NotEqual { a, b } => (4, a, b),
+        _ => return None,
+    };
+    match (a.as_ref(), b.as_ref()) {
+        (Executed::CtPos { .. }, Executed::Constant { c })
+        | (Executed::Constant { c }, Executed::CtPos { .. }) => Some((kind, *c)),
+        _ => None,
+    }
+}
+
+impl fmt::Display for ScheduleStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} operations in {} levels (max width {}), {} shared operations, {} comparison batches, at most {} results held",
+            self.operations,
+            self.levels,
+            self.max_width,
+            self.shared_operations,
+            self.comparison_batches,
+            self.peak_results,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::regex::execution::Executed;
+    use crate::regex::scheduler::{EvalOrder, Schedule};
+
+    fn eq(at: usize, c: u8) -> Executed {
+        Executed::eq(Executed::ct_pos(at), Executed::constant(c))
+    }
+
+    #[test]
+    fn test_levels_respect_dependencies() {
+        let plan = Executed::or(
+            Executed::and(eq(0, b'a'), eq(1, b'b')),
+            Executed::and(eq(1, b'a'), eq(2, b'b')),
+        );
+        let schedule = Schedule::new(&plan);
+
+        assert_eq!(3, schedule.levels().len());
+        assert_eq!(4, schedule.levels()[0].len());
+        assert_eq!(2, schedule.levels()[1].len());
+        assert_eq!(vec![plan], schedule.levels()[2]);
+    }
+
+    #[test]
+    fn test_comparisons_batched_by_constant() {
+        let plan = Executed::or(
+            Executed::and(eq(0, b'a'), eq(1, b'b')),
+            Executed::and(eq(1, b'a'), eq(2, b'b')),
+        );
+        let schedule = Schedule::new(&plan);
+
+        assert_eq!(
+            vec![eq(0, b'a'), eq(1, b'a'), eq(1, b'b'), eq(2, b'b')],
+            schedule.levels()[0],
+        );
+        assert_eq!(2, schedule.stats().comparison_batches);
+    }
+
+    #[test]
+    fn test_shared_operations_deduplicated() {
+        let shared = Executed::and(eq(0, b'a'), eq(1, b'b'));
+        let plan = Executed::or(
+            Executed::and(shared.clone(), eq(2, b'c')),
+            Executed::and(shared, eq(2, b'd')),
+        );
+        let schedule = Schedule::new(&plan);
+
+        assert_eq!(8, schedule.stats().operations);
+        assert_eq!(1, schedule.stats().shared_operations);
+    }
+
+    #[test]
+    fn test_intermediates_released_after_last_use() {
+        let a = Executed::and(eq(0, b'a'), eq(1, b'b'));
+        let b = Executed::and(eq(1, b'a'), eq(2, b'b'));
+        let plan = Executed::or(a.clone(), b.clone());
+        let schedule = Schedule::new(&plan);
+
+        // comparisons are kept, the ands are released once the or is done
+        assert!(schedule.released_after(0).is_empty());
+        assert!(schedule.released_after(1).is_empty());
+        let mut released = schedule.released_after(2).to_vec();
+        released.sort_by_key(|node| format!("{:?}", node));
+        let mut exp = vec![a, b];
+        exp.sort_by_key(|node| format!("{:?}", node));
+        assert_eq!(exp, released);
+        assert_eq!(7, schedule.stats().peak_results);
+    }
+
+    #[test]
+    fn test_eval_order() {
+        let shared = Executed::and(eq(0, b'a'), eq(1, b'b'));
+        let single = Executed::and(eq(2, b'c'), eq(3, b'd'));
+        let plan = Executed::or(
+            Executed::or(single.clone(), shared.clone()),
+            Executed::and(shared.clone(), eq(4, b'e')),
+        );
+
+        let roots = std::slice::from_ref(&plan);
+        let reuse_first = Schedule::from_roots_ordered(roots, EvalOrder::ReuseFirst);
+        assert_eq!(vec![shared.clone(), single.clone()], reuse_first.levels()[1]);
+        let planned = Schedule::from_roots_ordered(roots, EvalOrder::Planned);
+        assert_eq!(vec![single, shared], planned.levels()[1]);
+    }
+}
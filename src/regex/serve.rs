@@ -0,0 +1,722 @@
+use anyhow::{anyhow, Result};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::io::{ErrorKind, Read, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{sync_channel, SyncSender};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tfhe::integer::{RadixCiphertext, ServerKey};
+
+use crate::regex::batch::Batcher;
+use crate::regex::ciphertext::{
+    key_fingerprint, key_params, Fingerprint, StringCiphertext, DEFAULT_MAX_CONTENT_LEN,
+};
+use crate::regex::engine::{has_match_plans, has_match_with, plan_for, Engine, MatchOptions};
+use crate::regex::execution::Executed;
+use crate::regex::parser::ParseLimits;
+use crate::regex::shard::Coordinator;
+use crate::regex::warmup::{warmup, KeyCache, WarmKey};
+
+// messages larger than this are rejected before anything is allocated for
+// them, a corrupt length prefix must not make the server allocate gigabytes
+const MAX_MESSAGE_SIZE: u64 = 1 << 30;
+
+// the threads requests are planned on, apart from the threads ciphertext
+// operations are evaluated on
+pub const DEFAULT_PLANNER_THREADS: usize = 2;
+
+// the requests of a connection that are read and planned ahead of the one
+// being evaluated
+const PIPELINE_DEPTH: usize = 4;
+
+// A local matching service, for processes on the same host (e.g. a mail
+// server plugin) that hold encrypted content and want it matched without the
+// overhead of a network protocol. Every message on the socket, in either
+// direction, is a little endian u64 length followed by that many bytes of
+// bincode. A connection can send any number of requests, each answered by
+// one response, in order. Requests can be sent before the responses to
+// earlier ones are in: they are planned while the earlier ones are
+// evaluated, on a pool of planner threads of their own, so that the
+// evaluation of one request does not wait for the plan of the next one, and
+// planning never takes threads from evaluation.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct MatchRequest {
+    pub pattern: String,
+    pub content: StringCiphertext,
+    pub opts: MatchOptions,
+    // the server key of the client, the server's own when None
+    pub server_key: Option<KeyRef>,
+}
+
+// A server key a request is matched with. Server keys are large, so a server
+// keeps the keys it is sent (see UdsServer::with_key_slots), and later
+// requests can name them by fingerprint (see ciphertext::key_fingerprint).
+#[derive(Clone, Serialize, Deserialize)]
+pub enum KeyRef {
+    Held(Fingerprint),
+    Sent(Box<ServerKey>),
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub enum MatchResponse {
+    // the encrypted result of the match
+    Verdict(RadixCiphertext),
+    // the request could not be matched (e.g. the pattern did not parse)
+    Error(String),
+    // the request named a key the server does not hold (any longer), it is to
+    // be sent again
+    UnknownKey(Fingerprint),
+}
+
+// The limits a server holds every request to, whatever the options of the
+// request ask for: those come from the client, which must not get the server
+// to take on more than it allows. Limits of a request that are tighter are
+// kept.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ServerLimits {
+    pub parse: ParseLimits,
+    pub max_content_len: usize,
+    // as the options of a match (see MatchOptions), None leaves them to the
+    // request
+    pub max_memory: Option<u64>,
+    pub max_bootstraps: Option<usize>,
+    pub max_threads: Option<usize>,
+}
+
+impl Default for ServerLimits {
+    fn default() -> Self {
+        Self {
+            parse: ParseLimits::default(),
+            max_content_len: DEFAULT_MAX_CONTENT_LEN,
+            max_memory: None,
+            max_bootstraps: None,
+            max_threads: None,
+        }
+    }
+}
+
+impl ServerLimits {
+    pub(crate) fn apply(&self, opts: &mut MatchOptions) {
+        opts.parse_limits = opts.parse_limits.tighter(self.parse);
+        opts.max_content_len = opts.max_content_len.min(self.max_content_len);
+        opts.max_memory = tighter(opts.max_memory, self.max_memory);
+        opts.max_bootstraps = tighter(opts.max_bootstraps, self.max_bootstraps);
+        // no threads set means all cores
+        opts.threads = tighter(opts.threads, self.max_threads);
+    }
+}
+
+// the lower of two optional limits, where None is no limit
+fn tighter<T: Ord>(limit: Option<T>, other: Option<T>) -> Option<T> {
+    match (limit, other) {
+        (Some(limit), Some(other)) => Some(limit.min(other)),
+        (limit, None) => limit,
+        (None, other) => other,
+    }
+}
+
+// the keys a server matches with: its own, and those it was sent
+pub(crate) struct ServerKeys {
+    own: (Fingerprint, WarmKey),
+    sent: KeyCache,
+    // whether the keys sent are warmed up, only worth it when they match,
+    // and only done for keys that are kept for later requests
+    warm: bool,
+}
+
+impl ServerKeys {
+    pub(crate) fn new(own: WarmKey, slots: usize, warm: bool) -> Result<Self> {
+        Ok(Self {
+            own: (key_fingerprint(own.server_key())?, own),
+            sent: KeyCache::new(slots),
+            warm,
+        })
+    }
+
+    pub(crate) fn with_slots(&self, slots: usize) -> Self {
+        Self {
+            own: self.own.clone(),
+            sent: KeyCache::new(slots),
+            warm: self.warm,
+        }
+    }
+
+    pub(crate) fn own(&self) -> &(Fingerprint, WarmKey) {
+        &self.own
+    }
+
+    pub(crate) fn slots(&self) -> usize {
+        self.sent.slots()
+    }
+
+    // the key named by a request, or else the response to the request
+    pub(crate) fn resolve(&self, key: &Option<KeyRef>) -> std::result::Result<(Fingerprint, WarmKey), MatchResponse> {
+        let fingerprint = match key {
+            None => return Ok(self.own.clone()),
+            Some(KeyRef::Held(fingerprint)) => *fingerprint,
+            Some(KeyRef::Sent(sk)) => key_fingerprint(sk).map_err(|e| MatchResponse::Error(e.to_string()))?,
+        };
+        if fingerprint == self.own.0 {
+            return Ok(self.own.clone());
+        }
+        if let Some(key) = self.sent.get(&fingerprint) {
+            return Ok((fingerprint, key));
+        }
+        match key {
+            Some(KeyRef::Sent(sk)) => {
+                // warming up a key that is dropped after this request would
+                // cost more than it saves
+                let key = if self.warm && self.slots() > 0 {
+                    warmup(sk)
+                } else {
+                    WarmKey::cold(*sk.clone())
+                };
+                if let Some(evicted) = self.sent.insert(fingerprint, key.clone()) {
+                    debug!("evicted key {} for key {}", evicted, fingerprint);
+                }
+                Ok((fingerprint, key))
+            }
+            _ => Err(MatchResponse::UnknownKey(fingerprint)),
+        }
+    }
+}
+
+pub struct UdsServer {
+    listener: UnixListener,
+    path: PathBuf,
+    keys: Arc<ServerKeys>,
+    batcher: Option<Arc<Batcher>>,
+    coordinator: Option<Arc<Coordinator>>,
+    planner: Arc<rayon::ThreadPool>,
+    limits: Arc<RwLock<ServerLimits>>,
+}
+
+impl UdsServer {
+    // binds the socket at path, replacing a stale socket left behind by an
+    // earlier server
+    pub fn bind<P: AsRef<Path>>(path: P, key: WarmKey) -> Result<Self> {
+        let path = path.as_ref();
+        match std::fs::remove_file(path) {
+            Err(e) if e.kind() != ErrorKind::NotFound => {
+                return Err(anyhow!("failed to remove stale socket {:?}: {}", path, e))
+            }
+            _ => (),
+        }
+        let listener = UnixListener::bind(path)
+            .map_err(|e| anyhow!("failed to bind socket {:?}: {}", path, e))?;
+        Ok(Self {
+            listener,
+            path: path.to_path_buf(),
+            keys: Arc::new(ServerKeys::new(key, 0, true)?),
+            batcher: None,
+            coordinator: None,
+            planner: Arc::new(planner_pool(DEFAULT_PLANNER_THREADS)?),
+            limits: Arc::new(RwLock::new(ServerLimits::default())),
+        })
+    }
+
+    pub fn with_planner_threads(mut self, threads: usize) -> Result<Self> {
+        self.planner = Arc::new(planner_pool(threads)?);
+        Ok(self)
+    }
+
+    // keeps up to slots of the keys requests send (see KeyRef), least
+    // recently used first out, none by default: a key sent is then only used
+    // for the request it is sent with
+    pub fn with_key_slots(mut self, slots: usize) -> Self {
+        self.keys = Arc::new(self.keys.with_slots(slots));
+        self
+    }
+
+    // merges the requests of all connections that arrive within window of
+    // each other into one pass per content (see batch::Batcher). Only the
+    // requests matched with the server's own key are merged.
+    pub fn with_batching(mut self, window: Duration) -> Self {
+        self.batcher = Some(Arc::new(Batcher::new(self.keys.own().1.clone(), window)));
+        self
+    }
+
+    pub fn with_limits(self, limits: ServerLimits) -> Self {
+        *self.limits.write().unwrap() = limits;
+        self
+    }
+
+    // the limits requests are held to, shared with whatever changes them while
+    // the server runs (see config::ConfigWatcher): the limits set apply to
+    // every request read from then on, also on connections already open
+    pub fn limits(&self) -> Arc<RwLock<ServerLimits>> {
+        self.limits.clone()
+    }
+
+    // has the requests matched by the workers of coordinator, a shard of the
+    // content each, instead of matching them itself (see shard::Coordinator)
+    pub fn with_sharding(mut self, coordinator: Coordinator) -> Self {
+        self.coordinator = Some(Arc::new(coordinator));
+        self
+    }
+
+    // serves connections until the listener fails, every connection on a
+    // thread of its own
+    pub fn run(&self) -> Result<()> {
+        info!("serving on {:?}", self.path);
+        for stream in self.listener.incoming() {
+            let stream = stream.map_err(|e| anyhow!("failed to accept connection: {}", e))?;
+            let keys = self.keys.clone();
+            let batcher = self.batcher.clone();
+            let coordinator = self.coordinator.clone();
+            let planner = self.planner.clone();
+            let limits = self.limits.clone();
+            std::thread::spawn(move || {
+                let served = match (coordinator, batcher) {
+                    (Some(coordinator), _) => serve_sharded(stream, &coordinator, &limits),
+                    (None, Some(batcher)) => serve_batched(stream, &batcher, &keys, &limits),
+                    (None, None) => serve_connection(stream, &keys, planner, limits),
+                };
+                if let Err(e) = served {
+                    warn!("connection closed: {}", e);
+                }
+            });
+        }
+        Ok(())
+    }
+}
+
+fn planner_pool(threads: usize) -> Result<rayon::ThreadPool> {
+    rayon::ThreadPoolBuilder::new()
+        .num_threads(threads)
+        .thread_name(|i| format!("planner-{}", i))
+        .build()
+        .map_err(|e| anyhow!("failed to start {} planner threads: {}", threads, e))
+}
+
+type Planned = (MatchRequest, Result<(Engine, Executed)>);
+
+// reads and plans the requests of the connection on a thread of its own,
+// while the requests planned before are evaluated and answered here
+fn serve_connection(
+    mut stream: UnixStream,
+    keys: &ServerKeys,
+    planner: Arc<rayon::ThreadPool>,
+    limits: Arc<RwLock<ServerLimits>>,
+) -> Result<()> {
+    let reader = stream
+        .try_clone()
+        .map_err(|e| anyhow!("failed to clone connection: {}", e))?;
+    let (planned, received) = sync_channel(PIPELINE_DEPTH);
+    let planning = std::thread::spawn(move || plan_requests(reader, &planner, planned, limits));
+
+    for (request, plan) in received {
+        let key = match keys.resolve(&request.server_key) {
+            Ok((_, key)) => key,
+            Err(response) => {
+                write_message(&mut stream, &response)?;
+                continue;
+            }
+        };
+        let res = check_content(&request.content, &key).and_then(|_| plan).and_then(|(engine, plan)| {
+            has_match_plans(&key, &request.content, engine, &[plan], &request.opts)
+        });
+        let response = match res {
+            Ok((mut ct_res, stats)) => {
+                info!("{}", stats);
+                MatchResponse::Verdict(ct_res.remove(0))
+            }
+            Err(e) => MatchResponse::Error(e.to_string()),
+        };
+        // on failure, the planning thread ends along with the connection
+        write_message(&mut stream, &response)?;
+    }
+    planning
+        .join()
+        .map_err(|_| anyhow!("the planning thread panicked"))?
+}
+
+fn plan_requests(
+    mut reader: UnixStream,
+    planner: &rayon::ThreadPool,
+    planned: SyncSender<Planned>,
+    limits: Arc<RwLock<ServerLimits>>,
+) -> Result<()> {
+    while let Some(request) = read_request(&mut reader, &limits)? {
+        let plan = planner.install(|| plan_for(&request.pattern, request.content.len(), &request.opts));
+        if planned.send((request, plan)).is_err() {
+            // no longer answered
+            break;
+        }
+    }
+    Ok(())
+}
+
+// the batcher plans and evaluates the requests of all connections matched
+// with the server's own key, the others are matched one at a time
+fn serve_batched(
+    mut stream: UnixStream,
+    batcher: &Batcher,
+    keys: &ServerKeys,
+    limits: &RwLock<ServerLimits>,
+) -> Result<()> {
+    while let Some(request) = read_request(&mut stream, limits)? {
+        let res = match keys.resolve(&request.server_key) {
+            Ok((fingerprint, key)) if fingerprint == keys.own().0 => check_content(&request.content, &key)
+                .and_then(|_| batcher.has_match(request.content, &request.pattern, &request.opts)),
+            Ok((_, key)) => check_content(&request.content, &key)
+                .and_then(|_| has_match_with(&key, &request.content, &request.pattern, &request.opts))
+                .map(|(ct_res, _)| ct_res),
+            Err(response) => {
+                write_message(&mut stream, &response)?;
+                continue;
+            }
+        };
+        let response = match res {
+            Ok(ct_res) => MatchResponse::Verdict(ct_res),
+            Err(e) => MatchResponse::Error(e.to_string()),
+        };
+        write_message(&mut stream, &response)?;
+    }
+    Ok(())
+}
+
+// the workers of the coordinator match the requests, a shard each
+fn serve_sharded(mut stream: UnixStream, coordinator: &Coordinator, limits: &RwLock<ServerLimits>) -> Result<()> {
+    while let Some(request) = read_request(&mut stream, limits)? {
+        let key = match coordinator.keys().resolve(&request.server_key) {
+            Ok(key) => key,
+            Err(response) => {
+                write_message(&mut stream, &response)?;
+                continue;
+            }
+        };
+        let res = check_content(&request.content, &key.1)
+            .and_then(|_| coordinator.has_match_with_key(&request.content, &request.pattern, &request.opts, &key));
+        let response = match res {
+            Ok(ct_res) => MatchResponse::Verdict(ct_res),
+            Err(e) => MatchResponse::Error(e.to_string()),
+        };
+        write_message(&mut stream, &response)?;
+    }
+    Ok(())
+}
+
+// reads the next request of a connection, held to the limits of the moment
+fn read_request(stream: &mut UnixStream, limits: &RwLock<ServerLimits>) -> Result<Option<MatchRequest>> {
+    let mut request = match read_message::<_, MatchRequest>(stream)? {
+        Some(request) => request,
+        None => return Ok(None),
+    };
+    debug!("request to match {} on {} characters", request.pattern, request.content.len());
+    limits.read().unwrap().apply(&mut request.opts);
+    Ok(Some(request))
+}
+
+// padded content can't be matched (see StringCiphertext::check_unpadded),
+// and content encrypted for other parameters than those of key would be
+// matched into garbage
+fn check_content(content: &StringCiphertext, key: &WarmKey) -> Result<()> {
+    content.check_unpadded()?;
+    content.check_params(key_params(key.shortkey())?)
+}
+
+// the client side of the protocol
+pub struct UdsClient {
+    stream: UnixStream,
+}
+
+impl UdsClient {
+    pub fn connect<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let stream = UnixStream::connect(path.as_ref())
+            .map_err(|e| anyhow!("failed to connect to {:?}: {}", path.as_ref(), e))?;
+        Ok(Self { stream })
+    }
+
+    pub fn has_match(&mut self, request: &MatchRequest) -> Result<RadixCiphertext> {
+        match self.request(request)? {
+            MatchResponse::Verdict(ct_res) => Ok(ct_res),
+            MatchResponse::Error(e) => Err(anyhow!("server failed to match: {}", e)),
+            MatchResponse::UnknownKey(fingerprint) => Err(anyhow!("server does not hold key {}", fingerprint)),
+        }
+    }
+
+    // like has_match, only failing when the server can't be talked to, with
+    // the errors of the server in the response
+    pub fn request(&mut self, request: &MatchRequest) -> Result<MatchResponse> {
+        write_message(&mut self.stream, request)?;
+        read_message(&mut self.stream)?.ok_or_else(|| anyhow!("server closed the connection"))
+    }
+}
+
+pub fn write_message<W: Write, T: Serialize>(writer: &mut W, msg: &T) -> Result<()> {
+    let serialized = bincode::serialize(msg)?;
+    writer.write_all(&(serialized.len() as u64).to_le_bytes())?;
+    writer.write_all(&serialized)?;
+    writer
+        .flush()
+        .map_err(|e| anyhow!("failed to write message: {}", e))
+}
+
+// reads one message, None if the other side closed the connection before it
+pub fn read_message<R: Read, T: DeserializeOwned>(reader: &mut R) -> Result<Option<T>> {
+    let mut len = [0u8; 8];
+    match reader.read_exact(&mut len) {
+        Ok(()) => (),
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(anyhow!("failed to read message: {}", e)),
+    }
+    let len = u64::from_le_bytes(len);
+    if len > MAX_MESSAGE_SIZE {
+        return Err(anyhow!(
+            "message of {} bytes exceeds the maximum of {}",
+            len,
+            MAX_MESSAGE_SIZE
+        ));
+    }
+    let mut serialized = vec![0u8; len as usize];
+    reader
+        .read_exact(&mut serialized)
+        .map_err(|e| anyhow!("failed to read message: {}", e))?;
+    let msg = bincode::deserialize(&serialized)
+        .map_err(|e| anyhow!("failed to deserialize message: {}", e))?;
+    Ok(Some(msg))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::regex::ciphertext::{gen_server_key, key_fingerprint, Encoding, ParamsFingerprint, StringCiphertext};
+    use crate::regex::engine::tests::{encrypt_trivial, KEYS};
+    use crate::regex::engine::MatchOptions;
+    use crate::regex::parser::ParseLimits;
+    use crate::regex::profile::Profile;
+    use crate::regex::serve::{
+        read_message, write_message, KeyRef, MatchRequest, MatchResponse, ServerKeys, ServerLimits, UdsClient,
+        UdsServer,
+    };
+    use std::os::unix::net::UnixStream;
+    use crate::regex::test_keys::temp_path;
+    use crate::regex::warmup::{warmup, WarmKey};
+    use std::time::Duration;
+    use test_case::test_case;
+
+    #[test_case(false ; "one request at a time")]
+    #[test_case(true ; "batched")]
+    fn test_match_over_socket(batched: bool) {
+        let path = temp_path("socket");
+        let mut server = UdsServer::bind(&path, warmup(&KEYS.1)).unwrap();
+        if batched {
+            server = server.with_batching(Duration::from_millis(10));
+        }
+        std::thread::spawn(move || server.run());
+
+        let mut client = UdsClient::connect(&path).unwrap();
+        for (pattern, exp) in [("/b/", 1), ("/^b/", 0)] {
+            let request = MatchRequest {
+                pattern: pattern.to_string(),
+                content: encrypt_trivial("ab"),
+                opts: MatchOptions::default(),
+                server_key: None,
+            };
+            assert_eq!(exp, KEYS.0.decrypt(&client.has_match(&request).unwrap()));
+        }
+
+        let invalid = MatchRequest {
+            pattern: "/(/".to_string(),
+            content: encrypt_trivial("ab"),
+            opts: MatchOptions::default(),
+            server_key: None,
+        };
+        assert!(client.has_match(&invalid).is_err());
+    }
+
+    #[test_case(false ; "one request at a time")]
+    #[test_case(true ; "batched")]
+    fn test_content_checked(batched: bool) {
+        let path = temp_path("checked_socket");
+        let mut server = UdsServer::bind(&path, warmup(&KEYS.1)).unwrap();
+        if batched {
+            server = server.with_batching(Duration::from_millis(10));
+        }
+        std::thread::spawn(move || server.run());
+
+        let mut client = UdsClient::connect(&path).unwrap();
+        let request = |content: StringCiphertext| MatchRequest {
+            pattern: "/b$/".to_string(),
+            content,
+            opts: MatchOptions::default(),
+            server_key: None,
+        };
+        // the test keys are those of the balanced profile
+        let balanced = ParamsFingerprint::of_profile(Profile::Balanced).unwrap();
+        let content = StringCiphertext::new(encrypt_trivial("ab").into_chars(), Encoding::Ascii, balanced);
+        assert_eq!(1, KEYS.0.decrypt(&client.has_match(&request(content)).unwrap()));
+
+        let padded = encrypt_trivial("ab ").with_padding(1).unwrap();
+        let err = client.has_match(&request(padded)).unwrap_err();
+        assert!(err.to_string().contains("padded"), "{}", err);
+
+        let demo = ParamsFingerprint::of_profile(Profile::Demo).unwrap();
+        let content = StringCiphertext::new(encrypt_trivial("ab").into_chars(), Encoding::Ascii, demo);
+        let err = client.has_match(&request(content)).unwrap_err();
+        assert!(err.to_string().contains("encrypted with parameters"), "{}", err);
+    }
+
+    #[test]
+    fn test_server_limits_hold() {
+        let path = temp_path("limits_socket");
+        let limits = ServerLimits {
+            parse: ParseLimits {
+                max_repetitions: 2,
+                ..ParseLimits::default()
+            },
+            max_content_len: 3,
+            ..ServerLimits::default()
+        };
+        let server = UdsServer::bind(&path, warmup(&KEYS.1)).unwrap().with_limits(limits);
+        std::thread::spawn(move || server.run());
+
+        let mut client = UdsClient::connect(&path).unwrap();
+        let request = |pattern: &str, content: &str| MatchRequest {
+            pattern: pattern.to_string(),
+            content: encrypt_trivial(content),
+            // asking for more than the server allows
+            opts: MatchOptions {
+                parse_limits: ParseLimits {
+                    max_repetitions: usize::MAX,
+                    ..ParseLimits::default()
+                },
+                max_content_len: usize::MAX,
+                ..MatchOptions::default()
+            },
+            server_key: None,
+        };
+        assert_eq!(1, KEYS.0.decrypt(&client.has_match(&request("/a{2}/", "aaa")).unwrap()));
+        let err = client.has_match(&request("/a{3}/", "aaa")).unwrap_err();
+        assert!(err.to_string().contains("too complex"), "{}", err);
+        let err = client.has_match(&request("/a/", "aaaa")).unwrap_err();
+        assert!(err.to_string().contains("too long"), "{}", err);
+    }
+
+    #[test]
+    fn test_limits_changed_while_serving() {
+        let path = temp_path("changed_limits_socket");
+        let server = UdsServer::bind(&path, warmup(&KEYS.1)).unwrap();
+        let limits = server.limits();
+        std::thread::spawn(move || server.run());
+
+        let mut client = UdsClient::connect(&path).unwrap();
+        let request = MatchRequest {
+            pattern: "/a/".to_string(),
+            content: encrypt_trivial("aaaa"),
+            opts: MatchOptions::default(),
+            server_key: None,
+        };
+        assert_eq!(1, KEYS.0.decrypt(&client.has_match(&request).unwrap()));
+        // also on the connection already open
+        limits.write().unwrap().max_content_len = 3;
+        let err = client.has_match(&request).unwrap_err();
+        assert!(err.to_string().contains("too long"), "{}", err);
+    }
+
+    #[test_case(None, None, None ; "no limits")]
+    #[test_case(Some(4), None, Some(4) ; "limit of the request")]
+    #[test_case(None, Some(2), Some(2) ; "limit of the server")]
+    #[test_case(Some(4), Some(2), Some(2) ; "server limit tighter")]
+    #[test_case(Some(1), Some(2), Some(1) ; "request limit tighter")]
+    fn test_server_limits_clamp(requested: Option<usize>, max: Option<usize>, exp: Option<usize>) {
+        let limits = ServerLimits {
+            max_memory: max.map(|max| max as u64),
+            max_bootstraps: max,
+            max_threads: max,
+            ..ServerLimits::default()
+        };
+        let mut opts = MatchOptions {
+            max_memory: requested.map(|requested| requested as u64),
+            max_bootstraps: requested,
+            threads: requested,
+            ..MatchOptions::default()
+        };
+        limits.apply(&mut opts);
+        assert_eq!(exp.map(|exp| exp as u64), opts.max_memory);
+        assert_eq!(exp, opts.max_bootstraps);
+        assert_eq!(exp, opts.threads);
+    }
+
+    #[test]
+    fn test_pipelined_requests() {
+        let path = temp_path("pipelined");
+        let server = UdsServer::bind(&path, warmup(&KEYS.1))
+            .unwrap()
+            .with_planner_threads(1)
+            .unwrap();
+        std::thread::spawn(move || server.run());
+
+        // every request is sent before any response is read
+        let mut stream = UnixStream::connect(&path).unwrap();
+        let requests = [("/b/", Some(1)), ("/(/", None), ("/^a?c/", Some(0)), ("/ab$/", Some(1))];
+        for (pattern, _) in requests {
+            let request = MatchRequest {
+                pattern: pattern.to_string(),
+                content: encrypt_trivial("ab"),
+                opts: MatchOptions::default(),
+                server_key: None,
+            };
+            write_message(&mut stream, &request).unwrap();
+        }
+        for (_, exp) in requests {
+            let got = match read_message(&mut stream).unwrap().unwrap() {
+                MatchResponse::Verdict(ct_res) => Some(KEYS.0.decrypt(&ct_res)),
+                MatchResponse::Error(_) | MatchResponse::UnknownKey(_) => None,
+            };
+            assert_eq!(exp, got);
+        }
+    }
+
+    #[test]
+    fn test_keys_sent_and_held() {
+        let path = temp_path("keys_socket");
+        // another server key of the same client, so that the results of either
+        // decrypt with its client key
+        let server = UdsServer::bind(&path, warmup(&gen_server_key(&KEYS.0)))
+            .unwrap()
+            .with_key_slots(1);
+        std::thread::spawn(move || server.run());
+
+        let mut client = UdsClient::connect(&path).unwrap();
+        let request = |server_key| MatchRequest {
+            pattern: "/b/".to_string(),
+            content: encrypt_trivial("ab"),
+            opts: MatchOptions::default(),
+            server_key: Some(server_key),
+        };
+        let held = KeyRef::Held(key_fingerprint(&KEYS.1).unwrap());
+        assert!(matches!(
+            client.request(&request(held.clone())).unwrap(),
+            MatchResponse::UnknownKey(_)
+        ));
+        // once sent, the key is held
+        for server_key in [KeyRef::Sent(Box::new(KEYS.1.clone())), held] {
+            assert_eq!(1, KEYS.0.decrypt(&client.has_match(&request(server_key)).unwrap()));
+        }
+    }
+
+    // a key sent is only warmed up when it is kept
+    #[test_case(0, false ; "not kept")]
+    #[test_case(1, true ; "kept")]
+    fn test_sent_key_warmed_when_kept(slots: usize, exp_warm: bool) {
+        let own = WarmKey::cold(gen_server_key(&KEYS.0));
+        let keys = ServerKeys::new(own, slots, true).unwrap();
+        let sent = Some(KeyRef::Sent(Box::new(KEYS.1.clone())));
+        let (_, key) = keys.resolve(&sent).ok().unwrap();
+        assert_eq!(exp_warm, key.is_warm());
+    }
+
+    #[test]
+    fn test_oversized_message_rejected() {
+        let mut written = vec![];
+        write_message(&mut written, &"abc").unwrap();
+        assert_eq!(Some("abc".to_string()), read_message(&mut written.as_slice()).unwrap());
+        assert_eq!(None, read_message::<_, String>(&mut [0u8; 0].as_slice()).unwrap());
+
+        let oversized = u64::MAX.to_le_bytes();
+        assert!(read_message::<_, String>(&mut oversized.as_slice()).is_err());
+    }
+}
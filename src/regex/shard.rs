@@ -0,0 +1,663 @@
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tfhe::integer::{RadixCiphertext, ServerKey};
+
+use crate::regex::ciphertext::{check_content_len, key_fingerprint, Fingerprint, StringCiphertext};
+use crate::regex::engine::MatchOptions;
+use crate::regex::parser::parse_with;
+use crate::regex::serve::{
+    read_message, write_message, KeyRef, MatchRequest, MatchResponse, ServerKeys, UdsClient,
+};
+use crate::regex::verdict::fhe_any;
+use crate::regex::warmup::WarmKey;
+
+// how often registered workers tell the coordinator they are alive
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
+
+// a registered worker not heard from in this many heartbeat intervals is
+// taken to be dead, and is given no more shards
+const MISSED_HEARTBEATS: u32 = 3;
+
+// Matches content too long for one server by splitting it into shards, each
+// matched by one of a number of worker servers (see serve.rs), and or-ing
+// their encrypted results. Workers on other hosts are reached through their
+// forwarded sockets (e.g. ssh -L). Consecutive shards overlap by one
+// character less than the longest match of the pattern, so that every match
+// lies within at least one shard whole; a match in an overlap is found twice,
+// which the or doesn't mind. So only patterns with a longest match can be
+// sharded, and only patterns without anchors: a shard can't tell its start
+// from the start of the content. Content that fits in one shard is matched
+// whole by one worker, whatever the pattern.
+//
+// Workers are either given up front, or register themselves with the
+// coordinator (see register_worker and Coordinator::spawn_registry) and keep
+// sending heartbeats. Shards are dealt to the workers alive when a request
+// comes in; the shards of a worker that can't be reached, or that drops the
+// connection, are dealt again to the workers left, and the worker is given no
+// more shards (until it registers again, if it registered).
+//
+// Requests can be matched with the server keys of several clients (see
+// serve::KeyRef). Server keys are hundreds of megabytes, so the shards of a
+// client are only dealt to the workers that already hold its key; only when
+// none do is the key sent along, to one worker (see WorkerPool::place).
+pub struct Coordinator {
+    workers: Arc<WorkerPool>,
+    shard_len: usize,
+    // its own key, and the keys of the clients it was sent, which it only
+    // ors the results of the shards with
+    keys: ServerKeys,
+}
+
+// what a worker tells the coordinator about itself when it registers
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WorkerCapabilities {
+    pub threads: usize,
+    // whether it evaluates on a gpu, none of the workers built from this
+    // crate do
+    pub gpu: bool,
+    // the server keys it holds for good, by fingerprint (see
+    // key_fingerprint)
+    pub keys: Vec<Fingerprint>,
+    // how many of the keys it is sent it keeps (see
+    // serve::UdsServer::with_key_slots). A worker is only accepted when it
+    // holds the key of the coordinator or can be sent it.
+    pub key_slots: usize,
+}
+
+impl WorkerCapabilities {
+    // those of this process, serving with sk
+    pub fn of(sk: &ServerKey, key_slots: usize) -> Result<Self> {
+        Ok(Self {
+            threads: rayon::current_num_threads(),
+            gpu: false,
+            keys: vec![key_fingerprint(sk)?],
+            key_slots,
+        })
+    }
+}
+
+// the messages of the registration protocol, framed like those of serve.rs.
+// A worker connects to the registry of the coordinator, registers, is
+// answered with a RegistrationResponse, and then sends heartbeats on the same
+// connection, unanswered.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum WorkerMessage {
+    Register {
+        socket: PathBuf,
+        capabilities: WorkerCapabilities,
+    },
+    Heartbeat,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RegistrationResponse {
+    Accepted,
+    Rejected(String),
+}
+
+// the workers shards can be dealt to, by socket
+#[derive(Default)]
+pub struct WorkerPool {
+    workers: Mutex<HashMap<PathBuf, WorkerState>>,
+}
+
+struct WorkerState {
+    capabilities: WorkerCapabilities,
+    // None for the workers given up front, which send no heartbeats
+    last_seen: Option<Instant>,
+    // the keys it was sent, least recently used first, as it keeps them
+    sent: Vec<Fingerprint>,
+}
+
+impl WorkerState {
+    fn holds(&self, key: &Fingerprint) -> bool {
+        self.capabilities.keys.contains(key) || self.sent.contains(key)
+    }
+
+    // the key it evicts when sent another, if it has to
+    fn evicts(&self) -> Option<&Fingerprint> {
+        if self.sent.len() < self.capabilities.key_slots {
+            None
+        } else {
+            self.sent.first()
+        }
+    }
+
+    fn used(&mut self, key: &Fingerprint) {
+        if self.capabilities.keys.contains(key) {
+            return;
+        }
+        self.sent.retain(|fp| fp != key);
+        self.sent.push(*key);
+        if self.sent.len() > self.capabilities.key_slots {
+            self.sent.remove(0);
+        }
+    }
+}
+
+impl WorkerPool {
+    fn add(&self, socket: PathBuf, capabilities: WorkerCapabilities, last_seen: Option<Instant>) {
+        let state = WorkerState {
+            capabilities,
+            last_seen,
+            sent: vec![],
+        };
+        self.workers.lock().unwrap().insert(socket, state);
+    }
+
+    fn heartbeat(&self, socket: &Path) {
+        if let Some(state) = self.workers.lock().unwrap().get_mut(socket) {
+            state.last_seen = Some(Instant::now());
+        }
+    }
+
+    fn remove(&self, socket: &Path) {
+        self.workers.lock().unwrap().remove(socket);
+    }
+
+    // the workers given up front, and the registered workers heard from
+    // recently, those with the most threads first, as they are dealt the
+    // first shards
+    pub fn live(&self) -> Vec<PathBuf> {
+        let deadline = HEARTBEAT_INTERVAL * MISSED_HEARTBEATS;
+        let workers = self.workers.lock().unwrap();
+        let mut live: Vec<(usize, PathBuf)> = workers
+            .iter()
+            .filter(|(_, state)| state.last_seen.map_or(true, |seen| seen.elapsed() < deadline))
+            .map(|(socket, state)| (state.capabilities.threads, socket.clone()))
+            .collect();
+        live.sort_by(|a, b| b.cmp(a));
+        live.into_iter().map(|(_, socket)| socket).collect()
+    }
+
+    // the workers of live that shards matched with key are dealt to, along
+    // with whether key is to be sent to them: those holding key, or else the
+    // one worker it is sent to. That is a worker with a free slot if there is
+    // one, or else the worker whose least recently used key, which it evicts
+    // for key, is held by most other live workers (so that the evicted key
+    // need not be sent again).
+    pub fn place(&self, key: &Fingerprint, live: &[PathBuf]) -> Result<Vec<(PathBuf, bool)>> {
+        let mut workers = self.workers.lock().unwrap();
+        let holders: Vec<(PathBuf, bool)> = live
+            .iter()
+            .filter(|socket| workers.get(*socket).map_or(false, |state| state.holds(key)))
+            .map(|socket| (socket.clone(), false))
+            .collect();
+        if !holders.is_empty() {
+            for (socket, _) in &holders {
+                workers.get_mut(socket).unwrap().used(key);
+            }
+            return Ok(holders);
+        }
+
+        let held_by = |fp: &Fingerprint| {
+            live.iter()
+                .filter(|socket| workers.get(*socket).map_or(false, |state| state.holds(fp)))
+                .count()
+        };
+        let chosen = live
+            .iter()
+            .filter(|socket| workers.get(*socket).map_or(false, |state| state.capabilities.key_slots > 0))
+            .min_by_key(|socket| match workers[*socket].evicts() {
+                None => (false, 0),
+                Some(evicted) => (true, usize::MAX - held_by(evicted)),
+            })
+            .ok_or_else(|| anyhow!("no live worker holds key {} or has a slot to be sent it", key))?
+            .clone();
+        let state = workers.get_mut(&chosen).unwrap();
+        if let Some(evicted) = state.evicts() {
+            debug!("worker {:?} evicts key {} for key {}", chosen, evicted, key);
+        }
+        state.used(key);
+        Ok(vec![(chosen, true)])
+    }
+}
+
+impl Coordinator {
+    // shards are matched on the workers given up front, which hold sk, and
+    // on those that register later on (see spawn_registry)
+    pub fn new(workers: Vec<PathBuf>, shard_len: usize, sk: ServerKey) -> Result<Self> {
+        if shard_len == 0 {
+            return Err(anyhow!("shards must hold at least one character"));
+        }
+        let keys = ServerKeys::new(WarmKey::cold(sk), 0, false)?;
+        let pool = WorkerPool::default();
+        for socket in workers {
+            let capabilities = WorkerCapabilities {
+                threads: 0,
+                gpu: false,
+                keys: vec![keys.own().0],
+                key_slots: 0,
+            };
+            pool.add(socket, capabilities, None);
+        }
+        Ok(Self {
+            workers: Arc::new(pool),
+            shard_len,
+            keys,
+        })
+    }
+
+    // keeps up to slots of the keys of clients it is sent, like
+    // serve::UdsServer::with_key_slots
+    pub fn with_key_slots(mut self, slots: usize) -> Self {
+        self.keys = self.keys.with_slots(slots);
+        self
+    }
+
+    pub fn workers(&self) -> &WorkerPool {
+        &self.workers
+    }
+
+    pub(crate) fn keys(&self) -> &ServerKeys {
+        &self.keys
+    }
+
+    // accepts worker registrations on a socket at path, on a thread of its
+    // own, following every worker on a thread of its own
+    pub fn spawn_registry<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        let _ = std::fs::remove_file(path);
+        let listener = UnixListener::bind(path)
+            .map_err(|e| anyhow!("failed to bind registry socket {:?}: {}", path, e))?;
+        info!("accepting workers on {:?}", path);
+        let (workers, key) = (self.workers.clone(), self.keys.own().0);
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        warn!("failed to accept worker: {}", e);
+                        continue;
+                    }
+                };
+                let workers = workers.clone();
+                std::thread::spawn(move || {
+                    if let Err(e) = follow_worker(stream, &workers, key) {
+                        warn!("worker connection closed: {}", e);
+                    }
+                });
+            }
+        });
+        Ok(())
+    }
+
+    pub fn has_match(
+        &self,
+        content: &StringCiphertext,
+        pattern: &str,
+        opts: &MatchOptions,
+    ) -> Result<RadixCiphertext> {
+        self.has_match_with_key(content, pattern, opts, self.keys.own())
+    }
+
+    // like has_match, for the client of key
+    pub fn has_match_with_key(
+        &self,
+        content: &StringCiphertext,
+        pattern: &str,
+        opts: &MatchOptions,
+        key: &(Fingerprint, WarmKey),
+    ) -> Result<RadixCiphertext> {
+        check_content_len(content.len(), opts.max_content_len)?;
+        let mut pending = self.shard_ranges(content.len(), pattern, opts)?;
+        debug!("matching {} on {} shards", pattern, pending.len());
+
+        let mut verdicts = vec![];
+        while !pending.is_empty() {
+            let live = self.workers.live();
+            if live.is_empty() {
+                return Err(anyhow!("no live workers to match {} shards on", pending.len()));
+            }
+            let placed = self.workers.place(&key.0, &live)?;
+            // the shards are dealt to the workers in turn, every worker
+            // matches its shards one after the other on a connection of its
+            // own
+            let mut per_worker: Vec<Vec<(usize, usize)>> = vec![vec![]; placed.len()];
+            for (i, range) in pending.drain(..).enumerate() {
+                per_worker[i % placed.len()].push(range);
+            }
+            let rounds: Vec<Result<Dispatched>> = std::thread::scope(|scope| {
+                let handles: Vec<_> = placed
+                    .iter()
+                    .zip(per_worker)
+                    .filter(|(_, shards)| !shards.is_empty())
+                    .map(|((worker, send_key), shards)| {
+                        let send_key = *send_key;
+                        scope.spawn(move || dispatch(worker, shards, content, pattern, opts, key, send_key))
+                    })
+                    .collect();
+                handles
+                    .into_iter()
+                    .map(|handle| handle.join().unwrap_or_else(|_| Err(anyhow!("a worker thread panicked"))))
+                    .collect()
+            });
+            for round in rounds {
+                let dispatched = round?;
+                verdicts.extend(dispatched.verdicts);
+                if let Some((worker, e)) = dispatched.failed {
+                    warn!(
+                        "worker {:?} failed, dealing its {} shards again: {}",
+                        worker,
+                        dispatched.unmatched.len(),
+                        e
+                    );
+                    self.workers.remove(&worker);
+                    pending.extend(dispatched.unmatched);
+                }
+            }
+        }
+        Ok(fhe_any(key.1.server_key(), &verdicts))
+    }
+
+    // the shards of content_len characters, as (start, end)
+    fn shard_ranges(&self, content_len: usize, pattern: &str, opts: &MatchOptions) -> Result<Vec<(usize, usize)>> {
+        if content_len <= self.shard_len {
+            return Ok(vec![(0, content_len)]);
+        }
+        let re = parse_with(pattern, opts.precedence, opts.parse_limits)?;
+        if re.contains_sof() || re.contains_eof() {
+            return Err(anyhow!(
+                "cannot shard {}: its anchors only hold at the ends of the whole content",
+                pattern
+            ));
+        }
+        let max_width = re.max_width().ok_or_else(|| {
+            anyhow!(
+                "cannot shard {}: its matches are unbounded, bound its repetitions (e.g. .{{0,32}} rather than .*)",
+                pattern
+            )
+        })?;
+        let overlap = max_width.saturating_sub(1);
+        if overlap >= self.shard_len {
+            return Err(anyhow!(
+                "cannot shard {}: its matches of up to {} characters do not fit in shards of {}",
+                pattern,
+                max_width,
+                self.shard_len
+            ));
+        }
+
+        let mut ranges = vec![];
+        let mut start = 0;
+        loop {
+            let end = std::cmp::min(start + self.shard_len, content_len);
+            ranges.push((start, end));
+            if end == content_len {
+                return Ok(ranges);
+            }
+            start = end - overlap;
+        }
+    }
+}
+
+// what a worker did with its shards: the verdicts of the shards it matched,
+// and when it failed, why and the shards it left unmatched
+struct Dispatched {
+    verdicts: Vec<RadixCiphertext>,
+    failed: Option<(PathBuf, anyhow::Error)>,
+    unmatched: Vec<(usize, usize)>,
+}
+
+// matches shards on worker with key, sent along with the first shard when
+// send_key. A worker that can't be reached or drops the connection failed,
+// its shards can be dealt to another; a worker that answers with an error
+// (e.g. the pattern does not parse) fails the request.
+fn dispatch(
+    worker: &Path,
+    shards: Vec<(usize, usize)>,
+    content: &StringCiphertext,
+    pattern: &str,
+    opts: &MatchOptions,
+    key: &(Fingerprint, WarmKey),
+    mut send_key: bool,
+) -> Result<Dispatched> {
+    let mut dispatched = Dispatched {
+        verdicts: vec![],
+        failed: None,
+        unmatched: vec![],
+    };
+    let mut client = match UdsClient::connect(worker) {
+        Ok(client) => client,
+        Err(e) => {
+            dispatched.failed = Some((worker.to_path_buf(), e));
+            dispatched.unmatched = shards;
+            return Ok(dispatched);
+        }
+    };
+    let mut shards = shards.into_iter();
+    let mut next = shards.next();
+    while let Some((start, end)) = next {
+        let server_key = if send_key {
+            KeyRef::Sent(Box::new(key.1.server_key().clone()))
+        } else {
+            KeyRef::Held(key.0)
+        };
+        let request = MatchRequest {
+            pattern: pattern.to_string(),
+            content: shard(content, start, end)?,
+            opts: opts.clone(),
+            server_key: Some(server_key),
+        };
+        match client.request(&request) {
+            Ok(MatchResponse::Verdict(ct_res)) => {
+                dispatched.verdicts.push(ct_res);
+                send_key = false;
+                next = shards.next();
+            }
+            // the worker no longer holds the key (e.g. it was restarted), it
+            // is sent along with the shard again
+            Ok(MatchResponse::UnknownKey(_)) if !send_key => send_key = true,
+            Ok(MatchResponse::UnknownKey(fingerprint)) => {
+                return Err(anyhow!("worker {:?} does not take key {}", worker, fingerprint))
+            }
+            Ok(MatchResponse::Error(e)) => return Err(anyhow!("worker {:?} failed to match: {}", worker, e)),
+            Err(e) => {
+                dispatched.failed = Some((worker.to_path_buf(), e));
+                dispatched.unmatched = std::iter::once((start, end)).chain(shards).collect();
+                break;
+            }
+        }
+    }
+    Ok(dispatched)
+}
+
+// registers the worker, then records its heartbeats until it disconnects
+fn follow_worker(mut stream: UnixStream, workers: &WorkerPool, key: Fingerprint) -> Result<()> {
+    let (socket, capabilities) = match read_message(&mut stream)? {
+        Some(WorkerMessage::Register { socket, capabilities }) => (socket, capabilities),
+        Some(WorkerMessage::Heartbeat) => return Err(anyhow!("heartbeat before registration")),
+        None => return Ok(()),
+    };
+    if !capabilities.keys.contains(&key) && capabilities.key_slots == 0 {
+        let response = RegistrationResponse::Rejected(format!(
+            "the worker neither holds key {} nor has a slot to be sent it",
+            key
+        ));
+        return write_message(&mut stream, &response);
+    }
+    info!(
+        "worker {:?} registered ({} threads, {} key slots{})",
+        socket,
+        capabilities.threads,
+        capabilities.key_slots,
+        if capabilities.gpu { ", gpu" } else { "" }
+    );
+    workers.add(socket.clone(), capabilities, Some(Instant::now()));
+    write_message(&mut stream, &RegistrationResponse::Accepted)?;
+    while let Some(WorkerMessage::Heartbeat) = read_message(&mut stream)? {
+        workers.heartbeat(&socket);
+    }
+    Ok(())
+}
+
+// the worker side: registers the worker serving on socket with the registry
+// of a coordinator, and sends heartbeats every interval for as long as the
+// coordinator is there
+pub fn register_worker<P: AsRef<Path>, Q: AsRef<Path>>(
+    registry: P,
+    socket: Q,
+    capabilities: WorkerCapabilities,
+    interval: Duration,
+) -> Result<()> {
+    let registry = registry.as_ref();
+    let mut stream = UnixStream::connect(registry)
+        .map_err(|e| anyhow!("failed to connect to registry {:?}: {}", registry, e))?;
+    let register = WorkerMessage::Register {
+        socket: socket.as_ref().to_path_buf(),
+        capabilities,
+    };
+    write_message(&mut stream, &register)?;
+    match read_message(&mut stream)? {
+        Some(RegistrationResponse::Accepted) => (),
+        Some(RegistrationResponse::Rejected(e)) => return Err(anyhow!("registration rejected: {}", e)),
+        None => return Err(anyhow!("the registry closed the connection")),
+    }
+    loop {
+        std::thread::sleep(interval);
+        write_message(&mut stream, &WorkerMessage::Heartbeat)?;
+    }
+}
+
+// the characters of content from start to end, with the padding among them
+// (which trails the content, so is in the last shards)
+fn shard(content: &StringCiphertext, start: usize, end: usize) -> Result<StringCiphertext> {
+    let chars = StringCiphertext::new(content[start..end].to_vec(), content.encoding(), content.params());
+    let padding = content.padding().saturating_sub(content.len() - end).min(end - start);
+    chars.with_padding(padding)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::regex::engine::tests::{encrypt_trivial, KEYS};
+    use crate::regex::engine::MatchOptions;
+    use crate::regex::serve::UdsServer;
+    use crate::regex::ciphertext::fingerprint;
+    use crate::regex::shard::{register_worker, Coordinator, WorkerCapabilities, WorkerPool};
+    use crate::regex::warmup::warmup;
+    use crate::regex::test_keys::temp_path;
+    use std::path::PathBuf;
+    use std::time::Duration;
+    use test_case::test_case;
+
+    fn socket() -> PathBuf {
+        temp_path("shard")
+    }
+
+    // every test case starts workers of its own
+    fn workers(n: usize) -> Vec<PathBuf> {
+        (0..n)
+            .map(|_| {
+                let path = socket();
+                let server = UdsServer::bind(&path, warmup(&KEYS.1)).unwrap();
+                std::thread::spawn(move || server.run());
+                path
+            })
+            .collect()
+    }
+
+    #[test_case("xxxxxxabxxx", "/ab/", 1 ; "within a shard")]
+    #[test_case("xxxxabxxxxx", "/ab/", 1 ; "across shards")]
+    #[test_case("xxxxxxxxxxx", "/ab/", 0 ; "no match")]
+    #[test_case("xxxxxxxxxab", "/a?b/", 1 ; "in the last shard")]
+    fn test_sharded_match(content: &str, pattern: &str, exp: u64) {
+        let coordinator = Coordinator::new(workers(2), 5, KEYS.1.clone()).unwrap();
+        let ct_res = coordinator
+            .has_match(&encrypt_trivial(content), pattern, &MatchOptions::default())
+            .unwrap();
+        assert_eq!(exp, KEYS.0.decrypt(&ct_res));
+    }
+
+    #[test_case("/^ab/" ; "anchored")]
+    #[test_case("/a.*b/" ; "unbounded")]
+    #[test_case("/a.{0,8}b/" ; "longer than a shard")]
+    fn test_unshardable_pattern(pattern: &str) {
+        let coordinator = Coordinator::new(vec![PathBuf::from("unused.sock")], 5, KEYS.1.clone()).unwrap();
+        let opts = MatchOptions::default();
+        assert!(coordinator.shard_ranges(20, pattern, &opts).is_err());
+        // content that fits in one shard is not split
+        assert_eq!(vec![(0, 5)], coordinator.shard_ranges(5, pattern, &opts).unwrap());
+    }
+
+    #[test]
+    fn test_shard_ranges() {
+        let coordinator = Coordinator::new(vec![PathBuf::from("unused.sock")], 4, KEYS.1.clone()).unwrap();
+        let ranges = coordinator.shard_ranges(10, "/abc/", &MatchOptions::default()).unwrap();
+        assert_eq!(vec![(0, 4), (2, 6), (4, 8), (6, 10)], ranges);
+    }
+
+    #[test]
+    fn test_dead_worker_rescheduled() {
+        let mut sockets = workers(1);
+        sockets.push(socket());
+        let coordinator = Coordinator::new(sockets, 5, KEYS.1.clone()).unwrap();
+        let ct_res = coordinator
+            .has_match(&encrypt_trivial("xxxxxxxxxab"), "/ab/", &MatchOptions::default())
+            .unwrap();
+        assert_eq!(1, KEYS.0.decrypt(&ct_res));
+        assert_eq!(1, coordinator.workers().live().len());
+    }
+
+    #[test]
+    fn test_registered_worker() {
+        let coordinator = Coordinator::new(vec![], 5, KEYS.1.clone()).unwrap();
+        let content = encrypt_trivial("xxxxabxxxxx");
+        let opts = MatchOptions::default();
+        assert!(coordinator.has_match(&content, "/ab/", &opts).is_err());
+
+        let registry = socket();
+        coordinator.spawn_registry(&registry).unwrap();
+        let worker = workers(1).remove(0);
+        let capabilities = WorkerCapabilities::of(&KEYS.1, 0).unwrap();
+        let (r, w) = (registry.clone(), worker.clone());
+        std::thread::spawn(move || register_worker(r, w, capabilities, Duration::from_millis(50)));
+        while coordinator.workers().live().is_empty() {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(vec![worker], coordinator.workers().live());
+        let ct_res = coordinator.has_match(&content, "/ab/", &opts).unwrap();
+        assert_eq!(1, KEYS.0.decrypt(&ct_res));
+
+        // a worker without the key of the coordinator is turned away
+        let capabilities = WorkerCapabilities {
+            keys: vec![],
+            ..WorkerCapabilities::of(&KEYS.1, 0).unwrap()
+        };
+        assert!(register_worker(registry, socket(), capabilities, Duration::from_millis(50)).is_err());
+    }
+
+    #[test]
+    fn test_place_by_key() {
+        let [a, b, c, d] = ["a", "b", "c", "d"].map(|s| fingerprint(&encrypt_trivial(s)).unwrap());
+        let pool = WorkerPool::default();
+        let (w1, w2, w3) = (socket(), socket(), socket());
+        for (worker, keys, key_slots) in [(&w1, vec![a], 1), (&w2, vec![], 1), (&w3, vec![b], 0)] {
+            let capabilities = WorkerCapabilities {
+                threads: 1,
+                gpu: false,
+                keys,
+                key_slots,
+            };
+            pool.add(worker.clone(), capabilities, None);
+        }
+        let live = [w1.clone(), w2.clone(), w3.clone()];
+
+        // a key is dealt to its holders, a key no live worker holds is sent to
+        // a worker with a free slot
+        assert_eq!(vec![(w1.clone(), false)], pool.place(&a, &live).unwrap());
+        assert_eq!(vec![(w2.clone(), true)], pool.place(&c, &[w2.clone()]).unwrap());
+        assert_eq!(vec![(w1.clone(), true)], pool.place(&b, &[w1.clone(), w2.clone()]).unwrap());
+        assert_eq!(vec![(w1.clone(), false)], pool.place(&b, &live[..2]).unwrap());
+        // w1 evicts b, which w3 holds as well, rather than w2 evicting the
+        // only copy of c
+        assert_eq!(vec![(w1.clone(), true)], pool.place(&d, &live).unwrap());
+        assert_eq!(vec![(w3.clone(), false)], pool.place(&b, &live).unwrap());
+        assert_eq!(vec![(w2, false)], pool.place(&c, &live).unwrap());
+        assert!(pool.place(&d, &[w3]).is_err());
+    }
+}
@@ -0,0 +1,191 @@
+use anyhow::{anyhow, Result};
+use memmap2::Mmap;
+use std::fs::File;
+use std::path::Path;
+use tfhe::integer::RadixCiphertext;
+
+use crate::regex::ciphertext::{check_content_len, Header, StringCiphertext, DEFAULT_MAX_CONTENT_LEN, HEADER_SIZE};
+
+// Encrypted content in a file written by client::encrypt_content_to_writer
+// (or a serialized StringCiphertext), memory-mapped instead of read. The
+// ciphertext of every character serializes to the same number of bytes, so
+// the ciphertext of any character can be located and deserialized on its own,
+// without the whole content ever being held in memory.
+pub struct MappedContent {
+    mmap: Mmap,
+    header: Header,
+    len: usize,
+    ct_size: usize,
+}
+
+impl MappedContent {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let file = File::open(path.as_ref())
+            .map_err(|e| anyhow!("failed to open encrypted content {:?}: {}", path.as_ref(), e))?;
+        // safety: the file must not be modified while it is mapped, content
+        // files are written once by the client and only read afterwards
+        let mmap = unsafe { Mmap::map(&file) }
+            .map_err(|e| anyhow!("failed to map encrypted content {:?}: {}", path.as_ref(), e))?;
+
+        if mmap.len() < HEADER_SIZE {
+            return Err(anyhow!("encrypted content {:?} is truncated", path.as_ref()));
+        }
+        let header: Header = bincode::deserialize(&mmap[..HEADER_SIZE])
+            .map_err(|e| anyhow!("failed to read encrypted content: {}", e))?;
+        // the header is as untrusted as the rest of the file, its length is
+        // bounded before anything is computed from it
+        let len = usize::try_from(header.len).unwrap_or(usize::MAX);
+        check_content_len(len, DEFAULT_MAX_CONTENT_LEN)?;
+        if header.padding > header.len {
+            return Err(anyhow!(
+                "encrypted content {:?} has more padding than characters",
+                path.as_ref()
+            ));
+        }
+        let ct_size = if len == 0 {
+            0
+        } else {
+            let first: RadixCiphertext = bincode::deserialize(&mmap[HEADER_SIZE..])
+                .map_err(|e| anyhow!("failed to read encrypted content: {}", e))?;
+            bincode::serialized_size(&first)? as usize
+        };
+        let expected = len
+            .checked_mul(ct_size)
+            .and_then(|size| size.checked_add(HEADER_SIZE))
+            .ok_or_else(|| anyhow!("encrypted content {:?} claims too many characters", path.as_ref()))?;
+        if mmap.len() != expected {
+            return Err(anyhow!(
+                "encrypted content {:?} has {} bytes, expected {} for {} characters",
+                path.as_ref(),
+                mmap.len(),
+                expected,
+                len
+            ));
+        }
+        Ok(Self {
+            mmap,
+            header,
+            len,
+            ct_size,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub(crate) fn header(&self) -> Header {
+        self.header
+    }
+
+    // deserializes the ciphertext of the character at position i
+    pub fn get(&self, i: usize) -> Result<RadixCiphertext> {
+        if i >= self.len {
+            return Err(anyhow!("position {} is out of bounds ({} characters)", i, self.len));
+        }
+        let from = HEADER_SIZE + i * self.ct_size;
+        bincode::deserialize(&self.mmap[from..from + self.ct_size])
+            .map_err(|e| anyhow!("failed to read encrypted character {}: {}", i, e))
+    }
+
+    // deserializes the ciphertexts of the characters in [from, to), the
+    // padding characters in the window stay marked as padding
+    pub fn window(&self, from: usize, to: usize) -> Result<StringCiphertext> {
+        let to = to.min(self.len);
+        let chars = (from..to).map(|i| self.get(i)).collect::<Result<Vec<_>>>()?;
+        let content_len = self.len - self.header.padding as usize;
+        let header = Header {
+            padding: to.saturating_sub(content_len.max(from)) as u64,
+            len: chars.len() as u64,
+            ..self.header
+        };
+        Ok(StringCiphertext::from_header(header, chars))
+    }
+
+    // deserializes all of the content, one character at a time
+    pub fn iter(&self) -> impl Iterator<Item = Result<RadixCiphertext>> + '_ {
+        (0..self.len).map(|i| self.get(i))
+    }
+
+    pub fn load(&self) -> Result<StringCiphertext> {
+        let chars = self.iter().collect::<Result<Vec<_>>>()?;
+        Ok(StringCiphertext::from_header(self.header, chars))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::regex::ciphertext::{ContentTooLong, DEFAULT_MAX_CONTENT_LEN, HEADER_SIZE};
+    use crate::regex::client::encrypt_content_to_writer;
+    use crate::regex::storage::MappedContent;
+    use crate::regex::test_keys::{temp_path, test_client_key};
+    use test_case::test_case;
+
+    #[test]
+    fn test_lazy_characters() {
+        let client_key = test_client_key();
+        let path = temp_path("mapped_content");
+        let file = std::fs::File::create(&path).unwrap();
+        encrypt_content_to_writer(&client_key, "abcd", file, |_| ()).unwrap();
+
+        let content = MappedContent::open(&path).unwrap();
+        assert_eq!(4, content.len());
+        assert_eq!(b'c' as u64, client_key.decrypt(&content.get(2).unwrap()));
+        assert!(content.get(4).is_err());
+
+        let window: Vec<u64> = content
+            .window(1, 10)
+            .unwrap()
+            .iter()
+            .map(|ct| client_key.decrypt(ct))
+            .collect();
+        assert_eq!(vec![b'b' as u64, b'c' as u64, b'd' as u64], window);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_empty_content() {
+        let client_key = test_client_key();
+        let path = temp_path("mapped_empty");
+        let file = std::fs::File::create(&path).unwrap();
+        encrypt_content_to_writer(&client_key, "", file, |_| ()).unwrap();
+
+        let content = MappedContent::open(&path).unwrap();
+        assert!(content.is_empty());
+        assert!(content.load().unwrap().is_empty());
+        assert!(content.window(0, 1).unwrap().is_empty());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_truncated_rejected() {
+        let client_key = test_client_key();
+        let path = temp_path("mapped_truncated");
+        let mut written = vec![];
+        encrypt_content_to_writer(&client_key, "ab", &mut written, |_| ()).unwrap();
+        std::fs::write(&path, &written[..written.len() - 1]).unwrap();
+
+        assert!(MappedContent::open(&path).is_err());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    // the length in the header is the last field of it
+    #[test_case(u64::MAX ; "overflowing the size")]
+    #[test_case(DEFAULT_MAX_CONTENT_LEN as u64 + 1 ; "over the maximum")]
+    fn test_forged_length_rejected(len: u64) {
+        let client_key = test_client_key();
+        let path = temp_path("mapped_forged");
+        let mut written = vec![];
+        encrypt_content_to_writer(&client_key, "ab", &mut written, |_| ()).unwrap();
+        written[HEADER_SIZE - 8..HEADER_SIZE].copy_from_slice(&len.to_le_bytes());
+        std::fs::write(&path, &written).unwrap();
+
+        let err = MappedContent::open(&path).err().unwrap();
+        assert!(err.downcast_ref::<ContentTooLong>().is_some());
+        std::fs::remove_file(path).unwrap();
+    }
+}
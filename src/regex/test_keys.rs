@@ -0,0 +1,188 @@
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+#[cfg(test)]
+use std::path::PathBuf;
+#[cfg(test)]
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tfhe::integer::{RadixClientKey, ServerKey};
+use tfhe::shortint::parameters::{Parameters, PARAM_MESSAGE_2_CARRY_2};
+
+use crate::regex::ciphertext::{
+    deserialize_compressed_server_key, gen_compressed_server_key, gen_keys, gen_server_key,
+    serialize_compressed_server_key, NUM_BLOCKS,
+};
+use crate::regex::client::{load_client_key, save_client_key};
+
+// bump whenever the layout of the fixtures changes
+const TEST_KEYS_VERSION: u32 = 1;
+
+const CLIENT_KEY_FILE: &str = "client_key";
+const SERVER_KEY_FILE: &str = "server_key.compressed";
+const MANIFEST_FILE: &str = "manifest.json";
+
+// where the fixtures are expected, relative to the crate root (which is where
+// cargo runs tests and benches from)
+const TEST_KEYS_DIR: &str = "test_data";
+
+// The keys the tests run with (test_data/ in the repository): a client key, the
+// compressed server key that belongs to it and a manifest recording the
+// version of the fixtures and the parameters the keys were generated with, so
+// that fixtures that no longer match the parameters the crate is built around
+// are detected instead of failing tests in obscure ways.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+struct Manifest {
+    version: u32,
+    num_blocks: usize,
+    message_modulus: usize,
+    carry_modulus: usize,
+    lwe_dimension: usize,
+    glwe_dimension: usize,
+    polynomial_size: usize,
+}
+
+impl Manifest {
+    fn current() -> Self {
+        Self::of(&PARAM_MESSAGE_2_CARRY_2)
+    }
+
+    fn of(params: &Parameters) -> Self {
+        Self {
+            version: TEST_KEYS_VERSION,
+            num_blocks: NUM_BLOCKS,
+            message_modulus: params.message_modulus.0,
+            carry_modulus: params.carry_modulus.0,
+            lwe_dimension: params.lwe_dimension.0,
+            glwe_dimension: params.glwe_dimension.0,
+            polynomial_size: params.polynomial_size.0,
+        }
+    }
+}
+
+// generates fresh fixtures in dir, replacing any that were there
+pub fn generate_test_keys<P: AsRef<Path>>(dir: P) -> Result<()> {
+    let dir = dir.as_ref();
+    std::fs::create_dir_all(dir)
+        .map_err(|e| anyhow!("failed to create test keys directory {:?}: {}", dir, e))?;
+
+    let (client_key, _) = gen_keys();
+    save_client_key(&client_key, dir.join(CLIENT_KEY_FILE))?;
+
+    let server_key = serialize_compressed_server_key(&gen_compressed_server_key(&client_key))?;
+    std::fs::write(dir.join(SERVER_KEY_FILE), server_key)
+        .map_err(|e| anyhow!("failed to write server key to {:?}: {}", dir, e))?;
+
+    let manifest = serde_json::to_string_pretty(&Manifest::current())?;
+    std::fs::write(dir.join(MANIFEST_FILE), manifest + "\n")
+        .map_err(|e| anyhow!("failed to write test keys manifest to {:?}: {}", dir, e))
+}
+
+// checks that the fixtures in dir are complete, of the current version and
+// parameters, and that the server key belongs to the client key
+pub fn verify_test_keys<P: AsRef<Path>>(dir: P) -> Result<()> {
+    let dir = dir.as_ref();
+    let manifest = std::fs::read_to_string(dir.join(MANIFEST_FILE))
+        .map_err(|e| anyhow!("failed to read test keys manifest from {:?}: {}", dir, e))?;
+    let manifest: Manifest = serde_json::from_str(&manifest)
+        .map_err(|e| anyhow!("failed to parse test keys manifest: {}", e))?;
+    if manifest != Manifest::current() {
+        return Err(anyhow!(
+            "test keys in {:?} are outdated ({:?}, expected {:?}), regenerate them with `fhe-regex test-keys generate`",
+            dir,
+            manifest,
+            Manifest::current()
+        ));
+    }
+
+    if !dir.join(SERVER_KEY_FILE).exists() {
+        return Err(anyhow!("test keys in {:?} lack a server key", dir));
+    }
+    let (client_key, server_key) = load_test_keys(dir)?;
+    let mut ct_a = client_key.encrypt(1);
+    let mut ct_b = client_key.encrypt(2);
+    let sum: u64 = client_key.decrypt(&server_key.smart_add(&mut ct_a, &mut ct_b));
+    if sum != 3 {
+        return Err(anyhow!("server key in {:?} does not belong to the client key", dir));
+    }
+    Ok(())
+}
+
+// loads the fixtures in dir. A missing server key is derived from the client
+// key instead, which is slow but keeps older fixtures usable.
+pub fn load_test_keys<P: AsRef<Path>>(dir: P) -> Result<(RadixClientKey, ServerKey)> {
+    let dir = dir.as_ref();
+    let client_key = load_client_key(dir.join(CLIENT_KEY_FILE))?;
+    let server_key = match std::fs::read(dir.join(SERVER_KEY_FILE)) {
+        Ok(serialized) => deserialize_compressed_server_key(&serialized)?,
+        Err(_) => {
+            warn!("no server key in {:?}, deriving it from the client key", dir);
+            gen_server_key(&client_key)
+        }
+    };
+    Ok((client_key, server_key))
+}
+
+// The keys for tests and benches: the fixtures in test_data, or ephemeral keys
+// generated on the spot when the fixtures are missing (e.g. in a fresh clone
+// that did not fetch them), so that the tests can run anyway, only slower.
+pub fn test_keys() -> (RadixClientKey, ServerKey) {
+    match load_test_keys(TEST_KEYS_DIR) {
+        Ok(keys) => keys,
+        Err(e) => {
+            warn_ephemeral(e);
+            gen_keys()
+        }
+    }
+}
+
+// like test_keys, for tests that only need the client key
+pub fn test_client_key() -> RadixClientKey {
+    match load_client_key(Path::new(TEST_KEYS_DIR).join(CLIENT_KEY_FILE)) {
+        Ok(client_key) => client_key,
+        Err(e) => {
+            warn_ephemeral(e);
+            RadixClientKey::new(PARAM_MESSAGE_2_CARRY_2, NUM_BLOCKS)
+        }
+    }
+}
+
+#[cfg(test)]
+static TEMP_PATHS: AtomicUsize = AtomicUsize::new(0);
+
+// a path under the temp dir of its own per call, also across concurrent test
+// runs, for tests that write files or bind sockets
+#[cfg(test)]
+pub(crate) fn temp_path(name: &str) -> PathBuf {
+    let i = TEMP_PATHS.fetch_add(1, Ordering::Relaxed);
+    std::env::temp_dir().join(format!("fhe_regex_test_{}_{}_{}", name, std::process::id(), i))
+}
+
+fn warn_ephemeral(e: anyhow::Error) {
+    // printed as well as logged, tests and benches rarely set up a logger
+    let msg = format!(
+        "{}; falling back to ephemeral keys, run `fhe-regex test-keys generate` to create the fixtures",
+        e
+    );
+    eprintln!("warning: {}", msg);
+    warn!("{}", msg);
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::regex::test_keys::{generate_test_keys, temp_path, verify_test_keys, Manifest, MANIFEST_FILE};
+
+    #[test]
+    fn test_generate_verify() {
+        let dir = temp_path("keys");
+        generate_test_keys(&dir).unwrap();
+        verify_test_keys(&dir).unwrap();
+
+        let outdated = Manifest {
+            version: 0,
+            ..Manifest::current()
+        };
+        std::fs::write(dir.join(MANIFEST_FILE), serde_json::to_string(&outdated).unwrap()).unwrap();
+        assert!(verify_test_keys(&dir).is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
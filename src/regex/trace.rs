@@ -0,0 +1,245 @@
+use anyhow::{anyhow, Result};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tfhe::integer::RadixCiphertext;
+
+use crate::regex::execution::Executed;
+
+// A machine readable trace of the ciphertext operations of executions, one
+// json object per line. Every node gets an id the first time it is seen, which
+// later lines refer to as inputs. Content positions and constants get a line
+// of their own when they are first seen, so that every input id is defined
+// before it is used. Cloning is cheap, clones write to the same trace.
+//
+// For debugging, a trace can also capture the result of every operation by
+// id (see capture_results), for the client to decrypt and compare against the
+// plaintext reference (see explain::TracedRun). This reveals nothing to the
+// server, but sends the client every intermediate result of the plan.
+#[derive(Clone)]
+pub struct OpTrace {
+    inner: Arc<Mutex<Tracer>>,
+}
+
+struct Tracer {
+    writer: Box<dyn Write + Send>,
+    ids: HashMap<Executed, usize>,
+    results: Option<BTreeMap<usize, RadixCiphertext>>,
+    failed: bool,
+}
+
+#[derive(Serialize)]
+struct OpRecord {
+    id: usize,
+    kind: &'static str,
+    inputs: Vec<usize>,
+    duration_us: u64,
+    cache_hit: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    at: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    c: Option<u8>,
+    // the members of the class of in_class operations
+    #[serde(skip_serializing_if = "Option::is_none")]
+    class: Option<Vec<u8>>,
+}
+
+impl OpTrace {
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let file = File::create(path.as_ref())
+            .map_err(|e| anyhow!("failed to create op trace {:?}: {}", path.as_ref(), e))?;
+        Ok(Self::to_writer(BufWriter::new(file)))
+    }
+
+    pub fn to_writer<W: Write + Send + 'static>(writer: W) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(Tracer {
+                writer: Box::new(writer),
+                ids: HashMap::new(),
+                results: None,
+                failed: false,
+            })),
+        }
+    }
+
+    pub fn flush(&self) -> Result<()> {
+        self.inner
+            .lock()
+            .unwrap()
+            .writer
+            .flush()
+            .map_err(|e| anyhow!("failed to write op trace: {}", e))
+    }
+
+    // keeps the result of every operation recorded from now on
+    pub fn capture_results(&self) {
+        let mut tracer = self.inner.lock().unwrap();
+        if tracer.results.is_none() {
+            tracer.results = Some(BTreeMap::new());
+        }
+    }
+
+    // the captured results by operation id
+    pub fn results(&self) -> BTreeMap<usize, RadixCiphertext> {
+        self.inner.lock().unwrap().results.clone().unwrap_or_default()
+    }
+
+    pub fn save_results<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let serialized = bincode::serialize(&self.results())?;
+        std::fs::write(path.as_ref(), serialized)
+            .map_err(|e| anyhow!("failed to write op results {:?}: {}", path.as_ref(), e))
+    }
+
+    // records an operation on node resulting in res: its evaluation taking
+    // duration, or a hit on the result of an earlier evaluation
+    pub(crate) fn record(
+        &self,
+        node: &Executed,
+        res: &RadixCiphertext,
+        duration: Duration,
+        cache_hit: bool,
+    ) {
+        let mut tracer = self.inner.lock().unwrap();
+        let inputs = node
+            .operands()
+            .into_iter()
+            .map(|operand| tracer.id(operand))
+            .collect();
+        let id = tracer.id(node);
+        if let Some(results) = &mut tracer.results {
+            results.entry(id).or_insert_with(|| res.clone());
+        }
+        let class = match node {
+            Executed::InClass { class, .. } => Some(class.chars().collect()),
+            _ => None,
+        };
+        tracer.write(&OpRecord {
+            id,
+            kind: node.kind(),
+            inputs,
+            duration_us: duration.as_micros() as u64,
+            cache_hit,
+            at: None,
+            c: None,
+            class,
+        });
+    }
+}
+
+impl Tracer {
+    fn id(&mut self, node: &Executed) -> usize {
+        if let Some(id) = self.ids.get(node) {
+            return *id;
+        }
+        let id = self.ids.len();
+        self.ids.insert(node.clone(), id);
+        match node {
+            Executed::CtPos { at } => self.write(&OpRecord {
+                id,
+                kind: node.kind(),
+                inputs: vec![],
+                duration_us: 0,
+                cache_hit: false,
+                at: Some(*at),
+                c: None,
+                class: None,
+            }),
+            Executed::Constant { c } => self.write(&OpRecord {
+                id,
+                kind: node.kind(),
+                inputs: vec![],
+                duration_us: 0,
+                cache_hit: false,
+                at: None,
+                c: Some(*c),
+                class: None,
+            }),
+            _ => (),
+        }
+        id
+    }
+
+    // a failing trace must not fail the execution, the first failure is
+    // logged and everything after it is dropped
+    fn write(&mut self, record: &OpRecord) {
+        if self.failed {
+            return;
+        }
+        let res = serde_json::to_writer(&mut self.writer, record)
+            .map_err(|e| e.to_string())
+            .and_then(|_| self.writer.write_all(b"\n").map_err(|e| e.to_string()));
+        if let Err(e) = res {
+            warn!("failed to write op trace, dropping the rest of it: {}", e);
+            self.failed = true;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::regex::engine::tests::KEYS;
+    use crate::regex::execution::Executed;
+    use crate::regex::trace::OpTrace;
+    use std::io::Write;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_one_line_per_op() {
+        let written = Shared::default();
+        let trace = OpTrace::to_writer(written.clone());
+        let eq = Executed::eq(Executed::ct_pos(0), Executed::constant(b'a'));
+        let res = KEYS.0.encrypt(1u64);
+        trace.record(&eq, &res, Duration::from_micros(5), false);
+        trace.record(&eq, &res, Duration::ZERO, true);
+
+        let written = String::from_utf8(written.0.lock().unwrap().clone()).unwrap();
+        let lines: Vec<serde_json::Value> = written
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(4, lines.len());
+        assert_eq!("ct_pos", lines[0]["kind"]);
+        assert_eq!(0, lines[0]["at"]);
+        assert_eq!("constant", lines[1]["kind"]);
+        assert_eq!("eq", lines[2]["kind"]);
+        assert_eq!(serde_json::json!([0, 1]), lines[2]["inputs"]);
+        assert_eq!(5, lines[2]["duration_us"]);
+        assert_eq!(lines[2]["id"], lines[3]["id"]);
+        assert_eq!(true, lines[3]["cache_hit"]);
+        assert!(trace.results().is_empty());
+    }
+
+    #[test]
+    fn test_capture_results() {
+        let trace = OpTrace::to_writer(std::io::sink());
+        let eq = Executed::eq(Executed::ct_pos(0), Executed::constant(b'a'));
+        let not = Executed::not(eq.clone());
+        trace.capture_results();
+        trace.record(&eq, &KEYS.0.encrypt(1u64), Duration::ZERO, false);
+        trace.record(&not, &KEYS.0.encrypt(0u64), Duration::ZERO, false);
+
+        // the ids of the content position and the constant come first
+        let results = trace.results();
+        assert_eq!(vec![2, 3], results.keys().copied().collect::<Vec<usize>>());
+        assert_eq!(1, KEYS.0.decrypt(&results[&2]));
+        assert_eq!(0, KEYS.0.decrypt(&results[&3]));
+    }
+}
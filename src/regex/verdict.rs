@@ -0,0 +1,228 @@
+use anyhow::{anyhow, Result};
+use rayon::prelude::*;
+use tfhe::integer::{RadixCiphertext, ServerKey};
+use tfhe::shortint::Ciphertext;
+
+use crate::regex::ciphertext::{
+    create_trivial_radix, BLOCK_SIZE, MAX_UNCHECKED_ADDS, NUM_BLOCKS, VERDICTS_PER_RADIX,
+};
+use crate::regex::engine::evaluate;
+use crate::regex::execution::{Executed, Execution};
+
+// Combines encrypted match results (encryptions of 0 or 1, e.g. as returned
+// by has_match) homomorphically.
+
+pub fn fhe_and(sk: &ServerKey, a: &RadixCiphertext, b: &RadixCiphertext) -> RadixCiphertext {
+    fhe_all(sk, &[a.clone(), b.clone()])
+}
+
+pub fn fhe_or(sk: &ServerKey, a: &RadixCiphertext, b: &RadixCiphertext) -> RadixCiphertext {
+    fhe_any(sk, &[a.clone(), b.clone()])
+}
+
+pub fn fhe_not(sk: &ServerKey, a: &RadixCiphertext) -> RadixCiphertext {
+    combine(sk, &[a.clone()], Executed::not(Executed::ct_pos(0)))
+}
+
+// whether all verdicts hold, holds for no verdicts
+pub fn fhe_all(sk: &ServerKey, verdicts: &[RadixCiphertext]) -> RadixCiphertext {
+    let plan = (0..verdicts.len())
+        .map(Executed::ct_pos)
+        .fold(Executed::ct_true(), Executed::and);
+    combine(sk, verdicts, plan)
+}
+
+// whether any of the verdicts holds, does not hold for no verdicts
+pub fn fhe_any(sk: &ServerKey, verdicts: &[RadixCiphertext]) -> RadixCiphertext {
+    let plan = (0..verdicts.len())
+        .map(Executed::ct_pos)
+        .fold(Executed::ct_false(), Executed::or);
+    combine(sk, verdicts, plan)
+}
+
+// the largest count a radix can hold
+pub const MAX_COUNT: usize = (1 << (NUM_BLOCKS * BLOCK_SIZE)) - 1;
+
+// the number of verdicts that hold. Counts are held in a single radix, so
+// more than MAX_COUNT verdicts are rejected rather than wrapping around.
+pub fn fhe_count(sk: &ServerKey, verdicts: &[RadixCiphertext]) -> Result<RadixCiphertext> {
+    if verdicts.len() > MAX_COUNT {
+        return Err(anyhow!(
+            "cannot count {} verdicts, at most {} fit in a radix",
+            verdicts.len(),
+            MAX_COUNT
+        ));
+    }
+    let mut count = create_trivial_radix(sk, 0);
+    for verdict in verdicts {
+        count = sk.smart_add(&mut count, &mut verdict.clone());
+    }
+    Ok(count)
+}
+
+// the largest cap of fhe_count_saturating: the count is kept in the message
+// and carry bits of a single block, where it must leave room for at least one
+// more verdict before it is saturated again
+pub const MAX_SATURATING_COUNT: usize = (1 << (2 * BLOCK_SIZE)) - 2;
+
+// the number of verdicts that hold, saturated at cap: any count past cap is
+// cap. Often all that is needed is "none, a few or many", and this is far
+// cheaper than fhe_count: the count is kept in a single block, verdicts are
+// added to it a few at a time, each time followed by a single bootstrap that
+// saturates it, instead of radix additions with their carry propagations.
+// Any number of verdicts can be counted. The result is a radix like any
+// count, holding at most cap.
+pub fn fhe_count_saturating(
+    sk: &ServerKey,
+    verdicts: &[RadixCiphertext],
+    cap: usize,
+) -> Result<RadixCiphertext> {
+    if cap == 0 || cap > MAX_SATURATING_COUNT {
+        return Err(anyhow!(
+            "cannot saturate counts at {}, the cap must be between 1 and {}",
+            cap,
+            MAX_SATURATING_COUNT
+        ));
+    }
+    let shortkey = tfhe::shortint::ServerKey::from(sk.clone());
+    let saturate = shortkey.generate_accumulator(move |x| x.min(cap as u64));
+    // the count and the verdicts added to it fit in the block, and their
+    // noise stays within what a bootstrap can take
+    let per_bootstrap = (MAX_UNCHECKED_ADDS - 1).min(MAX_SATURATING_COUNT + 1 - cap);
+    let mut count = shortkey.create_trivial(0);
+    for chunk in verdicts.chunks(per_bootstrap) {
+        for verdict in chunk {
+            // the lowest block of a verdict holds its 0 or 1
+            shortkey.unchecked_add_assign(&mut count, &verdict.blocks()[0]);
+        }
+        count = shortkey.keyswitch_programmable_bootstrap(&count, &saturate);
+    }
+
+    // spread over the message bits of as many blocks as the block's message
+    // and carry bits take
+    let blocks = (0..NUM_BLOCKS)
+        .map(|i| {
+            if i >= 2 {
+                return shortkey.create_trivial(0);
+            }
+            let digit = shortkey.generate_accumulator(move |x| (x >> (i * BLOCK_SIZE)) & ((1 << BLOCK_SIZE) - 1));
+            shortkey.keyswitch_programmable_bootstrap(&count, &digit)
+        })
+        .collect();
+    Ok(RadixCiphertext::from(blocks))
+}
+
+// packs verdicts into as few radix ciphertexts as possible: verdict i ends up
+// as bit i % VERDICTS_PER_RADIX of packed radix i / VERDICTS_PER_RADIX, see
+// client::unpack_verdicts. Every verdict otherwise takes a full radix, most
+// of which encrypts zeros.
+pub fn fhe_pack(sk: &ServerKey, verdicts: &[RadixCiphertext]) -> Vec<RadixCiphertext> {
+    let shortkey = tfhe::shortint::ServerKey::from(sk.clone());
+    // the lowest block of a verdict holds its 0 or 1, the others are zero
+    let clean = shortkey.generate_accumulator(|x| x & ((1 << BLOCK_SIZE) - 1));
+    verdicts
+        .par_chunks(VERDICTS_PER_RADIX)
+        .map(|chunk| {
+            let mut blocks: Vec<Ciphertext> = chunk
+                .chunks(BLOCK_SIZE)
+                .map(|bits| {
+                    let mut block = shortkey.create_trivial(0);
+                    for (bit, verdict) in bits.iter().enumerate() {
+                        let shifted = shortkey.unchecked_scalar_mul(&verdict.blocks()[0], 1 << bit);
+                        shortkey.unchecked_add_assign(&mut block, &shifted);
+                    }
+                    shortkey.keyswitch_programmable_bootstrap(&block, &clean)
+                })
+                .collect();
+            blocks.resize_with(NUM_BLOCKS, || shortkey.create_trivial(0));
+            RadixCiphertext::from(blocks)
+        })
+        .collect()
+}
+
+// the verdicts take the place of the content, so that the plan refers to
+// them by position
+fn combine(sk: &ServerKey, verdicts: &[RadixCiphertext], plan: Executed) -> RadixCiphertext {
+    let mut exec = Execution::new(sk.clone());
+    evaluate(&mut exec, verdicts, &[plan]).remove(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::regex::ciphertext::create_trivial_radix;
+    use crate::regex::engine::tests::KEYS;
+    use crate::regex::client::unpack_verdicts;
+    use crate::regex::verdict::{
+        fhe_all, fhe_and, fhe_any, fhe_count, fhe_count_saturating, fhe_not, fhe_or, fhe_pack,
+        MAX_COUNT, MAX_SATURATING_COUNT,
+    };
+    use test_case::test_case;
+
+    #[test_case(0, 0)]
+    #[test_case(0, 1)]
+    #[test_case(1, 0)]
+    #[test_case(1, 1)]
+    fn test_binary(a: u64, b: u64) {
+        let ct_a = create_trivial_radix(&KEYS.1, a);
+        let ct_b = create_trivial_radix(&KEYS.1, b);
+        assert_eq!(a & b, KEYS.0.decrypt(&fhe_and(&KEYS.1, &ct_a, &ct_b)));
+        assert_eq!(a | b, KEYS.0.decrypt(&fhe_or(&KEYS.1, &ct_a, &ct_b)));
+        assert_eq!(1 - a, KEYS.0.decrypt(&fhe_not(&KEYS.1, &ct_a)));
+    }
+
+    #[test_case(&[], 1, 0 ; "no verdicts")]
+    #[test_case(&[1, 1, 0], 0, 1)]
+    #[test_case(&[1, 1, 1], 1, 1)]
+    #[test_case(&[0, 0, 0], 0, 0)]
+    fn test_all_any(verdicts: &[u64], exp_all: u64, exp_any: u64) {
+        let ct_verdicts: Vec<_> = verdicts
+            .iter()
+            .map(|v| create_trivial_radix(&KEYS.1, *v))
+            .collect();
+        assert_eq!(exp_all, KEYS.0.decrypt(&fhe_all(&KEYS.1, &ct_verdicts)));
+        assert_eq!(exp_any, KEYS.0.decrypt(&fhe_any(&KEYS.1, &ct_verdicts)));
+        let exp_count = verdicts.iter().sum::<u64>();
+        assert_eq!(exp_count, KEYS.0.decrypt(&fhe_count(&KEYS.1, &ct_verdicts).unwrap()));
+    }
+
+    #[test]
+    fn test_count_overflow_rejected() {
+        let ct_verdicts = vec![create_trivial_radix(&KEYS.1, 1); MAX_COUNT + 1];
+        assert!(fhe_count(&KEYS.1, &ct_verdicts).is_err());
+    }
+
+    #[test_case(0, 3, 0 ; "none")]
+    #[test_case(2, 3, 2 ; "below the cap")]
+    #[test_case(3, 3, 3 ; "at the cap")]
+    #[test_case(10, 3, 3 ; "saturated")]
+    #[test_case(20, MAX_SATURATING_COUNT, 14 ; "largest cap")]
+    #[test_case(MAX_COUNT + 1, 1, 1 ; "more verdicts than a radix counts")]
+    fn test_count_saturating(holding: usize, cap: usize, exp: u64) {
+        // interleaved with verdicts that don't hold
+        let ct_verdicts: Vec<_> = (0..2 * holding)
+            .map(|i| create_trivial_radix(&KEYS.1, (i % 2) as u64))
+            .collect();
+        let ct_count = fhe_count_saturating(&KEYS.1, &ct_verdicts, cap).unwrap();
+        assert_eq!(exp, KEYS.0.decrypt(&ct_count));
+    }
+
+    #[test_case(0)]
+    #[test_case(MAX_SATURATING_COUNT + 1)]
+    fn test_count_saturating_invalid_cap(cap: usize) {
+        assert!(fhe_count_saturating(&KEYS.1, &[], cap).is_err());
+    }
+
+    #[test_case(&[1, 0, 1] ; "single radix")]
+    #[test_case(&[1, 0, 1, 1, 0, 0, 1, 0, 1, 1] ; "multiple radixes")]
+    fn test_pack(verdicts: &[u64]) {
+        let ct_verdicts: Vec<_> = verdicts
+            .iter()
+            .map(|v| create_trivial_radix(&KEYS.1, *v))
+            .collect();
+        let packed = fhe_pack(&KEYS.1, &ct_verdicts);
+        assert_eq!((verdicts.len() + 7) / 8, packed.len());
+
+        let exp: Vec<bool> = verdicts.iter().map(|v| *v == 1).collect();
+        assert_eq!(exp, unpack_verdicts(&KEYS.0, &packed, verdicts.len()));
+    }
+}
@@ -0,0 +1,328 @@
+use rayon::prelude::*;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, RwLock};
+use tfhe::integer::{RadixCiphertext, ServerKey};
+
+use crate::regex::ciphertext::{trivial_radix, ClassAccumulators, Fingerprint, Progress};
+use crate::regex::class::CharClass;
+use crate::regex::execution::EqAccumulators;
+
+// Content is ascii, so only these constants are ever compared against.
+const NUM_CONSTANTS: usize = 128;
+
+// the classes patterns test for most often, their lookup tables (and those of
+// their complements) are precomputed when warming up
+fn common_classes() -> Vec<CharClass> {
+    let digit = CharClass::from_range(b'0', b'9');
+    let lower = CharClass::from_range(b'a', b'z');
+    let upper = CharClass::from_range(b'A', b'Z');
+    let alpha = lower.union(&upper);
+    let alnum = alpha.union(&digit);
+    let word = alnum.union(&CharClass::from_chars(b"_"));
+    let hex = digit
+        .union(&CharClass::from_range(b'a', b'f'))
+        .union(&CharClass::from_range(b'A', b'F'));
+    let space = CharClass::from_chars(b" \t\n\r\x0b\x0c");
+    [digit, lower, upper, alpha, alnum, word, hex, space]
+        .into_iter()
+        .flat_map(|class| [class, class.complement()])
+        .collect()
+}
+
+// A server key together with everything derived from it that evaluating a
+// pattern needs: the shortint key that lookup tables are generated with, the
+// trivial encryptions of constants, the equality lookup tables per constant
+// and the class membership lookup tables. A cold key derives these on demand
+// in every execution, a warmed up key (see warmup) has them precomputed once.
+// Cloning is cheap, clones share the precomputed state.
+#[derive(Clone)]
+pub struct WarmKey {
+    inner: Arc<Warmed>,
+}
+
+struct Warmed {
+    sk: ServerKey,
+    shortkey: tfhe::shortint::ServerKey,
+    constants: Vec<RadixCiphertext>,
+    eq_accumulators: Vec<EqAccumulators>,
+    class_accumulators: Option<ClassAccumulators>,
+}
+
+impl WarmKey {
+    pub(crate) fn cold(sk: ServerKey) -> Self {
+        let shortkey = tfhe::shortint::ServerKey::from(sk.clone());
+        Self {
+            inner: Arc::new(Warmed {
+                sk,
+                shortkey,
+                constants: vec![],
+                eq_accumulators: vec![],
+                class_accumulators: None,
+            }),
+        }
+    }
+
+    pub fn server_key(&self) -> &ServerKey {
+        &self.inner.sk
+    }
+
+    pub fn is_warm(&self) -> bool {
+        !self.inner.eq_accumulators.is_empty()
+    }
+
+    pub(crate) fn shortkey(&self) -> &tfhe::shortint::ServerKey {
+        &self.inner.shortkey
+    }
+
+    pub(crate) fn constant(&self, c: u8) -> RadixCiphertext {
+        match self.inner.constants.get(c as usize) {
+            Some(ct) => ct.clone(),
+            None => trivial_radix(&self.inner.shortkey, c as u64),
+        }
+    }
+
+    pub(crate) fn eq_accumulators(&self, c: u8) -> Option<&EqAccumulators> {
+        self.inner.eq_accumulators.get(c as usize)
+    }
+
+    pub(crate) fn class_accumulators(&self) -> Option<&ClassAccumulators> {
+        self.inner.class_accumulators.as_ref()
+    }
+}
+
+// The warmed up server keys of the clients a server serves, by client id.
+// Keys are warmed up when they are registered, so that the first match for a
+// client does not pay for it.
+#[derive(Default)]
+pub struct KeyRegistry {
+    keys: RwLock<HashMap<String, WarmKey>>,
+}
+
+impl KeyRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn register(&self, id: &str, sk: &ServerKey) -> WarmKey {
+        self.register_with_progress(id, sk, |_| ())
+    }
+
+    // warms up sk (see warmup_with_progress) and registers it under id,
+    // replacing any key registered under id before
+    pub fn register_with_progress<F: FnMut(Progress)>(
+        &self,
+        id: &str,
+        sk: &ServerKey,
+        progress: F,
+    ) -> WarmKey {
+        let key = warmup_with_progress(sk, progress);
+        self.keys.write().unwrap().insert(id.to_string(), key.clone());
+        key
+    }
+
+    pub fn get(&self, id: &str) -> Option<WarmKey> {
+        self.keys.read().unwrap().get(id).cloned()
+    }
+
+    pub fn remove(&self, id: &str) -> Option<WarmKey> {
+        self.keys.write().unwrap().remove(id)
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys.read().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+// The server keys a server was sent besides its own (see serve::KeyRef), by
+// fingerprint, so that later requests need not send them again. At most
+// slots keys are kept: keeping one more evicts the least recently used.
+pub struct KeyCache {
+    slots: usize,
+    // least recently used first
+    keys: Mutex<Vec<(Fingerprint, WarmKey)>>,
+}
+
+impl KeyCache {
+    pub fn new(slots: usize) -> Self {
+        Self {
+            slots,
+            keys: Mutex::new(vec![]),
+        }
+    }
+
+    pub fn slots(&self) -> usize {
+        self.slots
+    }
+
+    // the key of fingerprint, which is now the most recently used
+    pub fn get(&self, fingerprint: &Fingerprint) -> Option<WarmKey> {
+        let mut keys = self.keys.lock().unwrap();
+        let i = keys.iter().position(|(fp, _)| fp == fingerprint)?;
+        let entry = keys.remove(i);
+        let key = entry.1.clone();
+        keys.push(entry);
+        Some(key)
+    }
+
+    // keeps key (nothing without slots), resulting in the fingerprint of the
+    // key evicted for it
+    pub fn insert(&self, fingerprint: Fingerprint, key: WarmKey) -> Option<Fingerprint> {
+        if self.slots == 0 {
+            return None;
+        }
+        let mut keys = self.keys.lock().unwrap();
+        keys.retain(|(fp, _)| *fp != fingerprint);
+        keys.push((fingerprint, key));
+        if keys.len() > self.slots {
+            return Some(keys.remove(0).0);
+        }
+        None
+    }
+
+    // least recently used first
+    pub fn fingerprints(&self) -> Vec<Fingerprint> {
+        self.keys.lock().unwrap().iter().map(|(fp, _)| *fp).collect()
+    }
+}
+
+pub fn warmup(sk: &ServerKey) -> WarmKey {
+    warmup_with_progress(sk, |_| ())
+}
+
+// precomputes everything a has_match call would otherwise derive from the
+// server key, reporting progress after each stage
+pub fn warmup_with_progress<F: FnMut(Progress)>(sk: &ServerKey, mut progress: F) -> WarmKey {
+    let total = 4;
+    let shortkey = tfhe::shortint::ServerKey::from(sk.clone());
+    progress(Progress {
+        stage: "shortint key",
+        done: 1,
+        total,
+    });
+
+    let constants: Vec<RadixCiphertext> = (0..NUM_CONSTANTS)
+        .map(|c| trivial_radix(&shortkey, c as u64))
+        .collect();
+    progress(Progress {
+        stage: "constants",
+        done: 2,
+        total,
+    });
+
+    let eq_accumulators: Vec<EqAccumulators> = (0..NUM_CONSTANTS)
+        .into_par_iter()
+        .map(|c| EqAccumulators::new(&shortkey, c as u8))
+        .collect();
+    progress(Progress {
+        stage: "lookup tables",
+        done: 3,
+        total,
+    });
+
+    let class_accumulators = ClassAccumulators::new(&shortkey, &common_classes());
+    progress(Progress {
+        stage: "class lookup tables",
+        done: 4,
+        total,
+    });
+
+    WarmKey {
+        inner: Arc::new(Warmed {
+            sk: sk.clone(),
+            shortkey,
+            constants,
+            eq_accumulators,
+            class_accumulators: Some(class_accumulators),
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::regex::ciphertext::{class_flag_with, fingerprint, flag_to_radix, Progress};
+    use crate::regex::class::CharClass;
+    use crate::regex::engine::tests::{encrypt_trivial, KEYS};
+    use crate::regex::warmup::{warmup_with_progress, KeyCache, KeyRegistry, WarmKey};
+
+    #[test]
+    fn test_warmup_progress() {
+        let mut reported: Vec<Progress> = vec![];
+        let key = warmup_with_progress(&KEYS.1, |p| reported.push(p));
+
+        assert!(key.is_warm());
+        assert_eq!(
+            vec!["shortint key", "constants", "lookup tables", "class lookup tables"],
+            reported.iter().map(|p| p.stage).collect::<Vec<&str>>(),
+        );
+        assert!(reported.iter().all(|p| p.total == 4));
+        assert!(key.class_accumulators().unwrap().len() > 0);
+    }
+
+    #[test]
+    fn test_constants_match_cold_key() {
+        let warm = warmup_with_progress(&KEYS.1, |_| ());
+        let cold = WarmKey::cold(KEYS.1.clone());
+        assert!(!cold.is_warm());
+
+        for c in [0u8, 1, b'a', 127] {
+            assert_eq!(c as u64, KEYS.0.decrypt(&warm.constant(c)));
+            assert_eq!(c as u64, KEYS.0.decrypt(&cold.constant(c)));
+        }
+    }
+
+    #[test]
+    fn test_class_tables_match_cold_key() {
+        let warm = warmup_with_progress(&KEYS.1, |_| ());
+        let shortkey = warm.shortkey();
+        let digit = CharClass::from_range(b'0', b'9');
+        let odd = CharClass::from_chars(b"13579");
+
+        for c in [b'0', b'5', b'a', b' '] {
+            let ct = warm.constant(c);
+            for class in [digit, digit.complement(), odd] {
+                let exp = class.contains(c) as u64;
+                let warm_flag = class_flag_with(shortkey, &ct, &class, warm.class_accumulators());
+                let cold_flag = class_flag_with(shortkey, &ct, &class, None);
+                assert_eq!(exp, KEYS.0.decrypt(&flag_to_radix(shortkey, warm_flag)));
+                assert_eq!(exp, KEYS.0.decrypt(&flag_to_radix(shortkey, cold_flag)));
+            }
+        }
+    }
+
+    #[test]
+    fn test_key_registry() {
+        let registry = KeyRegistry::new();
+        assert!(registry.get("client").is_none());
+
+        let key = registry.register("client", &KEYS.1);
+        assert!(key.is_warm());
+        assert!(registry.get("client").unwrap().is_warm());
+        assert_eq!(1, registry.len());
+
+        assert!(registry.remove("client").is_some());
+        assert!(registry.is_empty());
+    }
+
+    #[test]
+    fn test_key_cache() {
+        let [a, b, c] = ["a", "b", "c"].map(|s| fingerprint(&encrypt_trivial(s)).unwrap());
+        let key = WarmKey::cold(KEYS.1.clone());
+
+        let cache = KeyCache::new(2);
+        assert_eq!(None, cache.insert(a, key.clone()));
+        assert_eq!(None, cache.insert(b, key.clone()));
+        // a is used after b, so b is evicted first
+        assert!(cache.get(&a).is_some());
+        assert_eq!(Some(b), cache.insert(c, key.clone()));
+        assert!(cache.get(&b).is_none());
+        assert_eq!(vec![a, c], cache.fingerprints());
+
+        let uncached = KeyCache::new(0);
+        assert_eq!(None, uncached.insert(a, key));
+        assert!(uncached.get(&a).is_none());
+    }
+}